@@ -1,18 +1,30 @@
-const CHUNK_SIZE: usize = 512;
+/// The default target chunk size, in bytes
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
 
 pub struct Chunks<E, I: Iterator<Item=Result<u8, E>> + ?Sized> {
     data: Vec<u8>,
+    size: usize,
     iter: I,
 }
 
 pub trait Chunkable<E> where Self: Iterator<Item=Result<u8, E>> {
+    /// Split the stream into chunks of the default size
     fn chunks(self) -> Chunks<E, Self>;
+
+    /// Split the stream into chunks of at most `size` bytes
+    fn chunks_sized(self, size: usize) -> Chunks<E, Self>;
 }
 
 impl<E,I> Chunkable<E> for I where I: Sized+Iterator<Item=Result<u8, E>> {
     fn chunks(self) -> Chunks<E, Self> {
+        self.chunks_sized(DEFAULT_CHUNK_SIZE)
+    }
+
+    fn chunks_sized(self, size: usize) -> Chunks<E, Self> {
+        assert!(size > 0, "chunk size must be nonzero");
         Chunks {
-            data: Vec::with_capacity(CHUNK_SIZE),
+            data: Vec::with_capacity(size),
+            size: size,
             iter: self
         }
     }
@@ -31,7 +43,7 @@ impl<E, I: Iterator<Item=Result<u8, E>>> Iterator for Chunks<E, I> {
             self.data.push(x);
 
             // check whether to break the chunk
-            if self.data.len() == CHUNK_SIZE {
+            if self.data.len() == self.size {
                 return Some(Ok(self.data.split_off(0)));
             }
         }
@@ -50,7 +62,7 @@ fn chunk_test() {
     use std::iter::repeat; 
 
     let ok: Result<u8, ()> = Ok(1u8);
-    let mut h1 = repeat(ok).take(600).chunks();
+    let mut h1 = repeat(ok).take(600).chunks_sized(512);
     let r1 = h1.next();
     let r2 = h1.next();
 
@@ -68,3 +80,15 @@ fn chunk_test() {
     assert_eq!(r2.len(), 600-512);
     assert_eq!(r2.iter().map(|x| x.clone() as u32).sum::<u32>(), 600-512);
 }
+
+#[test]
+fn default_chunk_test() {
+    use std::iter::repeat;
+
+    let ok: Result<u8, ()> = Ok(0u8);
+    let sizes: Vec<usize> = repeat(ok).take(DEFAULT_CHUNK_SIZE + 10)
+                                      .chunks()
+                                      .map(|c| c.unwrap().len())
+                                      .collect();
+    assert_eq!(sizes, vec![DEFAULT_CHUNK_SIZE, 10]);
+}
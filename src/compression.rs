@@ -0,0 +1,190 @@
+extern crate flate2;
+extern crate zstd;
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use self::flate2::write::DeflateEncoder;
+use self::flate2::read::DeflateDecoder;
+
+use keys::SEAL_OVERHEAD;
+
+/// Compression algorithms that can be applied to stored objects
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    /// Store the data as-is. Useful for data that's already compressed.
+    None,
+
+    /// Compress the data with DEFLATE
+    Deflate,
+
+    /// Compress the data with Zstandard, which is faster than DEFLATE at
+    /// similar ratios
+    Zstd,
+}
+
+impl Compression {
+    /// The header byte used to mark data compressed with this algorithm
+    fn header(&self) -> u8 {
+        match *self {
+            Compression::None    => 0u8,
+            Compression::Deflate => 1u8,
+            Compression::Zstd    => 2u8,
+        }
+    }
+
+    fn from_header(b: u8) -> Option<Compression> {
+        match b {
+            0u8 => Some(Compression::None),
+            1u8 => Some(Compression::Deflate),
+            2u8 => Some(Compression::Zstd),
+            _   => None
+        }
+    }
+
+    /// The name used for the algorithm in configuration files and options
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Compression::None    => "none",
+            Compression::Deflate => "deflate",
+            Compression::Zstd    => "zstd",
+        }
+    }
+
+    /// Look up an algorithm by its name
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none"    => Some(Compression::None),
+            "deflate" => Some(Compression::Deflate),
+            "zstd"    => Some(Compression::Zstd),
+            _         => None
+        }
+    }
+
+    /// The range of compression levels the algorithm accepts. Higher levels
+    /// compress better but more slowly.
+    pub fn levels(&self) -> (u32, u32) {
+        match *self {
+            Compression::None    => (0, 0),
+            Compression::Deflate => (0, 9),
+            Compression::Zstd    => (1, 21),
+        }
+    }
+
+    /// The level used when none is configured
+    pub fn default_level(&self) -> u32 {
+        match *self {
+            Compression::None    => 0,
+            Compression::Deflate => 6,
+            Compression::Zstd    => 3,
+        }
+    }
+
+    /// Check whether a compression level is valid for the algorithm
+    pub fn is_valid_level(&self, level: u32) -> bool {
+        let (lo, hi) = self.levels();
+        level >= lo && level <= hi
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self { Compression::Deflate }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Compress the given data, prefixing it with a header byte identifying the
+/// algorithm used.
+///
+/// If no level is given, the algorithm's default is used. Levels outside the
+/// algorithm's range are clamped to it.
+pub fn compress(alg: Compression, level: Option<u32>, data: &[u8])
+        -> io::Result<Vec<u8>> {
+    let (lo, hi) = alg.levels();
+    let level = level.unwrap_or(alg.default_level()).max(lo).min(hi);
+    // leave room for encryption, so the result can be sealed without copying
+    let mut out = Vec::with_capacity(data.len() + 1 + SEAL_OVERHEAD);
+    out.push(alg.header());
+
+    match alg {
+        Compression::None => out.extend_from_slice(data),
+        Compression::Deflate => {
+            let mut enc = DeflateEncoder::new(out,
+                                              flate2::Compression::new(level));
+            enc.write_all(data)?;
+            out = enc.finish()?;
+        },
+        Compression::Zstd => {
+            zstd::stream::copy_encode(data, &mut out, level as i32)?;
+        }
+    }
+
+    Ok(out)
+}
+
+/// Decompress data produced by `compress`
+pub fn decompress(mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let alg = data.first().and_then(|&b| Compression::from_header(b))
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData,
+                              "unknown compression type"))?;
+
+    match alg {
+        Compression::None => {
+            data.remove(0);
+            Ok(data)
+        },
+        Compression::Deflate => {
+            let mut out = Vec::new();
+            DeflateDecoder::new(&data[1..]).read_to_end(&mut out)?;
+            Ok(out)
+        },
+        Compression::Zstd => zstd::stream::decode_all(&data[1..])
+    }
+}
+
+#[test]
+fn compression_roundtrip_test() {
+    let data = vec![42u8; 64 * 1024];
+
+    let packed = compress(Compression::Deflate, None, &data).unwrap();
+    assert!(packed.len() < data.len() / 16);
+    assert_eq!(decompress(packed).unwrap(), data);
+
+    let packed = compress(Compression::Zstd, None, &data).unwrap();
+    assert!(packed.len() < data.len() / 16);
+    assert_eq!(decompress(packed).unwrap(), data);
+
+    let stored = compress(Compression::None, None, &data).unwrap();
+    assert_eq!(stored.len(), data.len() + 1);
+    assert_eq!(decompress(stored).unwrap(), data);
+}
+
+#[test]
+fn compression_levels_test() {
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+
+    // every level decompresses the same way, and out-of-range levels are
+    // clamped rather than rejected
+    for &alg in [Compression::Deflate, Compression::Zstd].iter() {
+        let (lo, hi) = alg.levels();
+        for &level in [lo, alg.default_level(), hi, hi + 10].iter() {
+            let packed = compress(alg, Some(level), &data).unwrap();
+            assert_eq!(decompress(packed).unwrap(), data);
+        }
+        assert!(alg.is_valid_level(alg.default_level()));
+        assert!(!alg.is_valid_level(hi + 1));
+        assert_eq!(Compression::from_name(alg.name()), Some(alg));
+    }
+    assert!(Compression::from_name("lzma").is_none());
+}
+
+#[test]
+fn bad_header_test() {
+    assert!(decompress(vec![]).is_err());
+    assert!(decompress(vec![0xff, 1, 2, 3]).is_err());
+}
@@ -9,7 +9,9 @@ use std::env;
 use pest::*;
 use pest;
 
-#[derive(Debug)]
+use compression::Compression;
+
+#[derive(Debug, Clone)]
 pub struct TargetOptions {
     /// whether data on this destination needs replicated elsewhere
     pub reliable: bool,
@@ -17,15 +19,79 @@ pub struct TargetOptions {
     /// the relative costs of data upload and download for this target
     pub upload_cost: i32,
     pub download_cost: i32,
+
+    /// the algorithm to compress objects with before storing them
+    pub compression: Compression,
+
+    /// the compression level to use, or `None` for the algorithm's default
+    pub compression_level: Option<u32>,
+
+    /// whether to refuse connecting to hosts whose keys aren't already known.
+    /// Unset, unknown keys are trusted on first use.
+    pub strict_host_keys: bool,
+
+    /// how many seconds a target may stay locked by another node before the
+    /// lock is assumed to be stale and broken
+    pub lock_timeout: u64,
+
+    /// how many seconds to wait for a connection to the target to be set up
+    pub connect_timeout: u64,
+
+    /// how often in seconds to send keepalive messages over idle connections,
+    /// or zero to never send them
+    pub keepalive: u64,
+
+    /// the maximum upload and download rates in bytes per second, or zero
+    /// for no limit
+    pub upload_limit: u64,
+    pub download_limit: u64,
+
+    /// whether to refuse removing anything stored on this target, so that a
+    /// compromised node can add snapshots but not destroy old ones
+    pub append_only: bool,
+
+    /// the hex SHA-256 hash of the admin key which lifts `append_only`, if
+    /// one has been set
+    pub admin_key_hash: Option<String>,
 }
 
-#[derive(Debug)]
+/// Lock timeout used unless a target sets its own
+pub const DEFAULT_LOCK_TIMEOUT: u64 = 24 * 60 * 60;
+
+/// Connection timeout used unless a target sets its own
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+
+/// Keepalive interval used unless a target sets its own
+pub const DEFAULT_KEEPALIVE: u64 = 60;
+
+impl Default for TargetOptions {
+    fn default() -> Self {
+        TargetOptions {
+            reliable: true,
+            upload_cost: 1,
+            download_cost: 1,
+            compression: Compression::default(),
+            compression_level: None,
+            strict_host_keys: true,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            keepalive: DEFAULT_KEEPALIVE,
+            upload_limit: 0,
+            download_limit: 0,
+            append_only: false,
+            admin_key_hash: None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct BackupTarget {
     pub name: String,
     pub url: Url,
     pub user: Option<String>,
     pub password: Option<String>,
     pub key_file: Option<PathBuf>,
+    pub agent_identity: Option<String>,
     pub options: TargetOptions
 }
 
@@ -45,8 +111,17 @@ pub struct Config {
     /// Backup target groups
     pub target_groups: Vec<TargetGroup>,
 
-    /// The current node's name
-    pub node_name: String
+    /// The current node's name, which the node's snapshots are stored under.
+    /// Changing it starts a new snapshot chain unless the old one is renamed.
+    pub node_name: String,
+
+    /// Where to keep the keystore, if not in the data directory. Relative
+    /// paths are taken relative to the config file.
+    pub keystore: Option<PathBuf>,
+
+    /// Extensions of files which are stored without compression, since their
+    /// contents are compressed already
+    pub no_compress_exts: Vec<String>
 }
 
 #[derive(Debug)]
@@ -65,9 +140,21 @@ pub enum TargetEntry {
     User(String),
     Password(String),
     KeyFile(PathBuf),
+    AgentIdentity(String),
     Reliable(bool),
     UploadCost(i32),
     DownloadCost(i32),
+    Compress(bool),
+    Compression(Compression),
+    CompressionLevel(u32),
+    StrictHostKeys(bool),
+    LockTimeout(u64),
+    ConnectTimeout(u64),
+    Keepalive(u64),
+    UploadLimit(u64),
+    DownloadLimit(u64),
+    AppendOnly(bool),
+    AdminKeyHash(String),
 }
 
 // set up the parser and run it
@@ -90,25 +177,45 @@ impl_rdp! {
         user = { ["user"] ~ eq ~ string ~ nl}
         password = { ["password"] ~ eq ~ string ~ nl}
         key_file = { ["key-file"] ~ eq ~ string ~ nl}
+        agent_identity = { ["agent-identity"] ~ eq ~ string ~ nl}
         reliable = { ["reliable"] ~ eq ~ boolean ~ nl}
         upload_cost = { ["upload-cost"] ~ eq ~ integer ~ nl}
         download_cost = { ["download-cost"] ~ eq ~ integer ~ nl}
-        option = _{ reliable | upload_cost | download_cost}
+        compression_alg = @{ ['a'..'z']+ }
+        compression = { ["compression"] ~ eq ~ compression_alg ~ nl}
+        compression_level = { ["compression-level"] ~ eq ~ integer ~ nl}
+        compress = { ["compress"] ~ eq ~ boolean ~ nl}
+        strict_host_keys = { ["strict-host-keys"] ~ eq ~ boolean ~ nl}
+        lock_timeout = { ["lock-timeout"] ~ eq ~ integer ~ nl}
+        connect_timeout = { ["connect-timeout"] ~ eq ~ integer ~ nl}
+        keepalive = { ["keepalive"] ~ eq ~ integer ~ nl}
+        upload_limit = { ["upload-limit"] ~ eq ~ integer ~ nl}
+        download_limit = { ["download-limit"] ~ eq ~ integer ~ nl}
+        append_only = { ["append-only"] ~ eq ~ boolean ~ nl}
+        admin_key_hash = { ["admin-key-hash"] ~ eq ~ string ~ nl}
+        option = _{ reliable | upload_cost | download_cost | compression |
+                    compression_level | compress | strict_host_keys | lock_timeout | connect_timeout |
+                    keepalive | upload_limit | download_limit | append_only |
+                    admin_key_hash }
         target = { ["target"] ~ par_tgt_name ~ open ~
-                (url | user | password | key_file | option)+ ~
+                (url | user | password | key_file | agent_identity | option)+ ~
             close}
         target_group = {
             ["target-group"] ~ ["("] ~ target_name ~ [")"] ~ open ~
                 target_name+ ~
             close}
         node_name = { ["node-name"] ~ eq ~ target_name ~ nl? }
+        keystore = { ["keystore"] ~ eq ~ string ~ nl? }
+        no_compress_ext = { ["no-compress-ext"] ~ eq ~ string ~ nl? }
         conf_eoi = {eoi}
-        config = { soi ~ ( node_name | target | target_group )* ~ conf_eoi }
+        config = { soi ~ ( node_name | keystore | no_compress_ext | target |
+                           target_group )* ~
+                   conf_eoi }
     }
 
     process! {
         _string(&self) -> String {
-            (&s: string) => { String::from(&s[1..s.len()-1]) }
+            (&s: string) => { unescape(&s[1..s.len()-1]) }
         }
         _bool(&self) -> bool { (&b: boolean) => (b == "true") }
         _integer(&self) -> i32 {
@@ -120,11 +227,41 @@ impl_rdp! {
             (_: password, s: _string()) => Ok(TargetEntry::Password(s)),
             (_: key_file, s: _string()) =>
                 Ok(TargetEntry::KeyFile(PathBuf::from(s))),
+            (_: agent_identity, s: _string()) =>
+                Ok(TargetEntry::AgentIdentity(s)),
             (_: reliable, b: _bool()) => Ok(TargetEntry::Reliable(b)),
             (_: upload_cost, n: _integer()) => {
                 Ok(TargetEntry::UploadCost(n)) },
             (_: download_cost, n: _integer()) => {
                 Ok(TargetEntry::DownloadCost(n)) },
+            (_: compression, &a: compression_alg) => Compression::from_name(a)
+                .map(TargetEntry::Compression)
+                .ok_or(format!("Unknown compression algorithm {}", a)),
+            (_: compression_level, &x: integer) => x.parse::<u32>()
+                .map(TargetEntry::CompressionLevel)
+                .map_err(|_| String::from("Invalid compression-level")),
+            (_: compress, b: _bool()) => Ok(TargetEntry::Compress(b)),
+            (_: strict_host_keys, b: _bool()) =>
+                Ok(TargetEntry::StrictHostKeys(b)),
+            (_: lock_timeout, &x: integer) => x.parse::<u64>()
+                .map(TargetEntry::LockTimeout)
+                .map_err(|_| String::from("Invalid lock-timeout")),
+            (_: connect_timeout, &x: integer) => x.parse::<u64>()
+                .map(TargetEntry::ConnectTimeout)
+                .map_err(|_| String::from("Invalid connect-timeout")),
+            (_: keepalive, &x: integer) => x.parse::<u64>()
+                .map(TargetEntry::Keepalive)
+                .map_err(|_| String::from("Invalid keepalive")),
+            (_: upload_limit, &x: integer) => x.parse::<u64>()
+                .map(TargetEntry::UploadLimit)
+                .map_err(|_| String::from("Invalid upload-limit")),
+            (_: download_limit, &x: integer) => x.parse::<u64>()
+                .map(TargetEntry::DownloadLimit)
+                .map_err(|_| String::from("Invalid download-limit")),
+            (_: append_only, b: _bool()) => Ok(TargetEntry::AppendOnly(b)),
+            (_: admin_key_hash, h: _string()) => {
+                if valid_key_hash(&h) { Ok(TargetEntry::AdminKeyHash(h)) }
+                else { Err(String::from("Invalid admin-key-hash")) } },
         }
         _node_name(&self) -> String {
             (&n: target_name) => { String::from(n) } }
@@ -149,9 +286,21 @@ impl_rdp! {
                 let mut user = None;
                 let mut password = None;
                 let mut key_file = None;
+                let mut agent_identity = None;
                 let mut reliable = None;
                 let mut upload = None;
                 let mut download = None;
+                let mut compress = None;
+                let mut compression = None;
+                let mut compression_level = None;
+                let mut strict = None;
+                let mut lock_timeout = None;
+                let mut connect_timeout = None;
+                let mut keepalive = None;
+                let mut upload_limit = None;
+                let mut download_limit = None;
+                let mut append_only = None;
+                let mut admin_key_hash = None;
 
                 if body.is_err() { return Err(body.unwrap_err()); }
 
@@ -173,6 +322,10 @@ impl_rdp! {
                             if key_file.is_some() {
                                 return Err(String::from("Duplicate keyfile found"));
                             } else { key_file = Some(p) } }
+                        TargetEntry::AgentIdentity(x) => {
+                            if agent_identity.is_some() {
+                                return Err(String::from("Duplicate agent-identity found"));
+                            } else { agent_identity = Some(x) } }
                         TargetEntry::Reliable(x) => {
                             if reliable.is_some() {
                                 return Err(String::from("Duplicate reliable found"));
@@ -185,21 +338,93 @@ impl_rdp! {
                             if download.is_some() {
                                 return Err(String::from("Duplicate download-cost found")); }
                             else { download = Some(x) } }
+                        TargetEntry::Compress(x) => {
+                            if compress.is_some() {
+                                return Err(String::from("Duplicate compress found")); }
+                            else { compress = Some(x) } }
+                        TargetEntry::Compression(x) => {
+                            if compression.is_some() {
+                                return Err(String::from("Duplicate compression found")); }
+                            else { compression = Some(x) } }
+                        TargetEntry::CompressionLevel(x) => {
+                            if compression_level.is_some() {
+                                return Err(String::from("Duplicate compression-level found")); }
+                            else { compression_level = Some(x) } }
+                        TargetEntry::StrictHostKeys(x) => {
+                            if strict.is_some() {
+                                return Err(String::from("Duplicate strict-host-keys found")); }
+                            else { strict = Some(x) } }
+                        TargetEntry::LockTimeout(x) => {
+                            if lock_timeout.is_some() {
+                                return Err(String::from("Duplicate lock-timeout found")); }
+                            else { lock_timeout = Some(x) } }
+                        TargetEntry::ConnectTimeout(x) => {
+                            if connect_timeout.is_some() {
+                                return Err(String::from("Duplicate connect-timeout found")); }
+                            else { connect_timeout = Some(x) } }
+                        TargetEntry::Keepalive(x) => {
+                            if keepalive.is_some() {
+                                return Err(String::from("Duplicate keepalive found")); }
+                            else { keepalive = Some(x) } }
+                        TargetEntry::UploadLimit(x) => {
+                            if upload_limit.is_some() {
+                                return Err(String::from("Duplicate upload-limit found")); }
+                            else { upload_limit = Some(x) } }
+                        TargetEntry::DownloadLimit(x) => {
+                            if download_limit.is_some() {
+                                return Err(String::from("Duplicate download-limit found")); }
+                            else { download_limit = Some(x) } }
+                        TargetEntry::AppendOnly(x) => {
+                            if append_only.is_some() {
+                                return Err(String::from("Duplicate append-only found")); }
+                            else { append_only = Some(x) } }
+                        TargetEntry::AdminKeyHash(x) => {
+                            if admin_key_hash.is_some() {
+                                return Err(String::from("Duplicate admin-key-hash found")); }
+                            else { admin_key_hash = Some(x) } }
                     }
                 }
 
                 if url.is_none() {
                     return Err(String::from("Target group must contain URL")); }
 
+                // `compress` is the older way of turning compression on or off
+                let compression = match (compression, compress) {
+                    (Some(_), Some(_)) => return Err(String::from(
+                            "Only one of compress and compression may be set")),
+                    (Some(c), None)     => c,
+                    (None, Some(false)) => Compression::None,
+                    (None, _)           => Compression::default()
+                };
+                if let Some(l) = compression_level {
+                    if !compression.is_valid_level(l) {
+                        return Err(format!("Invalid compression-level for {}",
+                                           compression));
+                    }
+                }
+
                 Ok(BackupTarget {
                     name: String::from(n),
                     url: url.unwrap(),
                     user: user, password: password,
                     key_file: key_file,
+                    agent_identity: agent_identity,
                     options: TargetOptions {
                         reliable: reliable.unwrap_or(false),
                         upload_cost: upload.unwrap_or(1) as i32,
-                        download_cost: download.unwrap_or(1) as i32}})
+                        download_cost: download.unwrap_or(1) as i32,
+                        compression: compression,
+                        compression_level: compression_level,
+                        strict_host_keys: strict.unwrap_or(true),
+                        lock_timeout: lock_timeout
+                            .unwrap_or(DEFAULT_LOCK_TIMEOUT),
+                        connect_timeout: connect_timeout
+                            .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+                        keepalive: keepalive.unwrap_or(DEFAULT_KEEPALIVE),
+                        upload_limit: upload_limit.unwrap_or(0),
+                        download_limit: download_limit.unwrap_or(0),
+                        append_only: append_only.unwrap_or(false),
+                        admin_key_hash: admin_key_hash}})
             }
         }
         _targets(&self) -> Vec<String> {
@@ -216,8 +441,8 @@ impl_rdp! {
         _target_group(&self) -> TargetGroup {
             (_: target_group, &nm: target_name, _: open, body: _targets()) => {
                 TargetGroup { name: String::from(nm), members: body }}}
-        _config_body(&self) -> Result<(Option<String>, Vec<BackupTarget>, Vec<TargetGroup>), String> {
-            (_: conf_eoi) => Ok((None, Vec::new(), Vec::new())),
+        _config_body(&self) -> Result<(Option<String>, Vec<BackupTarget>, Vec<TargetGroup>, Option<PathBuf>, Option<Vec<String>>), String> {
+            (_: conf_eoi) => Ok((None, Vec::new(), Vec::new(), None, None)),
             (_: node_name, n: _node_name(), rest: _config_body()) =>
                 rest.and_then(|mut r| {
                     if r.0.is_some() {
@@ -227,6 +452,24 @@ impl_rdp! {
                         Ok(r)
                     }
                 }),
+            (_: keystore, p: _string(), rest: _config_body()) =>
+                rest.and_then(|mut r| {
+                    if r.3.is_some() {
+                        Err(String::from("Found duplicate keystore"))
+                    } else {
+                        r.3 = Some(PathBuf::from(p));
+                        Ok(r)
+                    }
+                }),
+            (_: no_compress_ext, e: _string(), rest: _config_body()) =>
+                rest.and_then(|mut r| {
+                    if r.4.is_some() {
+                        Err(String::from("Found duplicate no-compress-ext"))
+                    } else {
+                        r.4 = Some(split_extensions(&e));
+                        Ok(r)
+                    }
+                }),
             (_: target, tgt: _target(), rest: _config_body()) =>
                 match tgt {
                     Err(s) => Err(s),
@@ -237,13 +480,15 @@ impl_rdp! {
         }
         _config(&self) -> Result<Config, String> {
             (_: config, body: _config_body()) => {
-                body.and_then(|(nm, tgts, grps)|
+                body.and_then(|(nm, tgts, grps, ks, exts)|
                     if let Some(nm) = nm {
                         Ok(Config {
                             node_name: nm,
                             location: PathBuf::new(),
                             targets: tgts,
-                            target_groups: grps
+                            target_groups: grps,
+                            keystore: ks,
+                            no_compress_exts: exts.unwrap_or_default()
                         })
                     } else {
                         Err(String::from("No node name specified"))
@@ -256,13 +501,47 @@ impl_rdp! {
 impl BackupTarget {
     fn save(&self, f: &mut File) -> io::Result<()> {
         writeln!(f, "target({}) {{", self.name)?;
-        writeln!(f, "\turl = \"{}\"", self.url)?;
-        if let Some(ref u) = self.user { writeln!(f, "\tuser = \"{}\"", u)?; }
-        if let Some(ref p) = self.password {writeln!(f, "\tpassword = \"{}\"", p)?;}
+        writeln!(f, "\turl = {}", quote(self.url.as_str()))?;
+        if let Some(ref u) = self.user { writeln!(f, "\tuser = {}", quote(u))?; }
+        if let Some(ref p) = self.password {writeln!(f, "\tpassword = {}", quote(p))?;}
+        if let Some(ref k) = self.key_file {
+            writeln!(f, "\tkey-file = {}", quote(&k.to_string_lossy()))?;
+        }
+        if let Some(ref a) = self.agent_identity {
+            writeln!(f, "\tagent-identity = {}", quote(a))?;
+        }
         if self.options.reliable { writeln!(f, "\treliable = true")?; }
         writeln!(f, "\tupload-cost = {}", self.options.upload_cost)?;
         writeln!(f, "\tdownload-cost = {}", self.options.download_cost)?;
-        write!(f, "}}")?;
+        if self.options.compression != Compression::default() {
+            writeln!(f, "\tcompression = {}", self.options.compression)?;
+        }
+        if let Some(l) = self.options.compression_level {
+            writeln!(f, "\tcompression-level = {}", l)?;
+        }
+        if !self.options.strict_host_keys {
+            writeln!(f, "\tstrict-host-keys = false")?;
+        }
+        if self.options.lock_timeout != DEFAULT_LOCK_TIMEOUT {
+            writeln!(f, "\tlock-timeout = {}", self.options.lock_timeout)?;
+        }
+        if self.options.connect_timeout != DEFAULT_CONNECT_TIMEOUT {
+            writeln!(f, "\tconnect-timeout = {}", self.options.connect_timeout)?;
+        }
+        if self.options.keepalive != DEFAULT_KEEPALIVE {
+            writeln!(f, "\tkeepalive = {}", self.options.keepalive)?;
+        }
+        if self.options.upload_limit != 0 {
+            writeln!(f, "\tupload-limit = {}", self.options.upload_limit)?;
+        }
+        if self.options.download_limit != 0 {
+            writeln!(f, "\tdownload-limit = {}", self.options.download_limit)?;
+        }
+        if self.options.append_only { writeln!(f, "\tappend-only = true")?; }
+        if let Some(ref h) = self.options.admin_key_hash {
+            writeln!(f, "\tadmin-key-hash = \"{}\"", h)?;
+        }
+        writeln!(f, "}}")?;
         Ok(())
     }
 }
@@ -297,18 +576,98 @@ impl Config {
     pub fn save(&self) -> io::Result<()> {
         let mut file = File::create(&self.location)?;
         writeln!(file, "node-name = {}", self.node_name)?;
+        if let Some(ref k) = self.keystore {
+            writeln!(file, "keystore = {}", quote(&k.to_string_lossy()))?;
+        }
+        if !self.no_compress_exts.is_empty() {
+            writeln!(file, "no-compress-ext = {}",
+                     quote(&self.no_compress_exts.join(",")))?;
+        }
         for t in self.targets.iter() { t.save(&mut file)?; }
         for t in self.target_groups.iter() { t.save(&mut file)?; }
         Ok(())
     }
 
+    /// The machine's current hostname, if it's changed from the node name.
+    ///
+    /// The configured node name is what snapshots are stored under, so it's
+    /// never updated to match; `bkp node rename` has to be used for that.
+    pub fn renamed_host(&self) -> Option<String> {
+        self::hostname::get_hostname().and_then(|h|
+            if h != self.node_name { Some(h) } else { None })
+    }
+
+    /// The configured keystore location, if any, resolved against the config
+    /// file's directory
+    pub fn keystore_path(&self) -> Option<PathBuf> {
+        self.keystore.as_ref().map(|k| match self.location.parent() {
+            Some(dir) => dir.join(k),
+            None      => k.clone()
+        })
+    }
+
     pub fn find_target(&self, name: &str) -> Option<&BackupTarget> {
         self.targets.iter().find(|ref t| t.name == name)
     }
 
+    pub fn find_target_mut(&mut self, name: &str) -> Option<&mut BackupTarget> {
+        self.targets.iter_mut().find(|ref t| t.name == name)
+    }
+
     pub fn find_group(&self, name: &str) -> Option<&TargetGroup> {
         self.target_groups.iter().find(|ref t| t.name == name)
     }
+
+    pub fn find_group_mut(&mut self, name: &str) -> Option<&mut TargetGroup> {
+        self.target_groups.iter_mut().find(|ref t| t.name == name)
+    }
+}
+
+/// Split a comma-separated list of file extensions, like `jpg,.mp4`, leaving
+/// out any leading dots and empty entries
+pub fn split_extensions(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|e| e.trim().trim_left_matches('.'))
+        .filter(|e| !e.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Quote a string for the config file, escaping backslashes and quotes
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' { out.push('\\'); }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+/// Undo `quote`'s escaping on the contents of a quoted string
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => if let Some(e) = chars.next() { out.push(e) },
+            _    => out.push(c)
+        }
+    }
+    out
+}
+
+/// Whether a string is a hex SHA-256 hash, as `admin-key-hash` has to be
+pub fn valid_key_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_digit(16))
+}
+
+/// Whether a name can be used as a node name. Node names are written into the
+/// config file unquoted, so they have to match the grammar's name rule.
+pub fn valid_node_name(name: &str) -> bool {
+    let mut parse = Rdp::new(pest::StringInput::new(name));
+    parse.target_name() && parse.end()
 }
 
 impl Default for Config {
@@ -317,7 +676,168 @@ impl Default for Config {
             location: env::home_dir().unwrap().join(".bkprc"),
             targets: Vec::new(),
             target_groups: Vec::new(),
-            node_name: self::hostname::get_hostname().unwrap()
+            node_name: self::hostname::get_hostname().unwrap(),
+            keystore: None,
+            no_compress_exts: Vec::new()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use url::Url;
+
+    use std::io::Write;
+
+    use compression::Compression;
+    use config::{valid_node_name, BackupTarget, Config, TargetGroup,
+                 TargetOptions, DEFAULT_CONNECT_TIMEOUT};
+
+    #[test]
+    fn save_load_roundtrip() {
+        let path = env::temp_dir().join("bkp-config-test");
+        let hash = "0123456789abcdef".repeat(4);
+        let cfg = Config {
+            location: path.clone(),
+            targets: vec![
+                BackupTarget {
+                    name: String::from("primary"),
+                    url: Url::parse("sftp://example.com/backups").unwrap(),
+                    user: Some(String::from("me")),
+                    password: None,
+                    key_file: None,
+                    agent_identity: Some(String::from("me@laptop")),
+                    options: TargetOptions { reliable: false, upload_cost: 5,
+                                             download_cost: 20,
+                                             strict_host_keys: false,
+                                             lock_timeout: 600,
+                                             connect_timeout: 5,
+                                             keepalive: 0,
+                                             upload_limit: 65536,
+                                             ..TargetOptions::default() }
+                },
+                BackupTarget {
+                    name: String::from("local"),
+                    url: Url::parse("file:///mnt/backup").unwrap(),
+                    user: None,
+                    password: None,
+                    key_file: None,
+                    agent_identity: None,
+                    options: TargetOptions { compression: Compression::Zstd,
+                                             compression_level: Some(19),
+                                             append_only: true,
+                                             admin_key_hash: Some(hash.clone()),
+                                             ..TargetOptions::default() }
+                }],
+            target_groups: vec![TargetGroup {
+                name: String::from("all"),
+                members: vec![String::from("primary"), String::from("local")]
+            }],
+            node_name: String::from("testnode"),
+            keystore: Some(PathBuf::from("keys/\"client\" \\a")),
+            no_compress_exts: vec![String::from("jpg"), String::from("mp4")]
+        };
+        cfg.save().unwrap();
+
+        let loaded = Config::load(&path).unwrap();
+        assert_eq!(loaded.node_name, "testnode");
+        assert_eq!(loaded.keystore_path(),
+                   Some(env::temp_dir().join("keys/\"client\" \\a")));
+        assert_eq!(loaded.targets.len(), 2);
+        assert_eq!(loaded.no_compress_exts, cfg.no_compress_exts);
+
+        let primary = loaded.find_target("primary").unwrap();
+        assert_eq!(primary.user, Some(String::from("me")));
+        assert!(!primary.options.reliable);
+        assert_eq!(primary.options.upload_cost, 5);
+        assert_eq!(primary.options.download_cost, 20);
+        assert!(!primary.options.strict_host_keys);
+        assert_eq!(primary.options.lock_timeout, 600);
+        assert_eq!(primary.options.connect_timeout, 5);
+        assert_eq!(primary.options.keepalive, 0);
+        assert_eq!(primary.options.upload_limit, 65536);
+        assert_eq!(primary.options.download_limit, 0);
+        assert_eq!(primary.agent_identity, Some(String::from("me@laptop")));
+
+        let local = loaded.find_target("local").unwrap();
+        assert!(local.options.reliable);
+        assert_eq!(local.options.compression, Compression::Zstd);
+        assert_eq!(local.options.compression_level, Some(19));
+        assert_eq!(primary.options.compression, Compression::Deflate);
+        assert_eq!(primary.options.compression_level, None);
+        assert_eq!(local.options.connect_timeout, DEFAULT_CONNECT_TIMEOUT);
+        assert!(local.options.append_only && !primary.options.append_only);
+        assert_eq!(local.options.admin_key_hash, Some(hash));
+        assert_eq!(loaded.find_group("all").unwrap().members.len(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn node_names() {
+        assert!(valid_node_name("laptop"));
+        assert!(valid_node_name("home-server"));
+        assert!(!valid_node_name(""));
+        assert!(!valid_node_name("host 2"));
+        assert!(!valid_node_name("db1"));
+        assert!(!valid_node_name("laptop\nkeystore = \"x\""));
+
+        // anything accepted survives being saved and loaded again
+        let path = env::temp_dir().join("bkp-config-node-name");
+        let cfg = Config { location: path.clone(),
+                           node_name: String::from("home-server"),
+                           ..Config::default() };
+        cfg.save().unwrap();
+        assert_eq!(Config::load(&path).unwrap().node_name, "home-server");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Parse a config file from its contents
+    fn parse(name: &str, text: &str) -> Result<Config, String> {
+        let path = env::temp_dir().join(name);
+        fs::File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+        let cfg = Config::load(&path).map_err(|e| format!("{:?}", e));
+        fs::remove_file(&path).unwrap();
+        cfg
+    }
+
+    #[test]
+    fn compression_options() {
+        let target = |opts: &str| format!(
+            "node-name = n\ntarget(t) {{\n\turl = \"file:///b\"\n{}}}\n", opts);
+
+        let cfg = parse("bkp-config-zstd",
+                        &target("\tcompression = zstd\n\
+                                 \tcompression-level = 12\n")).unwrap();
+        let opts = &cfg.targets[0].options;
+        assert_eq!(opts.compression, Compression::Zstd);
+        assert_eq!(opts.compression_level, Some(12));
+
+        // the older boolean option still works, and omitting both keeps the
+        // default
+        let cfg = parse("bkp-config-legacy",
+                        &target("\tcompress = false\n")).unwrap();
+        assert_eq!(cfg.targets[0].options.compression, Compression::None);
+        let cfg = parse("bkp-config-default", &target("")).unwrap();
+        assert_eq!(cfg.targets[0].options.compression, Compression::Deflate);
+        assert_eq!(cfg.targets[0].options.compression_level, None);
+        assert!(cfg.no_compress_exts.is_empty());
+
+        let cfg = parse("bkp-config-no-compress",
+                        "node-name = n\nno-compress-ext = \"jpg, .mp4,,zip\"\n")
+            .unwrap();
+        assert_eq!(cfg.no_compress_exts, vec!["jpg", "mp4", "zip"]);
+
+        assert!(parse("bkp-config-bad-alg",
+                      &target("\tcompression = lzma\n")).is_err());
+        assert!(parse("bkp-config-bad-level",
+                      &target("\tcompression = deflate\n\
+                               \tcompression-level = 15\n")).is_err());
+        assert!(parse("bkp-config-conflict",
+                      &target("\tcompress = true\n\
+                               \tcompression = zstd\n")).is_err());
+    }
+}
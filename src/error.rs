@@ -0,0 +1,228 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use clap;
+
+use config::ConfigErr;
+use history;
+use keys;
+use remote::BackendError;
+
+/// Exit code for failures that don't fall into any other category
+pub const EXIT_FAILURE: i32 = 1;
+
+/// Exit code for invalid command-line arguments
+pub const EXIT_USAGE: i32 = 2;
+
+/// Exit code for a config file that can't be read or parsed, or which doesn't
+/// define what was asked for
+pub const EXIT_CONFIG: i32 = 3;
+
+/// Exit code for keystore, password, and key problems
+pub const EXIT_AUTH: i32 = 4;
+
+/// Exit code for failures to reach or communicate with a destination
+pub const EXIT_NETWORK: i32 = 5;
+
+/// Exit code for missing or damaged backup data
+pub const EXIT_INTEGRITY: i32 = 6;
+
+/// An error which ends a `bkp` invocation.
+///
+/// Each kind of error exits with its own status code, so scripts can tell them
+/// apart:
+///
+/// | code | meaning                                                  |
+/// |------|----------------------------------------------------------|
+/// | 0    | success                                                  |
+/// | 1    | any other failure                                        |
+/// | 2    | invalid command-line arguments                           |
+/// | 3    | config file or destination configuration problems        |
+/// | 4    | keystore, password, or key problems                      |
+/// | 5    | a destination couldn't be reached or stopped responding  |
+/// | 6    | backup data is missing or damaged                        |
+#[derive(Debug)]
+pub enum CliError {
+    /// Invalid arguments, as reported by the argument parser
+    Args(clap::Error),
+
+    /// Arguments which parsed, but don't make sense together
+    Usage(String),
+
+    Config(String),
+    Auth(String),
+    Network(String),
+    Integrity(String),
+    Failure(String)
+}
+
+impl CliError {
+    /// The status code the process should exit with
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            &CliError::Args(_) | &CliError::Usage(_) => EXIT_USAGE,
+            &CliError::Config(_)    => EXIT_CONFIG,
+            &CliError::Auth(_)      => EXIT_AUTH,
+            &CliError::Network(_)   => EXIT_NETWORK,
+            &CliError::Integrity(_) => EXIT_INTEGRITY,
+            &CliError::Failure(_)   => EXIT_FAILURE
+        }
+    }
+
+    /// Prefix the error's message with a description of what was being done,
+    /// keeping its kind
+    pub fn context(self, what: &str) -> CliError {
+        let wrap = |msg: String| format!("{}: {}", what, msg);
+        match self {
+            CliError::Args(e)      => CliError::Usage(wrap(e.message)),
+            CliError::Usage(m)     => CliError::Usage(wrap(m)),
+            CliError::Config(m)    => CliError::Config(wrap(m)),
+            CliError::Auth(m)      => CliError::Auth(wrap(m)),
+            CliError::Network(m)   => CliError::Network(wrap(m)),
+            CliError::Integrity(m) => CliError::Integrity(wrap(m)),
+            CliError::Failure(m)   => CliError::Failure(wrap(m))
+        }
+    }
+
+    /// Replace the error's message, keeping its kind
+    pub fn with_message(self, msg: String) -> CliError {
+        match self {
+            CliError::Args(_) | CliError::Usage(_) => CliError::Usage(msg),
+            CliError::Config(_)    => CliError::Config(msg),
+            CliError::Auth(_)      => CliError::Auth(msg),
+            CliError::Network(_)   => CliError::Network(msg),
+            CliError::Integrity(_) => CliError::Integrity(msg),
+            CliError::Failure(_)   => CliError::Failure(msg)
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &CliError::Args(ref e) => write!(f, "{}", e.message),
+            &CliError::Usage(ref m) | &CliError::Config(ref m) |
+                &CliError::Auth(ref m) | &CliError::Network(ref m) |
+                &CliError::Integrity(ref m) | &CliError::Failure(ref m) =>
+                write!(f, "{}", m)
+        }
+    }
+}
+
+impl error::Error for CliError {
+    fn description(&self) -> &str {
+        match self {
+            &CliError::Args(_) | &CliError::Usage(_) => "invalid usage",
+            &CliError::Config(_)    => "configuration error",
+            &CliError::Auth(_)      => "authentication error",
+            &CliError::Network(_)   => "network error",
+            &CliError::Integrity(_) => "integrity error",
+            &CliError::Failure(_)   => "failure"
+        }
+    }
+}
+
+impl From<clap::Error> for CliError {
+    fn from(e: clap::Error) -> CliError { CliError::Args(e) }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> CliError { CliError::Failure(e.to_string()) }
+}
+
+impl From<ConfigErr> for CliError {
+    fn from(e: ConfigErr) -> CliError {
+        CliError::Config(match e {
+            ConfigErr::ParseError(x) => x,
+            ConfigErr::IOError(x)    => x.to_string()
+        })
+    }
+}
+
+impl From<keys::Error> for CliError {
+    fn from(e: keys::Error) -> CliError {
+        match e {
+            keys::Error::IOError(_) => CliError::Failure(e.to_string()),
+            _                       => CliError::Auth(e.to_string())
+        }
+    }
+}
+
+impl From<BackendError> for CliError {
+    fn from(e: BackendError) -> CliError {
+        let msg = e.to_string();
+        match e {
+            BackendError::ConnectionFailed | BackendError::CommsError =>
+                CliError::Network(msg),
+            BackendError::InvalidOption | BackendError::NoSuchScheme |
+                BackendError::InvalidURL(_) |
+                BackendError::NotInitialized => CliError::Config(msg),
+            BackendError::AppendOnly => CliError::Auth(msg),
+            BackendError::KeyError(k) => CliError::from(k).with_message(msg),
+            BackendError::ResourceError | BackendError::BackendError(_) |
+                BackendError::ReadOnly |
+                BackendError::IOError(_) => CliError::Failure(msg)
+        }
+    }
+}
+
+impl From<history::Error> for CliError {
+    fn from(e: history::Error) -> CliError {
+        let msg = e.to_string();
+        match e {
+            history::Error::InvalidArgument => CliError::Usage(msg),
+            history::Error::IntegrityError |
+                history::Error::Incomplete(_) => CliError::Integrity(msg),
+            history::Error::Backend(b) => CliError::from(b).with_message(msg),
+            history::Error::NoValidSnapshot | history::Error::WouldOverwrite |
+                history::Error::TooDeep(_) |
+                history::Error::IOError(_) => CliError::Failure(msg)
+        }
+    }
+}
+
+/// Describe what was being done when a fallible operation fails
+pub trait OrFail<T> {
+    /// Convert the error into a `CliError` whose message starts with `msg`
+    fn or_fail(self, msg: &str) -> Result<T, CliError>;
+}
+
+impl<T, E: Into<CliError>> OrFail<T> for Result<T, E> {
+    fn or_fail(self, msg: &str) -> Result<T, CliError> {
+        self.map_err(|e| e.into().context(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::{CliError, OrFail, EXIT_AUTH, EXIT_CONFIG, EXIT_FAILURE,
+                EXIT_INTEGRITY, EXIT_NETWORK, EXIT_USAGE};
+    use history;
+    use keys;
+    use remote::BackendError;
+
+    #[test]
+    fn wrapped_errors_keep_their_kind() {
+        let e = CliError::from(history::Error::Backend(
+                BackendError::ConnectionFailed));
+        assert_eq!(e.exit_code(), EXIT_NETWORK);
+        assert_eq!(e.to_string(), "backend error: connection failed");
+
+        let e = CliError::from(BackendError::KeyError(keys::Error::CryptoError));
+        assert_eq!(e.exit_code(), EXIT_AUTH);
+
+        let r: Result<(), _> = Err(history::Error::IntegrityError);
+        let e = r.or_fail("failed to read snapshot").unwrap_err();
+        assert_eq!(e.exit_code(), EXIT_INTEGRITY);
+        assert_eq!(e.to_string(), "failed to read snapshot: integrity error");
+
+        let e = CliError::from(BackendError::NotInitialized);
+        assert_eq!(e.exit_code(), EXIT_CONFIG);
+
+        let e = CliError::from(history::Error::InvalidArgument);
+        assert_eq!(e.exit_code(), EXIT_USAGE);
+        let e = CliError::from(history::Error::NoValidSnapshot);
+        assert_eq!(e.exit_code(), EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,254 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+
+/// Name of the per-directory file listing extra exclude patterns
+pub const IGNORE_FILE: &'static str = ".bkpignore";
+
+/// Name of the file marking a directory as a cache, as described at
+/// <http://www.brynosaurus.com/cachedir/>
+pub const CACHEDIR_TAG: &'static str = "CACHEDIR.TAG";
+
+/// What a cache directory tag file has to start with
+const CACHEDIR_SIGNATURE: &'static [u8] =
+    b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Check whether a directory is tagged as a cache, whose contents needn't be
+/// backed up
+pub fn is_cache_dir(dir: &Path) -> bool {
+    let mut sig = [0u8; 43];
+    match fs::File::open(dir.join(CACHEDIR_TAG)) {
+        Ok(mut f) => f.read_exact(&mut sig).is_ok() &&
+                     &sig[..] == CACHEDIR_SIGNATURE,
+        Err(_)    => false
+    }
+}
+
+/// Find the first of the given marker files that a directory contains, if any
+pub fn find_marker(dir: &Path, markers: &[OsString]) -> Option<OsString> {
+    markers.iter().find(|m| dir.join(m).symlink_metadata().is_ok()).cloned()
+}
+
+/// One component of a parsed pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    /// `**`, matching any number of path components (including none)
+    AnyDirs,
+
+    /// A glob matched against a single path component
+    Glob(Vec<u8>)
+}
+
+/// A single exclude pattern.
+///
+/// Patterns use the same syntax as gitignore files: `*` and `?` match within a
+/// path component, `**` matches any number of components, and a leading `!`
+/// re-includes paths excluded by an earlier pattern. Patterns containing a
+/// slash are anchored to the directory they're defined in; others match a name
+/// at any depth. A trailing slash only matches directories.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    /// Directory the pattern applies under, relative to the snapshot root
+    base: PathBuf,
+    segments: Vec<Segment>,
+    negated: bool,
+    dir_only: bool
+}
+
+impl Pattern {
+    /// Parse a pattern, returning `None` for blank lines and comments
+    pub fn parse(line: &str) -> Option<Pattern> {
+        let mut line = line.trim_right();
+        if line.is_empty() || line.starts_with('#') { return None; }
+
+        let negated = line.starts_with('!');
+        if negated { line = &line[1..]; }
+        let dir_only = line.ends_with('/');
+        let line = line.trim_right_matches('/');
+        if line.is_empty() { return None; }
+
+        let anchored = line.contains('/');
+        let mut segments = Vec::new();
+        if !anchored {
+            segments.push(Segment::AnyDirs);
+        }
+        for part in line.split('/').filter(|x| !x.is_empty()) {
+            if part == "**" {
+                segments.push(Segment::AnyDirs);
+            } else {
+                segments.push(Segment::Glob(part.as_bytes().to_vec()));
+            }
+        }
+
+        Some(Pattern { base: PathBuf::new(), segments: segments,
+                       negated: negated, dir_only: dir_only })
+    }
+
+    /// Check whether a path relative to the snapshot root matches
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir { return false; }
+        let rel = match rel.strip_prefix(&self.base) {
+            Ok(r)  => r,
+            Err(_) => return false
+        };
+
+        let parts: Vec<&[u8]> = rel.iter().map(|x| x.as_bytes()).collect();
+        match_segments(&self.segments, &parts)
+    }
+}
+
+/// The set of exclude patterns in effect within a directory
+#[derive(Clone, Debug)]
+pub struct ExcludeRules {
+    /// Root of the snapshot that patterns are relative to
+    root: PathBuf,
+    patterns: Vec<Pattern>
+}
+
+impl ExcludeRules {
+    /// Create a rule set for a snapshot rooted at a given path
+    pub fn new(root: &Path, patterns: &[Pattern]) -> Self {
+        ExcludeRules { root: root.to_owned(), patterns: patterns.to_vec() }
+    }
+
+    /// Get the rules in effect inside a directory, including any patterns from
+    /// an ignore file it contains
+    pub fn enter(&self, dir: &Path) -> io::Result<ExcludeRules> {
+        let mut data = Vec::new();
+        match fs::File::open(dir.join(IGNORE_FILE)) {
+            Ok(mut f) => { f.read_to_end(&mut data)?; },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(self.clone());
+            },
+            Err(e) => return Err(e)
+        }
+
+        let base = dir.strip_prefix(&self.root).unwrap_or(Path::new(""));
+        let mut rules = self.clone();
+        for line in String::from_utf8_lossy(&data).lines() {
+            if let Some(mut pat) = Pattern::parse(line) {
+                pat.base = base.to_owned();
+                rules.patterns.push(pat);
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Check whether a path should be left out of the snapshot. The last
+    /// matching pattern decides.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let rel = match path.strip_prefix(&self.root) {
+            Ok(r)  => r,
+            Err(_) => return false
+        };
+
+        self.patterns.iter().rev()
+            .find(|p| p.matches(rel, is_dir))
+            .map(|p| !p.negated)
+            .unwrap_or(false)
+    }
+}
+
+/// Match pattern segments against a list of path components
+fn match_segments(segs: &[Segment], parts: &[&[u8]]) -> bool {
+    match segs.split_first() {
+        None => parts.is_empty(),
+        Some((&Segment::AnyDirs, rest)) =>
+            (0..parts.len() + 1).any(|i| match_segments(rest, &parts[i..])),
+        Some((&Segment::Glob(ref g), rest)) =>
+            !parts.is_empty() && glob_match(g, parts[0]) &&
+                match_segments(rest, &parts[1..])
+    }
+}
+
+/// Match a single path component against a glob with `*` and `?` wildcards
+fn glob_match(pat: &[u8], name: &[u8]) -> bool {
+    match pat.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) =>
+            (0..name.len() + 1).any(|i| glob_match(rest, &name[i..])),
+        Some((&b'?', rest)) =>
+            !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) =>
+            name.first() == Some(c) && glob_match(rest, &name[1..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    use exclude::{ExcludeRules, Pattern, IGNORE_FILE};
+
+    fn rules(pats: &[&str]) -> ExcludeRules {
+        let pats: Vec<Pattern> = pats.iter().filter_map(|p| Pattern::parse(p))
+                                     .collect();
+        ExcludeRules::new(Path::new("/root"), &pats)
+    }
+
+    #[test]
+    fn pattern_matching() {
+        let r = rules(&["*.o", "/build", "docs/**/*.tmp", "cache/"]);
+        assert!(r.is_excluded(Path::new("/root/main.o"), false));
+        assert!(r.is_excluded(Path::new("/root/src/deep/x.o"), false));
+        assert!(!r.is_excluded(Path::new("/root/main.c"), false));
+
+        // anchored patterns only match at the root
+        assert!(r.is_excluded(Path::new("/root/build"), true));
+        assert!(!r.is_excluded(Path::new("/root/src/build"), true));
+
+        assert!(r.is_excluded(Path::new("/root/docs/a.tmp"), false));
+        assert!(r.is_excluded(Path::new("/root/docs/a/b/c.tmp"), false));
+        assert!(!r.is_excluded(Path::new("/root/a.tmp"), false));
+
+        // directory-only patterns
+        assert!(r.is_excluded(Path::new("/root/x/cache"), true));
+        assert!(!r.is_excluded(Path::new("/root/x/cache"), false));
+
+        assert!(Pattern::parse("# comment").is_none());
+        assert!(Pattern::parse("   ").is_none());
+    }
+
+    #[test]
+    fn negation() {
+        let r = rules(&["*.log", "!keep.log"]);
+        assert!(r.is_excluded(Path::new("/root/a.log"), false));
+        assert!(!r.is_excluded(Path::new("/root/keep.log"), false));
+
+        // later patterns win
+        let r = rules(&["!keep.log", "*.log"]);
+        assert!(r.is_excluded(Path::new("/root/keep.log"), false));
+    }
+
+    #[test]
+    fn nested_ignore_files() {
+        let root = env::temp_dir().join("bkp-exclude-test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub").join("deeper")).unwrap();
+        fs::File::create(root.join(IGNORE_FILE)).unwrap()
+            .write_all(b"*.tmp\n").unwrap();
+        fs::File::create(root.join("sub").join(IGNORE_FILE)).unwrap()
+            .write_all(b"# local rules\n!wanted.tmp\n/deeper/out\n").unwrap();
+
+        let top = ExcludeRules::new(&root, &[]).enter(&root).unwrap();
+        assert!(top.is_excluded(&root.join("wanted.tmp"), false));
+        assert!(!top.is_excluded(&root.join("deeper").join("out"), false));
+
+        let sub = top.enter(&root.join("sub")).unwrap();
+        assert!(sub.is_excluded(&root.join("sub").join("other.tmp"), false));
+        assert!(!sub.is_excluded(&root.join("sub").join("wanted.tmp"), false));
+        assert!(sub.is_excluded(&root.join("sub").join("deeper").join("out"),
+                                false));
+
+        // rules from a subdirectory's ignore file don't leak into siblings
+        assert!(top.is_excluded(&root.join("wanted.tmp"), false));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
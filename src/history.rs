@@ -1,30 +1,47 @@
+extern crate byteorder;
+extern crate libc;
+
 use std::boxed::Box;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::cmp;
 use std::result;
 use std::error;
 use std::fmt;
 use std::io;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::ffi::{OsStr, OsString};
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::io::prelude::*;
 use std::ops::Deref;
-use std::os::unix::ffi::OsStringExt;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, symlink};
+use std::time;
+
+use self::byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 
-use util::Hasher;
-use chunking::Chunkable;
+use util::{self, Hasher, DevNull};
+use chunking::{Chunkable, DEFAULT_CHUNK_SIZE};
+use progress::{Progress, NoProgress};
+use exclude::{self, ExcludeRules, Pattern};
+use journal::{Fingerprint, Journal};
+use metacache::{self, MetaCache};
 use remote::{BackendResult, BackendError, Backend};
-use metadata::{Snapshot, FileObject, SymlinkObject,
-               MetaObject, IdentityTag, TreeObject,
-               FSMetadata};
+use keys;
+use metadata::{Snapshot, FileObject, SymlinkObject, HardLinkObject,
+               SpecialObject, SpecialKind, MetaObject, IdentityTag, TreeObject,
+               FSMetadata, IntoFSMetadata, read_xattrs, write_xattrs,
+               tag_from_digest};
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum Error {
     InvalidArgument,
     IntegrityError,
     NoValidSnapshot,
     WouldOverwrite,
+    TooDeep(PathBuf),
+    Incomplete(usize),
     IOError(io::Error),
     Backend(BackendError),
 }
@@ -36,6 +53,10 @@ impl fmt::Display for Error {
             &Error::IntegrityError => write!(f, "integrity error"),
             &Error::NoValidSnapshot=> write!(f, "no valid snapshot"),
             &Error::WouldOverwrite => write!(f, "refusing to overwrite"),
+            &Error::TooDeep(ref p) =>
+                write!(f, "directory tree too deep at {}", p.display()),
+            &Error::Incomplete(n)  =>
+                write!(f, "snapshot refers to {} missing objects", n),
             &Error::IOError(ref e) => write!(f, "I/O error: {}", e),
             &Error::Backend(ref e) => write!(f, "backend error: {}", e),
         }
@@ -49,6 +70,8 @@ impl error::Error for Error {
             &Error::IntegrityError => "integrity error",
             &Error::NoValidSnapshot=> "no valid snapshot",
             &Error::WouldOverwrite => "refusing to overwrite",
+            &Error::TooDeep(_)     => "directory tree too deep",
+            &Error::Incomplete(_)  => "snapshot refers to missing objects",
             &Error::IOError(_)     => "I/O error",
             &Error::Backend(_)     => "backend error",
         }
@@ -56,11 +79,25 @@ impl error::Error for Error {
 }
 pub type Result<T> = result::Result<T, Error>;
 
+/// How many directories deep a stored path may be.
+///
+/// Directories are stored recursively, so this keeps pathological trees from
+/// overflowing the stack. Paths anywhere near this deep can't be opened by
+/// name anyway.
+pub const MAX_TREE_DEPTH: usize = 256;
+
 impl From<BackendError> for Error {
     fn from(e: BackendError) -> Error { Error::Backend(e) }
 }
 impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Error { Error::IOError(e) }
+    fn from(e: io::Error) -> Error {
+        // readers over stored data pass backend errors through as I/O errors
+        if e.get_ref().map_or(false, |x| x.is::<BackendError>()) {
+            let inner = e.into_inner().unwrap();
+            return Error::Backend(*inner.downcast::<BackendError>().unwrap());
+        }
+        Error::IOError(e)
+    }
 }
 
 /// The mode to use when running an integrity test
@@ -78,24 +115,393 @@ impl IntegrityTestMode {
     fn check_trees(&self) -> bool { *self >= IntegrityTestMode::Normal }
 }
 
+/// Summary statistics about the history stored on a backend
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The identity of the head snapshot the statistics were gathered from
+    pub head: Option<IdentityTag>,
+
+    /// Number of snapshots in the chain
+    pub snapshots: u64,
+
+    /// Number of distinct data blocks referenced by any snapshot
+    pub unique_blocks: u64,
+
+    /// Total length of the distinct files referenced by any snapshot
+    pub logical_bytes: u64,
+
+    /// Creation time of the most recent snapshot
+    pub latest: Option<time::SystemTime>,
+
+    /// Total space taken up on the remote by the blocks referenced by every
+    /// snapshot, counting a block again each time it's used
+    pub referenced_bytes: u64,
+
+    /// Total space taken up on the remote by the distinct blocks referenced by
+    /// any snapshot
+    pub stored_bytes: u64,
+
+    /// Space used by each snapshot, most recent first
+    pub snapshot_stats: Vec<SnapshotStats>,
+}
+
+/// Space used by a single snapshot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotStats {
+    pub id: IdentityTag,
+
+    /// Total space taken up on the remote by the blocks referenced by the
+    /// snapshot's files, counting a block again each time it's used
+    pub referenced_bytes: u64,
+
+    /// Total space taken up on the remote by the blocks which no older
+    /// snapshot references
+    pub added_bytes: u64,
+}
+
+impl Stats {
+    /// Read a set of statistics previously written with `save`
+    pub fn load<R: Read>(f: &mut R) -> io::Result<Stats> {
+        let head = if f.read_u8()? != 0 {
+            Some(IdentityTag::read_from(f)?)
+        } else {
+            None
+        };
+        let snapshots = f.read_u64::<LittleEndian>()?;
+        let unique_blocks = f.read_u64::<LittleEndian>()?;
+        let logical_bytes = f.read_u64::<LittleEndian>()?;
+        let latest = if f.read_u8()? != 0 {
+            Some(time::UNIX_EPOCH +
+                 time::Duration::from_secs(f.read_u64::<LittleEndian>()?))
+        } else {
+            None
+        };
+
+        let referenced_bytes = f.read_u64::<LittleEndian>()?;
+        let stored_bytes = f.read_u64::<LittleEndian>()?;
+        let mut snapshot_stats = Vec::new();
+        for _ in 0..f.read_u64::<LittleEndian>()? {
+            snapshot_stats.push(SnapshotStats {
+                id: IdentityTag::read_from(f)?,
+                referenced_bytes: f.read_u64::<LittleEndian>()?,
+                added_bytes: f.read_u64::<LittleEndian>()?
+            });
+        }
+
+        Ok(Stats { head, snapshots, unique_blocks, logical_bytes, latest,
+                   referenced_bytes, stored_bytes, snapshot_stats })
+    }
+
+    /// Write the statistics to the given stream
+    pub fn save<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        match self.head {
+            Some(ref t) => { f.write_u8(1)?; f.write_all(t.as_bytes())?; },
+            None        => f.write_u8(0)?
+        }
+        f.write_u64::<LittleEndian>(self.snapshots)?;
+        f.write_u64::<LittleEndian>(self.unique_blocks)?;
+        f.write_u64::<LittleEndian>(self.logical_bytes)?;
+        match self.latest.map(|t| t.duration_since(time::UNIX_EPOCH)) {
+            None    => f.write_u8(0)?,
+            Some(d) => {
+                f.write_u8(1)?;
+                let secs = d.map(|d| d.as_secs()).unwrap_or(0); // clamp
+                f.write_u64::<LittleEndian>(secs)?;
+            }
+        }
+        f.write_u64::<LittleEndian>(self.referenced_bytes)?;
+        f.write_u64::<LittleEndian>(self.stored_bytes)?;
+        f.write_u64::<LittleEndian>(self.snapshot_stats.len() as u64)?;
+        for snap in self.snapshot_stats.iter() {
+            f.write_all(snap.id.as_bytes())?;
+            f.write_u64::<LittleEndian>(snap.referenced_bytes)?;
+            f.write_u64::<LittleEndian>(snap.added_bytes)?;
+        }
+        Ok(())
+    }
+
+    /// How many bytes are referenced for every byte stored, or `None` if
+    /// nothing is stored
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        if self.stored_bytes == 0 { return None; }
+        Some(self.referenced_bytes as f64 / self.stored_bytes as f64)
+    }
+}
+
+/// The way a path changed between two snapshots
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The path only exists in the newer snapshot
+    Added,
+
+    /// The path only exists in the older snapshot
+    Removed,
+
+    /// The path exists in both, but its contents or metadata differ
+    Modified
+}
+
+/// A single path which differs between two snapshots
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathChange {
+    pub path: PathBuf,
+    pub kind: ChangeKind
+}
+
+/// What snapshotting a set of paths would store
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotPlan {
+    /// Paths which are new or changed since the last snapshot
+    pub changes: Vec<PathChange>,
+
+    /// Total size of the new and changed files. This is how much data has to
+    /// be read and hashed, and is what snapshot progress is measured against.
+    pub upload_bytes: u64,
+
+    /// Number of blocks in the new and changed files which aren't stored on
+    /// the backend yet
+    pub new_blocks: u64,
+
+    /// Total size of those blocks, before compression. This is roughly how
+    /// much data will actually be uploaded.
+    pub new_bytes: u64
+}
+
+/// A reason a data block failed verification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockFault {
+    /// The block couldn't be read from the backend
+    Missing,
+
+    /// The block's contents don't match its identity
+    Corrupt
+}
+
+impl fmt::Display for BlockFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match self {
+            &BlockFault::Missing => write!(f, "missing"),
+            &BlockFault::Corrupt => write!(f, "corrupt")
+        }
+    }
+}
+
+/// The outcome of verifying a single snapshot's contents
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of distinct blocks downloaded and checked
+    pub blocks_checked: u64,
+
+    /// Blocks which failed verification, along with the path of each file
+    /// that uses them
+    pub bad_blocks: Vec<(PathBuf, IdentityTag, BlockFault)>,
+
+    /// Metadata objects which couldn't be read or had an invalid type, along
+    /// with the path of the directory they're in
+    pub bad_objects: Vec<(PathBuf, IdentityTag)>,
+}
+
+impl VerifyReport {
+    /// Whether every object and block in the snapshot verified correctly
+    pub fn is_ok(&self) -> bool {
+        self.bad_blocks.is_empty() && self.bad_objects.is_empty()
+    }
+}
+
+/// The outcome of an integrity check of a whole snapshot chain
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// What was found under each snapshot checked, newest first. Snapshots
+    /// mostly share their objects, so each object is only checked once, and
+    /// problems with shared objects are listed under the newest snapshot
+    /// using them. A snapshot object which can't be read is listed as a bad
+    /// object of its own, with an empty path.
+    pub snapshots: Vec<(IdentityTag, VerifyReport)>
+}
+
+impl CheckReport {
+    /// Whether the check passed without finding any problems
+    pub fn is_ok(&self) -> bool {
+        self.snapshots.iter().all(|s| s.1.is_ok())
+    }
+}
+
+/// The outcome of regenerating a snapshot's bad blocks from local files
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Blocks which were uploaded again
+    pub repaired: Vec<IdentityTag>,
+
+    /// Bad blocks which couldn't be regenerated, as listed in the verify
+    /// report
+    pub unrepairable: Vec<(PathBuf, IdentityTag, BlockFault)>
+}
+
+/// The outcome of scanning a backend for a snapshot to recover the head from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Number of readable snapshot objects found on the backend
+    pub snapshots: usize,
+
+    /// The newest snapshot whose tree passed the integrity check, if any
+    pub newest_intact: Option<(IdentityTag, Snapshot)>,
+
+    /// How many snapshots the recovered chain holds, found by following
+    /// parent links from the newest intact one until one is missing
+    pub chain_length: usize
+}
+
+/// The outcome of a garbage collection pass
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Number of unreachable metadata objects
+    pub meta_objects: u64,
+
+    /// Number of unreachable data blocks
+    pub blocks: u64,
+
+    /// Total size of the unreachable objects and blocks. Metadata objects are
+    /// measured before compression and encryption, and blocks by the space
+    /// they take up on the remote.
+    pub bytes: u64,
+
+    /// Nodes whose snapshots couldn't be read, which kept data blocks from
+    /// being collected
+    pub skipped_nodes: Vec<String>
+}
+
+/// The head of one of the nodes storing snapshots on a backend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeHead {
+    /// The node's name
+    pub node: String,
+
+    /// The node's newest snapshot and its identity, or `None` if it couldn't
+    /// be read, e.g. because the node's metadata key isn't in the keystore
+    pub snapshot: Option<(IdentityTag, Snapshot)>,
+
+    /// Number of metadata objects and blocks under the snapshot's tree which
+    /// aren't stored
+    pub missing: usize,
+
+    /// Number of metadata objects under the snapshot's tree which are stored,
+    /// but can't be read with the node's own key. Usually another node stored
+    /// them first, and that node's gc removes them unless it can read this
+    /// node's snapshots.
+    pub at_risk: usize
+}
+
+/// What walking the trees under some snapshots found wrong with them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct TreeDamage {
+    /// Metadata objects and blocks which aren't stored
+    missing: usize,
+
+    /// Metadata objects which are stored, but can't be read with the current
+    /// metadata key
+    unreadable: usize
+}
+
+/// A step of the walk which tallies up object sizes for `stats`
+enum TallyStep {
+    /// Tally an object, unless its size is already known
+    Visit(IdentityTag),
+
+    /// Record a tree's size, once each of its children has been tallied
+    Sum(IdentityTag, Vec<IdentityTag>)
+}
+
+/// A pair of directories being compared by `diff`
+struct DiffFrame {
+    path: PathBuf,
+
+    /// Children of the older directory which haven't been compared yet
+    old: btree_map::IntoIter<OsString, (IdentityTag, MetaObject)>,
+
+    /// Children of the newer directory with no match found so far
+    new: BTreeMap<OsString, (IdentityTag, MetaObject)>
+}
+
+/// Which snapshots to keep when thinning out a chain by count rather than age.
+///
+/// `last` keeps that many of the newest snapshots. The other rules each keep
+/// the newest snapshot of each of their most recent periods which have any, so
+/// `daily: 7` keeps the last snapshot of each of the last seven days that had
+/// one. A snapshot kept by several rules counts toward each of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize
+}
+
+impl RetentionPolicy {
+    /// Check whether the policy keeps anything at all
+    pub fn is_empty(&self) -> bool {
+        self.last == 0 && self.daily == 0 && self.weekly == 0 &&
+            self.monthly == 0
+    }
+
+    /// Choose the snapshots to keep out of a chain
+    pub fn retained(&self, chain: &[(IdentityTag, Snapshot)])
+            -> HashSet<IdentityTag> {
+        let mut snaps: Vec<&(IdentityTag, Snapshot)> = chain.iter().collect();
+        snaps.sort_by(|a, b| b.1.create_time.cmp(&a.1.create_time));
+
+        let mut keep: HashSet<IdentityTag> = snaps.iter().take(self.last)
+                                                  .map(|s| s.0).collect();
+        let rules = [(self.daily, 0), (self.weekly, 1), (self.monthly, 2)];
+        for &(count, rule) in rules.iter() {
+            let mut periods = HashSet::new();
+            for s in snaps.iter() {
+                if periods.len() >= count { break; }
+                let (day, week, month) =
+                    util::calendar_periods(s.1.create_time);
+                let period = [day, week, month][rule];
+                if periods.insert(period) { keep.insert(s.0); }
+            }
+        }
+        keep
+    }
+}
+
 /// A struct which wraps metadata objects and associates them with a containing
 /// backend object.
 pub struct ContextWrapper<'a, T> {
     backend: &'a Box<Backend>,
+
+    /// Where metadata objects are read through, if the wrapper came from a
+    /// `History` which caches them
+    cache: Option<&'a MetaCache>,
+
     object: T
 }
 
 impl<'a, T> ContextWrapper<'a, T> {
     fn new(backend: &'a Box<Backend>, obj: T) -> Self {
-        ContextWrapper { backend: backend, object: obj }
+        ContextWrapper { backend: backend, cache: None, object: obj }
+    }
+
+    fn cached(backend: &'a Box<Backend>, cache: &'a MetaCache, obj: T)
+            -> Self {
+        ContextWrapper { backend: backend, cache: Some(cache), object: obj }
     }
 
     fn child<C>(&self, obj: C) -> ContextWrapper<'a, C> {
         ContextWrapper {
             backend: self.backend,
+            cache: self.cache,
             object: obj
         }
     }
+
+    fn read_meta(&self, tag: &IdentityTag) -> BackendResult<MetaObject> {
+        match self.cache {
+            Some(c) => c.read(&**self.backend, tag),
+            None    => self.backend.read_meta(tag)
+        }
+    }
 }
 
 /// allow easy derefs of context wrapper objects
@@ -108,7 +514,7 @@ impl<'a, T> Deref for ContextWrapper<'a, T> {
 impl<'a> ContextWrapper<'a, Snapshot> {
     /// Get the root tree inside this snapshot
     pub fn get_tree(&self) -> Result<ContextWrapper<'a, TreeObject>> {
-        let obj = self.backend.read_meta(&self.root)?;
+        let obj = self.read_meta(&self.root)?;
         match obj {
             MetaObject::Tree(t) => Ok(self.child(t)),
             _                   => Err(Error::IntegrityError)
@@ -120,7 +526,7 @@ impl<'a> ContextWrapper<'a, Snapshot> {
         match self.object.parent {
             None => Ok(None),
             Some(ident) => {
-                let obj = self.backend.read_meta(&ident)?;
+                let obj = self.read_meta(&ident)?;
                 match obj {
                     MetaObject::Snapshot(snap) => Ok(Some(self.child(snap))),
                     _                          => Err(Error::IntegrityError)
@@ -129,6 +535,16 @@ impl<'a> ContextWrapper<'a, Snapshot> {
         }
     }
 
+    /// Get the ID of the object at the given path in this snapshot, if any
+    pub fn get_id<P>(&self, pth: P) -> Result<Option<IdentityTag>>
+            where P: AsRef<Path> {
+        let pth = pth.as_ref().strip_prefix("/").unwrap_or(pth.as_ref());
+        if pth.as_os_str().is_empty() {
+            return Ok(Some(self.root));
+        }
+        self.get_tree()?.get_id(pth)
+    }
+
     /// Get the object at the given path in this snapshot, if any
     pub fn get<P>(&self, pth: P) -> Result<Option<ContextWrapper<'a, MetaObject>>> 
             where P: AsRef<Path> {
@@ -138,6 +554,11 @@ impl<'a> ContextWrapper<'a, Snapshot> {
             } else {
                 pth.as_ref()
             };
+
+        // the root is already known, so don't bother searching for it
+        if pth.as_os_str().is_empty() {
+            return Ok(Some(self.child(self.read_meta(&self.root)?)));
+        }
         self.get_tree()?.get(pth)
     }
 }
@@ -160,45 +581,33 @@ impl<'a> ContextWrapper<'a, TreeObject> {
                                 else { return Ok(None); };
                 let children: BackendResult<Vec<(IdentityTag,MetaObject)>> =
                     this_node.children.iter()
-                    .map(|x| self.backend.read_meta(&x).map(|m| (x.clone(), m)))
+                    .map(|x| self.read_meta(&x).map(|m| (x.clone(), m)))
                     .collect();
                 children?
             };
 
-            let mut found = false;
+            // find the child matching this component, keeping the tag it was
+            // stored under rather than re-encoding it
+            let mut found = None;
             for (ident,c) in children {
-                last_id = c.ident();
-                match c {
-                    MetaObject::Tree(t) => {
-                        if t.name == part_vec {
-                            node = Some(t);
-                            found = true;
-                            break;
-                        }
-                    },
-                    MetaObject::File(f) => {
-                        if f.name == part_vec {
-                            node = None;
-                            found = true;
-                            break;
-                        }
-                    },
-                    MetaObject::Symlink(ref f) => {
-                        if f.name == part_vec {
-                            node = None;
-                            found = true;
-                            break;
-                        }
-                    },
-                    _ => {
-                        // no other values are legal
-                        return Err(Error::IntegrityError);
-                    }
+                let name = match c.name() {
+                    Some(n) => n.into_vec(),
+                    None    => return Err(Error::IntegrityError) // snapshots
+                };
+                if name == part_vec {
+                    // only trees can be descended into further
+                    node = match c {
+                        MetaObject::Tree(t) => Some(t),
+                        _                   => None
+                    };
+                    found = Some(ident);
+                    break;
                 }
             }
 
-            if !found {
-                return Ok(None);
+            match found {
+                Some(id) => last_id = id,
+                None     => return Ok(None)
             }
         }
 
@@ -209,7 +618,7 @@ impl<'a> ContextWrapper<'a, TreeObject> {
     pub fn get<P>(&self, pth: P) -> Result<Option<ContextWrapper<'a, MetaObject>>> 
             where P: AsRef<Path> {
         if let Some(ident) = self.get_id(pth)? {
-            Ok(Some(self.child(self.backend.read_meta(&ident)?)))
+            Ok(Some(self.child(self.read_meta(&ident)?)))
         } else {
             Ok(None)
         }
@@ -222,526 +631,4122 @@ impl<'a> ContextWrapper<'a, FileObject> {
 impl<'a> ContextWrapper<'a, SymlinkObject> {
 }
 
+/// What to do when a restored object would replace something already on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverwriteMode {
+    /// Refuse, failing the restore
+    Never,
+
+    /// Always replace the existing object
+    Always,
+
+    /// Replace the existing object only if it's older than the stored one,
+    /// leaving newer local objects in place
+    IfNewer
+}
+
 pub struct RestoreOptions {
-    /// Whether to overwrite existing data found during restore
-    overwrite: bool,
+    /// What to do with existing data found during restore
+    overwrite: OverwriteMode,
+
+    /// Whether to apply the stored mode and ownership or use system defaults
+    restore_perms: bool,
+
+    /// Whether to apply the stored timestamps
+    restore_attrs: bool,
+
+    /// Whether to check each downloaded block against its tag
+    verify_blocks: bool,
+
+    /// Stored paths to restore somewhere else, and where to put them
+    mappings: Vec<(PathBuf, PathBuf)>,
+
+    /// Where each file object has been restored so far, so that hard links to
+    /// it can be recreated
+    restored: RefCell<HashMap<IdentityTag, PathBuf>>,
+
+    /// Where to report restore progress
+    progress: Rc<Progress>,
+
+    /// Number of restored objects whose ownership couldn't be set
+    unowned: Cell<usize>,
+
+    /// How ownership is changed, which tests replace to simulate failures
+    chown: fn(&CStr, u32, u32) -> io::Result<()>
+}
+
+/// Whether an error reading a stored object means that the object itself is
+/// damaged, rather than that it couldn't be fetched
+fn is_damaged(e: &BackendError) -> bool {
+    match e {
+        &BackendError::KeyError(keys::Error::CryptoError) => true,
+        &BackendError::IOError(ref e) => match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => true,
+            _ => false
+        },
+        _ => false
+    }
+}
 
-    /// Whether to apply the previous permissions or use the system defaults
-    use_perms: bool,
+/// Change the owner of a path, without following symlinks
+fn lchown(path: &CStr, uid: u32, gid: u32) -> io::Result<()> {
+    if unsafe { libc::lchown(path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
 impl RestoreOptions {
     /// Generate a RestoreOptions object with sane defaults
     pub fn new() -> Self {
         RestoreOptions {
-            overwrite: false,
-            use_perms: true
+            overwrite: OverwriteMode::Never,
+            restore_perms: true,
+            restore_attrs: true,
+            verify_blocks: true,
+            mappings: Vec::new(),
+            restored: RefCell::new(HashMap::new()),
+            progress: Rc::new(NoProgress),
+            unowned: Cell::new(0),
+            chown: lchown
         }
     }
 
+    /// Number of objects restored so far whose stored owner couldn't be set,
+    /// because restoring ownership needs root privileges
+    pub fn ownership_failures(&self) -> usize {
+        self.unowned.get()
+    }
+
+    /// Configure where to report progress as objects are restored
+    pub fn progress(mut self, progress: Rc<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
     /// Configure whether to overwrite files/dirs
-    pub fn overwrite(mut self, enable: bool) -> Self {
-        self.overwrite = enable;
+    pub fn overwrite(self, enable: bool) -> Self {
+        self.overwrite_mode(if enable { OverwriteMode::Always }
+                            else { OverwriteMode::Never })
+    }
+
+    /// Configure when to overwrite files/dirs
+    pub fn overwrite_mode(mut self, mode: OverwriteMode) -> Self {
+        self.overwrite = mode;
         self
     }
 
+    /// Decide whether an object with metadata `meta` should be restored over
+    /// whatever is at `path`. Fails if overwriting is disallowed.
+    fn replace_existing(&self, path: &Path, meta: &FSMetadata) -> Result<bool> {
+        let local = match fs::symlink_metadata(path) {
+            Ok(m)  => m,
+            Err(_) => return Ok(true) // nothing to replace
+        };
+
+        match self.overwrite {
+            OverwriteMode::Never   => Err(Error::WouldOverwrite),
+            OverwriteMode::Always  => Ok(true),
+            OverwriteMode::IfNewer => Ok(local.modified()? < meta.mtime)
+        }
+    }
+
     /// Configure whether to ignore stored permissions
     pub fn ignore_permissions(mut self, enable: bool) -> Self {
-        self.use_perms = !enable;
+        self.restore_perms = !enable;
+        self
+    }
+
+    /// Configure whether to ignore stored file attributes such as timestamps
+    pub fn ignore_attributes(mut self, enable: bool) -> Self {
+        self.restore_attrs = !enable;
+        self
+    }
+
+    /// Configure whether each downloaded block is hashed and compared to the
+    /// tag it was stored under. This is on by default; turning it off saves
+    /// some CPU time on large restores, but lets corrupt blocks through.
+    pub fn verify_blocks(mut self, enable: bool) -> Self {
+        self.verify_blocks = enable;
+        self
+    }
+
+    /// Configure the object stored at `from`, and everything under it, to be
+    /// restored at `to` instead of where it would otherwise go.
+    ///
+    /// Where mappings overlap, the one for the longest stored path wins.
+    /// Mapping the same path again replaces the earlier mapping. Mappings are
+    /// only applied to objects whose stored path is known, which is the case
+    /// when restoring a whole snapshot or using `restore_from`.
+    pub fn map_path<P, Q>(mut self, from: P, to: Q) -> Self
+            where P: AsRef<Path>, Q: AsRef<Path> {
+        let from = Path::new("/").join(from);
+        self.mappings.retain(|m| m.0 != from);
+        self.mappings.push((from, to.as_ref().to_owned()));
         self
     }
+
+    /// Find where the object stored at `stored` should be restored, if it's
+    /// covered by a mapping
+    fn mapped(&self, stored: &Path) -> Option<PathBuf> {
+        let best = self.mappings.iter()
+            .filter(|m| stored.starts_with(&m.0))
+            .max_by_key(|m| m.0.components().count());
+        best.map(|&(ref from, ref to)| {
+            let rest = stored.strip_prefix(from).unwrap();
+            if rest.as_os_str().is_empty() { to.clone() } else { to.join(rest) }
+        })
+    }
+
+    /// Apply the metadata selected by these options to the object at `path`
+    ///
+    /// If `is_link` is set, the link itself is updated rather than its target.
+    fn apply(&self, path: &Path, meta: &FSMetadata, is_link: bool)
+            -> Result<()> {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| Error::InvalidArgument)?;
+
+        if self.restore_perms {
+            // ownership has to be set first, since chown clears setuid bits
+            let mut mode = meta.mode;
+            match (self.chown)(&cpath, meta.uid, meta.gid) {
+                Ok(()) => {},
+
+                // unprivileged users can't give files away, so keep going.
+                // setuid and setgid bits would then apply to the wrong owner
+                Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {
+                    verbose!("bkp: cannot set owner of {}: {}",
+                             path.display(), e);
+                    self.unowned.set(self.unowned.get() + 1);
+                    mode &= !0o6000;
+                },
+                Err(e) => return Err(e.into())
+            }
+
+            // symlink modes are meaningless on Linux
+            if !is_link {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            }
+        }
+
+        if self.restore_attrs {
+            let to_timespec = |t: time::SystemTime| {
+                let d = t.duration_since(time::UNIX_EPOCH)
+                         .unwrap_or(time::Duration::from_secs(0));
+                libc::timespec { tv_sec: d.as_secs() as libc::time_t,
+                                 tv_nsec: d.subsec_nanos() as libc::c_long }
+            };
+
+            // atimes of zero weren't recorded, so leave the current one alone
+            let atime = if meta.atime == time::UNIX_EPOCH {
+                libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT }
+            } else {
+                to_timespec(meta.atime)
+            };
+            let times = [atime, to_timespec(meta.mtime)];
+            let flags = if is_link { libc::AT_SYMLINK_NOFOLLOW } else { 0 };
+            let r = unsafe {
+                libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(),
+                                times.as_ptr(), flags)
+            };
+            if r != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            write_xattrs(path, &meta.xattrs)?;
+        }
+
+        Ok(())
+    }
 }
 
 pub trait Restorable {
     /// Restore the given object into the tree rooted at `to`
     /// 
-    /// If `overwrite` is set, then overwrite any existing local data instead
-    /// of aborting when that would otherwise occur.
+    /// Existing local data is replaced, kept or causes the restore to abort
+    /// depending on the options' overwrite mode.
     fn restore<P: AsRef<Path>>(&self, to: P, opts: &RestoreOptions) -> Result<()>;
 }
 
-impl<'a, 'b> Restorable for ContextWrapper<'a, &'b FileObject> {
-    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
-        let path = base.as_ref().join(OsString::from_vec(self.name.clone()));
+/// A temporary file beside a path being restored, which is removed again
+/// unless it's moved into place.
+///
+/// Objects are built up under the temporary name and then renamed over the
+/// target, so an interrupted restore never leaves a partial object in place of
+/// whatever was there before.
+struct PartialRestore {
+    path: PathBuf,
+    target: PathBuf,
+    committed: bool
+}
 
-        // store the data before updating metadata attrs
-        { 
-            // TODO: honor the overwrite option
-            let mut f = fs::OpenOptions::new()
-                       .write(true)
-                       .create(true)
-                       .create_new(!opts.overwrite)
-                       .open(&path)?;
+impl PartialRestore {
+    fn new(target: &Path) -> Result<PartialRestore> {
+        let mut name = OsString::from(".");
+        name.push(target.file_name().ok_or(Error::InvalidArgument)?);
+        name.push(".bkp-partial");
+        let path = target.with_file_name(name);
 
-            // download each content block and copy them into the file
-            for block in self.body.iter() {
-                let data = self.backend.read_block(&block)?;
-                f.write_all(&data)?;
-            }
+        // clear out anything left behind by an earlier interrupted restore
+        if fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+        }
+        Ok(PartialRestore { path: path, target: target.to_path_buf(),
+                            committed: false })
+    }
 
-            if opts.use_perms {
-                // update metadata
-                let mut perms = f.metadata()?.permissions();
-                perms.set_mode(self.meta.mode);
-                f.set_permissions(perms)?;
+    /// Atomically replace the target with the temporary object
+    fn commit(mut self) -> Result<()> {
+        fs::rename(&self.path, &self.target)?;
+        self.committed = true;
+        Ok(())
+    }
+}
 
-                // TODO: handle uid/gid here
-            }
+impl Drop for PartialRestore {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
         }
+    }
+}
 
-        Ok(())
+/// Fail if there's a directory at `path`, since restoring a non-directory
+/// would mean replacing all of its contents
+fn refuse_directory(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.is_dir() => Err(Error::WouldOverwrite),
+        _                       => Ok(())
     }
 }
 
-impl<'a, 'b> Restorable for ContextWrapper<'a, &'b TreeObject> {
+impl<'a, 'b> Restorable for ContextWrapper<'a, &'b FileObject> {
     fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
         let path = base.as_ref().join(OsString::from_vec(self.name.clone()));
 
-        // create the directory if it doesn't already exist
-        if path.exists() {
-            let meta = path.metadata()?;
-
-            // refuse to overwrite
-            if !meta.is_dir() { return Err(Error::WouldOverwrite); }
-        } else {
-            // create it
-            fs::create_dir(&path)?;
-        }
+        if !opts.replace_existing(&path, &self.meta)? { return Ok(()); }
+        refuse_directory(&path)?;
 
-        if opts.use_perms {
-            // update metadata
-            let mut perms = fs::metadata(&path)?.permissions();
-            perms.set_mode(self.meta.mode);
-            fs::set_permissions(&path, perms);
-            // TODO: handle uid/gid here
-        }
+        // store the data before updating metadata attrs
+        let partial = PartialRestore::new(&path)?;
+        {
+            let mut f = fs::OpenOptions::new()
+                       .write(true)
+                       .create_new(true)
+                       .open(&partial.path)?;
 
-        // descend into children
-        for child in self.children.iter() {
-            match self.backend.read_meta(&child)? {
-                MetaObject::Snapshot(_)  => return Err(Error::IntegrityError),
-                MetaObject::Tree(t)      => self.child(&t).restore(&path, opts)?,
-                MetaObject::File(t)      => self.child(&t).restore(&path, opts)?,
-                MetaObject::Symlink(l)   => self.child(&l).restore(&path, opts)?,
-            }
+            // download each content block and copy them into the file
+            opts.progress.file_started(&path);
+            self.write_sparse(&mut f, &*opts.progress, opts.verify_blocks)?;
+            f.sync_all()?;
         }
 
+        opts.apply(&partial.path, &self.meta, false)?;
+        partial.commit()?;
+        opts.progress.object_stored();
         Ok(())
     }
 }
 
-impl<'a, 'b> Restorable for ContextWrapper<'a, &'b SymlinkObject> {
-    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
-        unimplemented!()
+impl<'a, 'b> ContextWrapper<'a, &'b FileObject> {
+    /// Reassemble the file's contents from its blocks, writing them to `out`
+    fn write_contents<W: Write>(&self, out: &mut W, progress: &Progress)
+            -> Result<()> {
+        let mut reader = BlockReader::new(&**self.backend, self.object)
+            .progress(progress);
+        io::copy(&mut reader, out)?;
+        Ok(())
     }
-}
 
-impl<'a> Restorable for ContextWrapper<'a, MetaObject> {
-    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
-        match self.object {
-            MetaObject::Tree(ref t) => self.child(t).restore(base, opts),
-            MetaObject::File(ref t) => self.child(t).restore(base, opts),
-            MetaObject::Symlink(ref t) => self.child(t).restore(base, opts),
-            _ => Err(Error::InvalidArgument)
+    /// Reassemble the file's contents into a new file, seeking past holes
+    /// rather than writing them out so that the file stays sparse. If `verify`
+    /// is set, blocks which don't match their tags are an integrity error.
+    fn write_sparse(&self, out: &mut fs::File, progress: &Progress,
+                    verify: bool) -> Result<()> {
+        let mut len = 0;
+        for tag in self.body.iter() {
+            let n = match tag.hole_len() {
+                Some(n) => {
+                    out.seek(io::SeekFrom::Current(n as i64))?;
+                    n
+                },
+                None    => {
+                    let data = if verify {
+                        read_verified(&**self.backend, tag)?
+                    } else {
+                        self.backend.read_block(tag)?
+                    };
+                    out.write_all(&data)?;
+                    data.len() as u64
+                }
+            };
+            len += n;
+            progress.bytes_written(n);
         }
+
+        // seeking doesn't extend the file by itself, so a trailing hole needs
+        // the length set
+        out.set_len(len)?;
+        Ok(())
     }
 }
 
-/// A wrapper struct to provide history access on top of a given backend
-pub struct History<'a> {
-    backend: &'a mut Box<Backend>
-}
+/// A reader over a file's contents, which fetches and decrypts each block only
+/// once the previous one has been used up. At most one block is held in memory
+/// at a time, however large the file is.
+///
+/// Backend errors are reported as I/O errors wrapping the `BackendError`.
+pub struct BlockReader<'a> {
+    backend: &'a Backend,
+    blocks: &'a [IdentityTag],
 
-impl<'a> History<'a> {
-    /// Wrap the given backend in the history layer
-    pub fn new(backend: &'a mut Box<Backend>) -> Result<Self> {
-        Ok(History { backend: backend })
-    }
+    /// The index of the next block to fetch
+    next: usize,
 
-    // run integrity tests on a block
-    fn check_block(&mut self, mode: IntegrityTestMode, tag: &IdentityTag)
-            -> Result<bool> {
-        // skip block checks in faster modes
-        if !mode.check_blocks() { return Ok(true); }
+    /// The current block, and how much of it has been read
+    current: Vec<u8>,
+    offset: usize,
 
-        let data = self.backend.read_block(tag)?;
+    /// Zeros left to produce from the current hole
+    hole: u64,
 
-        // check the hash if needed
-        if mode.check_hashes() {
-            let mut v = Vec::new();
-            let mut writer = Hasher::sha256(&mut v);
-            writer.write_all(&data)?;
-            let r = writer.finish();
-            if r.as_ref() != tag {
-                return Ok(false);
-            }
+    progress: Option<&'a Progress>
+}
+
+impl<'a> BlockReader<'a> {
+    /// Create a reader over a file's contents, stored on the given backend
+    pub fn new(backend: &'a Backend, file: &'a FileObject) -> Self {
+        BlockReader {
+            backend: backend,
+            blocks: &file.body,
+            next: 0,
+            current: Vec::new(),
+            offset: 0,
+            hole: 0,
+            progress: None
         }
-        Ok(true)
     }
 
-    // run integrity tests on a file or tree
-    fn check_file(&mut self, mode: IntegrityTestMode, tag: &IdentityTag)
-            -> Result<bool> {
-        let obj = self.backend.read_meta(tag)?;
-        match obj {
-            MetaObject::File(file) => {
-                for blk in file.body.iter() {
-                    if !self.check_block(mode, &blk)? {
-                        return Ok(false);
-                    }
-                }
-                Ok(true)
-            },
-            MetaObject::Symlink(_) => {Ok(true)},
-            MetaObject::Tree(_) => self.check_tree(mode, tag),
-            _ => Ok(false)
-        }
+    /// Report the bytes read to a progress reporter
+    pub fn progress(mut self, progress: &'a Progress) -> Self {
+        self.progress = Some(progress);
+        self
     }
+}
 
-    // run integrity tests on a filesystem tree
-    fn check_tree(&mut self, mode: IntegrityTestMode, tag: &IdentityTag)
-            -> Result<bool> {
-        let obj = self.backend.read_meta(tag)?;
-        if let MetaObject::Tree(tree) = obj {
-            for c in tree.children.iter() {
-                if !self.check_file(mode, c)? {
-                    return Ok(false);
+impl<'a> Read for BlockReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // move on once the current block is used up, skipping empty ones
+        while self.offset == self.current.len() && self.hole == 0 {
+            let tag = match self.blocks.get(self.next) {
+                Some(t) => t,
+                None    => return Ok(0)
+            };
+            match tag.hole_len() {
+                Some(len) => self.hole = len,
+                None      => {
+                    self.current = self.backend.read_block(tag)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    self.offset = 0;
                 }
             }
+            self.next += 1;
+        }
+
+        let n = if self.hole > 0 {
+            let n = cmp::min(buf.len() as u64, self.hole) as usize;
+            for b in buf[..n].iter_mut() { *b = 0; }
+            self.hole -= n as u64;
+            n
         } else {
-            // incorrect object type
-            return Ok(false);
+            let n = cmp::min(buf.len(), self.current.len() - self.offset);
+            buf[..n].copy_from_slice(
+                &self.current[self.offset..self.offset + n]);
+            self.offset += n;
+            n
+        };
+        if let Some(p) = self.progress {
+            p.bytes_written(n as u64);
         }
-        Ok(true)
+        Ok(n)
+    }
+}
+
+impl<'a, 'b> Restorable for ContextWrapper<'a, &'b TreeObject> {
+    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
+        self.restore_in(base.as_ref(), None, opts)
+    }
+}
+
+impl<'a, 'b> ContextWrapper<'a, &'b TreeObject> {
+    /// Restore the tree into the directory `base`. If the path it was stored
+    /// at is given, path mappings are applied to its children.
+    fn restore_in(&self, base: &Path, stored: Option<&Path>,
+                  opts: &RestoreOptions) -> Result<()> {
+        let path = base.join(OsString::from_vec(self.name.clone()));
+
+        create_tree_dir(&path)?;
+        self.restore_children(&path, stored, opts)?;
+
+        // update metadata last, so creating children doesn't change the mtime
+        opts.apply(&path, &self.meta, false)?;
+        opts.progress.object_stored();
+        Ok(())
+    }
+
+    /// Restore each of the tree's children into the directory at `path`,
+    /// except for any that are mapped elsewhere
+    fn restore_children(&self, path: &Path, stored: Option<&Path>,
+                        opts: &RestoreOptions) -> Result<()> {
+        // trees can be arbitrarily deep, so keep the work still to do on a
+        // stack of our own rather than recursing. children go on in reverse,
+        // so they're restored in order
+        let stored = stored.map(|s| s.to_owned());
+        let mut pending = self.children.iter().rev()
+            .map(|c| RestoreStep::Child(*c, path.to_owned(), stored.clone()))
+            .collect::<Vec<_>>();
+        while let Some(step) = pending.pop() {
+            let (child, path, stored) = match step {
+                RestoreStep::Child(c, p, s) => (c, p, s),
+
+                // update metadata last, so creating children doesn't change
+                // the mtime
+                RestoreStep::Finish(path, meta) => {
+                    opts.apply(&path, &meta, false)?;
+                    opts.progress.object_stored();
+                    continue;
+                }
+            };
+
+            let mut obj = self.read_meta(&child)?;
+            let name = obj.name().ok_or(Error::IntegrityError)?;
+            let child_path = stored.map(|s| s.join(&name));
+            let dir = match child_path.as_ref().and_then(|p| opts.mapped(p)) {
+                Some(dest) => {
+                    let (dir, name) = prepare_destination(&dest)?;
+                    obj.set_name(&name);
+                    dir
+                },
+                None => path
+            };
+
+            match obj {
+                MetaObject::Snapshot(_)  => return Err(Error::IntegrityError),
+                MetaObject::Tree(t)      => {
+                    let path = dir.join(OsString::from_vec(t.name));
+                    create_tree_dir(&path)?;
+                    pending.push(RestoreStep::Finish(path.clone(), t.meta));
+                    pending.extend(t.children.iter().rev().map(|c|
+                        RestoreStep::Child(*c, path.clone(),
+                                           child_path.clone())));
+                },
+                MetaObject::File(t)      => {
+                    self.child(&t).restore(&dir, opts)?;
+
+                    // remember where it went in case something links to it
+                    let name = OsString::from_vec(t.name.clone());
+                    opts.restored.borrow_mut().insert(child, dir.join(name));
+                },
+                MetaObject::Symlink(l)   => self.child(&l).restore(&dir, opts)?,
+                MetaObject::HardLink(l)  => self.child(&l).restore(&dir, opts)?,
+                MetaObject::Special(s)   => self.child(&s).restore(&dir, opts)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A step of restoring a tree's contents
+enum RestoreStep {
+    /// Restore the object with the given tag into a directory. The path the
+    /// directory was stored at is given if path mappings apply under it.
+    Child(IdentityTag, PathBuf, Option<PathBuf>),
+
+    /// Apply a restored directory's metadata, once everything in it is done
+    Finish(PathBuf, FSMetadata)
+}
+
+/// Create the directory a tree is restored into, if it doesn't already exist.
+/// Existing ones are merged into rather than replaced, and each child is
+/// restored atomically, so an interruption leaves only complete objects behind
+fn create_tree_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        // refuse to overwrite
+        if !path.metadata()?.is_dir() { return Err(Error::WouldOverwrite); }
+    } else {
+        fs::create_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Split a mapped destination into the directory it's restored into, which is
+/// created if needed, and the name it's restored under
+fn prepare_destination(dest: &Path) -> Result<(PathBuf, OsString)> {
+    let name = dest.file_name().ok_or(Error::InvalidArgument)?.to_owned();
+    let dir = match dest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_owned(),
+        _                                    => PathBuf::from(".")
+    };
+    fs::create_dir_all(&dir)?;
+    Ok((dir, name))
+}
+
+/// Restoring a snapshot restores the contents of its whole tree into the given
+/// directory, creating it if needed.
+///
+/// The root tree's own metadata isn't applied, since it's generated rather
+/// than taken from the filesystem.
+impl<'a> Restorable for ContextWrapper<'a, Snapshot> {
+    fn restore<P: AsRef<Path>>(&self, to: P, opts: &RestoreOptions) -> Result<()> {
+        fs::create_dir_all(to.as_ref())?;
+
+        let root = self.get_tree()?;
+        root.child(&root.object)
+            .restore_children(to.as_ref(), Some(Path::new("/")), opts)
+    }
+}
+
+impl<'a, 'b> Restorable for ContextWrapper<'a, &'b SymlinkObject> {
+    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
+        let path = base.as_ref().join(OsString::from_vec(self.name.clone()));
+
+        // make sure whatever's already there can be replaced
+        if !opts.replace_existing(&path, &self.meta)? { return Ok(()); }
+        refuse_directory(&path)?;
+
+        let partial = PartialRestore::new(&path)?;
+        symlink(OsString::from_vec(self.target.clone()), &partial.path)?;
+        opts.apply(&partial.path, &self.meta, true)?;
+        partial.commit()?;
+        opts.progress.object_stored();
+        Ok(())
+    }
+}
+
+impl<'a, 'b> Restorable for ContextWrapper<'a, &'b HardLinkObject> {
+    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
+        let path = base.as_ref().join(OsString::from_vec(self.name.clone()));
+        let existing = opts.restored.borrow().get(&self.target).cloned();
+
+        match existing {
+            Some(ref tgt) => {
+                if !opts.replace_existing(&path, &self.meta)? {
+                    return Ok(());
+                }
+                refuse_directory(&path)?;
+
+                let partial = PartialRestore::new(&path)?;
+                fs::hard_link(tgt, &partial.path)?;
+                partial.commit()?;
+                opts.progress.object_stored();
+                Ok(())
+            },
+            None => {
+                // the linked file wasn't restored (e.g. it's outside the
+                // restored subtree), so restore its contents under our name
+                let file = match self.read_meta(&self.target)? {
+                    MetaObject::File(f) => f,
+                    _                   => return Err(Error::IntegrityError)
+                };
+                let file = FileObject {
+                    name: self.name.clone(),
+                    meta: self.meta.clone(),
+                    body: file.body,
+                    size: file.size
+                };
+                self.child(&file).restore(base, opts)?;
+                opts.restored.borrow_mut().insert(self.target, path);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a, 'b> Restorable for ContextWrapper<'a, &'b SpecialObject> {
+    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
+        let path = base.as_ref().join(OsString::from_vec(self.name.clone()));
+
+        if !opts.replace_existing(&path, &self.meta)? { return Ok(()); }
+        refuse_directory(&path)?;
+
+        let partial = PartialRestore::new(&path)?;
+        let ftype = match self.kind {
+            SpecialKind::Fifo        => libc::S_IFIFO,
+            SpecialKind::Socket      => libc::S_IFSOCK,
+            SpecialKind::CharDevice  => libc::S_IFCHR,
+            SpecialKind::BlockDevice => libc::S_IFBLK,
+        };
+        let cpath = CString::new(partial.path.as_os_str().as_bytes())
+            .map_err(|_| Error::InvalidArgument)?;
+        let r = unsafe {
+            libc::mknod(cpath.as_ptr(), ftype | (self.meta.mode & 0o7777),
+                        self.rdev as libc::dev_t)
+        };
+        if r != 0 {
+            let err = io::Error::last_os_error();
+
+            // only root can create device nodes, so don't fail the restore
+            if err.raw_os_error() == Some(libc::EPERM) {
+                warn!("bkp: cannot create special file {:?}: {}",
+                      path, err);
+                return Ok(());
+            }
+            return Err(err.into());
+        }
+
+        opts.apply(&partial.path, &self.meta, false)?;
+        partial.commit()?;
+        opts.progress.object_stored();
+        Ok(())
+    }
+}
+
+impl<'a> ContextWrapper<'a, MetaObject> {
+    /// Restore an object which was stored at the path `stored` into the
+    /// directory `base`, like `restore`, but applying the options' path
+    /// mappings to it and everything under it
+    pub fn restore_from<P: AsRef<Path>>(&self, stored: &Path, base: P,
+                                        opts: &RestoreOptions) -> Result<()> {
+        let stored = Path::new("/").join(stored);
+        let mut obj = self.object.clone();
+        let dir = match opts.mapped(&stored) {
+            Some(dest) => {
+                let (dir, name) = prepare_destination(&dest)?;
+                obj.set_name(&name);
+                dir
+            },
+            None => base.as_ref().to_owned()
+        };
+
+        match obj {
+            MetaObject::Tree(ref t) =>
+                self.child(t).restore_in(&dir, Some(&stored), opts),
+            other => self.child(other).restore(&dir, opts)
+        }
+    }
+
+    /// Read the children of a tree object, sorted by name. Returns `None` for
+    /// other kinds of object.
+    pub fn children(&self) -> Result<Option<Vec<ContextWrapper<'a, MetaObject>>>> {
+        let tree = match self.object {
+            MetaObject::Tree(ref t) => t,
+            _                       => return Ok(None)
+        };
+
+        let mut children = Vec::new();
+        for id in tree.children.iter() {
+            children.push(self.child(self.read_meta(id)?));
+        }
+        children.sort_by_key(|c| c.name());
+        Ok(Some(children))
+    }
+
+    /// Write the contents of a file, or of the file a hard link points to, to
+    /// `out`. Fails with `InvalidArgument` for other kinds of object.
+    pub fn write_contents<W: Write>(&self, out: &mut W) -> Result<()> {
+        match self.object {
+            MetaObject::File(ref f) =>
+                self.child(f).write_contents(out, &NoProgress),
+            MetaObject::HardLink(ref l) =>
+                match self.read_meta(&l.target)? {
+                    MetaObject::File(ref f) =>
+                        self.child(f).write_contents(out, &NoProgress),
+                    _ => Err(Error::IntegrityError)
+                },
+            _ => Err(Error::InvalidArgument)
+        }
+    }
+
+    /// Get the file object holding a file's contents, following hard links.
+    /// Returns `None` for objects which don't have contents.
+    pub fn contents(&self) -> Result<Option<FileObject>> {
+        match self.object {
+            MetaObject::File(ref f) => Ok(Some(f.clone())),
+            MetaObject::HardLink(ref l) =>
+                match self.read_meta(&l.target)? {
+                    MetaObject::File(f) => Ok(Some(f)),
+                    _                   => Err(Error::IntegrityError)
+                },
+            _ => Ok(None)
+        }
+    }
+
+    /// Read one of the data blocks making up a file's contents
+    pub fn read_block(&self, tag: &IdentityTag) -> Result<Vec<u8>> {
+        Ok(self.backend.read_block(tag)?)
+    }
+}
+
+impl<'a> Restorable for ContextWrapper<'a, MetaObject> {
+    fn restore<P: AsRef<Path>>(&self, base: P, opts: &RestoreOptions) -> Result<()> {
+        match self.object {
+            MetaObject::Tree(ref t) => self.child(t).restore(base, opts),
+            MetaObject::File(ref t) => self.child(t).restore(base, opts),
+            MetaObject::Symlink(ref t) => self.child(t).restore(base, opts),
+            MetaObject::HardLink(ref t) => self.child(t).restore(base, opts),
+            MetaObject::Special(ref t) => self.child(t).restore(base, opts),
+            _ => Err(Error::InvalidArgument)
+        }
+    }
+}
+
+/// Find the most recent snapshot created at or before `t` in a chain ordered
+/// from newest to oldest
+fn newest_before(chain: &[(IdentityTag, Snapshot)], t: time::SystemTime)
+        -> Option<&(IdentityTag, Snapshot)> {
+    chain.iter().find(|s| s.1.create_time <= t)
+}
+
+/// Canonicalize a set of paths to be stored, sorted by depth so the shallowest
+/// ones are visited before their potential children. Paths inside another
+/// directory in the set are dropped, since storing that directory covers them.
+fn normalize_paths<'b, P, I>(paths: I) -> Vec<PathBuf>
+        where P: 'b + AsRef<OsStr> + ?Sized,
+              I: IntoIterator<Item=&'b P> {
+    let mut paths: Vec<PathBuf> = paths.into_iter()
+                                    .map(Path::new)
+                                    .map(|p| p.canonicalize().unwrap())
+                                    .collect();
+    paths.sort_by_key(|p| p.components().count());
+
+    // prune directories that are subdirs of another dir in the list
+    let mut result: Vec<PathBuf> = Vec::new();
+    for p in paths.into_iter() {
+        if !result.iter().any(|x| p.starts_with(x)) {
+            result.push(p);
+        }
+    }
+    result
+}
+
+/// Compute the identity a data block is stored under
+fn block_tag(data: &[u8]) -> IdentityTag {
+    let mut sink = DevNull::new();
+    let mut writer = Hasher::sha256(&mut sink);
+    writer.write_all(data).unwrap(); // writes to DevNull can't fail
+    tag_from_digest(writer.finish())
+}
+
+/// Read a block, checking that its contents match its tag. A block which
+/// can't be read or doesn't match is downloaded once more before giving up, in
+/// case it was only damaged on the way.
+fn read_verified(backend: &Backend, tag: &IdentityTag) -> Result<Vec<u8>> {
+    let mut last = Error::IntegrityError;
+    for _ in 0..2 {
+        match backend.read_block(tag) {
+            Ok(data) => {
+                if block_tag(&data) == *tag { return Ok(data); }
+                last = Error::IntegrityError;
+            },
+            Err(e) => last = e.into()
+        }
+    }
+    Err(last)
+}
+
+/// The shortest run of zeros stored as a hole rather than as a block
+const MIN_HOLE: usize = 4096;
+
+/// Check whether a chunk should be stored as a hole
+fn is_hole(chunk: &[u8]) -> bool {
+    chunk.len() >= MIN_HOLE && chunk.iter().all(|&b| b == 0)
+}
+
+/// Add a hole of `len` bytes to the end of a chunk list, extending the last
+/// entry if that's a hole already
+fn push_hole(blocks: &mut Vec<IdentityTag>, len: u64) {
+    let prev = blocks.last().and_then(|t| t.hole_len());
+    match prev {
+        Some(n) => *blocks.last_mut().unwrap() = IdentityTag::hole(n + len),
+        None    => blocks.push(IdentityTag::hole(len))
+    }
+}
+
+/// Default number of file chunks uploaded together
+const DEFAULT_UPLOAD_BATCH: usize = 8;
+
+/// A wrapper struct to provide history access on top of a given backend
+pub struct History<'a> {
+    backend: &'a mut Box<Backend>,
+
+    /// Target size of the content chunks that stored files are split into
+    chunk_size: usize,
+
+    /// File objects stored for multiply-linked inodes, keyed by device and
+    /// inode number
+    links: HashMap<(u64, u64), IdentityTag>,
+
+    /// Whether to record extended attributes of stored files
+    store_xattrs: bool,
+
+    /// Whether to record access times of stored paths
+    store_atime: bool,
+
+    /// How many chunks to hand to the backend at once, which bounds how many
+    /// uploads can be in flight
+    upload_batch: usize,
+
+    /// Whether files whose size and mtime match their previously stored
+    /// version can be assumed unchanged, rather than having their contents
+    /// hashed
+    trust_mtime: bool,
+
+    /// Where to report progress as paths are stored
+    progress: Rc<Progress>,
+
+    /// Patterns for paths to leave out of stored trees
+    excludes: Vec<Pattern>,
+
+    /// Whether to leave out anything on a different filesystem from the
+    /// directory containing it
+    one_file_system: bool,
+
+    /// Whether symlinks are followed, storing what they point to instead
+    dereference: bool,
+
+    /// Extensions of files whose contents are stored without compression,
+    /// in lowercase and without the leading dot
+    no_compress_exts: Vec<String>,
+
+    /// Whether to leave out the contents of directories tagged as caches
+    exclude_caches: bool,
+
+    /// Names of files which mark the directory containing them as excluded
+    exclude_markers: Vec<OsString>,
+
+    /// Whether to leave out directories with nothing stored in them
+    prune_empty_dirs: bool,
+
+    /// Whether to check that everything a new snapshot refers to is stored
+    /// before committing it
+    verify_commit: bool,
+
+    /// Where to journal stored files, so an interrupted snapshot can be resumed
+    journal_path: Option<PathBuf>,
+
+    /// The journal for the paths being stored, once `update_paths` opens it
+    journal: Option<Journal>,
+
+    /// Recently read metadata objects, so walking the same trees repeatedly
+    /// doesn't fetch and decrypt them each time
+    meta_cache: MetaCache
+}
+
+impl<'a> History<'a> {
+    /// Wrap the given backend in the history layer
+    pub fn new(backend: &'a mut Box<Backend>) -> Result<Self> {
+        Ok(History { backend: backend, chunk_size: DEFAULT_CHUNK_SIZE,
+                     links: HashMap::new(), store_xattrs: true,
+                     store_atime: true,
+                     upload_batch: DEFAULT_UPLOAD_BATCH, trust_mtime: true,
+                     progress: Rc::new(NoProgress), excludes: Vec::new(),
+                     one_file_system: false, dereference: false,
+                     no_compress_exts: Vec::new(),
+                     exclude_caches: false, exclude_markers: Vec::new(),
+                     prune_empty_dirs: false, verify_commit: false,
+                     journal_path: None, journal: None,
+                     meta_cache: MetaCache::new(metacache::DEFAULT_CAPACITY) })
+    }
+
+    /// Configure where to journal the files stored by `update_paths`.
+    ///
+    /// If a snapshot of the same paths was interrupted, files it stored which
+    /// haven't changed since are reused rather than read and uploaded again.
+    /// The journal is removed once `new_snapshot` commits a snapshot.
+    pub fn set_journal(&mut self, path: &Path) {
+        self.journal_path = Some(path.to_owned());
+    }
+
+    /// Configure patterns for paths to leave out of stored trees
+    pub fn set_excludes(&mut self, patterns: Vec<Pattern>) {
+        self.excludes = patterns;
+    }
+
+    /// Configure where to report progress as paths are stored
+    pub fn set_progress(&mut self, progress: Rc<Progress>) {
+        self.progress = progress;
+    }
+
+    /// Configure whether unchanged mtimes are enough to skip re-reading files
+    pub fn set_trust_mtime(&mut self, enable: bool) {
+        self.trust_mtime = enable;
+    }
+
+    /// Configure whether stored trees stay on the filesystem they start on,
+    /// leaving out anything mounted inside them
+    pub fn set_one_file_system(&mut self, enable: bool) {
+        self.one_file_system = enable;
+    }
+
+    /// Configure whether symlinks are followed, so the files and directories
+    /// they point to are stored under the link's name instead of the link
+    /// itself. Links which point back at a directory being stored are skipped
+    /// as directory loops, and dangling links are stored as links.
+    pub fn set_dereference(&mut self, enable: bool) {
+        self.dereference = enable;
+    }
+
+    /// Configure extensions of files, such as `jpg` or `zip`, which hold data
+    /// that's already compressed. Their contents are stored without being
+    /// compressed again. Extensions are matched without regard to case.
+    pub fn set_no_compress_exts(&mut self, exts: Vec<String>) {
+        self.no_compress_exts = exts.into_iter()
+            .map(|e| e.trim_left_matches('.').to_lowercase())
+            .collect();
+    }
+
+    /// Configure whether directories tagged as caches are stored empty, apart
+    /// from the tag file itself
+    pub fn set_exclude_caches(&mut self, enable: bool) {
+        self.exclude_caches = enable;
+    }
+
+    /// Configure names of marker files, such as `.nobackup`, which cause the
+    /// directory containing them to be stored empty apart from the marker,
+    /// just like a tagged cache
+    pub fn set_exclude_markers(&mut self, names: Vec<OsString>) {
+        self.exclude_markers = names;
+    }
+
+    /// Configure whether directories which end up with no stored children,
+    /// such as ones holding only excluded files, are left out. The paths
+    /// given to `update_paths` are always stored.
+    pub fn set_prune_empty_dirs(&mut self, enable: bool) {
+        self.prune_empty_dirs = enable;
+    }
+
+    /// Configure whether `new_snapshot` checks that every object and block
+    /// under the new root is stored before committing it. If anything is
+    /// missing, the head is left alone.
+    pub fn set_verify_commit(&mut self, enable: bool) {
+        self.verify_commit = enable;
+    }
+
+    /// Configure how many file chunks may be uploaded concurrently
+    pub fn set_upload_batch(&mut self, size: usize) {
+        self.upload_batch = size.max(1);
+    }
+
+    /// Configure whether extended attributes are recorded with stored files
+    pub fn set_store_xattrs(&mut self, enable: bool) {
+        self.store_xattrs = enable;
+    }
+
+    /// Configure whether access times are recorded with stored paths.
+    ///
+    /// Metadata objects are identified by their contents, so an atime which
+    /// changed since the last snapshot makes an otherwise unchanged file, and
+    /// every tree above it, into new objects which have to be stored again.
+    /// Leaving atimes out avoids that, but restored paths then keep whatever
+    /// access time they get when they're written.
+    pub fn set_store_atime(&mut self, enable: bool) {
+        self.store_atime = enable;
+    }
+
+    /// Configure the target size of newly-stored file chunks
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size;
+    }
+
+    /// Configure how many metadata objects are cached in memory, replacing
+    /// whatever is cached already. Zero disables caching.
+    pub fn set_meta_cache_size(&mut self, objects: usize) {
+        self.meta_cache = MetaCache::new(objects);
+    }
+
+    fn read_meta(&self, tag: &IdentityTag) -> BackendResult<MetaObject> {
+        self.meta_cache.read(&**self.backend, tag)
+    }
+
+    // run integrity tests on a block used by the file at `path`, unless it's
+    // in `checked` already
+    fn check_block(&self, mode: IntegrityTestMode, tag: &IdentityTag,
+                   path: &Path, report: &mut VerifyReport,
+                   checked: &mut HashSet<IdentityTag>) {
+        // skip block checks in faster modes. holes aren't stored, so there's
+        // nothing to check
+        if !mode.check_blocks() || tag.hole_len().is_some() { return; }
+        if !checked.insert(*tag) { return; }
+
+        report.blocks_checked += 1;
+        let fault = match self.backend.read_block(tag) {
+            Err(_)   => Some(BlockFault::Missing),
+            // check the hash if needed
+            Ok(data) => if mode.check_hashes() && block_tag(&data) != *tag {
+                Some(BlockFault::Corrupt)
+            } else { None }
+        };
+        if let Some(f) = fault {
+            report.bad_blocks.push((path.to_owned(), *tag, f));
+        }
+    }
+
+    // run integrity tests on an object stored in the directory `dir` and
+    // everything under it, skipping objects in `checked`
+    fn check_file(&self, mode: IntegrityTestMode, tag: &IdentityTag,
+                  dir: &Path, report: &mut VerifyReport,
+                  checked: &mut HashSet<IdentityTag>) {
+        // stored trees can be arbitrarily deep, so keep the objects still to
+        // visit on a stack of our own rather than recursing. each is stored
+        // with the depth of its directory below `dir`, and `path` is trimmed
+        // back to that depth when it's visited
+        let mut path = dir.to_owned();
+        let mut depth = 0;
+        let mut pending = vec![(0, *tag)];
+        while let Some((level, tag)) = pending.pop() {
+            while depth > level {
+                path.pop();
+                depth -= 1;
+            }
+            if !checked.insert(tag) { continue; }
+            let obj = match self.read_meta(&tag) {
+                Ok(o)  => o,
+                Err(_) => {
+                    report.bad_objects.push((path.clone(), tag));
+                    continue;
+                }
+            };
+
+            // snapshots are never valid children
+            let name = match obj.name() {
+                Some(n) => n,
+                None    => {
+                    report.bad_objects.push((path.clone(), tag));
+                    continue;
+                }
+            };
+
+            match obj {
+                MetaObject::File(file) => {
+                    let path = path.join(name);
+                    for blk in file.body.iter() {
+                        self.check_block(mode, &blk, &path, report, checked);
+                    }
+                },
+                MetaObject::HardLink(link) => pending.push((depth, link.target)),
+
+                // children go on in reverse, so they're checked in order
+                MetaObject::Tree(tree) => {
+                    path.push(name);
+                    depth += 1;
+                    pending.extend(tree.children.iter().rev()
+                                       .map(|c| (depth, *c)));
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // run integrity tests on a snapshot's root tree, skipping objects in
+    // `checked`
+    fn check_tree(&self, mode: IntegrityTestMode, tag: &IdentityTag,
+                  report: &mut VerifyReport,
+                  checked: &mut HashSet<IdentityTag>) {
+        if !checked.insert(*tag) { return; }
+        let root = Path::new("/");
+        match self.read_meta(tag) {
+            Ok(MetaObject::Tree(tree)) => {
+                for c in tree.children.iter() {
+                    self.check_file(mode, c, root, report, checked);
+                }
+            },
+            _ => report.bad_objects.push((root.to_owned(), *tag))
+        }
+    }
+
+    /// Find the paths which differ between two snapshots
+    ///
+    /// Objects are identified by their contents, so subtrees with the same tag
+    /// in both snapshots are skipped without being read. Changes are listed in
+    /// depth-first order, sorted by name within each directory. Added and
+    /// removed directories are listed once, without their contents.
+    pub fn diff(&self, from: &Snapshot, to: &Snapshot)
+            -> Result<Vec<PathChange>> {
+        let mut changes = Vec::new();
+        self.diff_trees(&from.root, &to.root, Path::new("/"), &mut changes)?;
+        Ok(changes)
+    }
+
+    // compare two versions of the tree at `path`
+    fn diff_trees(&self, from: &IdentityTag, to: &IdentityTag, path: &Path,
+                  changes: &mut Vec<PathChange>) -> Result<()> {
+        // identical tags mean identical subtrees
+        if from == to { return Ok(()); }
+
+        // trees can be arbitrarily deep, so rather than recursing, keep the
+        // directories being compared on a stack. the top one is worked
+        // through until a changed subdirectory is found, which is then
+        // finished before carrying on, so changes come out depth-first
+        let mut stack = vec![DiffFrame {
+            path: path.to_owned(),
+            old: self.read_children(from)?.into_iter(),
+            new: self.read_children(to)?
+        }];
+        while !stack.is_empty() {
+            let next = stack.last_mut().unwrap().old.next();
+            let (name, (old_tag, old_obj)) = match next {
+                Some(c) => c,
+                None    => {
+                    // whatever's left only exists in the newer tree
+                    let frame = stack.pop().unwrap();
+                    for (name, _) in frame.new.into_iter() {
+                        changes.push(PathChange { path: frame.path.join(name),
+                                                  kind: ChangeKind::Added });
+                    }
+                    continue;
+                }
+            };
+
+            let child = stack.last().unwrap().path.join(&name);
+            let removed = stack.last_mut().unwrap().new.remove(&name);
+            let (new_tag, new_obj) = match removed {
+                Some(n) => n,
+                None    => {
+                    changes.push(PathChange { path: child,
+                                              kind: ChangeKind::Removed });
+                    continue;
+                }
+            };
+            if old_tag == new_tag { continue; }
+
+            match (old_obj, new_obj) {
+                (MetaObject::Tree(a), MetaObject::Tree(b)) => {
+                    if a.meta != b.meta {
+                        changes.push(PathChange { path: child.clone(),
+                                                  kind: ChangeKind::Modified });
+                    }
+                    stack.push(DiffFrame {
+                        path: child,
+                        old: self.read_children(&old_tag)?.into_iter(),
+                        new: self.read_children(&new_tag)?
+                    });
+                },
+                _ => changes.push(PathChange { path: child,
+                                               kind: ChangeKind::Modified })
+            }
+        }
+        Ok(())
+    }
+
+    // read the children of a tree, keyed by name
+    fn read_children(&self, tag: &IdentityTag)
+            -> Result<BTreeMap<OsString, (IdentityTag, MetaObject)>> {
+        let tree = match self.read_meta(tag)? {
+            MetaObject::Tree(t) => t,
+            _                   => return Err(Error::IntegrityError)
+        };
+
+        let mut children = BTreeMap::new();
+        for c in tree.children.iter() {
+            let obj = self.read_meta(c)?;
+            let name = obj.name().ok_or(Error::IntegrityError)?;
+            children.insert(name, (*c, obj));
+        }
+        Ok(children)
+    }
+
+    /// Verify the contents of a single snapshot end-to-end
+    ///
+    /// Every block referenced by the snapshot's tree is downloaded and hashed.
+    /// Rather than stopping at the first problem, this records every bad block
+    /// and object it finds along with the paths they belong to.
+    pub fn verify_snapshot(&self, snap: &Snapshot) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut checked = HashMap::new();
+
+        // trees can be arbitrarily deep, so keep the objects still to verify
+        // on a stack of our own, along with the directory each is stored in
+        let mut pending = vec![(snap.root, PathBuf::from("/"))];
+        while let Some((tag, dir)) = pending.pop() {
+            let obj = match self.read_meta(&tag) {
+                Ok(o)  => o,
+                Err(_) => {
+                    report.bad_objects.push((dir, tag));
+                    continue;
+                }
+            };
+
+            // snapshots are never valid children
+            let path = match obj.name() {
+                Some(n) => dir.join(n),
+                None    => {
+                    report.bad_objects.push((dir, tag));
+                    continue;
+                }
+            };
+
+            match obj {
+                // pushed in reverse so children are still checked in order
+                MetaObject::Tree(t) => pending.extend(
+                    t.children.iter().rev().map(|c| (*c, path.clone()))),
+                MetaObject::File(f) =>
+                    self.verify_blocks(&f.body, &path, &mut report,
+                                       &mut checked),
+                MetaObject::HardLink(l) => match self.read_meta(&l.target) {
+                    Ok(MetaObject::File(f)) =>
+                        self.verify_blocks(&f.body, &path, &mut report,
+                                           &mut checked),
+                    _ => report.bad_objects.push((dir, l.target))
+                },
+                _ => {}
+            }
+        }
+        Ok(report)
+    }
+
+    // verify the blocks making up the file at `path`
+    fn verify_blocks(&self, body: &[IdentityTag], path: &Path,
+                     report: &mut VerifyReport,
+                     checked: &mut HashMap<IdentityTag, Option<BlockFault>>) {
+        for blk in body.iter().filter(|b| b.hole_len().is_none()) {
+            // blocks shared between files are only downloaded once
+            let fault = match checked.get(blk).cloned() {
+                Some(f) => f,
+                None    => {
+                    let f = match self.backend.read_block(blk) {
+                        Err(_) => Some(BlockFault::Missing),
+                        Ok(data) => if block_tag(&data) == *blk { None }
+                                    else { Some(BlockFault::Corrupt) }
+                    };
+                    report.blocks_checked += 1;
+                    checked.insert(*blk, f);
+                    f
+                }
+            };
+
+            if let Some(f) = fault {
+                report.bad_blocks.push((path.to_owned(), *blk, f));
+            }
+        }
+    }
+
+    /// Upload bad blocks found by `verify_snapshot` again, regenerating them
+    /// from the local files they were stored from.
+    ///
+    /// Each file using a bad block is chunked again with the configured chunk
+    /// size, and chunks matching a bad block replace it. Blocks from files
+    /// which are gone or have changed since can't be reproduced, and are
+    /// listed in the returned report.
+    pub fn repair_blocks(&mut self, report: &VerifyReport)
+            -> Result<RepairReport> {
+        let mut wanted: HashMap<IdentityTag, BlockFault> = report.bad_blocks
+            .iter().map(|&(_, tag, fault)| (tag, fault)).collect();
+        let mut tried = HashSet::new();
+        let mut result = RepairReport::default();
+
+        for &(ref path, ref tag, _) in report.bad_blocks.iter() {
+            // blocks shared between files only have to be found once
+            if !wanted.contains_key(tag) || !tried.insert(path.clone()) {
+                continue;
+            }
+            let f = match fs::File::open(path) {
+                Ok(f)  => f,
+                Err(e) => {
+                    verbose!("bkp: cannot read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            // store blocks the same way taking a snapshot would have
+            let compress = !self.is_incompressible(path);
+            for c in f.bytes().chunks_sized(self.chunk_size) {
+                let c = c?;
+                let tag = block_tag(&c);
+                let fault = match wanted.remove(&tag) {
+                    Some(f) => f,
+                    None    => continue
+                };
+
+                // a corrupt copy has to go first, or it'd be taken as stored
+                if fault == BlockFault::Corrupt {
+                    self.backend.delete_block(&tag)?;
+                }
+                let chunks = [c];
+                let stored = if compress {
+                    self.backend.write_blocks(&chunks)?
+                } else {
+                    self.backend.write_uncompressed(&chunks)?
+                };
+                if stored != [tag] {
+                    return Err(Error::IntegrityError);
+                }
+                result.repaired.push(tag);
+                if wanted.is_empty() { break; }
+            }
+        }
+
+        result.unrepairable = report.bad_blocks.iter()
+            .filter(|b| wanted.contains_key(&b.1)).cloned().collect();
+        Ok(result)
+    }
+
+    /// Run integrity tests on the history
+    ///
+    /// Every problem found is recorded in the returned report, in the same
+    /// form `verify_snapshot` uses, under the snapshot it was found in.
+    pub fn check(&self, mode: IntegrityTestMode) -> Result<CheckReport> {
+        let mut report = CheckReport::default();
+        let mut checked = HashSet::new();
+
+        // get the chain head
+        let mut next = self.head_id()?;
+
+        // traverse the snapshot chain
+        while let Some(tag) = next {
+            let mut found = VerifyReport::default();
+            let snap = match self.read_meta(&tag) {
+                Ok(MetaObject::Snapshot(s)) => s,
+                _ => {
+                    found.bad_objects.push((PathBuf::new(), tag));
+                    report.snapshots.push((tag, found));
+                    break;
+                }
+            };
+
+            // check the file structure
+            if mode.check_trees() {
+                self.check_tree(mode, &snap.root, &mut found, &mut checked);
+            }
+            report.snapshots.push((tag, found));
+
+            // move to the parent if needed
+            next = snap.parent;
+        }
+        Ok(report)
+    }
+
+    // accumulate statistics for the object with the given tag and its
+    // children, returning the size of the blocks under it counting duplicates.
+    //
+    // Identical objects have identical contents, so each is only read once and
+    // its size remembered in `sizes`. Blocks not yet in `block_sizes` haven't
+    // been seen before, and their sizes are added to `added`.
+    fn tally_object(&self, tag: &IdentityTag, stats: &mut Stats,
+                    sizes: &mut HashMap<IdentityTag, u64>,
+                    block_sizes: &mut HashMap<IdentityTag, u64>,
+                    added: &mut u64)
+            -> Result<u64> {
+        // trees can be arbitrarily deep, so walk them with a stack of our own.
+        // a tree's size is summed once everything above it on the stack, i.e.
+        // its children, has been tallied
+        let mut pending = vec![TallyStep::Visit(*tag)];
+        while let Some(step) = pending.pop() {
+            let tag = match step {
+                TallyStep::Visit(t) => t,
+                TallyStep::Sum(t, children) => {
+                    let total = children.iter().map(|c| sizes[c]).sum();
+                    sizes.insert(t, total);
+                    continue;
+                }
+            };
+            if sizes.contains_key(&tag) { continue; }
+
+            let total = match self.read_meta(&tag)? {
+                MetaObject::Tree(tree) => {
+                    let visits = tree.children.iter()
+                        .map(|c| TallyStep::Visit(*c)).collect::<Vec<_>>();
+                    pending.push(TallyStep::Sum(tag, tree.children));
+                    pending.extend(visits);
+                    continue;
+                },
+                MetaObject::File(file) => {
+                    // holes aren't stored, so they take up no space
+                    let mut total = 0;
+                    let blocks = file.body.iter()
+                        .filter(|b| b.hole_len().is_none());
+                    for blk in blocks {
+                        let size = match block_sizes.get(blk) {
+                            Some(&s) => s,
+                            None     => {
+                                let s = self.backend.block_size(blk)?;
+                                *added += s;
+                                s
+                            }
+                        };
+                        block_sizes.insert(*blk, size);
+                        total += size;
+                    }
+
+                    // objects written by older versions don't record their
+                    // length, so make do with what their blocks take up
+                    stats.logical_bytes += file.size.unwrap_or(total);
+                    total
+                },
+                MetaObject::Symlink(_) => 0,
+
+                // the target's data is counted where the target is, but it
+                // might only be reachable through here
+                MetaObject::HardLink(link) => {
+                    pending.push(TallyStep::Visit(link.target));
+                    0
+                },
+                MetaObject::Special(_) => 0,
+                MetaObject::Snapshot(_) => return Err(Error::IntegrityError)
+            };
+            sizes.insert(tag, total);
+        }
+        Ok(sizes[tag])
+    }
+
+    /// Gather statistics over the whole snapshot chain
+    ///
+    /// This reads every reachable object and looks up the size of every
+    /// reachable block, so it can be slow on large histories.
+    pub fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats::default();
+        let mut sizes = HashMap::new();
+        let mut block_sizes = HashMap::new();
+
+        let chain = self.snapshots()?;
+        stats.head = self.head_id()?;
+        stats.latest = chain.first().map(|&(_, ref s)| s.create_time);
+        stats.snapshots = chain.len() as u64;
+
+        // go oldest first, so each snapshot is credited with the blocks it
+        // added
+        for &(ref tag, ref snap) in chain.iter().rev() {
+            let mut added = 0;
+            let referenced = self.tally_object(&snap.root, &mut stats,
+                                               &mut sizes, &mut block_sizes,
+                                               &mut added)?;
+            stats.referenced_bytes += referenced;
+            stats.snapshot_stats.push(SnapshotStats {
+                id: *tag,
+                referenced_bytes: referenced,
+                added_bytes: added
+            });
+        }
+        stats.snapshot_stats.reverse();
+
+        stats.unique_blocks = block_sizes.len() as u64;
+        stats.stored_bytes = block_sizes.values().sum();
+        Ok(stats)
+    }
+
+    /// List the snapshot chain, starting with the most recent snapshot
+    pub fn snapshots(&self) -> Result<Vec<(IdentityTag, Snapshot)>> {
+        let mut result = Vec::new();
+        let mut next = self.head_id()?;
+        while let Some(tag) = next {
+            let snap = match self.read_meta(&tag)? {
+                MetaObject::Snapshot(s) => s,
+                _                       => return Err(Error::IntegrityError)
+            };
+            next = snap.parent;
+            result.push((tag, snap));
+        }
+        Ok(result)
+    }
+
+    /// Find a snapshot to point the head at when it's missing or broken.
+    ///
+    /// Every stored metadata object is scanned for snapshots, without relying
+    /// on the head at all. Objects which can't be read are skipped, so other
+    /// nodes' snapshots are never picked. Candidates are tried newest first,
+    /// and the first whose tree passes an integrity check in the given mode
+    /// is chosen.
+    pub fn find_recovery_point(&self, mode: IntegrityTestMode)
+            -> Result<Recovery> {
+        let mut snaps = HashMap::new();
+        for tag in self.backend.list_meta()? {
+            if let Ok(MetaObject::Snapshot(s)) = self.read_meta(&tag) {
+                snaps.insert(tag, s);
+            }
+        }
+
+        let mut candidates: Vec<(&IdentityTag, &Snapshot)> =
+            snaps.iter().collect();
+        candidates.sort_by(|a, b| b.1.create_time.cmp(&a.1.create_time));
+
+        let mut result = Recovery { snapshots: snaps.len(),
+                                    ..Recovery::default() };
+        for (tag, snap) in candidates {
+            // candidates are checked separately, since objects already seen
+            // under a damaged one could still be damaged here
+            let mut report = VerifyReport::default();
+            self.check_tree(mode, &snap.root, &mut report,
+                            &mut HashSet::new());
+            if !report.is_ok() { continue; }
+
+            // reconstruct as much of the chain as is still there
+            let mut next = Some(*tag);
+            while let Some(s) = next.and_then(|t| snaps.get(&t)) {
+                result.chain_length += 1;
+                next = s.parent;
+            }
+            result.newest_intact = Some((*tag, snap.clone()));
+            break;
+        }
+        Ok(result)
+    }
+
+    /// Point the head at a given snapshot, replacing whatever it pointed to
+    pub fn reset_head(&mut self, tag: &IdentityTag) -> Result<()> {
+        match self.read_meta(tag)? {
+            MetaObject::Snapshot(_) => {},
+            _                       => return Err(Error::InvalidArgument)
+        }
+        self.backend.set_head(tag)?;
+        Ok(())
+    }
+
+    /// Remove the given snapshots from the chain
+    ///
+    /// Since each snapshot embeds its parent's identity, every snapshot newer
+    /// than the oldest removed one is rewritten and the head is moved to the
+    /// new chain. Storage isn't reclaimed until the next call to `gc`.
+    pub fn remove_snapshots(&mut self, remove: &HashSet<IdentityTag>)
+            -> Result<()> {
+        let chain = self.snapshots()?;
+        if remove.is_empty() || chain.is_empty() { return Ok(()); }
+
+        // there has to be something left to point the head at
+        if chain.iter().all(|&(ref t, _)| remove.contains(t)) {
+            return Err(Error::InvalidArgument);
+        }
+
+        // walk from the oldest snapshot forwards
+        let mut parent = None;
+        let mut rewriting = false;
+        for (tag, snap) in chain.into_iter().rev() {
+            if remove.contains(&tag) {
+                rewriting = true;
+            } else if rewriting {
+                let obj = MetaObject::Snapshot(Snapshot {
+                    create_time: snap.create_time,
+                    root: snap.root,
+                    parent: parent });
+                parent = Some(self.backend.write_meta(&obj)?);
+            } else {
+                parent = Some(tag);
+            }
+        }
+
+        if rewriting {
+            self.backend.set_head(&parent.unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Check whether every path recorded in the given snapshot still exists on
+    /// the local filesystem
+    pub fn exists_locally(&self, snap: &Snapshot) -> Result<bool> {
+        let root = match self.read_meta(&snap.root)? {
+            MetaObject::Tree(t) => t,
+            _                   => return Err(Error::IntegrityError)
+        };
+
+        // walk the trees with a stack of our own rather than recursing, since
+        // they can be arbitrarily deep
+        let mut pending = vec![(PathBuf::from("/"), root)];
+        while let Some((path, tree)) = pending.pop() {
+            for c in tree.children.iter() {
+                let obj = self.read_meta(c)?;
+                let pth = path.join(obj.name().ok_or(Error::IntegrityError)?);
+                if fs::symlink_metadata(&pth).is_err() {
+                    return Ok(false);
+                }
+
+                if let MetaObject::Tree(t) = obj {
+                    pending.push((pth, t));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // mark an object and everything it references as reachable
+    fn mark(&self, tag: &IdentityTag, meta: &mut HashSet<IdentityTag>,
+            blocks: &mut HashSet<IdentityTag>) -> Result<()> {
+        // trees can be arbitrarily deep, so keep the objects still to visit on
+        // a stack of our own rather than recursing
+        let mut pending = vec![*tag];
+        while let Some(tag) = pending.pop() {
+            if !meta.insert(tag) { continue; }
+
+            match self.read_meta(&tag)? {
+                MetaObject::Tree(tree) => pending.extend(tree.children),
+                MetaObject::File(file) =>
+                    blocks.extend(file.body.iter().cloned()),
+                MetaObject::Symlink(_) => {},
+                MetaObject::HardLink(link) => pending.push(link.target),
+                MetaObject::Special(_) => {},
+                MetaObject::Snapshot(_) => return Err(Error::IntegrityError)
+            }
+        }
+        Ok(())
+    }
+
+    // mark every snapshot in the chain currently being read, along with
+    // everything they reference
+    fn mark_chain(&self, meta: &mut HashSet<IdentityTag>,
+                  blocks: &mut HashSet<IdentityTag>) -> Result<()> {
+        for (tag, snap) in self.snapshots()? {
+            meta.insert(tag);
+            self.mark(&snap.root, meta, blocks)?;
+        }
+        Ok(())
+    }
+
+    /// Remove objects that aren't reachable from any node's snapshot chain
+    ///
+    /// Every node with a head on the backend is marked, reading its chain with
+    /// that node's metadata key. If some node's chain can't be read, e.g.
+    /// because its key isn't available here, data blocks are left alone and
+    /// only metadata objects this node can read are collected. If `dry_run` is
+    /// set, nothing is removed.
+    pub fn gc(&mut self, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport::default();
+        let mut live_meta = HashSet::new();
+        let mut live_blocks = HashSet::new();
+        self.mark_chain(&mut live_meta, &mut live_blocks)?;
+
+        let heads = self.backend.list_heads()?;
+        match self.backend.viewed_node() {
+            Some(own) => {
+                for node in heads.into_iter().filter(|n| *n != own) {
+                    let marked = self.backend.view_node(&node)
+                        .map_err(Error::from)
+                        .and_then(|_| self.mark_chain(&mut live_meta,
+                                                      &mut live_blocks));
+                    if marked.is_err() { report.skipped_nodes.push(node); }
+                }
+                self.backend.view_node(&own)?;
+            },
+            None => {
+                // other nodes' chains can't be read at all
+                let exclusive = heads.is_empty() ||
+                    (heads.len() == 1 && self.backend.get_head()?.is_some());
+                if !exclusive { report.skipped_nodes = heads; }
+            }
+        }
+        let complete = report.skipped_nodes.is_empty();
+
+        for tag in self.backend.list_meta()? {
+            if live_meta.contains(&tag) { continue; }
+
+            // objects we can't read could belong to a node we didn't mark
+            let size = match self.read_meta(&tag) {
+                Ok(obj) => {
+                    let mut v = Vec::new();
+                    obj.save(&mut v)?;
+                    v.len() as u64
+                },
+                Err(_) if complete => 0,
+                Err(_) => continue
+            };
+
+            verbose!("{} metadata object {}",
+                     if dry_run { "unreferenced" } else { "removing" }, tag);
+            if !dry_run {
+                self.backend.delete_meta(&tag)?;
+                self.meta_cache.remove(&tag);
+            }
+            report.meta_objects += 1;
+            report.bytes += size;
+        }
+
+        if complete {
+            for tag in self.backend.list_blocks()? {
+                if live_blocks.contains(&tag) { continue; }
+
+                let size = self.backend.block_size(&tag).unwrap_or(0);
+                verbose!("{} block {}",
+                         if dry_run { "unreferenced" } else { "removing" }, tag);
+                if !dry_run { self.backend.delete_block(&tag)?; }
+                report.blocks += 1;
+                report.bytes += size;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// List the head of every node with snapshots on the backend, sorted by
+    /// node name, and check that everything each head's tree refers to is
+    /// still stored, and safe from other nodes' gc.
+    ///
+    /// Reading another node's head needs its metadata key. Heads which can't be
+    /// read are listed without a snapshot.
+    pub fn node_heads(&mut self) -> Result<Vec<NodeHead>> {
+        let own = self.backend.viewed_node();
+        let mut nodes = self.backend.list_heads()?;
+        nodes.sort();
+
+        // put our own view back even if reading some head failed
+        let heads = self.read_node_heads(nodes, own.is_some());
+        if let Some(own) = own {
+            self.backend.view_node(&own)?;
+        }
+        heads
+    }
+
+    // read the heads of the given nodes, switching views if `views` is set
+    fn read_node_heads(&mut self, nodes: Vec<String>, views: bool)
+            -> Result<Vec<NodeHead>> {
+        let stored = self.stored_blocks()?;
+        let mut heads = Vec::new();
+        let count = nodes.len();
+        for node in nodes {
+            let snapshot = match views {
+                true  => self.backend.view_node(&node).map_err(Error::from)
+                             .and_then(|_| self.get_head_snapshot()),
+                // only a backend's own head can be read without views
+                false if count == 1 => self.get_head_snapshot(),
+                false => Ok(None)
+            };
+
+            let head = match snapshot {
+                Ok(Some(s)) => {
+                    let damage = self.find_damage(&[s.root], &stored)?;
+                    let tag = MetaObject::Snapshot(s.clone()).ident();
+                    NodeHead { node: node, snapshot: Some((tag, s)),
+                               missing: damage.missing,
+                               at_risk: damage.unreadable }
+                },
+                _ => NodeHead { node: node, snapshot: None, missing: 0,
+                                at_risk: 0 }
+            };
+            heads.push(head);
+        }
+        Ok(heads)
+    }
+
+    /// Retrieve the identity of the most recent snapshot, if any
+    pub fn head_id(&self) -> Result<Option<IdentityTag>> {
+        Ok(self.get_head_snapshot()?
+               .map(|s| MetaObject::Snapshot(s).ident()))
+    }
+
+    /// Retrieve a context-wrapped version of the most recent snapshot, if any
+    pub fn get_snapshot<'b>(&'b self)
+            -> Result<Option<ContextWrapper<'b, Snapshot>>> {
+        self.get_head_snapshot().map(|o| o.map(|x| {
+            ContextWrapper::cached(self.backend, &self.meta_cache, x)
+        }))
+    }
+
+    /// Retrieve a context-wrapped version of the most recent snapshot created
+    /// at or before the given time, if any
+    pub fn snapshot_as_of<'b>(&'b self, t: time::SystemTime)
+            -> Result<Option<ContextWrapper<'b, Snapshot>>> {
+        let chain = self.snapshots()?;
+        Ok(newest_before(&chain, t)
+               .map(|s| ContextWrapper::cached(self.backend, &self.meta_cache,
+                                               s.1.clone())))
+    }
+
+    /// Retrieve the most recent snapshot, if any
+    fn get_head_snapshot(&self) -> Result<Option<Snapshot>> {
+        let snapshot = self.backend.get_head()?;
+        if snapshot.is_none() {
+            return Ok(None);
+        }
+        let snapshot = snapshot.unwrap();
+
+        if let MetaObject::Snapshot(s) = snapshot {
+            Ok(Some(s))
+        } else {
+            Err(Error::NoValidSnapshot)
+        }
+    }
+
+    /// Creates a new snapshot with the given root tree
+    /// 
+    /// If a snapshot is already stored, then the resulting snapshot will use it
+    /// as its parent. Otherwise, the new snapshot will be an origin snapshot.
+    pub fn new_snapshot(&mut self, root: IdentityTag) -> Result<IdentityTag> {
+        if self.verify_commit {
+            let stored = self.stored_blocks()?;
+            let missing = self.find_damage(&[root], &stored)?.missing;
+            if missing > 0 {
+                // whatever lost them may have misled the journal or the
+                // backend's block index too, so don't trust either next time
+                if let Some(j) = self.journal.take() {
+                    j.remove()?;
+                }
+                let _ = self.backend.rebuild_index();
+                return Err(Error::Incomplete(missing));
+            }
+        }
+
+        let snap = self.get_head_snapshot()?;
+        let new_obj = MetaObject::snapshot(root,
+                                           snap.map(|o| MetaObject::Snapshot(o)
+                                                        .ident()));
+
+        // store it
+        let ident = self.backend.write_meta(&new_obj)?;
+
+        // and commit it by modifying the head pointer
+        self.backend.set_head(&ident)?;
+
+        // everything journaled is now referenced by the head
+        if let Some(j) = self.journal.take() {
+            j.remove()?;
+        }
+        Ok(ident)
+    }
+
+    // list the stored blocks. this asks the backend for a listing rather than
+    // about each block, since backends may answer that from a local index,
+    // which is what might be wrong.
+    fn stored_blocks(&self) -> Result<HashSet<IdentityTag>> {
+        Ok(self.backend.list_blocks()?.into_iter().collect())
+    }
+
+    // find the metadata objects and blocks under some trees which aren't
+    // stored, or can't be read. `stored` holds every stored block.
+    fn find_damage(&self, roots: &[IdentityTag], stored: &HashSet<IdentityTag>)
+            -> Result<TreeDamage> {
+        let mut seen = HashSet::new();
+        let mut damage = TreeDamage::default();
+        let mut pending = roots.to_vec();
+        while let Some(tag) = pending.pop() {
+            if !seen.insert(tag) { continue; }
+
+            // the cache may hold objects read through another node's view, so
+            // it can't say whether this one is readable with the current key.
+            // objects which can't be read for other reasons, like a dropped
+            // connection, aren't known to be damaged.
+            let obj = match self.backend.read_meta(&tag) {
+                Ok(o)  => o,
+                Err(e) => {
+                    if !self.backend.has_meta(&tag)? {
+                        damage.missing += 1;
+                    } else if is_damaged(&e) {
+                        damage.unreadable += 1;
+                    } else {
+                        return Err(e.into());
+                    }
+                    continue;
+                }
+            };
+            match obj {
+                MetaObject::File(f) => {
+                    for blk in f.body.iter() {
+                        if blk.hole_len().is_none() && !stored.contains(blk) &&
+                                seen.insert(*blk) {
+                            damage.missing += 1;
+                        }
+                    }
+                },
+                MetaObject::HardLink(l) => pending.push(l.target),
+                MetaObject::Tree(t) => pending.extend(t.children.into_iter()),
+                _ => {}
+            }
+        }
+        Ok(damage)
+    }
+
+    /// Try to retrieve the given path from the latest snapshot
+    /// 
+    /// If no snapshots are stored or the object doesn't exist, this will return
+    /// `Ok(None)`.
+    pub fn get_path(&self, path: &Path) -> Result<Option<MetaObject>> {
+        use std::path::Component;
+
+        let snapshot = self.get_head_snapshot()?;
+
+        if snapshot.is_none() { return Ok(None); }
+        let snapshot = snapshot.unwrap();
+
+        let mut current = snapshot.root;
+        for comp in path.components() {
+            let cur_elem = self.read_meta(&current)?;
+
+            // snapshots are never valid child targets, and other objects
+            // just mean the path was stored as something besides a directory
+            let tree = match cur_elem {
+                MetaObject::Tree(t)     => t,
+                MetaObject::Snapshot(_) => return Err(Error::IntegrityError),
+                _                       => return Ok(None)
+            };
+
+            // descend a level based on the component
+            match comp {
+                Component::Prefix(_) => unimplemented!(), // Windows only - don't care yet
+                Component::RootDir => {}, // skip, since we already start at /
+                Component::CurDir => panic!("retrieved path is not canonical"),
+                Component::ParentDir => panic!("retrieved path is not canonical"),
+                Component::Normal(cmp) => { // try to pull the item
+                    // retrieve the tree's children
+                    let children: Result<Vec<(IdentityTag, MetaObject)>> =
+                        tree.children.iter()
+                                     .map(|id| self.read_meta(&id)
+                                              .map_err(|e| Error::Backend(e))
+                                              .map(|r| (id.to_owned(), r)))
+                                     .collect();
+                    let children: Vec<(IdentityTag, MetaObject)> = children?;
+
+                    // try to find one matching the path component
+                    if let Some(itm) = children.into_iter()
+                                      .filter(|c| c.1.name() == Some(cmp.to_os_string()))
+                                      .map(|c| c.0)
+                                      .next() {
+                        current = itm;
+                    } else {
+                        // no item matching what the path specified
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        Ok(Some(self.read_meta(&current)?))
+    }
+
+    /// Create a file, tree, symlink, or special file object from a path on
+    /// disk, returning `None` if the file can't be represented.
+    /// 
+    /// The given path should be canonical. If `prev` holds the object stored
+    /// for the path in the previous snapshot, unchanged files reuse its chunk
+    /// list rather than being read and uploaded again. `ancestors` holds the
+    /// device and inode numbers of the directories being stored above this
+    /// one, so that directory loops can be skipped.
+    fn store_path(&mut self, path: &Path, prev: Option<MetaObject>,
+                  rules: &ExcludeRules,
+                  ancestors: &mut HashSet<(u64, u64)>)
+            -> Result<Option<IdentityTag>> {
+        let (meta, followed) = self.path_metadata(path)?;
+        let ftype = meta.file_type();
+        let mut fsmeta = meta.clone().into_metadata();
+        if self.store_xattrs {
+            // attributes are read without following the final symlink
+            fsmeta.xattrs = if followed {
+                read_xattrs(&fs::canonicalize(path)?)
+            } else {
+                read_xattrs(path)
+            };
+        }
+        if !self.store_atime {
+            fsmeta.atime = time::UNIX_EPOCH;
+        }
+
+        // the root directory has no name of its own, so it gets the same empty
+        // name as the root trees built by `build_tree_skeleton`
+        let fname = match path.file_name() {
+            Some(n) => n,
+            None if path == Path::new("/") => OsStr::new(""),
+            None => return Err(Error::InvalidArgument)
+        };
+
+        // inodes we've already stored under another name become hard links
+        let inode = (meta.dev(), meta.ino());
+        if ftype.is_file() && meta.nlink() > 1 {
+            if let Some(&target) = self.links.get(&inode) {
+                let obj = MetaObject::hard_link(fname, fsmeta, target);
+                return Ok(Some(self.store_object(&obj)?));
+            }
+        }
+
+        if ftype.is_file() {
+            self.progress.file_started(path);
+            let size = meta.len();
+            let print = Fingerprint::new(&meta);
+            if let Some(tag) = self.journaled(path, &print)? {
+                self.progress.bytes_written(size);
+                self.progress.object_stored();
+                if meta.nlink() > 1 { self.links.insert(inode, tag); }
+                return Ok(Some(tag));
+            }
+
+            let unchanged = match prev {
+                Some(MetaObject::File(ref old)) =>
+                    if self.is_unchanged(path, &fsmeta, size, old)? {
+                        Some(old.body.clone())
+                    } else {
+                        None
+                    },
+                _ => None
+            };
+
+            let blocks = match unchanged {
+                Some(b) => b,
+                None    => self.store_chunks(path)?
+            };
+
+            // construct a new meta-object and store it
+            let multiply_linked = meta.nlink() > 1;
+            let obj = MetaObject::File(FileObject {
+                name: fname.to_os_string().into_vec(),
+                meta: fsmeta,
+                body: blocks,
+                size: Some(size)
+            });
+            let tag = self.store_object(&obj)?;
+            if multiply_linked { self.links.insert(inode, tag); }
+            if let Some(ref mut j) = self.journal {
+                j.insert(path, &print, &tag)?;
+            }
+            Ok(Some(tag))
+        } else if ftype.is_dir() {
+            if path.components().count() > MAX_TREE_DEPTH {
+                return Err(Error::TooDeep(path.to_owned()));
+            }
+
+            // a directory mounted inside itself would never finish
+            if !ancestors.insert(inode) {
+                warn!("bkp: skipping {:?}: directory loop", path);
+                return Ok(None);
+            }
+
+            // store each child
+            let mut children = Vec::new();
+            let rules = rules.enter(path)?;
+            let device = self.device_filter(&meta);
+            for (pth, old) in self.dir_entries(path, prev, &rules, device)? {
+                if let Some(id) = self.store_path(&pth, old, &rules,
+                                                  ancestors)? {
+                    children.push(id);
+                }
+            }
+            ancestors.remove(&inode);
+
+            // only the paths being stored have no ancestors, and those are
+            // kept even if empty
+            if children.is_empty() && self.prune_empty_dirs &&
+                    !ancestors.is_empty() {
+                self.progress.path_skipped(path, "empty directory");
+                return Ok(None);
+            }
+
+            // build and store the new object
+            let obj = MetaObject::tree(fname, fsmeta, children);
+            Ok(Some(self.store_object(&obj)?))
+        } else if ftype.is_symlink() {
+            // store the symlink object
+            let tgt = fs::read_link(&path)?;
+            let obj = MetaObject::symlink(fname, fsmeta, &tgt);
+            Ok(Some(self.store_object(&obj)?))
+        } else {
+            let kind = if ftype.is_fifo() { SpecialKind::Fifo }
+                else if ftype.is_socket() { SpecialKind::Socket }
+                else if ftype.is_char_device() { SpecialKind::CharDevice }
+                else if ftype.is_block_device() { SpecialKind::BlockDevice }
+                else {
+                    // don't let one odd file abort the whole snapshot
+                    warn!("bkp: skipping {:?}: unsupported file type", path);
+                    return Ok(None);
+                };
+
+            let rdev = meta.rdev();
+            let obj = MetaObject::special(fname, fsmeta, kind, rdev);
+            Ok(Some(self.store_object(&obj)?))
+        }
+    }
+
+    /// List the entries of a directory on disk which aren't excluded, each
+    /// paired with its previous version from `prev` if it has one.
+    ///
+    /// If `device` is given, entries on any other device are left out too.
+    /// Cache directories keep only their tag file if caches are excluded, and
+    /// directories holding an exclude marker keep only the marker.
+    fn dir_entries(&self, path: &Path, prev: Option<MetaObject>,
+                   rules: &ExcludeRules, device: Option<u64>)
+            -> Result<Vec<(PathBuf, Option<MetaObject>)>> {
+        let tag = if self.exclude_caches && exclude::is_cache_dir(path) {
+            self.progress.path_skipped(path, "cache directory");
+            Some(OsString::from(exclude::CACHEDIR_TAG))
+        } else {
+            let marker = exclude::find_marker(path, &self.exclude_markers);
+            if let Some(ref m) = marker {
+                self.progress.path_skipped(
+                    path, &format!("contains {}", m.to_string_lossy()));
+            }
+            marker
+        };
+
+        // index the previous version's children so each child can be compared
+        // against its old self
+        let mut old_children = HashMap::new();
+        if let Some(MetaObject::Tree(ref t)) = prev {
+            for id in t.children.iter() {
+                let child = self.read_meta(id)?;
+                if let Some(name) = child.name() {
+                    old_children.insert(name, child);
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?; // safely unwrap the result
+            let old = old_children.remove(&entry.file_name());
+
+            // prune excluded entries here, so excluded dirs aren't descended
+            let ftype = entry.file_type()?;
+            let followed = if self.dereference && ftype.is_symlink() {
+                Some(self.path_metadata(&entry.path())?.0)
+            } else {
+                None
+            };
+            let is_dir = match followed {
+                Some(ref m) => m.is_dir(),
+                None        => ftype.is_dir()
+            };
+            if rules.is_excluded(&entry.path(), is_dir) { continue; }
+            if let Some(ref t) = tag {
+                if entry.file_name() != *t { continue; }
+            }
+            if let Some(dev) = device {
+                let entry_dev = match followed {
+                    Some(ref m) => m.dev(),
+                    None        => entry.metadata()?.dev()
+                };
+                if entry_dev != dev {
+                    self.progress.path_skipped(&entry.path(),
+                                               "on another filesystem");
+                    continue;
+                }
+            }
+            entries.push((entry.path(), old));
+        }
+        Ok(entries)
+    }
+
+    /// Read a path's metadata, following it if it's a symlink and symlinks are
+    /// being dereferenced. Also returns whether the link was followed.
+    fn path_metadata(&self, path: &Path) -> io::Result<(fs::Metadata, bool)> {
+        let meta = fs::symlink_metadata(path)?;
+        if !self.dereference || !meta.file_type().is_symlink() {
+            return Ok((meta, false));
+        }
+
+        match fs::metadata(path) {
+            Ok(m)  => Ok((m, true)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                verbose!("bkp: storing dangling symlink {:?} as a link", path);
+                Ok((meta, false))
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /// The device a directory's entries must be on to be stored, if they're
+    /// restricted to one
+    fn device_filter(&self, dir: &fs::Metadata) -> Option<u64> {
+        if self.one_file_system { Some(dir.dev()) } else { None }
+    }
+
+    /// Work out what storing a path would do, without storing anything.
+    ///
+    /// `seen` holds the blocks already counted as new, so that blocks repeated
+    /// within the snapshot are only counted once. `ancestors` is as for
+    /// `store_path`.
+    fn plan_path(&self, path: &Path, prev: Option<MetaObject>,
+                 rules: &ExcludeRules, plan: &mut SnapshotPlan,
+                 seen: &mut HashSet<IdentityTag>,
+                 ancestors: &mut HashSet<(u64, u64)>) -> Result<()> {
+        let meta = self.path_metadata(path)?.0;
+        let ftype = meta.file_type();
+
+        if ftype.is_dir() {
+            if path.components().count() > MAX_TREE_DEPTH {
+                return Err(Error::TooDeep(path.to_owned()));
+            }
+            let inode = (meta.dev(), meta.ino());
+            if !ancestors.insert(inode) { return Ok(()); }
+
+            let rules = rules.enter(path)?;
+            let device = self.device_filter(&meta);
+            for (pth, old) in self.dir_entries(path, prev, &rules, device)? {
+                self.plan_path(&pth, old, &rules, plan, seen, ancestors)?;
+            }
+            ancestors.remove(&inode);
+            return Ok(());
+        }
+
+        let kind = match prev {
+            Some(MetaObject::File(ref old)) if ftype.is_file() => {
+                let fsmeta = meta.clone().into_metadata();
+                if self.is_unchanged(path, &fsmeta, meta.len(), old)? {
+                    return Ok(());
+                }
+                ChangeKind::Modified
+            },
+            Some(_) if !ftype.is_file() => return Ok(()),
+            Some(_) => ChangeKind::Modified,
+            None    => ChangeKind::Added
+        };
+
+        if ftype.is_file() {
+            plan.upload_bytes += meta.len();
+
+            // find out which of the file's blocks would actually be uploaded
+            let f = fs::File::open(path)?;
+            for c in f.bytes().chunks_sized(self.chunk_size) {
+                let c = c?;
+                if is_hole(&c) { continue; }
+                let tag = block_tag(&c);
+                if !seen.contains(&tag) && !self.backend.has_block(&tag)? {
+                    seen.insert(tag);
+                    plan.new_blocks += 1;
+                    plan.new_bytes += c.len() as u64;
+                }
+            }
+        }
+        plan.changes.push(PathChange { path: path.to_owned(), kind: kind });
+        Ok(())
+    }
+
+    /// Check whether a file on disk still matches its previously stored object
+    ///
+    /// When mtimes are trusted, matching sizes and mtimes are taken to mean the
+    /// file is unchanged. Otherwise, its contents are hashed and compared to the
+    /// stored chunk list.
+    fn is_unchanged(&self, path: &Path, fsmeta: &FSMetadata, size: u64,
+                    prev: &FileObject) -> Result<bool> {
+        if self.trust_mtime {
+            return Ok(prev.size == Some(size) && prev.meta.mtime == fsmeta.mtime);
+        }
+
+        // holes are merged, so the chunk list has to be built up in full before
+        // comparing
+        let f = fs::File::open(path)?;
+        let mut tags = Vec::with_capacity(prev.body.len());
+        for c in f.bytes().chunks_sized(self.chunk_size) {
+            let c = c?;
+            if is_hole(&c) {
+                push_hole(&mut tags, c.len() as u64);
+            } else {
+                tags.push(block_tag(&c));
+            }
+        }
+        Ok(tags == prev.body)
+    }
+
+    /// Find the object an interrupted snapshot stored for a file, if the file
+    /// hasn't changed since and the object is still on the backend
+    fn journaled(&self, path: &Path, print: &Fingerprint)
+            -> Result<Option<IdentityTag>> {
+        let tag = match self.journal {
+            Some(ref j) => j.get(path, print),
+            None        => None
+        };
+        // garbage collection may have removed it in the meantime
+        match tag {
+            Some(t) if self.backend.has_meta(&t)? => Ok(Some(t)),
+            _                                     => Ok(None)
+        }
+    }
+
+    /// Work out which journal belongs to a set of paths being stored. Settings
+    /// which change how files are stored are included, since objects stored
+    /// under other settings can't be reused.
+    fn journal_key(&self, paths: &[PathBuf]) -> IdentityTag {
+        let mut key = Vec::new();
+        for p in paths.iter() {
+            key.extend_from_slice(p.as_os_str().as_bytes());
+            key.push(0);
+        }
+        key.write_u64::<LittleEndian>(self.chunk_size as u64).unwrap();
+        key.push(self.store_xattrs as u8);
+        key.push(self.store_atime as u8);
+        key.push(self.dereference as u8);
+        block_tag(&key)
+    }
+
+    /// Store a metadata object for a path, reporting it as progress
+    fn store_object(&mut self, obj: &MetaObject) -> Result<IdentityTag> {
+        let tag = self.write_new_meta(obj)?;
+        self.progress.object_stored();
+        Ok(tag)
+    }
+
+    /// Write a metadata object unless an identical one is already stored.
+    ///
+    /// Most objects in a snapshot are unchanged since the last one, and this
+    /// saves encoding, encrypting and uploading them all over again.
+    fn write_new_meta(&mut self, obj: &MetaObject) -> Result<IdentityTag> {
+        let tag = obj.ident();
+        if self.backend.has_meta(&tag)? {
+            return Ok(tag);
+        }
+        Ok(self.backend.write_new_meta(obj)?)
+    }
+
+    /// Break a file into chunks and store them, returning the chunk list
+    fn store_chunks(&mut self, path: &Path) -> Result<Vec<IdentityTag>> {
+        let f = fs::OpenOptions::new()
+                        .read(true)
+                        .open(path)?;
+        let compress = !self.is_incompressible(path);
+        let mut blocks = Vec::new();
+        let mut pending = Vec::new();
+        for c in f.bytes().chunks_sized(self.chunk_size) {
+            let c = c?;
+
+            // runs of zeros are recorded without storing anything, but the
+            // blocks before them have to go first to keep the list in order
+            if is_hole(&c) {
+                if !pending.is_empty() {
+                    self.store_batch(&pending, &mut blocks, compress)?;
+                    pending.clear();
+                }
+                push_hole(&mut blocks, c.len() as u64);
+                self.progress.bytes_written(c.len() as u64);
+                continue;
+            }
+
+            pending.push(c);
+            if pending.len() >= self.upload_batch {
+                self.store_batch(&pending, &mut blocks, compress)?;
+                pending.clear();
+            }
+        }
+        if !pending.is_empty() {
+            self.store_batch(&pending, &mut blocks, compress)?;
+        }
+        Ok(blocks)
+    }
+
+    /// Whether a file's name marks it as holding data which is already
+    /// compressed
+    fn is_incompressible(&self, path: &Path) -> bool {
+        match path.extension() {
+            Some(ext) => {
+                let ext = ext.to_string_lossy().to_lowercase();
+                self.no_compress_exts.iter().any(|e| *e == ext)
+            },
+            None => false
+        }
+    }
+
+    /// Store a batch of chunks, appending their tags to `blocks`
+    fn store_batch(&mut self, chunks: &[Vec<u8>], blocks: &mut Vec<IdentityTag>,
+                   compress: bool) -> Result<()> {
+        if compress {
+            blocks.extend(self.backend.write_blocks(chunks)?);
+        } else {
+            blocks.extend(self.backend.write_uncompressed(chunks)?);
+        }
+        let bytes: usize = chunks.iter().map(|c| c.len()).sum();
+        self.progress.bytes_written(bytes as u64);
+        Ok(())
+    }
+
+    /// Construct a skeleton tree containing the given subtrees at the point of
+    /// the given root, and return its identity.
+    /// 
+    /// For example, given the root `/usr` and the files `/usr/bin/ls` and
+    /// `/usr/share/dict/words`, this function will construct the following set
+    /// of tree objects:
+    /// 
+    ///     /usr
+    ///         /bin
+    ///             [ls]
+    ///         /share
+    ///             /dict
+    ///                 [words]
+    ///
+    /// The generated objects will have 
+    fn build_tree_skeleton<P: AsRef<Path>>(&mut self, root: &Path,
+                                           objects: &Vec<(P, IdentityTag)>) ->
+            Result<IdentityTag> {
+        // iterate through relevant children and build their trees
+        let mut children = Vec::new();
+        for child in objects.iter().filter(|p| p.0.as_ref().starts_with(root)) {
+            children.push(
+                if child.0.as_ref().parent() == Some(root) {
+                    // if we hit the child's parent, just add the child ID
+                    child.1
+                } else {
+                    // figure out which path to create next
+                    let relative = child.0.as_ref().strip_prefix(root).unwrap();
+                    let part = relative.iter().next().unwrap();
+                    
+                    // create intermediary dirs
+                    let path = root.join(part);
+                    self.build_tree_skeleton(&path, objects)?
+                });
+        }
+
+        let name = match root.file_name() {
+            None => OsStr::new(""),
+            Some(n) => n };
+        let tree = MetaObject::tree(name, FSMetadata::default(), children);
+        self.write_new_meta(&tree)
+    }
+
+    /// Build a new tree based on the previous root - start at `/` and move
+    /// down, inserting updated elements in the right positions along the way.
+    /// 
+    /// For each directory visited, this will choose whether to use the previous
+    /// stored tree (if no updated path is rooted there), use a path from the
+    /// input set, or use a new stored tree with recursively updated versions of
+    /// the tree's children.
+    fn update_tree<P: AsRef<Path>>(&mut self, root: &Path,
+                       new_vals: &Vec<(P, IdentityTag)>) ->
+            Result<IdentityTag> {
+        match new_vals.iter().find(|x| x.0.as_ref() == root) {
+            Some(r) => Ok(r.1), // return the updated object
+            None => { // no override - look backwards
+                let old_version = match self.get_path(root)? {
+                    Some(r) => r,
+                    None    => {
+                        // the folder didn't exist before, so create a new tree
+                        // object to hold it and insert the object there
+                        return self.build_tree_skeleton(root, new_vals)
+                    }
+                };
+
+                // check whether we actually need to store this - if nothing
+                // under it was changed, we can just re-use the old object
+                if new_vals.iter().any(|x| x.0.as_ref().starts_with(root)) {
+                    // yep - build a new tree
+                    //
+                    // this involves iterating through the old tree's children
+                    // and recursively updating each, then generating a new tree
+                    // object with the updated children list
+                    let old_tag = old_version.ident();
+                    if let MetaObject::Tree(mut t) = old_version {
+                        let mut new_children = Vec::new();
+                        let mut names = HashSet::new();
+                        let mut changed = false;
+                        for child in t.children.drain(..) {
+                            // grab a copy and pull out the path component
+                            let obj = self.read_meta(&child)?;
+                            let name = OsString::from_vec(match obj {
+                                MetaObject::Snapshot(_) => {
+                                    // trees can't have snapshots as children
+                                    return Err(Error::IntegrityError);
+                                },
+                                MetaObject::Tree(t) => t.name,
+                                MetaObject::File(f) => f.name,
+                                MetaObject::Symlink(l) => l.name,
+                                MetaObject::HardLink(l) => l.name,
+                                MetaObject::Special(s) => s.name,
+                            });
+
+                            // build the new root path and update it, unless
+                            // nothing new is stored under it
+                            let pth = root.join(&name);
+                            let new_id = if new_vals.iter().any(
+                                    |x| x.0.as_ref().starts_with(&pth)) {
+                                self.update_tree(&pth, new_vals)?
+                            } else {
+                                child
+                            };
+                            changed = changed || new_id != child;
+                            new_children.push(new_id);
+                            names.insert(name);
+                        }
+
+                        // paths which weren't in the old tree become new
+                        // children, e.g. after a snapshot of just the root
+                        let mut added: Vec<OsString> = Vec::new();
+                        for x in new_vals.iter() {
+                            let part = match x.0.as_ref().strip_prefix(root) {
+                                Ok(rel) => match rel.iter().next() {
+                                    Some(p) => p.to_os_string(),
+                                    None    => continue
+                                },
+                                Err(_) => continue
+                            };
+                            if !names.contains(&part) && !added.contains(&part) {
+                                added.push(part);
+                            }
+                        }
+                        for part in added.iter() {
+                            let pth = root.join(part);
+                            new_children.push(self.update_tree(&pth, new_vals)?);
+                            changed = true;
+                        }
+
+                        // if every child came out the same, so does the tree
+                        if !changed {
+                            return Ok(old_tag);
+                        }
+                        t.children = new_children;
+                        self.write_new_meta(&MetaObject::Tree(t))
+                    } else {
+                        // to get here, one of the new paths must be rooted at
+                        // this node, but for a non-tree node that doesn't make
+                        // sense
+                        Err(Error::IntegrityError)
+                    }
+                } else {
+                    // no, reuse the old one
+                    Ok(old_version.ident())
+                }
+            }
+        }
+    }
+
+    /// Generate a new root tree where the nodes corresponding to the specified
+    /// paths point to newly-stored copies.
+    /// 
+    /// Input paths will be canonicalized before further usage.
+    pub fn update_paths<'b, P, I>(&mut self, paths: I) -> Result<IdentityTag>
+            where P: 'b + AsRef<OsStr> + ?Sized,
+                  I: IntoIterator<Item=&'b P> {
+        // store a copy of the paths being updated, for later use when building
+        // an updated root tree
+        let paths = normalize_paths(paths);
+        if let Some(ref p) = self.journal_path {
+            self.journal = Some(Journal::open(p, &self.journal_key(&paths))?);
+        }
+
+        // store each copy of the dirs to update
+        let mut path_copies = Vec::new();
+        for x in paths.into_iter() {
+            let prev = self.get_path(&x)?;
+            let rules = ExcludeRules::new(&x, &self.excludes);
+            let mut ancestors = HashSet::new();
+            if let Some(r) = self.store_path(&x, prev, &rules,
+                                             &mut ancestors)? {
+                path_copies.push((x, r));
+            }
+        }
+
+        // store the new root tree
+        self.update_tree(&Path::new("/"), &path_copies)
+    }
+
+    /// Work out which files `update_paths` would store for the given paths,
+    /// without writing anything to the backend.
+    ///
+    /// Input paths will be canonicalized before further usage.
+    pub fn plan_paths<'b, P, I>(&self, paths: I) -> Result<SnapshotPlan>
+            where P: 'b + AsRef<OsStr> + ?Sized,
+                  I: IntoIterator<Item=&'b P> {
+        let mut plan = SnapshotPlan::default();
+        let mut seen = HashSet::new();
+        for x in normalize_paths(paths).into_iter() {
+            let prev = self.get_path(&x)?;
+            let rules = ExcludeRules::new(&x, &self.excludes);
+            self.plan_path(&x, prev, &rules, &mut plan, &mut seen,
+                           &mut HashSet::new())?;
+        }
+        Ok(plan)
+    }
+}
+
+#[test]
+fn newest_before_test() {
+    let tag = |b: u8| IdentityTag::from_bytes([b; 32]);
+    let at = |secs| time::UNIX_EPOCH + time::Duration::from_secs(secs);
+    let snap = |secs, parent| Snapshot {
+        create_time: at(secs),
+        root: tag(0),
+        parent: parent
+    };
+    let chain = vec![(tag(3), snap(300, Some(tag(2)))),
+                     (tag(2), snap(200, Some(tag(1)))),
+                     (tag(1), snap(100, None))];
+
+    assert_eq!(newest_before(&chain, at(1000)).map(|s| s.0), Some(tag(3)));
+    assert_eq!(newest_before(&chain, at(300)).map(|s| s.0), Some(tag(3)));
+    assert_eq!(newest_before(&chain, at(299)).map(|s| s.0), Some(tag(2)));
+    assert_eq!(newest_before(&chain, at(150)).map(|s| s.0), Some(tag(1)));
+    assert_eq!(newest_before(&chain, at(99)).map(|s| s.0), None);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::io::prelude::*;
+    use std::os::unix::fs::{MetadataExt, symlink};
+    use std::path::{Path, PathBuf};
+    use std::time;
+
+    use history::{block_tag, BlockFault, BlockReader, ChangeKind,
+                  ContextWrapper, Error, History, IntegrityTestMode,
+                  MAX_TREE_DEPTH,
+                  OverwriteMode, PathChange, Restorable, RestoreOptions,
+                  NodeHead, RepairReport, RetentionPolicy, SnapshotStats,
+                  Stats, TreeDamage};
+    use exclude::{ExcludeRules, Pattern};
+    use metadata::{IdentityTag, MetaObject, FileObject, FSMetadata, Snapshot};
+    use remote::*;
+    use remote::memory::MemoryBackend;
+    use super::libc::EPERM;
+
+    fn dir_meta() -> FSMetadata {
+        FSMetadata { mode: 0o755, ..FSMetadata::default() }
+    }
+
+    /// A made-up identity tag with every byte set to `b`
+    fn tag(b: u8) -> IdentityTag {
+        IdentityTag::from_bytes([b; 32])
+    }
+
+    /// Build a snapshot holding `/outer/inner/file` and return it
+    fn build_tree(backend: &mut Box<Backend>) -> Snapshot {
+        let block = backend.write_block(b"file contents").unwrap();
+        let file = MetaObject::file("file", FSMetadata::default(), vec![block]);
+        let file = backend.write_meta(&file).unwrap();
+        let inner = MetaObject::tree("inner", dir_meta(), vec![file]);
+        let inner = backend.write_meta(&inner).unwrap();
+        let outer = MetaObject::tree("outer", dir_meta(), vec![inner]);
+        let outer = backend.write_meta(&outer).unwrap();
+        let root = MetaObject::tree("", dir_meta(), vec![outer]);
+        let root = backend.write_meta(&root).unwrap();
+
+        Snapshot { create_time: time::UNIX_EPOCH, root: root,
+                   parent: None }
+    }
+
+    fn restore_into(name: &str, path: &str) -> PathBuf {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = ContextWrapper::new(&backend, snap);
+
+        let dest = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+
+        let obj = snap.get(path).unwrap().expect("path should exist");
+        let opts = RestoreOptions::new().ignore_permissions(true);
+        obj.restore(&dest, &opts).unwrap();
+        dest
+    }
+
+    fn read_file(path: PathBuf) -> Vec<u8> {
+        let mut data = Vec::new();
+        fs::File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn restore_detects_corrupt_blocks() {
+        let mut mem = MemoryBackend::new();
+        let reads = mem.block_reads.clone();
+        let tag = block_tag(b"file contents");
+        mem.blocks.insert(tag, b"file contentz".to_vec());
+        let backend: Box<Backend> = Box::new(mem);
+        let file = FileObject {
+            name: b"file".to_vec(),
+            meta: FSMetadata::default(),
+            body: vec![tag],
+            size: None
+        };
+        let obj = ContextWrapper::new(&backend, &file);
+
+        let dest = env::temp_dir().join("bkp-restore-corrupt-test");
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+
+        // the block is fetched a second time before giving up, and nothing is
+        // left behind
+        let opts = RestoreOptions::new().ignore_permissions(true);
+        match obj.restore(&dest, &opts) {
+            Err(Error::IntegrityError) => {},
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert_eq!(reads.get(), 2);
+        assert!(!dest.join("file").exists());
+
+        // unless checking is turned off
+        let opts = RestoreOptions::new().ignore_permissions(true)
+                                        .verify_blocks(false);
+        obj.restore(&dest, &opts).unwrap();
+        assert_eq!(read_file(dest.join("file")), b"file contentz".to_vec());
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn restore_nested_file() {
+        let dest = restore_into("bkp-restore-file-test", "/outer/inner/file");
+        assert_eq!(read_file(dest.join("file")), b"file contents".to_vec());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn write_file_contents() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = ContextWrapper::new(&backend, snap);
+
+        let mut out = Vec::new();
+        let file = snap.get("/outer/inner/file").unwrap().unwrap();
+        file.write_contents(&mut out).unwrap();
+        assert_eq!(out, b"file contents".to_vec());
+
+        let dir = snap.get("/outer").unwrap().unwrap();
+        match dir.write_contents(&mut out) {
+            Err(Error::InvalidArgument) => {},
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn block_reader_streams_blocks() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let chunks = vec![vec![1u8; 5000], vec![2u8; 20000], vec![3u8; 7]];
+        let file = FileObject {
+            name: b"file".to_vec(),
+            meta: FSMetadata::default(),
+            body: chunks.iter().map(|c| backend.write_block(c).unwrap())
+                        .collect(),
+            size: None
+        };
+        let expected: Vec<u8> = chunks.concat();
+
+        // reads never span blocks, but the whole file comes through in order
+        let mut reader = BlockReader::new(&*backend, &file);
+        let mut buf = [0u8; 4096];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4096);
+        assert_eq!(reader.read(&mut buf).unwrap(), 5000 - 4096);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(&rest[..], &expected[5000..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        // missing blocks come out as backend errors once they're reached
+        let mut broken = file.clone();
+        broken.body.insert(1, tag(9));
+        let mut out = Vec::new();
+        let mut reader = BlockReader::new(&*backend, &broken);
+        match io::copy(&mut reader, &mut out).map_err(Error::from) {
+            Err(Error::Backend(BackendError::InvalidOption)) => {},
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert_eq!(&out[..], &expected[..5000]);
+    }
+
+    #[test]
+    fn restore_directory() {
+        let dest = restore_into("bkp-restore-dir-test", "/outer/inner");
+        assert!(dest.join("inner").is_dir());
+        assert_eq!(read_file(dest.join("inner").join("file")),
+                   b"file contents".to_vec());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn restore_overwrite_modes() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = ContextWrapper::new(&backend, snap);
+        let obj = snap.get("/outer/inner/file").unwrap().unwrap();
+
+        let dest = env::temp_dir().join("bkp-restore-overwrite-test");
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+        fs::File::create(dest.join("file")).unwrap()
+            .write_all(b"local").unwrap();
+
+        let restore = |mode| {
+            let opts = RestoreOptions::new().ignore_permissions(true)
+                                            .overwrite_mode(mode);
+            obj.restore(&dest, &opts)
+        };
+
+        match restore(OverwriteMode::Never) {
+            Err(Error::WouldOverwrite) => {},
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        // the local copy is newer than the stored one, so it's kept
+        restore(OverwriteMode::IfNewer).unwrap();
+        assert_eq!(read_file(dest.join("file")), b"local".to_vec());
+
+        restore(OverwriteMode::Always).unwrap();
+        assert_eq!(read_file(dest.join("file")), b"file contents".to_vec());
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn interrupted_restore_keeps_original() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let good = backend.write_block(b"first block").unwrap();
+        let file = FileObject {
+            name: b"file".to_vec(),
+            meta: FSMetadata::default(),
+            body: vec![good, tag(9)], // the second block is missing
+            size: None
+        };
+        let obj = ContextWrapper::new(&backend, MetaObject::File(file));
+
+        let dest = env::temp_dir().join("bkp-restore-interrupted-test");
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+        fs::File::create(dest.join("file")).unwrap()
+            .write_all(b"local").unwrap();
+
+        // the restore fails partway through writing the contents
+        let opts = RestoreOptions::new().ignore_permissions(true)
+                                        .overwrite_mode(OverwriteMode::Always);
+        match obj.restore(&dest, &opts) {
+            Err(Error::Backend(_)) => {},
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        // the original is untouched, and nothing partial is left around
+        assert_eq!(read_file(dest.join("file")), b"local".to_vec());
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 1);
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn restore_whole_snapshot() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = ContextWrapper::new(&backend, snap);
+
+        // the destination should be created as needed
+        let dest = env::temp_dir().join("bkp-restore-all-test");
+        let _ = fs::remove_dir_all(&dest);
+        let opts = RestoreOptions::new().ignore_permissions(true);
+        snap.restore(dest.join("nested"), &opts).unwrap();
+
+        let file = dest.join("nested").join("outer").join("inner").join("file");
+        assert_eq!(read_file(file), b"file contents".to_vec());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn restore_mapped_paths() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = ContextWrapper::new(&backend, snap);
+
+        let dest = env::temp_dir().join("bkp-restore-map-test");
+        let _ = fs::remove_dir_all(&dest);
+        let moved = dest.join("moved");
+
+        // a remapped subtree, with the deeper mapping taking precedence
+        let opts = RestoreOptions::new().ignore_permissions(true)
+            .map_path("/outer", dest.join("old-outer"))
+            .map_path("/outer/inner/file", moved.join("first"))
+            .map_path("outer/inner/file/", moved.join("renamed"));
+        snap.restore(dest.join("all"), &opts).unwrap();
+        assert!(dest.join("all").exists());
+        assert!(!dest.join("all").join("outer").exists());
+        assert!(dest.join("old-outer").join("inner").is_dir());
+        assert!(!dest.join("old-outer").join("inner").join("file").exists());
+        assert!(!moved.join("first").exists());
+        assert_eq!(read_file(moved.join("renamed")), b"file contents".to_vec());
+
+        // a single remapped file
+        let opts = RestoreOptions::new().ignore_permissions(true)
+            .map_path("/outer/inner/file", dest.join("single"));
+        snap.get("/outer/inner/file").unwrap().unwrap()
+            .restore_from(Path::new("/outer/inner/file"), &dest, &opts)
+            .unwrap();
+        assert_eq!(read_file(dest.join("single")), b"file contents".to_vec());
+        assert!(!dest.join("file").exists());
+
+        // mappings also apply under a restored directory
+        fs::create_dir_all(dest.join("sub")).unwrap();
+        let opts = RestoreOptions::new().ignore_permissions(true)
+            .map_path("/outer/inner/file", moved.join("from-sub"));
+        snap.get("/outer").unwrap().unwrap()
+            .restore_from(Path::new("/outer"), dest.join("sub"), &opts)
+            .unwrap();
+        assert!(dest.join("sub").join("outer").join("inner").is_dir());
+        assert!(!dest.join("sub").join("outer").join("inner").join("file")
+                     .exists());
+        assert_eq!(read_file(moved.join("from-sub")),
+                   b"file contents".to_vec());
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn check_visits_shared_objects_once() {
+        let mem = MemoryBackend::new();
+        let reads = mem.block_reads.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+
+        // the second snapshot adds a file next to the first one's subtree
+        let first = build_tree(&mut backend);
+        let outer = match backend.read_meta(&first.root).unwrap() {
+            MetaObject::Tree(t) => t.children[0],
+            _                   => panic!("root isn't a tree")
+        };
+        let block = backend.write_block(b"other contents").unwrap();
+        let file = MetaObject::file("other", FSMetadata::default(), vec![block]);
+        let file = backend.write_meta(&file).unwrap();
+        let root = MetaObject::tree("", dir_meta(), vec![outer, file]);
+        let root = backend.write_meta(&root).unwrap();
+        let first = backend.write_meta(&MetaObject::Snapshot(first)).unwrap();
+        let second = Snapshot {
+            create_time: time::UNIX_EPOCH + time::Duration::from_secs(60),
+            root: root,
+            parent: Some(first)
+        };
+        let second = backend.write_meta(&MetaObject::Snapshot(second)).unwrap();
+        backend.set_head(&second).unwrap();
+
+        let history = History::new(&mut backend).unwrap();
+        let report = history.check(IntegrityTestMode::Exhaustive).unwrap();
+        assert!(report.is_ok());
+
+        // the block in the shared subtree is only read for the newer snapshot
+        assert_eq!(reads.get(), 2);
+    }
+
+    #[test]
+    fn node_heads_report_missing_objects() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let tag = backend.write_meta(&MetaObject::Snapshot(snap)).unwrap();
+        backend.set_head(&tag).unwrap();
+
+        let check = |backend: &mut Box<Backend>| -> NodeHead {
+            let mut history = History::new(backend).unwrap();
+            let mut heads = history.node_heads().unwrap();
+            assert_eq!(heads.len(), 1);
+            heads.remove(0)
+        };
+        let head = check(&mut backend);
+        assert_eq!(head.node, "memory");
+        assert_eq!(head.snapshot.map(|s| s.0), Some(tag));
+        assert_eq!(head.missing, 0);
+
+        // as if another node's gc had removed the file's only block
+        backend.delete_block(&block_tag(b"file contents")).unwrap();
+        assert_eq!(check(&mut backend).missing, 1);
+    }
+
+    #[test]
+    fn node_heads_report_objects_at_risk() {
+        let mem = MemoryBackend::new();
+        let foreign = mem.foreign_meta.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        let snap = build_tree(&mut backend);
+        let tag = backend.write_meta(&MetaObject::Snapshot(snap.clone()))
+                         .unwrap();
+        backend.set_head(&tag).unwrap();
+
+        // as if another node had stored the root tree under its own key
+        foreign.borrow_mut().insert(snap.root);
+        let mut history = History::new(&mut backend).unwrap();
+        let heads = history.node_heads().unwrap();
+        assert_eq!(heads[0].missing, 0);
+        assert_eq!(heads[0].at_risk, 1);
+    }
+
+    #[test]
+    fn unreadable_objects_not_counted_missing() {
+        let mut mem = MemoryBackend::new();
+        let fail = mem.fail_meta_reads.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        let snap = build_tree(&mut backend);
+
+        // a dropped connection says nothing about what's stored
+        let history = History::new(&mut backend).unwrap();
+        let stored = history.stored_blocks().unwrap();
+        fail.set(true);
+        assert!(history.find_damage(&[snap.root], &stored).is_err());
+        fail.set(false);
+        assert_eq!(history.find_damage(&[snap.root], &stored).unwrap(),
+                   TreeDamage::default());
+    }
+
+    #[test]
+    fn retention_policies() {
+        let day = 86400;
+        let snap = |n: u8, secs: u64| {
+            (IdentityTag::from_bytes([n; 32]),
+             Snapshot { create_time: time::UNIX_EPOCH +
+                                     time::Duration::from_secs(secs),
+                        root: IdentityTag::from_bytes([0; 32]),
+                        parent: None })
+        };
+
+        // 1970-01-05 was a Monday
+        let chain = vec![snap(1, 4 * day + 3600), snap(2, 4 * day + 7200),
+                         snap(3, 5 * day), snap(4, 11 * day),
+                         snap(5, 12 * day)];
+        let tags = |ns: &[u8]| {
+            ns.iter().map(|&n| IdentityTag::from_bytes([n; 32]))
+              .collect::<HashSet<_>>()
+        };
+
+        // the newest snapshot is both the last of its day and of its week,
+        // and is kept once
+        let policy = RetentionPolicy { daily: 2, weekly: 2,
+                                       ..RetentionPolicy::default() };
+        assert_eq!(policy.retained(&chain), tags(&[3, 4, 5]));
+
+        let policy = RetentionPolicy { last: 2, daily: 4, monthly: 1,
+                                       ..RetentionPolicy::default() };
+        assert_eq!(policy.retained(&chain), tags(&[2, 3, 4, 5]));
+
+        assert!(RetentionPolicy::default().is_empty());
+        assert!(RetentionPolicy::default().retained(&chain).is_empty());
+    }
+
+    #[test]
+    fn restore_without_ownership() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let block = backend.write_block(b"setuid program").unwrap();
+        let meta = FSMetadata { mode: 0o4755, ..FSMetadata::default() };
+        let file = MetaObject::file("program", meta, vec![block]);
+        let file = backend.write_meta(&file).unwrap();
+        let root = MetaObject::tree("", dir_meta(), vec![file]);
+        let root = backend.write_meta(&root).unwrap();
+        let snap = Snapshot { create_time: time::UNIX_EPOCH, root: root,
+                              parent: None };
+        let snap = ContextWrapper::new(&backend, snap);
+
+        let dest = env::temp_dir().join("bkp-restore-unowned-test");
+        let _ = fs::remove_dir_all(&dest);
+
+        // as if running unprivileged, whoever is actually running the test
+        let mut opts = RestoreOptions::new();
+        opts.chown = |_, _, _| Err(io::Error::from_raw_os_error(EPERM));
+        snap.restore(&dest, &opts).unwrap();
+        assert_eq!(opts.ownership_failures(), 1);
+
+        let program = dest.join("program");
+        assert_eq!(read_file(program.clone()), b"setuid program".to_vec());
+        let mode = fs::metadata(&program).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn missing_path() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = ContextWrapper::new(&backend, snap);
+
+        assert!(snap.get("/outer/missing").unwrap().is_none());
+        assert!(snap.get("/outer/inner/file/below").unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshot_and_read_back() {
+        let src = env::temp_dir().join("bkp-snapshot-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::File::create(src.join("sub").join("data")).unwrap()
+            .write_all(b"some file data").unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+
+            match history.get_path(&src.join("sub").join("data")).unwrap() {
+                Some(MetaObject::File(f)) => {
+                    assert_eq!(f.size, Some(14));
+                    assert_eq!(f.body.len(), 1);
+                },
+                other => panic!("unexpected object {:?}", other)
+            }
+            assert!(history.get_path(&src.join("missing")).unwrap().is_none());
+            assert!(history.check(IntegrityTestMode::Exhaustive).unwrap().is_ok());
+        }
+
+        // an unchanged file shouldn't need its blocks uploaded again
+        let blocks = backend.list_blocks().unwrap();
+        for b in blocks.iter() { backend.delete_block(b).unwrap(); }
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+        }
+        assert!(backend.list_blocks().unwrap().is_empty());
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn no_atime_keeps_tags_stable() {
+        use history::libc;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let src = env::temp_dir().join("bkp-no-atime-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        fs::File::create(src.join("file")).unwrap()
+            .write_all(b"read often").unwrap();
+        let src = src.canonicalize().unwrap();
+
+        // reading the file only updates its atime if the filesystem is mounted
+        // to, so change it directly
+        let file = src.join("file");
+        let touch = |secs: i64| {
+            let path = CString::new(file.as_os_str().as_bytes()).unwrap();
+            let times = [libc::timespec { tv_sec: secs as libc::time_t,
+                                          tv_nsec: 0 },
+                         libc::timespec { tv_sec: 0,
+                                          tv_nsec: libc::UTIME_OMIT }];
+            let r = unsafe {
+                libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(),
+                                0)
+            };
+            assert_eq!(r, 0);
+        };
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut snap = |atime: bool| {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_store_atime(atime);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+            match history.get_path(&file).unwrap() {
+                Some(MetaObject::File(f)) =>
+                    assert_eq!(f.meta.atime == time::UNIX_EPOCH, !atime),
+                other => panic!("unexpected object {:?}", other)
+            }
+            history.get_path(&src).unwrap().unwrap().ident()
+        };
+
+        touch(1000);
+        let first = snap(false);
+        touch(2000);
+        assert_eq!(snap(false), first);
+
+        // recorded atimes make the same tree into a different object
+        touch(3000);
+        let with_atime = snap(true);
+        touch(4000);
+        assert!(snap(true) != with_atime);
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn empty_file_roundtrip() {
+        let src = env::temp_dir().join("bkp-empty-file-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        fs::File::create(src.join("empty")).unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+
+        // no chunks, but still a file
+        match history.get_path(&src.join("empty")).unwrap() {
+            Some(MetaObject::File(f)) => {
+                assert_eq!(f.size, Some(0));
+                assert!(f.body.is_empty());
+            },
+            other => panic!("unexpected object {:?}", other)
+        }
+
+        let dest = env::temp_dir().join("bkp-empty-file-restore");
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+        let snap = history.get_snapshot().unwrap().unwrap();
+        let opts = RestoreOptions::new().ignore_permissions(true);
+        snap.get(&src.join("empty")).unwrap().unwrap()
+            .restore(&dest, &opts).unwrap();
+        let meta = fs::symlink_metadata(dest.join("empty")).unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.len(), 0);
+
+        fs::remove_dir_all(&dest).unwrap();
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn prune_empty_dirs() {
+        let src = env::temp_dir().join("bkp-prune-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("keep")).unwrap();
+        fs::create_dir_all(src.join("empty")).unwrap();
+        fs::create_dir_all(src.join("objs")).unwrap();
+        fs::create_dir_all(src.join("deep").join("er")).unwrap();
+        fs::File::create(src.join("keep").join("file")).unwrap();
+        fs::File::create(src.join("objs").join("main.o")).unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let snap = |prune: bool| {
+            let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_excludes(vec![Pattern::parse("*.o").unwrap()]);
+            history.set_prune_empty_dirs(prune);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+
+            let mut found = Vec::new();
+            for name in &["keep", "empty", "objs", "deep"] {
+                if history.get_path(&src.join(name)).unwrap().is_some() {
+                    found.push(*name);
+                }
+            }
+            found
+        };
+        assert_eq!(snap(false), vec!["keep", "empty", "objs", "deep"]);
+
+        // directories emptied by pruning their own children go too
+        assert_eq!(snap(true), vec!["keep"]);
+
+        // a path asked for by name is stored even if there's nothing in it
+        fs::remove_dir_all(src.join("keep")).unwrap();
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_prune_empty_dirs(true);
+        let empty = src.join("empty");
+        let root = history.update_paths(vec![empty.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+        match history.get_path(&empty).unwrap() {
+            Some(MetaObject::Tree(t)) => assert!(t.children.is_empty()),
+            other => panic!("unexpected object {:?}", other)
+        }
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn verify_before_commit() {
+        let src = env::temp_dir().join("bkp-verify-commit-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        fs::File::create(src.join("a")).unwrap().write_all(b"first").unwrap();
+        fs::File::create(src.join("b")).unwrap().write_all(b"second").unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let first = {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_verify_commit(true);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap()
+        };
+
+        // a new block goes missing after it was supposedly stored
+        fs::File::create(src.join("b")).unwrap().write_all(b"changed").unwrap();
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_verify_commit(true);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.backend.delete_block(&block_tag(b"changed")).unwrap();
+            match history.new_snapshot(root) {
+                Err(Error::Incomplete(1)) => {},
+                other => panic!("unexpected result {:?}", other)
+            }
+        }
+
+        // so the last good snapshot stays the head
+        let head = backend.get_head().unwrap().expect("head was removed");
+        assert_eq!(head.ident(), first);
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn resume_interrupted_snapshot() {
+        let dir = env::temp_dir().join("bkp-resume-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        for i in 0..20 {
+            fs::File::create(dir.join("src").join(format!("f{:02}", i)))
+                .unwrap().write_all(format!("file {}", i).as_bytes()).unwrap();
+        }
+        let dir = dir.canonicalize().unwrap();
+        let src = dir.join("src");
+        let journal = dir.join("journal");
+
+        let mem = MemoryBackend::new();
+        let writes = mem.block_writes.clone();
+        let limit = mem.block_limit.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+
+        // the connection drops after 15 of the 20 files are uploaded
+        limit.set(Some(15));
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_journal(&journal);
+            assert!(history.update_paths(vec![src.as_os_str()]).is_err());
+        }
+        assert!(backend.get_head().unwrap().is_none());
+        assert!(journal.exists());
+
+        // one file changes before the next attempt, so if it was stored before
+        // its journal entry is out of date
+        let changed = backend.has_block(&block_tag(b"file 0")).unwrap();
+        fs::File::create(src.join("f00")).unwrap()
+            .write_all(b"changed file").unwrap();
+
+        limit.set(None);
+        writes.set(0);
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_journal(&journal);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+
+            match history.get_path(&src.join("f00")).unwrap() {
+                Some(MetaObject::File(f)) => assert_eq!(f.size, Some(12)),
+                other => panic!("unexpected object {:?}", other)
+            }
+            assert!(history.check(IntegrityTestMode::Exhaustive).unwrap()
+                           .is_ok());
+        }
+        assert_eq!(writes.get(), 5 + changed as usize);
+        assert!(!journal.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sparse_file_roundtrip() {
+        // data, a long hole, more data, and a hole running to the end
+        let src = env::temp_dir().join("bkp-sparse-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        {
+            let mut f = fs::File::create(src.join("image")).unwrap();
+            f.write_all(&[1u8; 4096]).unwrap();
+            f.seek(io::SeekFrom::Start(69632)).unwrap();
+            f.write_all(&[2u8; 4096]).unwrap();
+            f.set_len(106496).unwrap();
+        }
+        let src = src.canonicalize().unwrap();
+        let expected = read_file(src.join("image"));
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_chunk_size(4096);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+
+            // only the two data chunks are stored
+            match history.get_path(&src.join("image")).unwrap() {
+                Some(MetaObject::File(f)) => {
+                    assert_eq!(f.body.len(), 4);
+                    assert_eq!(f.body[1].hole_len(), Some(65536));
+                    assert_eq!(f.body[3].hole_len(), Some(32768));
+                },
+                other => panic!("unexpected object {:?}", other)
+            }
+            history.get_head_snapshot().unwrap().unwrap()
+        };
+        assert_eq!(backend.list_blocks().unwrap().len(), 2);
+
+        let dest = env::temp_dir().join("bkp-sparse-restore-test");
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(&dest).unwrap();
+        let snap = ContextWrapper::new(&backend, snap);
+        let obj = snap.get(src.join("image")).unwrap().unwrap();
+        obj.restore(&dest, &RestoreOptions::new().ignore_permissions(true))
+           .unwrap();
+
+        // the restored copy has the same contents, but no space for the holes
+        let meta = fs::metadata(dest.join("image")).unwrap();
+        assert_eq!(meta.len(), 106496);
+        assert!(meta.blocks() * 512 < 65536);
+        assert_eq!(read_file(dest.join("image")), expected);
+
+        let mut out = Vec::new();
+        obj.write_contents(&mut out).unwrap();
+        assert_eq!(out, expected);
+
+        fs::remove_dir_all(&src).unwrap();
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn plan_lists_changed_files() {
+        let src = env::temp_dir().join("bkp-plan-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        fs::File::create(src.join("same")).unwrap().write_all(b"same").unwrap();
+        fs::File::create(src.join("edit")).unwrap().write_all(b"old").unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        let plan = history.plan_paths(vec![src.as_os_str()]).unwrap();
+        assert_eq!(plan.changes.len(), 2);
+        assert_eq!(plan.upload_bytes, 7);
+        assert_eq!((plan.new_blocks, plan.new_bytes), (2, 7));
+        assert!(history.backend.list_meta().unwrap().is_empty());
+
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+
+        fs::File::create(src.join("edit")).unwrap().write_all(b"new!").unwrap();
+        fs::File::create(src.join("added")).unwrap().write_all(b"x").unwrap();
+        let mut plan = history.plan_paths(vec![src.as_os_str()]).unwrap();
+        plan.changes.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(plan.changes, vec![
+            PathChange { path: src.join("added"), kind: ChangeKind::Added },
+            PathChange { path: src.join("edit"), kind: ChangeKind::Modified }]);
+        assert_eq!(plan.upload_bytes, 5);
+        assert_eq!((plan.new_blocks, plan.new_bytes), (2, 5));
+
+        // contents which are already stored don't need uploading
+        fs::File::create(src.join("added")).unwrap().write_all(b"same")
+            .unwrap();
+        fs::File::create(src.join("copy")).unwrap().write_all(b"new!")
+            .unwrap();
+        let plan = history.plan_paths(vec![src.as_os_str()]).unwrap();
+        assert_eq!(plan.changes.len(), 3);
+        assert_eq!(plan.upload_bytes, 12);
+        assert_eq!((plan.new_blocks, plan.new_bytes), (1, 4));
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn plan_writes_nothing() {
+        let src = env::temp_dir().join("bkp-plan-empty-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::File::create(src.join("sub").join("file")).unwrap()
+            .write_all(b"contents").unwrap();
+        let src = src.canonicalize().unwrap();
+
+        // a dry run against a target nothing has been stored on yet
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            let plan = history.plan_paths(vec![src.as_os_str()]).unwrap();
+            assert_eq!((plan.new_blocks, plan.new_bytes), (1, 8));
+        }
+        assert!(backend.list_blocks().unwrap().is_empty());
+        assert!(backend.list_meta().unwrap().is_empty());
+        assert!(backend.get_head().unwrap().is_none());
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn excluded_paths_not_stored() {
+        let src = env::temp_dir().join("bkp-exclude-snapshot-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("target").join("debug")).unwrap();
+        fs::File::create(src.join("target").join("debug").join("out")).unwrap();
+        fs::File::create(src.join("kept")).unwrap();
+        fs::File::create(src.join("skipped.tmp")).unwrap();
+        fs::File::create(src.join(".bkpignore")).unwrap()
+            .write_all(b"*.tmp\n").unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_excludes(vec![Pattern::parse("/target").unwrap()]);
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+
+        assert!(history.get_path(&src.join("kept")).unwrap().is_some());
+        assert!(history.get_path(&src.join(".bkpignore")).unwrap().is_some());
+        assert!(history.get_path(&src.join("skipped.tmp")).unwrap().is_none());
+        assert!(history.get_path(&src.join("target")).unwrap().is_none());
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn too_deep_tree_refused() {
+        let src = env::temp_dir().join("bkp-deep-tree-test");
+        let _ = fs::remove_dir_all(&src);
+        let mut deep = src.clone();
+        for _ in 0..MAX_TREE_DEPTH {
+            deep.push("d");
+        }
+        fs::create_dir_all(&deep).unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        match history.plan_paths(vec![src.as_os_str()]) {
+            Err(Error::TooDeep(_)) => {},
+            _ => panic!("planned a tree deeper than the limit")
+        }
+        match history.update_paths(vec![src.as_os_str()]) {
+            Err(Error::TooDeep(_)) => {},
+            _ => panic!("stored a tree deeper than the limit")
+        }
+
+        fs::remove_dir_all(&src).unwrap();
     }
 
-    /// Run integrity tests on the history
-    pub fn check(&mut self, mode: IntegrityTestMode) -> Result<bool> {
-        // get the chain head
-        let mut head = self.backend.get_head()?;
+    #[test]
+    fn check_very_deep_tree() {
+        // far deeper than the stack would allow if each level recursed
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let block = backend.write_block(b"at the bottom").unwrap();
+        let file = MetaObject::file("file", FSMetadata::default(), vec![block]);
+        let mut tree = backend.write_meta(&file).unwrap();
+        for _ in 0..100000 {
+            let obj = MetaObject::tree("d", dir_meta(), vec![tree]);
+            tree = backend.write_meta(&obj).unwrap();
+        }
+        let snapshot = Snapshot { create_time: time::UNIX_EPOCH, root: tree,
+                                  parent: None };
+        let snap = backend.write_meta(&MetaObject::Snapshot(snapshot.clone()))
+            .unwrap();
+        backend.set_head(&snap).unwrap();
 
-        // traverse the snapshot chain
-        while let Some(root) = head {
-            if let MetaObject::Snapshot(snap) = root {
-                // move to the parent if needed
-                if let Some(p) = snap.parent {
-                    head = Some(self.backend.read_meta(&p)?);
-                } else {
-                    head = None;
-                }
+        backend.delete_block(&block).unwrap();
+        let history = History::new(&mut backend).unwrap();
+        let report = history.check(IntegrityTestMode::Slow).unwrap();
+        let mut deep = PathBuf::from("/");
+        for _ in 1..100000 { deep.push("d"); }
+        deep.push("file");
+        assert_eq!(report.snapshots.len(), 1);
+        assert_eq!(report.snapshots[0].0, snap);
+        assert_eq!(report.snapshots[0].1.bad_blocks,
+                   vec![(deep.clone(), block, BlockFault::Missing)]);
 
-                // check the file structure
-                if mode.check_trees() {
-                    if !self.check_tree(mode, &snap.root)? {
-                        return Ok(false);
-                    }
-                }
-            } else {
-                return Ok(false);
+        // verifying just the one snapshot walks it the same way
+        let report = history.verify_snapshot(&snapshot).unwrap();
+        assert_eq!(report.blocks_checked, 1);
+        assert!(report.bad_objects.is_empty());
+        assert_eq!(report.bad_blocks, vec![(deep, block, BlockFault::Missing)]);
+    }
+
+    #[test]
+    fn walk_very_deep_trees() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let deep = |backend: &mut Box<Backend>, data: &[u8]| {
+            let block = backend.write_block(data).unwrap();
+            let file = MetaObject::file("file", FSMetadata::default(),
+                                        vec![block]);
+            let mut tree = backend.write_meta(&file).unwrap();
+            for _ in 0..100000 {
+                let obj = MetaObject::tree("d", dir_meta(), vec![tree]);
+                tree = backend.write_meta(&obj).unwrap();
             }
+            tree
+        };
+        let old_root = deep(&mut backend, b"old");
+        let new_root = deep(&mut backend, b"new contents");
+        let old = Snapshot { create_time: time::UNIX_EPOCH, root: old_root,
+                             parent: None };
+        let old_tag = backend.write_meta(&MetaObject::Snapshot(old.clone()))
+            .unwrap();
+        let new = Snapshot { create_time: time::UNIX_EPOCH, root: new_root,
+                             parent: Some(old_tag) };
+        let new_tag = backend.write_meta(&MetaObject::Snapshot(new.clone()))
+            .unwrap();
+        backend.set_head(&new_tag).unwrap();
+
+        let mut history = History::new(&mut backend).unwrap();
+        let mut path = PathBuf::from("/");
+        for _ in 1..100000 { path.push("d"); }
+        path.push("file");
+        assert_eq!(history.diff(&old, &new).unwrap(),
+                   vec![PathChange { path: path,
+                                     kind: ChangeKind::Modified }]);
+
+        let stats = history.stats().unwrap();
+        assert_eq!(stats.referenced_bytes, 3 + 12);
+        assert_eq!(stats.stored_bytes, 3 + 12);
+
+        // everything is reachable
+        let report = history.gc(true).unwrap();
+        assert_eq!((report.meta_objects, report.blocks), (0, 0));
+    }
+
+    #[test]
+    fn other_filesystems_skipped() {
+        let src = env::temp_dir().join("bkp-one-fs-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::File::create(src.join("file")).unwrap();
+        let src = src.canonicalize().unwrap();
+        let dev = fs::metadata(&src).unwrap().dev();
+
+        // everything here is on the same device, so pretending the directory
+        // is elsewhere should leave out all of its entries
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let history = History::new(&mut backend).unwrap();
+        let rules = ExcludeRules::new(&src, &[]);
+        let entries = history.dir_entries(&src, None, &rules, Some(dev))
+                             .unwrap();
+        assert_eq!(entries.len(), 2);
+        let entries = history.dir_entries(&src, None, &rules, Some(dev + 1))
+                             .unwrap();
+        assert!(entries.is_empty());
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn tagged_caches_skipped() {
+        let src = env::temp_dir().join("bkp-exclude-caches-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("cache").join("sub")).unwrap();
+        fs::create_dir_all(src.join("fake")).unwrap();
+        fs::File::create(src.join("cache").join("CACHEDIR.TAG")).unwrap()
+            .write_all(b"Signature: 8a477f597d28d172789f06886806bc55\n\
+                         # a cache tag\n").unwrap();
+        fs::File::create(src.join("cache").join("sub").join("data")).unwrap();
+        fs::File::create(src.join("cache").join("data")).unwrap();
+        fs::File::create(src.join("fake").join("CACHEDIR.TAG")).unwrap()
+            .write_all(b"Signature: not really\n").unwrap();
+        fs::File::create(src.join("fake").join("data")).unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_exclude_caches(true);
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+
+        let cache = src.join("cache");
+        assert!(history.get_path(&cache).unwrap().is_some());
+        assert!(history.get_path(&cache.join("CACHEDIR.TAG")).unwrap()
+                       .is_some());
+        assert!(history.get_path(&cache.join("data")).unwrap().is_none());
+        assert!(history.get_path(&cache.join("sub")).unwrap().is_none());
+
+        // tags without the right signature don't count
+        assert!(history.get_path(&src.join("fake").join("data")).unwrap()
+                       .is_some());
+
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn marked_dirs_skipped() {
+        let src = env::temp_dir().join("bkp-exclude-markers-test");
+        let _ = fs::remove_dir_all(&src);
+        for dir in ["a", "b", "c"].iter() {
+            fs::create_dir_all(src.join(dir).join("sub")).unwrap();
+            fs::File::create(src.join(dir).join("data")).unwrap();
         }
-        Ok(true)
+        fs::File::create(src.join("a").join(".nobackup")).unwrap();
+        fs::File::create(src.join("b").join(".skip-me")).unwrap();
+        let src = src.canonicalize().unwrap();
+
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_exclude_markers(vec![".nobackup".into(),
+                                         ".skip-me".into()]);
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+
+        for &(dir, marker) in [("a", ".nobackup"), ("b", ".skip-me")].iter() {
+            let dir = src.join(dir);
+            assert!(history.get_path(&dir).unwrap().is_some());
+            assert!(history.get_path(&dir.join(marker)).unwrap().is_some());
+            assert!(history.get_path(&dir.join("data")).unwrap().is_none());
+            assert!(history.get_path(&dir.join("sub")).unwrap().is_none());
+        }
+        assert!(history.get_path(&src.join("c").join("data")).unwrap()
+                       .is_some());
+
+        fs::remove_dir_all(&src).unwrap();
     }
 
-    /// Retrieve a context-wrapped version of the most recent snapshot, if any
-    pub fn get_snapshot<'b>(&'b self)
-            -> Result<Option<ContextWrapper<'b, Snapshot>>> {
-        self.get_head_snapshot()
-            .map(|o| o.map(|x| ContextWrapper::new(self.backend, x)))
+    #[test]
+    fn incompressible_extensions() {
+        let dir = env::temp_dir().join("bkp-no-compress-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("photo.JPG")).unwrap()
+            .write_all(b"pretend this is a jpeg").unwrap();
+        fs::File::create(dir.join("notes.txt")).unwrap()
+            .write_all(b"plain old text").unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let mem = MemoryBackend::new();
+        let uncompressed = mem.uncompressed.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_no_compress_exts(vec![String::from(".jpg"),
+                                          String::from("mp4")]);
+        let root = history.update_paths(vec![dir.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+
+        let body = |name: &str| match history.get_path(&dir.join(name)) {
+            Ok(Some(MetaObject::File(f))) => f.body,
+            r => panic!("unexpected object: {:?}", r)
+        };
+        let uncompressed = uncompressed.borrow();
+        assert!(body("photo.JPG").iter().all(|b| uncompressed.contains(b)));
+        assert!(!body("notes.txt").iter().any(|b| uncompressed.contains(b)));
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    /// Retrieve the most recent snapshot, if any
-    fn get_head_snapshot(&self) -> Result<Option<Snapshot>> {
-        let snapshot = self.backend.get_head()?;
-        if snapshot.is_none() {
-            return Ok(None);
+    #[test]
+    fn dereferenced_symlinks() {
+        let src = env::temp_dir().join("bkp-dereference-test");
+        let _ = fs::remove_dir_all(&src);
+        let dir = src.join("stored");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(src.join("elsewhere").join("sub")).unwrap();
+        fs::File::create(src.join("elsewhere").join("sub").join("data"))
+            .unwrap().write_all(b"linked data").unwrap();
+        symlink(src.join("elsewhere").join("sub").join("data"),
+                dir.join("file-link")).unwrap();
+        symlink(src.join("elsewhere"), dir.join("dir-link")).unwrap();
+        symlink(&dir, dir.join("loop")).unwrap();
+        symlink(src.join("missing"), dir.join("dangling")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let stored = |dereference: bool| {
+            let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_dereference(dereference);
+            let root = history.update_paths(vec![dir.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+            let get = |p: &str| history.get_path(&dir.join(p)).unwrap();
+            (get("file-link"), get("dir-link/sub/data"), get("loop"),
+             get("dangling"))
+        };
+
+        // by default, links are stored as links
+        match stored(false) {
+            (Some(MetaObject::Symlink(_)), None, Some(MetaObject::Symlink(_)),
+             Some(MetaObject::Symlink(_))) => {},
+            r => panic!("unexpected objects: {:?}", r)
         }
-        let snapshot = snapshot.unwrap();
 
-        if let MetaObject::Snapshot(s) = snapshot {
-            Ok(Some(s))
-        } else {
-            Err(Error::NoValidSnapshot)
+        // otherwise their targets are stored in their place, except for the
+        // loop back to the stored directory
+        match stored(true) {
+            (Some(MetaObject::File(ref a)), Some(MetaObject::File(ref b)),
+             None, Some(MetaObject::Symlink(_))) => {
+                assert_eq!(a.name, b"file-link".to_vec());
+                assert_eq!(a.size, Some(11));
+                assert_eq!(a.body, b.body);
+            },
+            r => panic!("unexpected objects: {:?}", r)
         }
+
+        fs::remove_dir_all(&src).unwrap();
     }
 
-    #[allow(dead_code)]
-    /// Creates a new snapshot with the given root tree
-    /// 
-    /// If a snapshot is already stored, then the resulting snapshot will use it
-    /// as its parent. Otherwise, the new snapshot will be an origin snapshot.
-    pub fn new_snapshot(&mut self, root: IdentityTag) -> Result<IdentityTag> {
-        let snap = self.get_head_snapshot()?;
-        let new_obj = MetaObject::snapshot(root,
-                                           snap.map(|o| MetaObject::Snapshot(o)
-                                                        .ident()));
+    #[test]
+    fn small_change_writes_little() {
+        let src = env::temp_dir().join("bkp-small-change-test");
+        let _ = fs::remove_dir_all(&src);
+        let deep = src.join("a").join("b").join("c");
+        fs::create_dir_all(&deep).unwrap();
+        fs::File::create(deep.join("file")).unwrap().write_all(b"old").unwrap();
+        for d in 0..10 {
+            let dir = src.join(format!("dir{}", d));
+            fs::create_dir(&dir).unwrap();
+            for f in 0..10 {
+                fs::File::create(dir.join(format!("file{}", f))).unwrap()
+                    .write_all(format!("{} {}", d, f).as_bytes()).unwrap();
+            }
+        }
+        let src = src.canonicalize().unwrap();
+        let deep = src.join("a").join("b").join("c");
 
-        // store it
-        let ident = self.backend.write_meta(&new_obj)?;
+        let mem = MemoryBackend::new();
+        let writes = mem.meta_writes.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        let mut history = History::new(&mut backend).unwrap();
 
-        // and commit it by modifying the head pointer
-        self.backend.set_head(&ident)?;
-        Ok(ident)
+        // reading files may bump their access times, depending on how the
+        // filesystem is mounted, so leave those out
+        history.set_store_atime(false);
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+        assert!(writes.get() > 110);
+        writes.set(0);
+        assert_eq!(history.update_paths(vec![src.as_os_str()]).unwrap(), root);
+        assert_eq!(writes.get(), 0);
+
+        // only the file and the trees above it need writing again
+        fs::File::create(deep.join("file")).unwrap().write_all(b"new").unwrap();
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        assert_eq!(writes.get(), deep.components().count() + 1);
+        history.new_snapshot(root).unwrap();
+
+        fs::remove_dir_all(&src).unwrap();
     }
 
-    #[allow(dead_code)]
-    /// Try to retrieve the given path from the latest snapshot
-    /// 
-    /// If no snapshots are stored or the object doesn't exist, this will return
-    /// `Ok(None)`.
-    pub fn get_path(&self, path: &Path) -> Result<Option<MetaObject>> {
-        use std::path::Component;
+    #[test]
+    fn store_root_directory() {
+        let src = env::temp_dir().join("bkp-root-snapshot-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        fs::File::create(src.join("file")).unwrap();
+        let src = src.canonicalize().unwrap();
 
-        let snapshot = self.get_head_snapshot()?;
+        // leave out everything under the root, so only it gets stored
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_excludes(vec![Pattern::parse("/*").unwrap()]);
+        let root = history.update_paths(vec!["/"]).unwrap();
+        history.new_snapshot(root).unwrap();
+        match history.get_path(Path::new("/")).unwrap() {
+            Some(MetaObject::Tree(t)) => {
+                assert!(t.name.is_empty());
+                assert!(t.children.is_empty());
+            },
+            _ => panic!("root wasn't stored as a tree")
+        }
 
-        if snapshot.is_none() { return Ok(None); }
-        let snapshot = snapshot.unwrap();
+        // later snapshots build on the stored root
+        history.set_excludes(Vec::new());
+        let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+        history.new_snapshot(root).unwrap();
+        assert!(history.get_path(&src.join("file")).unwrap().is_some());
 
-        let mut current = snapshot.root;
-        for comp in path.components() {
-            let cur_elem = self.backend.read_meta(&current)?;
+        fs::remove_dir_all(&src).unwrap();
+    }
 
-            // snapshots are never valid child targets
-            let tree = if let MetaObject::Tree(t) = cur_elem {
-                t
-            } else {
-                return Err(Error::IntegrityError);
-            };
+    #[test]
+    fn verify_reports_bad_blocks() {
+        let mut mem = MemoryBackend::new();
+        mem.blocks.insert(tag(9), b"not the original data".to_vec());
+        let mut backend: Box<Backend> = Box::new(mem);
 
-            // descend a level based on the component
-            match comp {
-                Component::Prefix(_) => unimplemented!(), // Windows only - don't care yet
-                Component::RootDir => {}, // skip, since we already start at /
-                Component::CurDir => panic!("retrieved path is not canonical"),
-                Component::ParentDir => panic!("retrieved path is not canonical"),
-                Component::Normal(cmp) => { // try to pull the item
-                    // retrieve the tree's children
-                    let children: Result<Vec<(IdentityTag, MetaObject)>> =
-                        tree.children.iter()
-                                     .map(|id| self.backend
-                                              .read_meta(&id)
-                                              .map_err(|e| Error::Backend(e))
-                                              .map(|r| (id.to_owned(), r)))
-                                     .collect();
-                    let children: Vec<(IdentityTag, MetaObject)> = children?;
+        let good = backend.write_block(b"fine").unwrap();
+        let files = vec![
+            MetaObject::file("good", FSMetadata::default(), vec![good]),
+            MetaObject::file("corrupt", FSMetadata::default(),
+                             vec![good, tag(9)]),
+            MetaObject::file("gone", FSMetadata::default(), vec![tag(8)])];
+        let children = files.iter().map(|f| backend.write_meta(f).unwrap())
+                                   .collect();
+        let dir = MetaObject::tree("dir", dir_meta(), children);
+        let dir = backend.write_meta(&dir).unwrap();
+        let root = MetaObject::tree("", dir_meta(), vec![dir, tag(7)]);
+        let root = backend.write_meta(&root).unwrap();
+        let snap = Snapshot { create_time: time::UNIX_EPOCH, root: root,
+                              parent: None };
 
-                    // try to find one matching the path component
-                    if let Some(itm) = children.into_iter()
-                                      .filter(|c| c.1.name() == Some(cmp.to_os_string()))
-                                      .map(|c| c.0)
-                                      .next() {
-                        current = itm;
-                    } else {
-                        // no item matching what the path specified
-                        return Ok(None);
-                    }
+        let history = History::new(&mut backend).unwrap();
+        let report = history.verify_snapshot(&snap).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.blocks_checked, 3);
+        assert_eq!(report.bad_blocks,
+                   vec![(PathBuf::from("/dir/corrupt"), tag(9),
+                         BlockFault::Corrupt),
+                        (PathBuf::from("/dir/gone"), tag(8),
+                         BlockFault::Missing)]);
+        assert_eq!(report.bad_objects, vec![(PathBuf::from("/"), tag(7))]);
+    }
+
+    #[test]
+    fn metadata_cache() {
+        let mem = MemoryBackend::new();
+        let reads = mem.meta_reads.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        let snap = build_tree(&mut backend);
+        let snap = backend.write_meta(&MetaObject::Snapshot(snap)).unwrap();
+        backend.set_head(&snap).unwrap();
+
+        // look up paths sharing the same ancestors, twice over
+        let paths = ["/outer", "/outer/inner", "/outer/inner/file"];
+        let mut counts = Vec::new();
+        for &size in [0, 64].iter() {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_meta_cache_size(size);
+            let before = reads.get();
+            for _ in 0..2 {
+                for p in paths.iter() {
+                    assert!(history.get_path(Path::new(p)).unwrap().is_some());
                 }
             }
+            let snap = history.get_snapshot().unwrap().unwrap();
+            assert!(snap.get("outer/inner/file").unwrap().is_some());
+            counts.push(reads.get() - before);
         }
 
-        Ok(Some(self.backend.read_meta(&current)?))
+        // each object in the tree is only read once, apart from the head
+        // snapshot which is read for every lookup
+        assert_eq!(counts[1], 4 + 7);
+        assert!(counts[0] > 2 * counts[1]);
     }
 
-    #[allow(dead_code)]
-    /// Create a file, tree, or symlink object from a path on disk.
-    /// 
-    /// The given path should be canonical.
-    fn store_path(&mut self, path: &Path) -> Result<IdentityTag> {
-        let meta = fs::symlink_metadata(path)?;
-        let ftype = meta.file_type();
-        let fname = path.file_name().ok_or(Error::InvalidArgument)?;
+    #[test]
+    fn repair_regenerates_blocks() {
+        let src = env::temp_dir().join("bkp-repair-test");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        fs::File::create(src.join("kept.jpg")).unwrap()
+            .write_all(b"aaaabbbbcccc").unwrap();
+        fs::File::create(src.join("gone")).unwrap()
+            .write_all(b"dddd").unwrap();
+        let src = src.canonicalize().unwrap();
 
-        // TODO: handle stores of the root directory
+        let mem = MemoryBackend::new();
+        let uncompressed = mem.uncompressed.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        {
+            let mut history = History::new(&mut backend).unwrap();
+            history.set_chunk_size(4);
+            let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+            history.new_snapshot(root).unwrap();
+        }
 
-        // TODO: checks here to avoid redundant stores
-        // this should check the mtime or hash of the files on disk against
-        // the mtime/hash of the most recent nodes in the tree
-        
-        if ftype.is_file() {
-            // break it into chunks and store them
-            let f = fs::OpenOptions::new()
-                            .read(true)
-                            .open(path)?;
-            let mut blocks = Vec::new();
-            for c in f.bytes().chunks() {
-                blocks.push(self.backend.write_block(&c?)?);
-            }
+        // lose a block of each file, and one of the files too
+        backend.delete_block(&block_tag(b"bbbb")).unwrap();
+        backend.delete_block(&block_tag(b"dddd")).unwrap();
+        fs::remove_file(src.join("gone")).unwrap();
 
-            // construct a new meta-object and store it
-            let obj = MetaObject::file(fname, meta, blocks);
-            Ok(self.backend.write_meta(&obj)?)
-        } else if ftype.is_dir() {
-            // store each child
-            let mut children = Vec::new();
-            for entry in fs::read_dir(&path)? {
-                let entry = entry?; // safely unwrap the result
-                let pth = entry.path();
+        // regenerated blocks are stored the way a snapshot would store them
+        let mut history = History::new(&mut backend).unwrap();
+        history.set_chunk_size(4);
+        history.set_no_compress_exts(vec![String::from("jpg")]);
+        let snap = history.snapshots().unwrap().remove(0).1;
+        let report = history.verify_snapshot(&snap).unwrap();
+        assert_eq!(report.bad_blocks.len(), 2);
 
-                // store the child node
-                children.push(self.store_path(&pth)?);
-            }
+        let repair = history.repair_blocks(&report).unwrap();
+        assert_eq!(repair, RepairReport {
+            repaired: vec![block_tag(b"bbbb")],
+            unrepairable: vec![(src.join("gone"), block_tag(b"dddd"),
+                                BlockFault::Missing)]
+        });
+        let expected: HashSet<_> = vec![block_tag(b"bbbb")].into_iter()
+            .collect();
+        assert_eq!(*uncompressed.borrow(), expected);
 
-            // build and store the new object
-            let obj = MetaObject::tree(fname, meta, children);
-            Ok(self.backend.write_meta(&obj)?)
-        } else if ftype.is_symlink() {
-            // store the symlink object
-            let tgt = fs::read_link(&path)?;
-            let obj = MetaObject::symlink(fname, meta, &tgt);
-            Ok(self.backend.write_meta(&obj)?)
-        } else {
-            unimplemented!()
-        }
+        let report = history.verify_snapshot(&snap).unwrap();
+        assert_eq!(report.bad_blocks, repair.unrepairable);
+        fs::remove_dir_all(&src).unwrap();
     }
 
-    /// Construct a skeleton tree containing the given subtrees at the point of
-    /// the given root, and return its identity.
-    /// 
-    /// For example, given the root `/usr` and the files `/usr/bin/ls` and
-    /// `/usr/share/dict/words`, this function will construct the following set
-    /// of tree objects:
-    /// 
-    ///     /usr
-    ///         /bin
-    ///             [ls]
-    ///         /share
-    ///             /dict
-    ///                 [words]
-    ///
-    /// The generated objects will have 
-    fn build_tree_skeleton<P: AsRef<Path>>(&mut self, root: &Path,
-                                           objects: &Vec<(P, IdentityTag)>) ->
-            Result<IdentityTag> {
-        // iterate through relevant children and build their trees
-        let mut children = Vec::new();
-        for child in objects.iter().filter(|p| p.0.as_ref().starts_with(root)) {
-            children.push(
-                if child.0.as_ref().parent() == Some(root) {
-                    // if we hit the child's parent, just add the child ID
-                    child.1
-                } else {
-                    // figure out which path to create next
-                    let relative = child.0.as_ref().strip_prefix(root).unwrap();
-                    let part = relative.iter().next().unwrap();
-                    
-                    // create intermediary dirs
-                    let path = root.join(part);
-                    self.build_tree_skeleton(&path, objects)?
-                });
-        }
+    #[test]
+    fn check_reports_faults() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let snap = build_tree(&mut backend);
+        let snap = backend.write_meta(&MetaObject::Snapshot(snap)).unwrap();
+        backend.set_head(&snap).unwrap();
 
-        let name = match root.file_name() {
-            None => OsStr::new(""),
-            Some(n) => n };
-        let tree = MetaObject::tree(name, FSMetadata::default(), children);
-        Ok(self.backend.write_meta(&tree)?)
+        let block = backend.list_blocks().unwrap()[0];
+        backend.delete_block(&block).unwrap();
+
+        let history = History::new(&mut backend).unwrap();
+        assert!(history.check(IntegrityTestMode::Normal).unwrap().is_ok());
+        let report = history.check(IntegrityTestMode::Slow).unwrap();
+        assert_eq!(report.snapshots.len(), 1);
+        assert_eq!(report.snapshots[0].0, snap);
+        assert_eq!(report.snapshots[0].1.bad_blocks,
+                   vec![(PathBuf::from("/outer/inner/file"), block,
+                         BlockFault::Missing)]);
     }
 
-    /// Build a new tree based on the previous root - start at `/` and move
-    /// down, inserting updated elements in the right positions along the way.
-    /// 
-    /// For each directory visited, this will choose whether to use the previous
-    /// stored tree (if no updated path is rooted there), use a path from the
-    /// input set, or use a new stored tree with recursively updated versions of
-    /// the tree's children.
-    fn update_tree<P: AsRef<Path>>(&mut self, root: &Path,
-                       new_vals: &Vec<(P, IdentityTag)>) ->
-            Result<IdentityTag> {
-        match new_vals.iter().find(|x| x.0.as_ref() == root) {
-            Some(r) => Ok(r.1), // return the updated object
-            None => { // no override - look backwards
-                let old_version = match self.get_path(root)? {
-                    Some(r) => r,
-                    None    => {
-                        // the folder didn't exist before, so create a new tree
-                        // object to hold it and insert the object there
-                        return self.build_tree_skeleton(root, new_vals)
-                    }
-                };
+    #[test]
+    fn recover_from_broken_head() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let mut good = build_tree(&mut backend);
+        good.create_time = time::UNIX_EPOCH + time::Duration::from_secs(1);
+        let good_root = good.root;
+        let good = backend.write_meta(&MetaObject::Snapshot(good)).unwrap();
 
-                // check whether we actually need to store this - if nothing
-                // under it was changed, we can just re-use the old object
-                if new_vals.iter().any(|x| x.0.as_ref().starts_with(root)) {
-                    // yep - build a new tree
-                    //
-                    // this involves iterating through the old tree's children
-                    // and recursively updating each, then generating a new tree
-                    // object with the updated children list
-                    if let MetaObject::Tree(mut t) = old_version {
-                        let mut new_children = Vec::new();
-                        for child in t.children.drain(..) {
-                            // grab a copy and pull out the path component
-                            let obj = self.backend.read_meta(&child)?;
-                            let name = OsString::from_vec(match obj {
-                                MetaObject::Snapshot(_) => {
-                                    // trees can't have snapshots as children
-                                    return Err(Error::IntegrityError);
-                                },
-                                MetaObject::Tree(t) => t.name,
-                                MetaObject::File(f) => f.name,
-                                MetaObject::Symlink(l) => l.name,
-                            });
+        // a newer snapshot whose tree has lost an object
+        let root = MetaObject::tree("", dir_meta(), vec![tag(7)]);
+        let root = backend.write_meta(&root).unwrap();
+        let broken = MetaObject::Snapshot(Snapshot {
+            create_time: time::UNIX_EPOCH + time::Duration::from_secs(2),
+            root: root, parent: Some(good) });
+        backend.write_meta(&broken).unwrap();
 
-                            // build the new root path and update it
-                            let pth = root.join(&name);
-                            let new_id = self.update_tree(&pth, new_vals)?;
-                            new_children.push(new_id);
-                        }
+        // and a head pointing at nothing at all
+        backend.set_head(&tag(5)).unwrap();
 
-                        t.children = new_children;
-                        Ok(self.backend.write_meta(&MetaObject::Tree(t))?)
-                    } else {
-                        // to get here, one of the new paths must be rooted at
-                        // this node, but for a non-tree node that doesn't make
-                        // sense
-                        Err(Error::IntegrityError)
-                    }
-                } else {
-                    // no, reuse the old one
-                    Ok(old_version.ident())
-                }
-            }
+        let mut history = History::new(&mut backend).unwrap();
+        assert!(history.get_snapshot().is_err());
+        let found = history.find_recovery_point(IntegrityTestMode::Normal)
+                           .unwrap();
+        assert_eq!(found.snapshots, 2);
+        assert_eq!(found.chain_length, 1);
+        let (tag, snap) = found.newest_intact.unwrap();
+        assert_eq!(tag, good);
+        assert_eq!(snap.root, good_root);
+
+        match history.reset_head(&root) {
+            Err(Error::InvalidArgument) => {},
+            _ => panic!("head reset to a non-snapshot")
         }
+        history.reset_head(&tag).unwrap();
+        assert_eq!(history.head_id().unwrap(), Some(good));
     }
 
-    #[allow(dead_code)]
-    /// Generate a new root tree where the nodes corresponding to the specified
-    /// paths point to newly-stored copies.
-    /// 
-    /// Input paths will be canonicalized before further usage.
-    pub fn update_paths<'b, P, I>(&mut self, paths: I) -> Result<IdentityTag>
-            where P: 'b + AsRef<OsStr> + ?Sized,
-                  I: IntoIterator<Item=&'b P> {
-        // store a copy of the paths being updated, for later use when building
-        // an updated root tree
-        let paths: Vec<PathBuf> = {
-            // first sort all the paths by depth, so the shallowest ones are
-            // visited before their potential children
-            let mut paths: Vec<PathBuf> = paths.into_iter()
-                                            .map(Path::new)
-                                            .map(|p| p.canonicalize().unwrap())
-                                            .collect();
-            paths.sort_by_key(|p| p.components().count());
+    #[test]
+    fn diff_skips_unchanged_subtrees() {
+        let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+        let file = |backend: &mut Box<Backend>, name: &str, data: &[u8]| {
+            let blk = backend.write_block(data).unwrap();
+            let obj = MetaObject::file(name, FSMetadata::default(), vec![blk]);
+            backend.write_meta(&obj).unwrap()
+        };
+        let tree = |backend: &mut Box<Backend>, name: &str, children| {
+            let obj = MetaObject::tree(name, dir_meta(), children);
+            backend.write_meta(&obj).unwrap()
+        };
 
-            // prune directories that are subdirs of another dir in the list
-            let mut result: Vec<PathBuf> = Vec::new();
-            for p in paths.into_iter() {
-                if !result.iter().any(|x| p.starts_with(x)) {
-                    result.push(p);
-                }
-            }
+        let deep = file(&mut backend, "deep", b"untouched");
+        let same = tree(&mut backend, "same", vec![deep]);
+        let old_f = file(&mut backend, "changed", b"before");
+        let gone = file(&mut backend, "gone", b"removed");
+        let old_root = tree(&mut backend, "", vec![same, old_f, gone]);
+
+        let new_f = file(&mut backend, "changed", b"after");
+        let added = file(&mut backend, "added", b"new");
+        let new_root = tree(&mut backend, "", vec![same, new_f, added]);
+
+        // the unchanged subtree's contents can't be read, so the diff will fail
+        // if it tries to descend into it
+        backend.delete_meta(&deep).unwrap();
+
+        let snap = |root| Snapshot { create_time: time::UNIX_EPOCH, root: root,
+                                     parent: None };
+        let history = History::new(&mut backend).unwrap();
+        let changes = history.diff(&snap(old_root), &snap(new_root)).unwrap();
+        assert_eq!(changes, vec![
+            PathChange { path: PathBuf::from("/changed"),
+                         kind: ChangeKind::Modified },
+            PathChange { path: PathBuf::from("/gone"),
+                         kind: ChangeKind::Removed },
+            PathChange { path: PathBuf::from("/added"),
+                         kind: ChangeKind::Added }]);
+    }
 
-            result
+    #[test]
+    fn stats_count_duplicate_blocks() {
+        let mem = MemoryBackend::new();
+        let reads = mem.block_reads.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+        let x = backend.write_block(&[1u8; 100]).unwrap();
+        let y = backend.write_block(&[2u8; 50]).unwrap();
+        let z = backend.write_block(&[3u8; 30]).unwrap();
+        let file = |backend: &mut Box<Backend>, name: &str,
+                    body: Vec<IdentityTag>| {
+            let obj = MetaObject::file(name, FSMetadata::default(), body);
+            backend.write_meta(&obj).unwrap()
         };
-        
-        // store each copy of the dirs to update
-        let path_copies: Result<Vec<(PathBuf, IdentityTag)>> = paths
-            .into_iter()
-            .map(|x| {self.store_path(&x).map(|r| (x, r))})
-            .collect();
-        let path_copies = path_copies?;
+        let a = file(&mut backend, "a", vec![x]);
+        let b = file(&mut backend, "b", vec![x, y]);
+        let c = file(&mut backend, "c", vec![z]);
 
-        // store the new root tree
-        self.update_tree(&Path::new("/"), &path_copies)
+        // the second snapshot keeps both files, and adds one more
+        let old_root = MetaObject::tree("", dir_meta(), vec![a, b]);
+        let old_root = backend.write_meta(&old_root).unwrap();
+        let old = backend.write_meta(&MetaObject::Snapshot(Snapshot {
+            create_time: time::UNIX_EPOCH, root: old_root, parent: None
+        })).unwrap();
+        let new_root = MetaObject::tree("", dir_meta(), vec![a, b, c]);
+        let new_root = backend.write_meta(&new_root).unwrap();
+        let new = backend.write_meta(&MetaObject::Snapshot(Snapshot {
+            create_time: time::UNIX_EPOCH, root: new_root, parent: Some(old)
+        })).unwrap();
+        backend.set_head(&new).unwrap();
+
+        let history = History::new(&mut backend).unwrap();
+        let stats = history.stats().unwrap();
+        assert_eq!(stats.snapshots, 2);
+        assert_eq!(stats.unique_blocks, 3);
+        assert_eq!(stats.logical_bytes, 280);
+        assert_eq!(stats.referenced_bytes, 250 + 280);
+        assert_eq!(stats.stored_bytes, 180);
+        assert_eq!(stats.snapshot_stats, vec![
+            SnapshotStats { id: new, referenced_bytes: 280, added_bytes: 30 },
+            SnapshotStats { id: old, referenced_bytes: 250, added_bytes: 150 }]);
+        assert_eq!(stats.dedup_ratio(), Some(530.0 / 180.0));
+
+        // blocks are sized without downloading them
+        assert_eq!(reads.get(), 0);
+
+        // and it all survives the local cache
+        let mut cached = Vec::new();
+        stats.save(&mut cached).unwrap();
+        assert_eq!(Stats::load(&mut &cached[..]).unwrap(), stats);
     }
 }
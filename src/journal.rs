@@ -0,0 +1,231 @@
+extern crate byteorder;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use self::byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use metadata::{IdentityTag, IDENTITY_LEN};
+
+/// What a file looked like on disk when it was stored.
+///
+/// Anything which changes a file's contents, metadata or extended attributes
+/// also changes its ctime, so a file with the same fingerprint as a journaled
+/// one still matches the object stored for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64)
+}
+
+impl Fingerprint {
+    pub fn new(meta: &fs::Metadata) -> Fingerprint {
+        Fingerprint {
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec())
+        }
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Fingerprint> {
+        Ok(Fingerprint {
+            ino: r.read_u64::<LittleEndian>()?,
+            size: r.read_u64::<LittleEndian>()?,
+            mtime: (r.read_i64::<LittleEndian>()?,
+                    r.read_i64::<LittleEndian>()?),
+            ctime: (r.read_i64::<LittleEndian>()?,
+                    r.read_i64::<LittleEndian>()?)
+        })
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u64::<LittleEndian>(self.ino)?;
+        w.write_u64::<LittleEndian>(self.size)?;
+        w.write_i64::<LittleEndian>(self.mtime.0)?;
+        w.write_i64::<LittleEndian>(self.mtime.1)?;
+        w.write_i64::<LittleEndian>(self.ctime.0)?;
+        w.write_i64::<LittleEndian>(self.ctime.1)
+    }
+}
+
+/// A local record of the files stored so far by a snapshot which hasn't been
+/// committed yet.
+///
+/// If a snapshot is interrupted, the objects it already uploaded aren't
+/// referenced by any head. The journal lets the next attempt pick them up
+/// again rather than reading and uploading every file a second time. Trees
+/// aren't journaled, since rebuilding them from journaled children is cheap
+/// and notices anything added or removed in the meantime.
+///
+/// The journal starts with a key identifying the paths and settings it was
+/// made for, and is thrown away if opened with a different one. After that,
+/// it's an append-only log of `(path, fingerprint, tag)` records.
+pub struct Journal {
+    path: PathBuf,
+    entries: HashMap<PathBuf, (Fingerprint, IdentityTag)>,
+    log: fs::File
+}
+
+impl Journal {
+    /// Open the journal at a given path, starting a new one if it doesn't
+    /// exist or was made for a different key
+    pub fn open(path: &Path, key: &IdentityTag) -> io::Result<Journal> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut entries = HashMap::new();
+        let mut valid = None;
+        if path.exists() {
+            let mut data = Vec::new();
+            fs::File::open(path)?.read_to_end(&mut data)?;
+            if data.len() >= IDENTITY_LEN &&
+                    &data[..IDENTITY_LEN] == key.as_bytes() {
+                let len = replay(&data[IDENTITY_LEN..], &mut entries);
+                valid = Some(IDENTITY_LEN + len);
+            }
+        }
+
+        let log = if let Some(len) = valid {
+            // drop any partial record left by an interrupted write, so new
+            // ones don't end up appended to it
+            let f = fs::OpenOptions::new().append(true).open(path)?;
+            f.set_len(len as u64)?;
+            f
+        } else {
+            let mut f = fs::File::create(path)?;
+            f.write_all(key.as_bytes())?;
+            f
+        };
+        Ok(Journal { path: path.to_owned(), entries: entries, log: log })
+    }
+
+    /// Number of files recorded in the journal
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Find the object stored for a path, if it was stored while it had the
+    /// given fingerprint
+    pub fn get(&self, path: &Path, print: &Fingerprint)
+            -> Option<IdentityTag> {
+        match self.entries.get(path) {
+            Some(&(ref p, tag)) if p == print => Some(tag),
+            _                                 => None
+        }
+    }
+
+    /// Record that a path was stored as the given object
+    pub fn insert(&mut self, path: &Path, print: &Fingerprint,
+                  tag: &IdentityTag) -> io::Result<()> {
+        let name = path.as_os_str().as_bytes();
+        let mut rec = Vec::with_capacity(name.len() + 84);
+        rec.write_u32::<LittleEndian>(name.len() as u32)?;
+        rec.extend_from_slice(name);
+        print.write(&mut rec)?;
+        rec.extend_from_slice(tag.as_bytes());
+
+        // a single write, so an interruption leaves at most one partial record
+        self.log.write_all(&rec)?;
+        self.entries.insert(path.to_owned(), (*print, *tag));
+        Ok(())
+    }
+
+    /// Remove the journal, once the snapshot it was kept for is committed
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+/// Read journal records into `entries`, stopping at the first incomplete one.
+/// Returns the length of the complete records.
+fn replay(data: &[u8],
+          entries: &mut HashMap<PathBuf, (Fingerprint, IdentityTag)>)
+        -> usize {
+    let mut cursor = io::Cursor::new(data);
+    let mut complete = 0;
+    loop {
+        let len = match cursor.read_u32::<LittleEndian>() {
+            Ok(l)  => l as usize,
+            Err(_) => break
+        };
+        let mut name = vec![0u8; len];
+        if cursor.read_exact(&mut name).is_err() { break; }
+        let print = match Fingerprint::read(&mut cursor) {
+            Ok(p)  => p,
+            Err(_) => break
+        };
+        let mut tag = [0u8; IDENTITY_LEN];
+        if cursor.read_exact(&mut tag).is_err() { break; }
+
+        let path = PathBuf::from(OsString::from_vec(name));
+        entries.insert(path, (print, IdentityTag::from_bytes(tag)));
+        complete = cursor.position() as usize;
+    }
+    complete
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    use journal::{Fingerprint, Journal};
+    use metadata::IdentityTag;
+
+    #[test]
+    fn journal_persists() {
+        let tag = |b: u8| IdentityTag::from_bytes([b; 32]);
+        let dir = env::temp_dir().join("bkp-journal-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::File::create(&file).unwrap().write_all(b"contents").unwrap();
+        let print = Fingerprint::new(&fs::metadata(&file).unwrap());
+        let path = dir.join("journal");
+
+        {
+            let mut j = Journal::open(&path, &tag(1)).unwrap();
+            assert_eq!(j.get(&file, &print), None);
+            j.insert(&file, &print, &tag(2)).unwrap();
+            j.insert(Path::new("/other"), &print, &tag(3)).unwrap();
+        }
+
+        // records survive a trailing partial one, and new ones can follow
+        fs::OpenOptions::new().append(true).open(&path).unwrap()
+            .write_all(&[9, 0, 0]).unwrap();
+        {
+            let mut j = Journal::open(&path, &tag(1)).unwrap();
+            assert_eq!(j.len(), 2);
+            j.insert(Path::new("/third"), &print, &tag(5)).unwrap();
+        }
+        let j = Journal::open(&path, &tag(1)).unwrap();
+        assert_eq!(j.len(), 3);
+        assert_eq!(j.get(&file, &print), Some(tag(2)));
+        assert_eq!(j.get(Path::new("/third"), &print), Some(tag(5)));
+
+        // changing the file invalidates its record
+        fs::File::create(&file).unwrap().write_all(b"new contents").unwrap();
+        let changed = Fingerprint::new(&fs::metadata(&file).unwrap());
+        assert_eq!(j.get(&file, &changed), None);
+
+        // a journal for something else is started over
+        let j = Journal::open(&path, &tag(4)).unwrap();
+        assert_eq!(j.len(), 0);
+        j.remove().unwrap();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,16 +14,47 @@ use std::fs;
 use std::error;
 use std::fmt;
 use std::cell;
+use std::process;
+use std::rc::Rc;
 
 const SALT_LENGTH: usize = 256;
-const PBKDF2_ITERATIONS: u32 = 100000;
 const AEAD_KEY_LENGTH: usize = 32; // 256 bits
 static DIGEST_ALG: &'static ring::digest::Algorithm = &ring::digest::SHA256;
 
+/// PBKDF2 iteration count for new keystores. Keystores using fewer are
+/// upgraded to this the next time their password is entered, by wrapping their
+/// existing master key under a key derived with it.
+const PBKDF2_ITERATIONS: u32 = 600000;
+
+/// PBKDF2 iteration count used by keystores without a parameters file
+const LEGACY_PBKDF2_ITERATIONS: u32 = 100000;
+
+const KDF_PARAMS_VERSION: u16 = 1;
+
+/// Identifier of `DIGEST_ALG` in the keystore parameters file
+const DIGEST_ID_SHA256: u8 = 1;
+
 const KEY_FMT_VERSION: u16 = 1;
 
+/// Bytes encryption adds to an object: a 12-byte nonce and a 16-byte tag.
+/// Buffers with this much spare capacity are encrypted without reallocating.
+pub const SEAL_OVERHEAD: usize = 12 + 16;
+
+/// Files holding the master key's derivation parameters, which are always
+/// replaced together
+const MASTER_PARAM_FILES: &'static [&'static str] =
+    &["mkey_params", "mkey_salt", "mkey_hash", "mkey_wrapped"];
+
+/// Entries every keystore directory contains, and whether each is a directory
+const KEYSTORE_LAYOUT: &'static [(&'static str, bool)] =
+    &[("data", true), ("metakey", false), ("mkey_salt", false),
+      ("mkey_hash", false)];
+
+/// Magic number at the start of an exported keystore
+const EXPORT_MAGIC: &'static [u8; 8] = b"bkpkeys\0";
+const EXPORT_FMT_VERSION: u16 = 3;
+
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum Error {
     PasswordError,
     InvalidKeystore,
@@ -31,7 +62,11 @@ pub enum Error {
     NotFound,
     WrongFormat,
     IOError(io::Error),
-    Unsupported
+    Unsupported,
+    MissingNodeKey(String),
+
+    /// A file every keystore needs isn't there, or isn't a regular file
+    MissingFile(&'static str)
 }
 
 impl fmt::Display for Error {
@@ -46,7 +81,11 @@ impl fmt::Display for Error {
                 write!(f, "I/O Error: ")?;
                 e.fmt(f)
             },
-            &Error::Unsupported      => write!(f, "Unsupported operation")
+            &Error::Unsupported      => write!(f, "Unsupported operation"),
+            &Error::MissingNodeKey(ref n) =>
+                write!(f, "No metadata key for node '{}' is available", n),
+            &Error::MissingFile(n) =>
+                write!(f, "Keystore is damaged: '{}' is missing", n)
         }
     }
 }
@@ -61,6 +100,8 @@ impl error::Error for Error {
             &Error::WrongFormat      => "Wrong format",
             &Error::IOError(_)       => "I/O error",
             &Error::Unsupported      => "Unsupported operation",
+            &Error::MissingNodeKey(_) => "Missing node metadata key",
+            &Error::MissingFile(_)   => "Missing keystore file",
         }
     }
 }
@@ -73,6 +114,9 @@ impl From<interfaces::InterfacesError> for Error {
     fn from(_: interfaces::InterfacesError) -> Error { Error::Unsupported }
 }
 
+/// Length of the MAC-derived fixed field at the start of each nonce
+const NONCE_FIXED_LEN: usize = 4;
+
 /// Find the lowest-numbered MAC address of a local network interface
 fn find_mac_addr() -> Result<[u8; 6], Error> {
     use self::interfaces::{Interface, InterfacesError, HardwareAddr};
@@ -92,7 +136,8 @@ fn find_mac_addr() -> Result<[u8; 6], Error> {
     }
 }
 
-/// Decrypt some data in place
+/// Decrypt some data in place, returning the same buffer with the nonce and
+/// tag stripped off
 fn decrypt_inplace(key: &[u8; AEAD_KEY_LENGTH],
                    name: &str,
                    mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
@@ -100,19 +145,22 @@ fn decrypt_inplace(key: &[u8; AEAD_KEY_LENGTH],
                                           key).unwrap();
 
     // pull the top 12 bytes of nonce out
-    let (nonce, mut body) = data.split_at_mut(12);
-    if nonce.len() < 12 {
+    if data.len() < 12 {
         return Err(Error::CryptoError);
     }
-
-    let res = ring::aead::open_in_place(&key, &nonce,
-                                        name.as_bytes(),
-                                        0, // no prefix
-                                        &mut body);
-    match res {
-        Err(_) => Err(Error::CryptoError),
-        Ok(pt) => Ok(pt.iter().cloned().collect())
-    }
+    let len = {
+        let (nonce, mut body) = data.split_at_mut(12);
+        let res = ring::aead::open_in_place(&key, &nonce,
+                                            name.as_bytes(),
+                                            0, // no prefix
+                                            &mut body);
+        res.map_err(|_| Error::CryptoError)?.len()
+    };
+
+    // the plaintext is left right after the nonce
+    data.truncate(12 + len);
+    data.drain(..12);
+    Ok(data)
 }
 
 // Note on Nonce Generation for ChaCha20-Poly1305:
@@ -122,13 +170,19 @@ fn decrypt_inplace(key: &[u8; AEAD_KEY_LENGTH],
 // which are used on multiple systems at once, the 96-bit nonce is constructed
 // via the following method:
 //
-// [48-bit MAC address] [48-bit random value]
+// [32-bit MAC suffix] [64-bit random value]
 //
 // Using the terminology from NIST Special Publication 800-38D, section 8, the
-// 48-bit MAC address field is the "fixed field" of the deterministic
-// construction algorithm. The 48-bit random value forms the invocation field.
-// If the system has multiple NICs (aside from the loopback interface), the
-// lowest nonzero MAC is used.
+// low 32 bits of the MAC address are the "fixed field" and the random value is
+// the invocation field. If the system has multiple NICs (aside from the
+// loopback interface), the lowest nonzero MAC is used.
+//
+// A single node can encrypt billions of blocks under one key, so the birthday
+// bound on the random field is what limits safety. With 48 random bits, reuse
+// becomes plausible after about 2^24 blocks; with 64 it takes around 2^32. The
+// fixed field only has to tell apart the handful of nodes sharing a key, which
+// 32 bits does comfortably, so the remaining bits go to the random field. The
+// low half of the MAC is used since the high half is the vendor prefix.
 //
 // This algorithm explicitly *does not* require that the nonces are secret, so
 // they are prepended to the message after encryption.
@@ -137,37 +191,38 @@ fn gen_nonce() -> Result<[u8; 12], Error> {
     let mac: [u8; 6] = find_mac_addr()?;
     let mut nonce: [u8; 12] = [0u8; 12];
     SystemRandom::new().fill(&mut nonce).map_err(|_| Error::CryptoError)?;
-    for i in 0..6 { nonce[i] = mac[i]; }
+    nonce[..NONCE_FIXED_LEN].copy_from_slice(&mac[6 - NONCE_FIXED_LEN..]);
 
     Ok(nonce)
 }
 
-/// Encrypt the data block in place
+/// Encrypt the data block in place. The nonce is inserted before the data
+/// and the tag appended after it, so the buffer only has to grow if it doesn't
+/// have `SEAL_OVERHEAD` bytes of spare capacity.
 fn encrypt_inplace(key: &[u8; AEAD_KEY_LENGTH],
                    name: &str,
                    mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
     let nonce = gen_nonce()?;
-
-    // insert the nonce at the beginning of the output
     let tag_len = ring::aead::CHACHA20_POLY1305.tag_len();
-    let mut out = Vec::new();
-    out.extend_from_slice(&nonce);
+    debug_assert_eq!(12 + tag_len, SEAL_OVERHEAD);
 
-    // place the data at the start of the encryption buffer
-    let tgt_len = 12 + data.len() + tag_len;
-    out.append(&mut data); // load the data to encrypt
-    out.resize(tgt_len, 0);
+    // insert the nonce at the beginning of the output, and leave room for the
+    // tag at the end
+    data.reserve(SEAL_OVERHEAD);
+    data.splice(..0, nonce.iter().cloned());
+    let tgt_len = data.len() + tag_len;
+    data.resize(tgt_len, 0);
 
     // build the key and encode the data
     let key = ring::aead::SealingKey::new(&ring::aead::CHACHA20_POLY1305,
                                           key).unwrap();
     let res = ring::aead::seal_in_place(&key, &nonce,
                                         name.as_bytes(),
-                                        &mut out[12..], tag_len);
+                                        &mut data[12..], tag_len);
     match res {
         Ok(sz) => {
-            out.resize(12+sz, 0);
-            Ok(out)
+            data.truncate(12+sz);
+            Ok(data)
         },
         Err(_) => Err(Error::CryptoError)
     }
@@ -213,7 +268,6 @@ impl MetaKey {
         Ok(())
     }
 
-    #[allow(dead_code)]
     /// Read a securely-encoded key from a target stream and verify it
     pub fn read<R: ReadBytesExt>(ks: &Keystore,
                                  s: &mut R) -> Result<MetaKey, Error> {
@@ -305,6 +359,27 @@ fn test_encryption() {
     let new = dkey.encrypt(buf).unwrap();
     let dec = dkey.decrypt(new).unwrap();
     assert_eq!(dec, orig);
+
+    // truncated objects are rejected rather than panicking
+    assert!(dkey.decrypt(vec![0; 5]).is_err());
+}
+
+#[test]
+fn test_encryption_reuses_buffer() {
+    let dkey = DataKey { data: [7u8; AEAD_KEY_LENGTH] };
+
+    // a large block with room for the overhead is never copied, so it's only
+    // ever held in memory once
+    let mut buf = Vec::with_capacity((16 << 20) + SEAL_OVERHEAD);
+    buf.resize(16 << 20, 0x5a);
+    let addr = buf.as_ptr();
+    let enc = dkey.encrypt(buf).unwrap();
+    assert_eq!(enc.as_ptr(), addr);
+    assert_eq!(enc.len(), (16 << 20) + SEAL_OVERHEAD);
+    let dec = dkey.decrypt(enc).unwrap();
+    assert_eq!(dec.as_ptr(), addr);
+    assert_eq!(dec.len(), 16 << 20);
+    assert!(dec.iter().all(|&b| b == 0x5a));
 }
 
 type MasterKey = [u8; ring::digest::SHA256_OUTPUT_LEN];
@@ -314,33 +389,337 @@ pub struct Keystore {
     /// The location of the keystore's location on disk
     loc: PathBuf,
 
-    /// In-memory master key cache to avoid multiple prompting
-    mkey: cell::Cell<Option<MasterKey>>
+    /// In-memory master key cache to avoid multiple prompting. Shared between
+    /// clones, so that each backend doesn't prompt separately.
+    mkey: Rc<cell::Cell<Option<MasterKey>>>,
+
+    /// Where to get the password from when the master key is needed
+    password: PasswordSource
 }
 
-impl Keystore {
-    fn get_master_key(&self) -> Result<MasterKey, Error> {
-        if let Some(r) = self.mkey.get() {
-            return Ok(r);
+/// Where a keystore's password comes from when it has to be unlocked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PasswordSource {
+    /// Ask for it on the terminal
+    Prompt,
+
+    /// Run a shell command and use the first line of its output, so the
+    /// password can come from a password manager or secret store
+    Command(String),
+
+    /// Use the first line of a file, or of standard input if the path is `-`
+    File(PathBuf)
+}
+
+impl PasswordSource {
+    /// Get a password from the source, showing `prompt` if it's interactive
+    pub fn read(&self, prompt: &str) -> Result<String, Error> {
+        let data = match self {
+            &PasswordSource::Prompt =>
+                return Ok(prompt_password_stderr(prompt)?),
+            &PasswordSource::Command(ref cmd) => {
+                let out = process::Command::new("sh").arg("-c").arg(cmd)
+                    .stdin(process::Stdio::null())
+                    .stderr(process::Stdio::inherit())
+                    .output()?;
+                if !out.status.success() {
+                    return Err(Error::IOError(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("password command failed ({})", out.status))));
+                }
+                out.stdout
+            },
+            &PasswordSource::File(ref path) => {
+                let mut data = Vec::new();
+                if path == Path::new("-") {
+                    io::stdin().read_to_end(&mut data)?;
+                } else {
+                    fs::File::open(path)?.read_to_end(&mut data)?;
+                }
+                data
+            }
+        };
+
+        // passwords can contain spaces, so only the line ending is dropped
+        let line = data.split(|&b| b == b'\n').next().unwrap_or(&[]);
+        let line = if line.ends_with(b"\r") { &line[..line.len() - 1] }
+                   else { line };
+        String::from_utf8(line.to_vec()).map_err(|_| Error::PasswordError)
+    }
+}
+
+/// Encrypt data under a master key, authenticating `ad` along with it
+fn seal_master(key: &MasterKey, nonce: &[u8; 12], ad: &[u8],
+               mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let key = ring::aead::SealingKey::new(&ring::aead::CHACHA20_POLY1305,
+                                          key).unwrap();
+    let tag_len = ring::aead::CHACHA20_POLY1305.tag_len();
+    let out_len = data.len() + tag_len;
+    data.resize(out_len, 0);
+    let res = ring::aead::seal_in_place(&key, nonce.as_ref(), ad,
+                                        &mut data, tag_len);
+    match res {
+        Ok(_) => Ok(data),
+        Err(_) => Err(Error::CryptoError)
+    }
+}
+
+/// Decrypt data sealed by `seal_master`, checking that it and `ad` are intact
+fn open_master(key: &MasterKey, nonce: &[u8; 12], ad: &[u8],
+               mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let key = ring::aead::OpeningKey::new(&ring::aead::CHACHA20_POLY1305,
+                                          key).unwrap();
+    let res = ring::aead::open_in_place(&key, nonce.as_ref(), ad,
+                                        0, // no prefix
+                                        &mut data);
+    match res {
+        Ok(pt) => Ok(pt.to_vec()),
+        Err(_) => Err(Error::CryptoError)
+    }
+}
+
+/// Whether a path relative to a keystore's root is one a key is stored at
+fn valid_key_name(name: &str) -> bool {
+    if name == "metakey" {
+        return true;
+    }
+
+    let mut parts = name.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some("data"), Some(n)) | (Some("nodes"), Some(n)) =>
+            !n.is_empty() && !n.contains('/') && n != "." && n != "..",
+        _ => false
+    }
+}
+
+/// Prompt the user for a new password, making them enter it twice
+fn prompt_new_password(prompt: &str) -> Result<String, Error> {
+    let passwd = prompt_password_stderr(prompt)?;
+    let passwd_conf = prompt_password_stderr("Confirm keystore password: ")?;
+    if passwd != passwd_conf {
+        writeln!(io::stderr(), "Error: passwords do not match")?;
+        return Err(Error::PasswordError);
+    }
+    Ok(passwd)
+}
+
+/// How a keystore's master key is derived from its password
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct KdfParams {
+    iterations: u32,
+    salt_len: usize,
+    digest: u8
+}
+
+impl KdfParams {
+    /// The parameters new keystores are created with
+    fn current() -> Self {
+        KdfParams {
+            iterations: PBKDF2_ITERATIONS,
+            salt_len: SALT_LENGTH,
+            digest: DIGEST_ID_SHA256
         }
+    }
 
-        // prompt password
-        let passwd = prompt_password_stderr("Keystore password: ")?;
+    /// The parameters used before they were stored in the keystore
+    fn legacy() -> Self {
+        KdfParams {
+            iterations: LEGACY_PBKDF2_ITERATIONS,
+            ..KdfParams::current()
+        }
+    }
 
-        // get the salt out of the filesystem
-        let mut salt = [0u8; SALT_LENGTH];
-        {
-            let meta_path = self.loc.join("mkey_salt");
-            let mut infile = fs::OpenOptions::new()
-                .read(true)
-                .open(meta_path)?;
-            infile.read_exact(&mut salt)?;
+    /// Read a keystore's parameters. Keystores created before the parameters
+    /// were stored don't have them, and use the legacy ones.
+    fn load(p: &Path) -> Result<Self, Error> {
+        match fs::File::open(p.join("mkey_params")) {
+            Ok(mut f) => KdfParams::read(&mut f),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                Ok(KdfParams::legacy()),
+            Err(e) => Err(e.into())
         }
+    }
 
-        // derive key
+    fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        if r.read_u16::<BigEndian>()? > KDF_PARAMS_VERSION {
+            return Err(Error::WrongFormat);
+        }
+        let params = KdfParams {
+            iterations: r.read_u32::<BigEndian>()?,
+            salt_len: r.read_u16::<BigEndian>()? as usize,
+            digest: r.read_u8()?
+        };
+        if params.iterations == 0 || params.salt_len == 0 {
+            return Err(Error::InvalidKeystore);
+        }
+        params.algorithm()?;
+        Ok(params)
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        w.write_u16::<BigEndian>(KDF_PARAMS_VERSION)?;
+        w.write_u32::<BigEndian>(self.iterations)?;
+        w.write_u16::<BigEndian>(self.salt_len as u16)?;
+        w.write_u8(self.digest)?;
+        Ok(())
+    }
+
+    /// The digest PBKDF2 is run with
+    fn algorithm(&self) -> Result<&'static ring::digest::Algorithm, Error> {
+        match self.digest {
+            DIGEST_ID_SHA256 => Ok(DIGEST_ALG),
+            _                => Err(Error::Unsupported)
+        }
+    }
+
+    /// Whether these parameters are weaker than the ones new keystores get
+    fn is_outdated(&self) -> bool {
+        self.iterations < PBKDF2_ITERATIONS
+    }
+
+    /// Derive a master key from a password and salt
+    fn derive(&self, salt: &[u8], passwd: &str) -> Result<MasterKey, Error> {
+        if salt.len() != self.salt_len {
+            return Err(Error::InvalidKeystore);
+        }
         let mut buf = [0u8; ring::digest::SHA256_OUTPUT_LEN];
-        ring::pbkdf2::derive(DIGEST_ALG, PBKDF2_ITERATIONS, &salt,
+        ring::pbkdf2::derive(self.algorithm()?, self.iterations, salt,
                              passwd.as_bytes(), &mut buf);
+        Ok(buf)
+    }
+}
+
+/// Generate a fresh salt for deriving a master key
+fn gen_salt(params: &KdfParams) -> Result<Vec<u8>, Error> {
+    let mut salt = vec![0u8; params.salt_len];
+    SystemRandom::new().fill(&mut salt).map_err(|_| Error::CryptoError)?;
+    Ok(salt)
+}
+
+/// Write the derivation parameters, salt and hash for a freshly-derived key
+/// into a keystore.
+///
+/// `key` is the key derived from the password. It's the master key itself
+/// unless `wrapped` holds the master key encrypted under it, as written by
+/// `wrap_master`.
+///
+/// Each file is written to a temporary name and then renamed over the original,
+/// so an interrupted write never leaves a truncated file behind.
+fn write_master_params(p: &Path, params: &KdfParams, salt: &[u8],
+                       key: &MasterKey, wrapped: Option<&[u8]>)
+        -> Result<(), Error> {
+    let hash = ring::digest::digest(&ring::digest::SHA256, key);
+    let mut encoded = Vec::new();
+    params.write(&mut encoded)?;
+    let mut files: Vec<(&str, &[u8])> = vec![("mkey_params", &encoded[..]),
+                                             ("mkey_salt", salt),
+                                             ("mkey_hash", hash.as_ref())];
+    if let Some(w) = wrapped {
+        files.push(("mkey_wrapped", w));
+    }
+
+    for &(name, data) in files.iter() {
+        let mut outf = fs::File::create(&staging_path(&p.join(name)))?;
+        outf.write_all(data)?;
+        outf.sync_all()?;
+    }
+
+    // the files only work as a set, so they're swapped in by listing them in
+    // a commit file. once that's in place, an interrupted swap is finished the
+    // next time the keystore is opened
+    let names = files.iter().map(|f| f.0).collect::<Vec<_>>().join("\n");
+    let commit = p.join("mkey_commit");
+    {
+        let mut outf = fs::File::create(&staging_path(&commit))?;
+        outf.write_all(names.as_bytes())?;
+        outf.sync_all()?;
+    }
+    fs::rename(staging_path(&commit), &commit)?;
+    finish_master_params(p)
+}
+
+/// Move a committed set of master key parameters written by
+/// `write_master_params` into place, if there is one
+fn finish_master_params(p: &Path) -> Result<(), Error> {
+    let commit = p.join("mkey_commit");
+    let mut names = String::new();
+    match fs::File::open(&commit) {
+        Ok(mut f) => { f.read_to_string(&mut names)?; },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into())
+    }
+
+    // anything already moved was done before an interruption
+    let names = names.lines()
+        .filter(|n| MASTER_PARAM_FILES.iter().any(|m| m == n))
+        .collect::<Vec<_>>();
+    for name in names.iter() {
+        let staged = staging_path(&p.join(name));
+        if staged.exists() {
+            fs::rename(staged, p.join(name))?;
+        }
+    }
+
+    // without a wrapped key, the password derives the master key directly
+    if !names.contains(&"mkey_wrapped") {
+        match fs::remove_file(p.join("mkey_wrapped")) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            r => r?
+        }
+    }
+
+    fs::remove_file(&commit)?;
+    Ok(())
+}
+
+/// Encrypt a master key under a key derived from the password
+fn wrap_master(kek: &MasterKey, mkey: &MasterKey) -> Result<Vec<u8>, Error> {
+    let nonce = gen_nonce()?;
+    let mut out = Vec::new();
+    out.write_u16::<BigEndian>(KEY_FMT_VERSION)?;
+    out.write_all(&nonce)?;
+    out.write_all(&seal_master(kek, &nonce, &[], mkey.to_vec())?)?;
+    Ok(out)
+}
+
+/// Decrypt a master key written by `wrap_master`
+fn unwrap_master(kek: &MasterKey, data: &[u8]) -> Result<MasterKey, Error> {
+    let mut s = io::Cursor::new(data);
+    if s.read_u16::<BigEndian>()? > KEY_FMT_VERSION {
+        return Err(Error::WrongFormat);
+    }
+    let mut nonce = [0u8; 12];
+    s.read_exact(&mut nonce)?;
+    let sealed = data[s.position() as usize..].to_vec();
+
+    let key = open_master(kek, &nonce, &[], sealed)?;
+    if key.len() != ring::digest::SHA256_OUTPUT_LEN {
+        return Err(Error::InvalidKeystore);
+    }
+    let mut mkey = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    mkey.copy_from_slice(&key);
+    Ok(mkey)
+}
+
+/// The temporary path a replacement for `path` is written to before it's
+/// renamed into place
+fn staging_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".new");
+    PathBuf::from(name)
+}
+
+impl Keystore {
+    /// Derive the master key from a password, and verify it against the stored
+    /// key hash. If the keystore has been upgraded, the derived key is the one
+    /// its master key is wrapped under.
+    fn derive_master_key(&self, passwd: &str) -> Result<MasterKey, Error> {
+        // get the derivation parameters and salt out of the filesystem
+        let params = KdfParams::load(&self.loc)?;
+        let salt = self.read_salt()?;
+
+        // derive key
+        let buf = params.derive(&salt, passwd)?;
 
         // read and verify the key hash
         let hash = ring::digest::digest(&ring::digest::SHA256, &buf);
@@ -357,53 +736,105 @@ impl Keystore {
             }
         }
 
-        // store the key
-        self.mkey.replace(Some(buf.clone()));
+        match self.read_wrapped()? {
+            Some(w) => unwrap_master(&buf, &w),
+            None    => Ok(buf)
+        }
+    }
+
+    /// Read the wrapped master key, if the keystore has one
+    fn read_wrapped(&self) -> Result<Option<Vec<u8>>, Error> {
+        let mut data = Vec::new();
+        match fs::File::open(self.loc.join("mkey_wrapped")) {
+            Ok(mut f) => { f.read_to_end(&mut data)?; Ok(Some(data)) },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn get_master_key(&self) -> Result<MasterKey, Error> {
+        if let Some(r) = self.mkey.get() {
+            return Ok(r);
+        }
 
-        return Ok(buf)
+        // only asked for once, since the key is cached from then on
+        let passwd = self.password.read("Keystore password: ")?;
+        self.unlock(&passwd)
+    }
+
+    /// Derive and cache the master key from the keystore password.
+    ///
+    /// If the keystore's key derivation is weaker than what new keystores get,
+    /// it's upgraded while the password is at hand. Failing to upgrade doesn't
+    /// stop the keystore from being used, so that's only reported.
+    fn unlock(&self, passwd: &str) -> Result<MasterKey, Error> {
+        let buf = self.derive_master_key(passwd)?;
+        self.mkey.replace(Some(buf));
+
+        if KdfParams::load(&self.loc)?.is_outdated() {
+            if let Err(e) = self.wrap_with_password(passwd, &buf) {
+                warn!("bkp: warning: failed to upgrade keystore: {}", e);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Read the salt the master key is derived with
+    fn read_salt(&self) -> Result<Vec<u8>, Error> {
+        let mut salt = Vec::new();
+        fs::File::open(self.loc.join("mkey_salt"))?.read_to_end(&mut salt)?;
+        Ok(salt)
+    }
+
+    /// Protect the master key under a password, with the current key
+    /// derivation parameters.
+    ///
+    /// The master key itself never changes, since copies of the keys encrypted
+    /// under it are kept on remotes for other nodes and re-imported keystores
+    /// to fetch. Instead, it's wrapped under a key derived from the password
+    /// with the current parameters, which is what the password unlocks from
+    /// then on.
+    fn wrap_with_password(&self, passwd: &str, mkey: &MasterKey)
+            -> Result<(), Error> {
+        let params = KdfParams::current();
+        let salt = gen_salt(&params)?;
+        let kek = params.derive(&salt, passwd)?;
+        let wrapped = wrap_master(&kek, mkey)?;
+        write_master_params(&self.loc, &params, &salt, &kek, Some(&wrapped))
     }
 
     /// Create a new local keystore at the given path.
     /// 
     /// Prompt the user for a password to use when encrypting the given keystore
     pub fn create(p: &Path) -> Result<Self, Error> {
-        // create a directory there
+        // create a directory there. Keystores can live anywhere, so make any
+        // missing parents, but never reuse an existing directory
+        if let Some(parent) = p.parent() {
+            fs::create_dir_all(parent)?;
+        }
         fs::create_dir(p)?;
 
         // create subdirectories
         fs::create_dir(p.join("data"))?;
 
         // derive a root key
-        let passwd = prompt_password_stderr("New keystore password: ")?;
-        let passwd_conf = prompt_password_stderr("Confirm keystore password: ")?;
-        if passwd != passwd_conf {
-            writeln!(io::stderr(), "Error: passwords do not match")?;
-            return Err(Error::PasswordError);
-        }
+        let passwd = prompt_new_password("New keystore password: ")?;
 
         // derive a key from the master password
-        let mut buf = [0u8; ring::digest::SHA256_OUTPUT_LEN];
-        let mut salt = [0u8; SALT_LENGTH];
-        SystemRandom::new().fill(&mut salt).map_err(|_| Error::CryptoError)?;
-        ring::pbkdf2::derive(DIGEST_ALG, PBKDF2_ITERATIONS, &salt,
-                             passwd.as_bytes(), &mut buf);
+        let params = KdfParams::current();
+        let salt = gen_salt(&params)?;
+        let buf = params.derive(&salt, &passwd)?;
 
-        // write the password salt for rederivation
-        {
-            let meta_path = p.join("mkey_salt");
-            let mut outf = fs::File::create(&meta_path)?;
-            outf.write(&salt)?;
-            outf.sync_all()?;
-        }
+        // write the derivation parameters and password salt for rederivation
+        // and a hash of the key for verification
+        write_master_params(p, &params, &salt, &buf, None)?;
 
-        // write a hash of the password for verification
-        let hash = ring::digest::digest(&ring::digest::SHA256, &buf);
-        {
-            let meta_path = p.join("mkey_hash");
-            let mut outf = fs::File::create(&meta_path)?;
-            outf.write(hash.as_ref())?;
-            outf.sync_all()?;
-        }
+        // we already know the master key, so don't prompt for it again
+        let ks = Keystore {
+            loc: p.to_path_buf(),
+            mkey: Rc::new(cell::Cell::new(Some(buf))),
+            password: PasswordSource::Prompt
+        };
 
         {
             // generate a metadata key
@@ -412,17 +843,11 @@ impl Keystore {
                 .map_err(|_| Error::CryptoError)?;
 
             // store the key on disk
-            let keypath = p.join("metakey");
-            let mut f = fs::File::create(&keypath)?;
-            f.write(&metakey)?;
-            f.sync_all()?;
+            ks.write_local_key(&p.join("metakey"), &metakey)?;
         }
 
         // finish
-        Ok(Keystore {
-            loc: p.to_path_buf(),
-            mkey: cell::Cell::new(None)
-        })
+        Ok(ks)
     }
 
     /// Open the keystore located at a given local path
@@ -433,63 +858,174 @@ impl Keystore {
 
         // verify keystore
         let root_meta = fs::metadata(&cpath)?;
-        let mkhash_meta = fs::metadata(&cpath.join("mkey_hash"))?;
-        let mksalt_meta = fs::metadata(&cpath.join("mkey_salt"))?;
-
         if !root_meta.is_dir() { return Err(Error::InvalidKeystore); }
-        if !mkhash_meta.is_file() { return Err(Error::InvalidKeystore); }
-        if !mksalt_meta.is_file() { return Err(Error::InvalidKeystore); }
+        finish_master_params(&cpath)?;
+
+        // the master key can't be derived or checked without these, so fail
+        // now rather than when it's first needed
+        for &name in ["mkey_salt", "mkey_hash"].iter() {
+            match fs::metadata(cpath.join(name)) {
+                Ok(ref m) if m.is_file() => {},
+                _                        => return Err(Error::MissingFile(name))
+            }
+        }
 
         Ok(Keystore {
             loc: p.to_path_buf(),
-            mkey: cell::Cell::new(None)
+            mkey: Rc::new(cell::Cell::new(None)),
+            password: PasswordSource::Prompt
         })
     }
 
+    /// Check a keystore directory's layout, returning the names of any entries
+    /// it should contain which are missing or of the wrong type
+    pub fn missing_entries(p: &Path) -> Vec<&'static str> {
+        KEYSTORE_LAYOUT.iter()
+            .filter(|&&(name, is_dir)| match fs::metadata(p.join(name)) {
+                Ok(m)  => m.is_dir() != is_dir,
+                Err(_) => true
+            })
+            .map(|&(name, _)| name)
+            .collect()
+    }
+
+    /// Configure where the password comes from when the keystore has to be
+    /// unlocked. By default, it's asked for on the terminal.
+    pub fn set_password_source(&mut self, source: PasswordSource) {
+        self.password = source;
+    }
+
+    /// Create a keystore at the given path with a known master key, without
+    /// prompting for a password
+    #[cfg(test)]
+    pub fn with_master_key(p: &Path, mkey: MasterKey) -> Result<Self, Error> {
+        fs::create_dir_all(p.join("data"))?;
+        let params = KdfParams::current();
+        write_master_params(p, &params, &gen_salt(&params)?, &mkey, None)?;
+
+        let ks = Keystore {
+            loc: p.to_path_buf(),
+            mkey: Rc::new(cell::Cell::new(Some(mkey))),
+            password: PasswordSource::Prompt
+        };
+        let mut metakey = [0u8; AEAD_KEY_LENGTH];
+        SystemRandom::new().fill(&mut metakey).map_err(|_| Error::CryptoError)?;
+        ks.write_local_key(&p.join("metakey"), &metakey)?;
+        Ok(ks)
+    }
+
+    /// Change the keystore's password.
+    ///
+    /// This prompts for the current password, then for the new one. The master
+    /// key stays the same and is wrapped under the new password, so the local
+    /// keys and the copies of them on remotes stay readable without being
+    /// re-encrypted.
+    pub fn change_password(&self) -> Result<(), Error> {
+        let old_passwd = prompt_password_stderr("Current keystore password: ")?;
+
+        // check the current password before asking for a new one
+        self.derive_master_key(&old_passwd)?;
+        let passwd = prompt_new_password("New keystore password: ")?;
+        self.replace_password(&old_passwd, &passwd)
+    }
+
+    /// Switch the keystore from one password to another, without prompting
+    fn replace_password(&self, old: &str, new: &str) -> Result<(), Error> {
+        let mkey = self.derive_master_key(old)?;
+        self.mkey.replace(Some(mkey));
+        self.wrap_with_password(new, &mkey)
+    }
+
     /// Encrypt some data with the master key. This *will* prompt the user to
     /// enter the master password.
     fn encrypt_master(&self,
-                      mut data: Vec<u8>,
+                      data: Vec<u8>,
                       nonce: &[u8; 12]) -> Result<Vec<u8>, Error> {
         let key = self.get_master_key()?;
-
-        // encrypt the data
-        let key = ring::aead::SealingKey::new(&ring::aead::CHACHA20_POLY1305,
-                                              &key).unwrap();
-        let empty = Vec::new();
-        let tag_len = ring::aead::CHACHA20_POLY1305.tag_len();
-        let out_len = data.len() + tag_len;
-        data.resize(out_len, 0);
-        let res = ring::aead::seal_in_place(&key, nonce.as_ref(),
-                                            &empty, // no additional data
-                                            &mut data, tag_len);
-        match res {
-            Ok(_) => Ok(data),
-            Err(_) => Err(Error::CryptoError)
-        }
+        seal_master(&key, nonce, &[], data)
     }
 
     /// Decrypt some data with the master key. This *will* prompt the user to
     /// enter the master password.
     fn decrypt_master(&self,
-                      mut data: Vec<u8>,
+                      data: Vec<u8>,
                       nonce: &[u8; 12]) -> Result<Vec<u8>, Error> {
         let key = self.get_master_key()?;
+        open_master(&key, nonce, &[], data)
+    }
+
+    /// Write a key to a local file, encrypted under the master key
+    fn write_local_key(&self, path: &Path, key: &[u8; AEAD_KEY_LENGTH])
+            -> Result<(), Error> {
+        let nonce = gen_nonce()?;
+        let enc = self.encrypt_master(key.to_vec(), &nonce)?;
+
+        let mut f = fs::File::create(path)?;
+        f.write_u16::<BigEndian>(KEY_FMT_VERSION)?;
+        f.write_all(&nonce)?;
+        f.write_all(&enc)?;
+        f.sync_all()?;
+        Ok(())
+    }
+
+    /// Read a key from a local file.
+    ///
+    /// Keystores created by older versions hold their keys as raw, unencrypted
+    /// bytes. Those are accepted and rewritten in encrypted form.
+    fn read_local_key(&self, path: &Path)
+            -> Result<[u8; AEAD_KEY_LENGTH], Error> {
+        let content = {
+            let mut buf = Vec::new();
+            let mut f = fs::File::open(path)?;
+            f.read_to_end(&mut buf)?;
+            buf
+        };
+
+        let mut arr = [0u8; AEAD_KEY_LENGTH];
+        if content.len() == AEAD_KEY_LENGTH {
+            // legacy plaintext key; migrate it
+            arr.copy_from_slice(&content);
+            self.write_local_key(path, &arr)?;
+            return Ok(arr);
+        }
+
+        let mut s = io::Cursor::new(content);
+        let vsn = s.read_u16::<BigEndian>()?;
+        if vsn > KEY_FMT_VERSION {
+            return Err(Error::WrongFormat);
+        }
 
-        // encrypt the data
-        let key = ring::aead::SealingKey::new(&ring::aead::CHACHA20_POLY1305,
-                                              &key).unwrap();
-        let empty = Vec::new();
-        let tag_len = ring::aead::CHACHA20_POLY1305.tag_len();
-        let out_len = data.len() + tag_len;
-        data.resize(out_len, 0);
-        let res = ring::aead::seal_in_place(&key, nonce.as_ref(),
-                                            &empty, // no additional data
-                                            &mut data, tag_len);
-        match res {
-            Ok(_) => Ok(data),
-            Err(_) => Err(Error::CryptoError)
+        let mut nonce = [0u8; 12];
+        s.read_exact(&mut nonce)?;
+        let mut crypted = Vec::new();
+        s.read_to_end(&mut crypted)?;
+
+        let key = self.decrypt_master(crypted, &nonce)?;
+        if key.len() != AEAD_KEY_LENGTH {
+            return Err(Error::CryptoError);
+        }
+        arr.copy_from_slice(&key);
+        Ok(arr)
+    }
+
+    /// Paths of all keys stored in this keystore
+    fn local_key_paths(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut result = vec![self.loc.join("metakey")];
+        for dir in ["data", "nodes"].iter() {
+            let dir = self.loc.join(dir);
+            if !dir.exists() { continue; }
+
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                // skip replacements left over from an interrupted update
+                let staged = entry.path().extension()
+                                  .map_or(false, |e| e == "new");
+                if entry.file_type()?.is_file() && !staged {
+                    result.push(entry.path());
+                }
+            }
         }
+        Ok(result)
     }
 
     /// Create a new data block key
@@ -498,13 +1034,8 @@ impl Keystore {
         SystemRandom::new().fill(&mut key).map_err(|_| Error::CryptoError)?;
 
         // store the key on disk
-        let data_loc = self.loc.join("data");
-        {
-            let keypath = data_loc.join(remote);
-            let mut f = fs::File::create(&keypath)?;
-            f.write(&key)?;
-            f.sync_all()?;
-        }
+        let keypath = self.loc.join("data").join(remote);
+        self.write_local_key(&keypath, &key)?;
 
         Ok(DataKey { data: key })
     }
@@ -512,22 +1043,34 @@ impl Keystore {
     /// Get the local metadata key
     pub fn get_meta_key(&self) -> Result<MetaKey, Error> {
         let keypath = self.loc.join("metakey");
+        Ok(MetaKey { data: self.read_local_key(&keypath)? })
+    }
 
-        let content = {
-            let mut buf = Vec::new();
-            let mut f = fs::File::open(keypath)?;
-            f.read_to_end(&mut buf)?;
-            buf
-        };
-
-        // try to parse the key
-        if content.len() != AEAD_KEY_LENGTH {
-            Err(Error::CryptoError)
-        } else {
-            let mut arr = [0u8; AEAD_KEY_LENGTH];
-            for i in 0..AEAD_KEY_LENGTH { arr[i] = content[i]; }
-            Ok(MetaKey { data: arr })
+    /// Get the metadata key of another node, if it's been stored locally
+    pub fn get_node_meta_key(&self, node: &str) -> Result<MetaKey, Error> {
+        let keypath = self.loc.join("nodes").join(node);
+        if !keypath.exists() {
+            return Err(Error::MissingNodeKey(node.to_owned()));
         }
+        Ok(MetaKey { data: self.read_local_key(&keypath)? })
+    }
+
+    /// Decode another node's metadata key, as stored on a remote, and store it
+    /// locally.
+    ///
+    /// Remotes keep each node's key encrypted under that node's master key, so
+    /// this only succeeds if the other node uses the same master password, salt
+    /// and derivation parameters as this keystore.
+    pub fn store_node_meta_key<R: ReadBytesExt>(&self, node: &str, s: &mut R)
+            -> Result<MetaKey, Error> {
+        let key = MetaKey::read(&self, s).map_err(|e| match e {
+            Error::CryptoError => Error::MissingNodeKey(node.to_owned()),
+            e                  => e
+        })?;
+
+        fs::create_dir_all(self.loc.join("nodes"))?;
+        self.write_local_key(&self.loc.join("nodes").join(node), &key.data)?;
+        Ok(key)
     }
 
     /// Decode and store a data key locally
@@ -537,36 +1080,536 @@ impl Keystore {
         let key = DataKey::read(&self, &mut s)?;
 
         // store it locally
-        let data_loc = self.loc.join("data");
-        {
-            let keypath = data_loc.join(remote);
-            let mut f = fs::File::create(&keypath)?;
-            f.write(&key.data)?;
-            f.sync_all()?;
-        }
+        let keypath = self.loc.join("data").join(remote);
+        self.write_local_key(&keypath, &key.data)?;
 
         Ok(key)
     }
 
     /// Read a given data block key
     pub fn get_data_key(&self, remote: &str) -> Result<DataKey, Error> {
-        let data_loc = self.loc.join("data");
-        let keypath = data_loc.join(remote);
+        let keypath = self.loc.join("data").join(remote);
+        Ok(DataKey { data: self.read_local_key(&keypath)? })
+    }
 
-        let content = {
-            let mut buf = Vec::new();
-            let mut f = fs::File::open(keypath)?;
-            f.read_to_end(&mut buf)?;
-            buf
+    /// Write a copy of every key in the keystore to `w`, for safekeeping.
+    ///
+    /// The copy starts with a header holding the master key's derivation
+    /// parameters, salt and hash, and the wrapped master key of an upgraded
+    /// keystore, followed by the keys, encrypted under the master key. The
+    /// header is
+    /// authenticated along with the keys, so the copy is only usable with the
+    /// keystore password and can't be altered undetected. Importing it restores
+    /// the same master key, so keys stored on remotes stay readable.
+    pub fn export(&self, w: &mut Write) -> Result<(), Error> {
+        let mkey = self.get_master_key()?;
+        let params = KdfParams::load(&self.loc)?;
+        let salt = self.read_salt()?;
+        let wrapped = self.read_wrapped()?.unwrap_or_default();
+        let mut hash = Vec::new();
+        fs::File::open(self.loc.join("mkey_hash"))?.read_to_end(&mut hash)?;
+
+        let mut header = Vec::new();
+        header.write_all(EXPORT_MAGIC)?;
+        header.write_u16::<BigEndian>(EXPORT_FMT_VERSION)?;
+        params.write(&mut header)?;
+        header.write_all(&salt)?;
+        header.write_all(&hash)?;
+        header.write_u16::<BigEndian>(wrapped.len() as u16)?;
+        header.write_all(&wrapped)?;
+
+        // each key is stored under its path within the keystore
+        let paths = self.local_key_paths()?;
+        let mut table = Vec::new();
+        table.write_u32::<BigEndian>(paths.len() as u32)?;
+        for path in paths {
+            let key = self.read_local_key(&path)?;
+            let name = path.strip_prefix(&self.loc).ok()
+                           .and_then(|n| n.to_str())
+                           .ok_or(Error::InvalidKeystore)?;
+            table.write_u16::<BigEndian>(name.len() as u16)?;
+            table.write_all(name.as_bytes())?;
+            table.write_all(&key)?;
+        }
+
+        let nonce = gen_nonce()?;
+        let sealed = seal_master(&mkey, &nonce, &header, table)?;
+        w.write_all(&header)?;
+        w.write_all(&nonce)?;
+        w.write_all(&sealed)?;
+        Ok(())
+    }
+
+    /// Create a new keystore at `p` from a copy written by `export`, prompting
+    /// for the password it was exported under
+    pub fn import<R: Read>(p: &Path, r: &mut R) -> Result<Keystore, Error> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        if !data.starts_with(EXPORT_MAGIC) {
+            return Err(Error::WrongFormat);
+        }
+
+        let passwd = prompt_password_stderr("Keystore password: ")?;
+        Keystore::import_with_password(p, &data, &passwd)
+    }
+
+    fn import_with_password(p: &Path, data: &[u8], passwd: &str)
+            -> Result<Keystore, Error> {
+        if !data.starts_with(EXPORT_MAGIC) {
+            return Err(Error::WrongFormat);
+        }
+
+        // copies from before the parameters were stored use the legacy ones
+        let mut s = io::Cursor::new(data);
+        s.set_position(EXPORT_MAGIC.len() as u64);
+        let version = s.read_u16::<BigEndian>()?;
+        let params = match version {
+            1                            => KdfParams::legacy(),
+            v if v <= EXPORT_FMT_VERSION => KdfParams::read(&mut s)?,
+            _                            => return Err(Error::WrongFormat)
         };
+        let mut salt = vec![0u8; params.salt_len];
+        s.read_exact(&mut salt)?;
+        let mut hash = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+        s.read_exact(&mut hash)?;
+
+        // only upgraded keystores have a wrapped master key
+        let mut wrapped = Vec::new();
+        if version >= 3 {
+            wrapped.resize(s.read_u16::<BigEndian>()? as usize, 0);
+            s.read_exact(&mut wrapped)?;
+        }
+
+        let (header, rest) = data.split_at(s.position() as usize);
+        if rest.len() < 12 {
+            return Err(Error::WrongFormat);
+        }
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&rest[..12]);
 
-        // try to parse the key
-        if content.len() != AEAD_KEY_LENGTH {
-            Err(Error::CryptoError)
-        } else {
-            let mut arr = [0u8; AEAD_KEY_LENGTH];
-            for i in 0..AEAD_KEY_LENGTH { arr[i] = content[i]; }
-            Ok(DataKey { data: arr })
+        // check the password before trying to decrypt anything
+        let kek = params.derive(&salt, passwd)?;
+        if ring::digest::digest(DIGEST_ALG, &kek).as_ref() != &hash[..] {
+            return Err(Error::PasswordError);
+        }
+        let (mkey, wrapped) = if wrapped.is_empty() { (kek, None) }
+                              else { (unwrap_master(&kek, &wrapped)?,
+                                      Some(&wrapped[..])) };
+        let table = open_master(&mkey, &nonce, header, rest[12..].to_vec())?;
+
+        let mut s = io::Cursor::new(table);
+        let mut keys = Vec::new();
+        for _ in 0..s.read_u32::<BigEndian>()? {
+            let mut name = vec![0u8; s.read_u16::<BigEndian>()? as usize];
+            s.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|_| Error::WrongFormat)?;
+            if !valid_key_name(&name) {
+                return Err(Error::WrongFormat);
+            }
+
+            let mut key = [0u8; AEAD_KEY_LENGTH];
+            s.read_exact(&mut key)?;
+            keys.push((name, key));
+        }
+        if !keys.iter().any(|k| k.0 == "metakey") {
+            return Err(Error::InvalidKeystore);
         }
+
+        // everything checks out, so build the keystore
+        fs::create_dir(p)?;
+        fs::create_dir(p.join("data"))?;
+        fs::create_dir(p.join("nodes"))?;
+        write_master_params(p, &params, &salt, &kek, wrapped)?;
+        let ks = Keystore {
+            loc: p.to_path_buf(),
+            mkey: Rc::new(cell::Cell::new(Some(mkey))),
+            password: PasswordSource::Prompt
+        };
+        for &(ref name, ref key) in keys.iter() {
+            ks.write_local_key(&p.join(name), key)?;
+        }
+        Ok(ks)
+    }
+}
+
+#[test]
+fn test_nonce_fields() {
+    let mac = match find_mac_addr() {
+        Ok(m)  => m,
+        Err(_) => return // no NICs to derive a fixed field from
+    };
+
+    let a = gen_nonce().unwrap();
+    let b = gen_nonce().unwrap();
+    assert!(a != b);
+    assert_eq!(&a[..NONCE_FIXED_LEN], &mac[6 - NONCE_FIXED_LEN..]);
+    assert_eq!(&b[..NONCE_FIXED_LEN], &mac[6 - NONCE_FIXED_LEN..]);
+}
+
+#[test]
+fn test_master_params() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-keys-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+
+    let params = KdfParams::current();
+    let salt = [3u8; SALT_LENGTH];
+    let mkey = params.derive(&salt, "hunter2").unwrap();
+    write_master_params(&dir, &params, &salt, &mkey, None).unwrap();
+    assert_eq!(KdfParams::load(&dir).unwrap(), params);
+
+    let ks = Keystore::open(&dir).unwrap();
+    assert_eq!(ks.derive_master_key("hunter2").unwrap(), mkey);
+    match ks.derive_master_key("hunter3") {
+        Err(Error::PasswordError) => {},
+        _ => panic!("wrong password accepted")
     }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_missing_entries() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-keystore-layout-test");
+    let _ = fs::remove_dir_all(&dir);
+    Keystore::with_master_key(&dir, [4u8; 32]).unwrap();
+    assert!(Keystore::missing_entries(&dir).is_empty());
+
+    fs::remove_file(dir.join("mkey_salt")).unwrap();
+    match Keystore::open(&dir) {
+        Err(Error::MissingFile("mkey_salt")) => {},
+        other => panic!("unexpected result {:?}", other.map(|_| ()))
+    }
+
+    fs::remove_dir(dir.join("data")).unwrap();
+    fs::File::create(dir.join("data")).unwrap();
+    assert_eq!(Keystore::missing_entries(&dir), vec!["data", "mkey_salt"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_password_command() {
+    let source = PasswordSource::Command(String::from(
+            "printf 'correct horse \\nignored\\n'"));
+    assert_eq!(source.read("unused").unwrap(), "correct horse ");
+    let source = PasswordSource::Command(String::from("printf 'a\\r\\n'"));
+    assert_eq!(source.read("unused").unwrap(), "a");
+    assert!(PasswordSource::Command(String::from("exit 1")).read("unused")
+                                                           .is_err());
+}
+
+#[test]
+fn test_password_source_unlocks() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-password-source-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let params = KdfParams::current();
+    let salt = [5u8; SALT_LENGTH];
+    let mkey = params.derive(&salt, "hunter2").unwrap();
+    write_master_params(&dir, &params, &salt, &mkey, None).unwrap();
+
+    // the command is only run once, since the key is cached after that
+    let count = dir.join("count");
+    let mut ks = Keystore::open(&dir).unwrap();
+    ks.set_password_source(PasswordSource::Command(format!(
+                "echo >> '{}'; echo hunter2", count.display())));
+    assert_eq!(ks.get_master_key().unwrap(), mkey);
+    assert_eq!(ks.clone().get_master_key().unwrap(), mkey);
+    let mut runs = Vec::new();
+    fs::File::open(&count).unwrap().read_to_end(&mut runs).unwrap();
+    assert_eq!(runs, b"\n");
+
+    let mut ks = Keystore::open(&dir).unwrap();
+    ks.set_password_source(PasswordSource::Command(String::from(
+                "echo hunter3")));
+    match ks.get_master_key() {
+        Err(Error::PasswordError) => {},
+        _ => panic!("wrong password accepted")
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_master_roundtrip() {
+    // pre-load the master key so we don't need to prompt for it
+    let mut mkey = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    SystemRandom::new().fill(&mut mkey).unwrap();
+    let ks = Keystore {
+        loc: PathBuf::new(),
+        mkey: Rc::new(cell::Cell::new(Some(mkey))),
+        password: PasswordSource::Prompt
+    };
+
+    let nonce = [7u8; 12];
+    let orig = vec![9,8,7,6,5,4,3,2,1,0];
+    let enc = ks.encrypt_master(orig.clone(), &nonce).unwrap();
+    assert!(enc != orig);
+    let dec = ks.decrypt_master(enc, &nonce).unwrap();
+    assert_eq!(dec, orig);
+}
+
+#[test]
+fn test_local_key_encrypted() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-local-key-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("data")).unwrap();
+
+    let mut mkey = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    SystemRandom::new().fill(&mut mkey).unwrap();
+    let mut ks = Keystore {
+        loc: dir.clone(),
+        mkey: Rc::new(cell::Cell::new(Some(mkey))),
+        password: PasswordSource::Prompt
+    };
+
+    // the raw key shouldn't appear anywhere in the file
+    let key = ks.new_data_key("remote").unwrap();
+    let mut contents = Vec::new();
+    fs::File::open(dir.join("data").join("remote")).unwrap()
+        .read_to_end(&mut contents).unwrap();
+    assert!(!contents.windows(AEAD_KEY_LENGTH).any(|w| w == &key.data[..]));
+    assert_eq!(ks.get_data_key("remote").unwrap().data, key.data);
+
+    // and it shouldn't be readable under another master key
+    let mut other = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    SystemRandom::new().fill(&mut other).unwrap();
+    let other_ks = Keystore {
+        loc: dir.clone(),
+        mkey: Rc::new(cell::Cell::new(Some(other))),
+        password: PasswordSource::Prompt
+    };
+    assert!(other_ks.get_data_key("remote").is_err());
+
+    // legacy plaintext keys are migrated on read
+    fs::File::create(dir.join("data").join("legacy")).unwrap()
+        .write_all(&key.data).unwrap();
+    assert_eq!(ks.get_data_key("legacy").unwrap().data, key.data);
+    let len = fs::metadata(dir.join("data").join("legacy")).unwrap().len();
+    assert!(len != AEAD_KEY_LENGTH as u64);
+    assert_eq!(ks.get_data_key("legacy").unwrap().data, key.data);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_export_import() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-export-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let params = KdfParams::current();
+    let salt = [5u8; SALT_LENGTH];
+    let mkey = params.derive(&salt, "hunter2").unwrap();
+    let orig = dir.join("orig");
+    let mut ks = Keystore::with_master_key(&orig, mkey).unwrap();
+    write_master_params(&orig, &params, &salt, &mkey, None).unwrap();
+    let data_key = ks.new_data_key("remote").unwrap();
+
+    let mut exported = Vec::new();
+    ks.export(&mut exported).unwrap();
+    let meta = ks.get_meta_key().unwrap();
+    assert!(!exported.windows(AEAD_KEY_LENGTH).any(|w| w == &meta.data[..]));
+
+    // the wrong password is caught before anything is written
+    let copy = dir.join("copy");
+    match Keystore::import_with_password(&copy, &exported, "hunter3") {
+        Err(Error::PasswordError) => {},
+        _ => panic!("wrong password accepted")
+    }
+    assert!(!copy.exists());
+
+    // so is any tampering, including with the header
+    for &i in [12, exported.len() - 1].iter() {
+        let mut bad = exported.clone();
+        bad[i] ^= 1;
+        assert!(Keystore::import_with_password(&copy, &bad, "hunter2")
+                .is_err());
+        assert!(!copy.exists());
+    }
+
+    let imported = Keystore::import_with_password(&copy, &exported, "hunter2")
+        .unwrap();
+    assert_eq!(imported.get_meta_key().unwrap().data, meta.data);
+    assert_eq!(imported.get_data_key("remote").unwrap().data, data_key.data);
+    assert_eq!(imported.derive_master_key("hunter2").unwrap(), mkey);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_separate_keystores() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-separate-keystores-test");
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut mkey = [0u8; ring::digest::SHA256_OUTPUT_LEN];
+    SystemRandom::new().fill(&mut mkey).unwrap();
+    let a_path = dir.join("client-a").join("keystore");
+    let b_path = dir.join("elsewhere").join("client-b");
+    let mut a = Keystore::with_master_key(&a_path, mkey).unwrap();
+    let mut b = Keystore::with_master_key(&b_path, mkey).unwrap();
+
+    // the same remote name gets an independent key in each
+    let a_key = a.new_data_key("remote").unwrap();
+    let b_key = b.new_data_key("remote").unwrap();
+    assert!(a_key.data != b_key.data);
+    assert!(a.get_meta_key().unwrap().data != b.get_meta_key().unwrap().data);
+
+    // and reopening each path finds only its own keys
+    let mut a = Keystore::open(&a_path).unwrap();
+    a.mkey.replace(Some(mkey));
+    assert_eq!(a.get_data_key("remote").unwrap().data, a_key.data);
+    a.new_data_key("other-remote").unwrap();
+    let b = Keystore::open(&b_path).unwrap();
+    b.mkey.replace(Some(mkey));
+    assert_eq!(b.get_data_key("remote").unwrap().data, b_key.data);
+    assert!(b.get_data_key("other-remote").is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_kdf_upgrade() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-kdf-upgrade-test");
+    let _ = fs::remove_dir_all(&dir);
+
+    // a keystore from before the derivation parameters were stored
+    let legacy = KdfParams::legacy();
+    let salt = [9u8; SALT_LENGTH];
+    let old_key = legacy.derive(&salt, "hunter2").unwrap();
+    let mut ks = Keystore::with_master_key(&dir, old_key).unwrap();
+    write_master_params(&dir, &legacy, &salt, &old_key, None).unwrap();
+    fs::remove_file(dir.join("mkey_params")).unwrap();
+    let meta = ks.get_meta_key().unwrap();
+    let data = ks.new_data_key("remote").unwrap();
+    let mut remote_copy = Vec::new();
+    data.write(&ks, &mut remote_copy).unwrap();
+
+    let ks = Keystore::open(&dir).unwrap();
+    assert_eq!(KdfParams::load(&dir).unwrap(), legacy);
+    assert_eq!(ks.derive_master_key("hunter2").unwrap(), old_key);
+
+    // entering the password upgrades it, keeping the master key, so copies of
+    // keys on remotes stay readable
+    assert_eq!(ks.unlock("hunter2").unwrap(), old_key);
+    assert_eq!(KdfParams::load(&dir).unwrap(), KdfParams::current());
+    assert!(dir.join("mkey_wrapped").exists());
+    assert_eq!(ks.get_meta_key().unwrap().data, meta.data);
+
+    let ks = Keystore::open(&dir).unwrap();
+    assert_eq!(ks.unlock("hunter2").unwrap(), old_key);
+    assert_eq!(ks.get_data_key("remote").unwrap().data, data.data);
+    assert_eq!(DataKey::read(&ks, &mut io::Cursor::new(&remote_copy))
+                   .unwrap().data, data.data);
+    match ks.derive_master_key("hunter3") {
+        Err(Error::PasswordError) => {},
+        _ => panic!("wrong password accepted")
+    }
+
+    // as do exported copies of the upgraded keystore
+    let mut exported = Vec::new();
+    ks.export(&mut exported).unwrap();
+    let copy = dir.join("copy");
+    let imported = Keystore::import_with_password(&copy, &exported, "hunter2")
+        .unwrap();
+    assert_eq!(imported.derive_master_key("hunter2").unwrap(), old_key);
+    assert_eq!(KdfParams::load(&copy).unwrap(), KdfParams::current());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_change_password() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-change-password-test");
+    let _ = fs::remove_dir_all(&dir);
+
+    let params = KdfParams::current();
+    let salt = [3u8; SALT_LENGTH];
+    let mkey = params.derive(&salt, "hunter2").unwrap();
+    let mut ks = Keystore::with_master_key(&dir, mkey).unwrap();
+    write_master_params(&dir, &params, &salt, &mkey, None).unwrap();
+    let meta = ks.get_meta_key().unwrap();
+    let data = ks.new_data_key("remote").unwrap();
+    let mut remote_copy = Vec::new();
+    data.write(&ks, &mut remote_copy).unwrap();
+
+    let ks = Keystore::open(&dir).unwrap();
+    ks.replace_password("hunter2", "swordfish").unwrap();
+
+    // only the new password works from now on, and it unlocks the same master
+    // key, so local keys and copies fetched from remotes are still readable
+    let ks = Keystore::open(&dir).unwrap();
+    match ks.derive_master_key("hunter2") {
+        Err(Error::PasswordError) => {},
+        _ => panic!("old password accepted")
+    }
+    assert_eq!(ks.unlock("swordfish").unwrap(), mkey);
+    assert_eq!(ks.get_meta_key().unwrap().data, meta.data);
+    let fetched = ks.store_data_key("fetched",
+                                    &mut io::Cursor::new(&remote_copy))
+                    .unwrap();
+    assert_eq!(fetched.data, data.data);
+    assert_eq!(ks.get_data_key("fetched").unwrap().data, data.data);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_interrupted_param_update() {
+    use std::env;
+
+    let dir = env::temp_dir().join("bkp-param-update-test");
+    let _ = fs::remove_dir_all(&dir);
+    let params = KdfParams::current();
+    let old = params.derive(&[1u8; SALT_LENGTH], "hunter2").unwrap();
+    Keystore::with_master_key(&dir, old).unwrap();
+    write_master_params(&dir, &params, &[1u8; SALT_LENGTH], &old, None)
+        .unwrap();
+
+    // build the replacement set elsewhere, and stage it beside the current one
+    let other = dir.join("other");
+    fs::create_dir(&other).unwrap();
+    let kek = params.derive(&[2u8; SALT_LENGTH], "swordfish").unwrap();
+    let wrapped = wrap_master(&kek, &old).unwrap();
+    write_master_params(&other, &params, &[2u8; SALT_LENGTH], &kek,
+                        Some(&wrapped)).unwrap();
+    for name in MASTER_PARAM_FILES.iter() {
+        fs::copy(other.join(name), staging_path(&dir.join(name))).unwrap();
+    }
+
+    // staged files without a commit file are ignored
+    let ks = Keystore::open(&dir).unwrap();
+    assert_eq!(ks.derive_master_key("hunter2").unwrap(), old);
+
+    // but once it's there, the swap is finished on opening, even if it was
+    // interrupted partway through
+    fs::File::create(dir.join("mkey_commit")).unwrap()
+        .write_all(MASTER_PARAM_FILES.join("\n").as_bytes()).unwrap();
+    fs::rename(staging_path(&dir.join("mkey_salt")), dir.join("mkey_salt"))
+        .unwrap();
+    let ks = Keystore::open(&dir).unwrap();
+    assert_eq!(ks.derive_master_key("swordfish").unwrap(), old);
+    assert!(!dir.join("mkey_commit").exists());
+    for name in MASTER_PARAM_FILES.iter() {
+        assert!(!staging_path(&dir.join(name)).exists());
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
 }
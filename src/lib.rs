@@ -0,0 +1,92 @@
+//! Encrypted, deduplicated backups of local files to remote destinations.
+//!
+//! This is the library underneath the `bkp` command-line tool. The main pieces
+//! are:
+//!
+//! - [`Config`](config/struct.Config.html), which describes the destinations
+//!   data can be stored in.
+//! - [`Keystore`](keys/struct.Keystore.html), which holds the keys that stored
+//!   data is encrypted with.
+//! - [`Backend`](remote/trait.Backend.html), the interface to a destination,
+//!   usually built with [`connect_tgt`](remote/fn.connect_tgt.html).
+//! - [`History`](history/struct.History.html), which stores snapshots of local
+//!   paths in a backend and reads them back as
+//!   [`MetaObject`](metadata/enum.MetaObject.html) trees.
+//!
+//! # Example
+//!
+//! Store a directory in a configured destination, then restore one of the files
+//! in it somewhere else:
+//!
+//! ```no_run
+//! extern crate bkp;
+//!
+//! use std::error::Error;
+//! use std::path::Path;
+//!
+//! use bkp::{Config, History, Keystore, Restorable, RestoreOptions};
+//! use bkp::remote::connect_tgt;
+//!
+//! fn run() -> Result<(), Box<Error>> {
+//!     let cfg = Config::load(Path::new("/home/me/.bkprc"))
+//!         .map_err(|_| "cannot load the configuration")?;
+//!     let data_dir = Path::new("/home/me/.bkp");
+//!     let ks = Keystore::open(&data_dir.join("keystore"))?;
+//!
+//!     let target = cfg.find_target("offsite").ok_or("no such destination")?;
+//!     let mut backend = connect_tgt(target, &cfg.node_name, &ks, data_dir)?;
+//!     let mut history = History::new(&mut backend)?;
+//!
+//!     // take a snapshot
+//!     let root = history.update_paths(vec!["/home/me/documents"])?;
+//!     history.new_snapshot(root)?;
+//!
+//!     // and restore a file from it
+//!     let snap = history.get_snapshot()?.ok_or("no snapshot was stored")?;
+//!     let file = snap.get("/home/me/documents/notes.txt")?
+//!                    .ok_or("the file wasn't stored")?;
+//!     file.restore("/tmp/restored", &RestoreOptions::new())?;
+//!     Ok(())
+//! }
+//!
+//! fn main() {
+//!     run().unwrap();
+//! }
+//! ```
+//!
+//! Anything not re-exported here, or reachable through the public modules, is
+//! an implementation detail of the command-line tool and may change at any
+//! time.
+
+// required for pest
+#![recursion_limit="128"]
+
+#[doc(hidden)]
+#[macro_use]
+pub mod log;
+pub mod config;
+pub mod keys;
+pub mod metadata;
+pub mod remote;
+#[doc(hidden)]
+pub mod util;
+pub mod history;
+mod journal;
+mod metacache;
+mod chunking;
+pub mod compression;
+pub mod progress;
+pub mod exclude;
+
+extern crate ring;
+extern crate untrusted;
+
+#[macro_use]
+extern crate pest;
+extern crate url;
+
+pub use config::Config;
+pub use history::{History, Restorable, RestoreOptions};
+pub use keys::Keystore;
+pub use metadata::MetaObject;
+pub use remote::{Backend, BlockStore, MetadataStore};
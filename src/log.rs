@@ -0,0 +1,85 @@
+//! Terminal output which respects the global `--verbose` and `--quiet` flags.
+//!
+//! Informational messages go to stdout and are hidden by `--quiet`, and extra
+//! detail is only shown with `--verbose`. Warnings and errors always go to
+//! stderr, whatever the flags say.
+//!
+//! The macros are private to the library, so that their names can't clash
+//! with other crates' logging macros. The command-line tool has its own.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// How much output to show. Levels are ordered from least to most output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose
+}
+
+// stored so that the zero it starts out as means `Normal`
+static LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set how much output to show for the rest of the run
+pub fn set_level(level: Level) {
+    let v = match level {
+        Level::Normal  => 0,
+        Level::Quiet   => 1,
+        Level::Verbose => 2
+    };
+    LEVEL.store(v, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Quiet,
+        2 => Level::Verbose,
+        _ => Level::Normal
+    }
+}
+
+/// Whether messages meant for the given level should be shown
+pub fn enabled(level: Level) -> bool {
+    self::level() >= level
+}
+
+/// Print an informational message to stdout, unless `--quiet` was given
+macro_rules! info {
+    ($($arg: tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Normal) {
+            println!($($arg)*);
+        }
+    }
+}
+
+/// Print a detailed message to stdout, only if `--verbose` was given
+macro_rules! verbose {
+    ($($arg: tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Verbose) {
+            println!($($arg)*);
+        }
+    }
+}
+
+/// Print a warning to stderr. These are shown even with `--quiet`, since they
+/// point at something which may need looking into.
+macro_rules! warn {
+    ($($arg: tt)*) => { eprintln!($($arg)*) }
+}
+
+/// Print an error to stderr
+macro_rules! error {
+    ($($arg: tt)*) => { eprintln!($($arg)*) }
+}
+
+#[test]
+fn test_levels() {
+    assert_eq!(level(), Level::Normal);
+    assert!(enabled(Level::Normal) && !enabled(Level::Verbose));
+
+    set_level(Level::Quiet);
+    assert!(enabled(Level::Quiet) && !enabled(Level::Normal));
+    set_level(Level::Verbose);
+    assert!(enabled(Level::Normal) && enabled(Level::Verbose));
+    set_level(Level::Normal);
+}
@@ -1,38 +1,53 @@
-// required for pest
+// required for the argument parser's macros
 #![recursion_limit="128"]
 
-mod config;
-mod keys;
-mod metadata;
-mod remote;
-mod util;
-mod history;
-mod chunking;
-
-extern crate ring;
-extern crate untrusted;
-
-#[macro_use]
-extern crate pest;
+extern crate bkp;
 #[macro_use]
 extern crate clap;
 extern crate url;
+#[macro_use]
+extern crate serde_derive;
+
+// the library's output macros are private to it, so the tool has its own
+// which respect the same `--verbose` and `--quiet` flags
+
+/// Print an informational message to stdout, unless `--quiet` was given
+macro_rules! info {
+    ($($arg: tt)*) => {
+        if ::bkp::log::enabled(::bkp::log::Level::Normal) {
+            println!($($arg)*);
+        }
+    }
+}
+
+/// Print a warning to stderr, even with `--quiet`
+macro_rules! warn {
+    ($($arg: tt)*) => { eprintln!($($arg)*) }
+}
+
+/// Print an error to stderr
+macro_rules! error {
+    ($($arg: tt)*) => { eprintln!($($arg)*) }
+}
+
+mod error;
+mod report;
+#[cfg(feature = "mount")]
+mod mount;
+
+use bkp::{config, keys, metadata, remote, util, history, compression,
+          progress, exclude, log};
 
 use url::Url;
 use std::io::Write;
-use std::error::Error;
 use std::fs;
 use std::path::{Path,PathBuf};
+use std::rc::Rc;
 
 use metadata::MetaObject;
 use history::Restorable;
-
-macro_rules! err_write {
-    ($s: tt) => {
-        writeln!(std::io::stderr(), $s).ok().unwrap_or(())};
-    ($s: tt, $($e: expr),*) => {
-        writeln!(std::io::stderr(), $s, $($e,)*).ok().unwrap_or(())}
-}
+use util::ToHex;
+use error::{CliError, OrFail};
 
 #[allow(dead_code)]
 struct GlobalOptions {
@@ -40,234 +55,1852 @@ struct GlobalOptions {
     keystore: keys::Keystore,
     cfg: config::Config,
     verbose: bool,
-    quiet: bool
+    quiet: bool,
+
+    /// Transfer rate limit overriding the targets' own, in bytes per second
+    limit_rate: Option<u64>,
+
+    /// Compression algorithm and level overriding the targets' own
+    compression: Option<compression::Compression>,
+    compression_level: Option<u32>,
+
+    /// Name snapshots are stored under, which may override the configured one
+    node_name: String,
+
+    /// Hex SHA-256 hash of the admin key given on the command line, if any
+    admin_key: Option<String>
+}
+
+fn connect_backend(name: String, opts: &GlobalOptions)
+        -> Result<Box<remote::Backend>, remote::BackendError> {
+    if let Some(t) = opts.cfg.find_target(&name) {
+        remote::connect_tgt(&override_target(t, opts), &opts.node_name,
+                            &opts.keystore, &opts.data_dir)
+    } else if let Some(g) = opts.cfg.find_group(&name) {
+        let tgts = group_targets(g, opts)?;
+        remote::connect_group(tgts.iter().collect(), &opts.node_name,
+                              &opts.keystore, &opts.data_dir)
+    } else {
+        Err(remote::BackendError::InvalidOption)
+    }
+}
+
+/// Open a target or group for commands which only read from it. Nothing is
+/// set up on targets which haven't been backed up to yet.
+fn open_backend(name: String, opts: &GlobalOptions)
+        -> Result<Box<remote::Backend>, remote::BackendError> {
+    if let Some(t) = opts.cfg.find_target(&name) {
+        remote::connect_tgt_read_only(&override_target(t, opts),
+                                      &opts.node_name, &opts.keystore,
+                                      &opts.data_dir)
+    } else if let Some(g) = opts.cfg.find_group(&name) {
+        let tgts = group_targets(g, opts)?;
+        remote::connect_group_read_only(tgts.iter().collect(),
+                                        &opts.node_name, &opts.keystore,
+                                        &opts.data_dir)
+    } else {
+        Err(remote::BackendError::InvalidOption)
+    }
+}
+
+/// Apply command-line overrides, if any, on top of a target's configuration
+fn override_target(t: &config::BackupTarget, opts: &GlobalOptions)
+        -> config::BackupTarget {
+    let mut t = t.clone();
+    if let Some(rate) = opts.limit_rate {
+        t.options.upload_limit = rate;
+        t.options.download_limit = rate;
+    }
+    if let Some(alg) = opts.compression {
+        // the configured level may not suit a different algorithm
+        t.options.compression = alg;
+        t.options.compression_level = None;
+    }
+    if opts.compression_level.is_some() {
+        t.options.compression_level = opts.compression_level;
+    }
+    if t.options.append_only && t.options.admin_key_hash.is_some() &&
+            t.options.admin_key_hash == opts.admin_key {
+        t.options.append_only = false;
+    }
+    t
+}
+
+/// Make sure none of the targets a destination stands for are append-only,
+/// before starting to remove anything from it.
+///
+/// The admin key's hash is kept in the config file, which anyone who can run
+/// bkp here can edit, so this only guards against mistakes. Protection from a
+/// compromised node has to come from the server.
+fn check_removable(name: &str, opts: &GlobalOptions) -> Result<(), CliError> {
+    let tgts = match opts.cfg.find_target(name) {
+        Some(t) => vec![override_target(t, opts)],
+        None    => match opts.cfg.find_group(name) {
+            Some(g) => group_targets(g, opts)
+                .or_fail("group refers to an unknown destination")?,
+            None    => Vec::new()
+        }
+    };
+    match tgts.iter().find(|t| t.options.append_only) {
+        Some(t) if t.options.admin_key_hash.is_none() =>
+            Err(CliError::Auth(format!(
+                    "Destination '{}' is append-only and has no admin key, so \
+                     nothing can be removed from it until append-only mode is \
+                     turned off in the config file", t.name))),
+        Some(t) if opts.admin_key.is_some() => Err(CliError::Auth(format!(
+                    "The admin key given doesn't unlock append-only \
+                     destination '{}'", t.name))),
+        Some(t) => Err(CliError::Auth(format!(
+                    "Destination '{}' is append-only, so nothing can be \
+                     removed from it without --admin-key", t.name))),
+        None    => Ok(())
+    }
+}
+
+/// Store the hash of the admin key given on the command line for a target
+/// which is append-only and doesn't have an admin key yet
+fn record_admin_key(name: &str, options: &mut config::TargetOptions,
+                    admin_key: &Option<String>) {
+    if !options.append_only || options.admin_key_hash.is_some() { return; }
+    options.admin_key_hash = admin_key.clone();
+    if options.admin_key_hash.is_none() {
+        warn!("bkp: {} has no admin key, so nothing can be removed from it \
+               until it's no longer append-only", name);
+    }
+}
+
+/// Hash the admin key stored in a file
+fn read_admin_key(path: &Path) -> Result<String, CliError> {
+    let mut f = fs::File::open(path).or_fail("Cannot read admin key")?;
+    let digest = {
+        let mut hasher = util::Hasher::sha256(&mut f);
+        std::io::copy(&mut hasher, &mut util::DevNull::new())
+            .or_fail("Cannot read admin key")?;
+        hasher.finish()
+    };
+    Ok(digest.as_ref().to_hex())
+}
+
+/// Bind the names of a group's members to actual targets
+fn group_targets(g: &config::TargetGroup, opts: &GlobalOptions)
+        -> Result<Vec<config::BackupTarget>, remote::BackendError> {
+    g.members.iter()
+        .map(|n| opts.cfg.find_target(n).map(|t| override_target(t, opts))
+                     .ok_or(remote::BackendError::InvalidOption))
+        .collect()
+}
+
+/// Build the progress reporter selected by the global output flags
+fn make_progress(opts: &GlobalOptions) -> Rc<progress::Progress> {
+    if opts.quiet {
+        Rc::new(progress::NoProgress)
+    } else {
+        Rc::new(progress::TerminalProgress::new(opts.verbose))
+    }
+}
+
+/// Apply any target options given on the command line
+fn set_target_options(args: &clap::ArgMatches,
+                      options: &mut config::TargetOptions) {
+    // values were already validated by clap
+    if let Some(x) = args.value_of("reliable") {
+        options.reliable = x == "true";
+    }
+    if let Some(x) = args.value_of("upload_cost") {
+        options.upload_cost = x.parse().unwrap();
+    }
+    if let Some(x) = args.value_of("download_cost") {
+        options.download_cost = x.parse().unwrap();
+    }
+    if let Some(x) = args.value_of("strict_host_keys") {
+        options.strict_host_keys = x == "true";
+    }
+    if let Some(x) = args.value_of("connect_timeout") {
+        options.connect_timeout = x.parse().unwrap();
+    }
+    if let Some(x) = args.value_of("keepalive") {
+        options.keepalive = x.parse().unwrap();
+    }
+    if let Some(x) = args.value_of("upload_limit") {
+        options.upload_limit = util::parse_size(x).unwrap();
+    }
+    if let Some(x) = args.value_of("download_limit") {
+        options.download_limit = util::parse_size(x).unwrap();
+    }
+    if let Some(x) = args.value_of("append_only") {
+        options.append_only = x == "true";
+    }
+}
+
+/// Check that a string is a valid transfer cost
+fn validate_cost(s: String) -> Result<(), String> {
+    s.parse::<i32>().map_err(|e| e.to_string())
+        .and_then(|n| if n >= 0 { Ok(()) }
+                      else { Err(String::from("must not be negative")) })
+}
+
+/// Check that a string is a valid transfer rate
+fn validate_rate(s: String) -> Result<(), String> {
+    util::parse_size(&s).map(|_| ())
+        .ok_or(String::from("Not a valid rate, e.g. 500k or 2M"))
+}
+
+/// Check that a string names a known compression algorithm
+fn validate_compression(s: String) -> Result<(), String> {
+    compression::Compression::from_name(&s).map(|_| ())
+        .ok_or(String::from("Not a known compression algorithm"))
+}
+
+/// Check that a string can be used as a node name
+fn validate_node_name(s: String) -> Result<(), String> {
+    if config::valid_node_name(&s) { Ok(()) }
+    else { Err(String::from("Node names may only contain letters and '-'")) }
+}
+
+/// Check that a string is a valid number of seconds
+fn validate_seconds(s: String) -> Result<(), String> {
+    s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn do_dest(args: &clap::ArgMatches, opts: &mut GlobalOptions)
+        -> Result<(), CliError> {
+    match args.subcommand() {
+        ("add", Some(m)) => { // add a destination
+            let name = m.value_of("name").unwrap();
+            let url = m.value_of("url").unwrap();
+            let user = m.value_of("user");
+            let password = m.value_of("password");
+
+            // make sure the specified destination doesn't already exist
+            if opts.cfg.targets.iter().any(|t| {t.name == name}) {
+                return Err(CliError::Config(
+                        format!("Destination '{}' already exists", name)));
+            }
+
+            // parse the target URL
+            let url = Url::parse(&url).map_err(|e| CliError::Usage(
+                    format!("Cannot parse given URL: {}", e)))?;
+            if !remote::scheme_supported(url.scheme()) {
+                return Err(CliError::Usage(
+                        format!("Unsupported URL scheme '{}' (supported: {})",
+                                url.scheme(), remote::SCHEMES.join(", "))));
+            }
+
+            // build the new target
+            let mut tgt = config::BackupTarget {
+                name: name.to_owned(),
+                url: url,
+                user: user.map(String::from),
+                password: password.map(String::from),
+                key_file: None,
+                agent_identity: None,
+                options: config::TargetOptions::default()
+            };
+            set_target_options(m, &mut tgt.options);
+            record_admin_key(name, &mut tgt.options, &opts.admin_key);
+            opts.cfg.targets.push(tgt);
+            opts.cfg.save().or_fail("Failed to save config file")?;
+        },
+        ("set", Some(m)) => { // change a destination's options
+            let name = m.value_of("name").unwrap();
+            if m.value_of("append_only") == Some("false") {
+                check_removable(name, opts)?;
+            }
+            let admin_key = opts.admin_key.clone();
+            match opts.cfg.find_target_mut(name) {
+                Some(t) => {
+                    set_target_options(m, &mut t.options);
+                    record_admin_key(name, &mut t.options, &admin_key);
+                },
+                None    => return Err(CliError::Config(
+                        format!("Destination '{}' does not exist", name)))
+            }
+            opts.cfg.save().or_fail("Failed to save config file")?;
+        },
+        (s, m) if (s == "list") || s.is_empty() => { // list destinations
+            let show_groups = !m.map_or(false, |m| m.is_present("no_groups"));
+            let max_left_col = opts.cfg.targets.iter()
+                    .map(|ref x| x.name.len())
+                    .chain(opts.cfg.target_groups.iter()
+                           .filter(|_| show_groups)
+                           .map(|ref x| x.name.len()))
+                    .max().unwrap_or(0);
+            for t in opts.cfg.targets.iter() {
+                println!("{1:0$}  {2}", max_left_col, t.name, t.url.as_str());
+            }
+            if show_groups {
+                for g in opts.cfg.target_groups.iter() {
+                    println!("{1:0$}  group: {2}", max_left_col, g.name,
+                             g.members.join(", "));
+                }
+            }
+        },
+        ("group", Some(m)) => do_dest_group(m, opts)?,
+        ("unlock", Some(m)) => { // break a leftover lock
+            let name = m.value_of("name").unwrap();
+            let tgt = match opts.cfg.find_target(name) {
+                Some(t) => t,
+                None    => return Err(CliError::Config(
+                        format!("Destination '{}' does not exist", name)))
+            };
+
+            let holder = remote::unlock_tgt(tgt, &opts.node_name,
+                                            &opts.keystore, &opts.data_dir)
+                .or_fail("Failed to unlock destination")?;
+            match holder {
+                Some(h) => println!("removed lock held by {}", h),
+                None    => println!("{} was not locked", name)
+            }
+        },
+        ("remove", Some(m)) => { // remove destinations
+            unimplemented!()
+        },
+        ("test", Some(m)) => { // test destination connectivity
+            let mut failure: Option<CliError> = None;
+            let stages = opts.verbose || m.is_present("verbose");
+
+            // groups are tested by probing each of their members in turn
+            let mut tgts = Vec::new();
+            for name in m.values_of("name").unwrap() {
+                if let Some(t) = opts.cfg.find_target(name) {
+                    tgts.push((name.to_owned(), Some(override_target(t, opts))));
+                } else if let Some(g) = opts.cfg.find_group(name) {
+                    for member in g.members.iter() {
+                        let t = opts.cfg.find_target(member)
+                                        .map(|t| override_target(t, opts));
+                        tgts.push((format!("{}/{}", name, member), t));
+                    }
+                } else {
+                    tgts.push((name.to_owned(), None));
+                }
+            }
+
+            let max_col = tgts.iter().map(|x| x.0.len()).max().unwrap_or(0);
+            for (name, tgt) in tgts {
+                let tgt = match tgt {
+                    Some(t) => t,
+                    None    => {
+                        let e = CliError::Config(
+                            format!("Destination '{}' does not exist", name));
+                        println!("{1:0$}:   {2}", max_col, name, e);
+                        failure = failure.or(Some(e));
+                        continue;
+                    }
+                };
+
+                let report = remote::probe_tgt(&tgt, &opts.node_name,
+                                               &opts.keystore, &opts.data_dir);
+                match report.failure() {
+                    None    => println!("{1:0$}:   successful", max_col, name),
+                    Some(s) => {
+                        println!("{1:0$}:   {2}", max_col, name, s);
+                        failure = failure.or(Some(probe_error(s)));
+                    }
+                }
+                if stages {
+                    for s in report.stages.iter() {
+                        println!("{1:0$}    {2}", max_col, "", s);
+                    }
+                }
+            }
+
+            // exit with the status of the first failure
+            if let Some(e) = failure {
+                return Err(e.with_message(
+                        String::from("Cannot connect to every destination")));
+            }
+        },
+        (_, _) => panic!("No subcommand handler found")
+    }
+    Ok(())
+}
+
+/// The error a failed stage of connecting to a target exits with
+fn probe_error(stage: &remote::ProbeStage) -> CliError {
+    let msg = stage.to_string();
+    match stage.name {
+        "resolve" | "connect" | "handshake" | "sftp" => CliError::Network(msg),
+        "authenticate" | "keys" => CliError::Auth(msg),
+        _                       => CliError::Failure(msg)
+    }
+}
+
+/// Fail unless every name refers to an existing target
+fn check_targets_exist<'a, I: Iterator<Item=&'a str>>(names: I,
+                                                       opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    for name in names {
+        if opts.cfg.find_target(name).is_none() {
+            return Err(CliError::Config(
+                    format!("Destination '{}' does not exist", name)));
+        }
+    }
+    Ok(())
+}
+
+fn do_dest_group(args: &clap::ArgMatches, opts: &mut GlobalOptions)
+        -> Result<(), CliError> {
+    match args.subcommand() {
+        ("add", Some(m)) => { // create a group
+            let name = m.value_of("name").unwrap();
+            if opts.cfg.find_target(name).is_some() ||
+                    opts.cfg.find_group(name).is_some() {
+                return Err(CliError::Config(
+                        format!("Destination '{}' already exists", name)));
+            }
+            check_targets_exist(m.values_of("member").unwrap(), opts)?;
+
+            let mut members: Vec<String> = Vec::new();
+            for x in m.values_of("member").unwrap() {
+                if !members.iter().any(|y| y == x) {
+                    members.push(x.to_owned());
+                }
+            }
+            opts.cfg.target_groups.push(config::TargetGroup {
+                name: name.to_owned(),
+                members: members
+            });
+        },
+        ("remove", Some(m)) => { // delete a group
+            let name = m.value_of("name").unwrap();
+            if opts.cfg.find_group(name).is_none() {
+                return Err(CliError::Config(
+                        format!("Group '{}' does not exist", name)));
+            }
+            opts.cfg.target_groups.retain(|g| g.name != name);
+        },
+        ("members", Some(m)) => { // change a group's membership
+            let name = m.value_of("name").unwrap();
+            let added: Vec<&str> = m.values_of("add")
+                .map(|x| x.collect()).unwrap_or_default();
+            check_targets_exist(added.iter().cloned(), opts)?;
+
+            let grp = match opts.cfg.find_group_mut(name) {
+                Some(g) => g,
+                None    => return Err(CliError::Config(
+                        format!("Group '{}' does not exist", name)))
+            };
+            for x in added {
+                if !grp.members.iter().any(|y| y == x) {
+                    grp.members.push(x.to_owned());
+                }
+            }
+            if let Some(removed) = m.values_of("remove") {
+                for x in removed {
+                    grp.members.retain(|y| y != x);
+                }
+            }
+
+            if grp.members.is_empty() {
+                return Err(CliError::Usage(
+                        format!("Group '{}' would have no members", name)));
+            }
+        },
+        (_, _) => return Err(CliError::Usage(
+                String::from("No group operation specified")))
+    }
+
+    opts.cfg.save().or_fail("Failed to save config file")?;
+    Ok(())
+}
+
+fn do_keystore(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    match args.subcommand() {
+        ("passwd", Some(_)) => {
+            opts.keystore.change_password()
+                .or_fail("Failed to change keystore password")?;
+            info!("keystore password changed.");
+        },
+        ("export", Some(m)) => {
+            let path = m.value_of("file").unwrap();
+            let mut buf = Vec::new();
+            opts.keystore.export(&mut buf)
+                .or_fail("Failed to export keystore")?;
+            fs::File::create(path).and_then(|mut f| f.write_all(&buf))
+                .or_fail(&format!("Cannot write {}", path))?;
+            info!("keystore exported to {}.", path);
+        },
+        (_, _) => return Err(CliError::Usage(
+                String::from("No keystore operation specified")))
+    }
+    Ok(())
+}
+
+fn do_node(args: &clap::ArgMatches, opts: &mut GlobalOptions)
+        -> Result<(), CliError> {
+    match args.subcommand() {
+        ("rename", Some(m)) => {
+            let old = m.value_of("old").unwrap();
+            let new = m.value_of("new").unwrap();
+            if old == new {
+                return Err(CliError::Usage(
+                        String::from("The old and new names are the same")));
+            }
+
+            rename_node(opts, old, new)?;
+        },
+        (_, _) => return Err(CliError::Usage(
+                String::from("No node operation specified")))
+    }
+    Ok(())
+}
+
+/// Move a node's snapshots on every destination over to a new name, and
+/// update the configured name if it's the one being renamed
+fn rename_node(opts: &mut GlobalOptions, old: &str, new: &str)
+        -> Result<(), CliError> {
+    // check every destination before touching any, so a conflict doesn't
+    // leave the node half-renamed
+    let mut affected = Vec::new();
+    for tgt in opts.cfg.targets.iter() {
+        let fail = format!("Cannot read heads of {}", tgt.name);
+        let heads = match open_backend(tgt.name.clone(), opts) {
+            // nothing is stored there, so there's nothing to rename
+            Err(remote::BackendError::NotInitialized) => continue,
+            r => r.and_then(|b| b.list_heads()).or_fail(&fail)?
+        };
+        if !heads.iter().any(|n| n == old) { continue; }
+        if heads.iter().any(|n| n == new) {
+            return Err(CliError::Usage(format!(
+                "Node '{}' already has snapshots on {}", new,
+                tgt.name)));
+        }
+        check_removable(&tgt.name, opts)?;
+        affected.push(tgt.name.clone());
+    }
+
+    // only the destinations which store the node are written to
+    for name in affected.iter() {
+        let mut backend = connect_backend(name.clone(), opts)
+            .or_fail(&format!("Cannot connect to {}", name))?;
+        backend.rename_node(old, new)
+            .or_fail(&format!("Cannot rename node on {}", name))?;
+        info!("{}: renamed", name);
+    }
+
+    if opts.cfg.node_name == old {
+        opts.cfg.node_name = new.to_owned();
+        opts.cfg.save().or_fail("Cannot save config file")?;
+    }
+    info!("node '{}' renamed to '{}' on {} destination(s).", old, new,
+          affected.len());
+    Ok(())
+}
+
+/// List the destinations which have snapshots stored under each of the given
+/// node names
+fn nodes_stored(opts: &GlobalOptions, names: &[&str])
+        -> Result<Vec<Vec<String>>, CliError> {
+    let mut found = vec![Vec::new(); names.len()];
+    for tgt in opts.cfg.targets.iter() {
+        let fail = format!("Cannot read heads of {}", tgt.name);
+        let heads = open_backend(tgt.name.clone(), opts)
+            .and_then(|b| b.list_heads()).or_fail(&fail)?;
+        for (name, tgts) in names.iter().zip(found.iter_mut()) {
+            if heads.iter().any(|n| n == *name) {
+                tgts.push(tgt.name.clone());
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn do_config(args: &clap::ArgMatches, opts: &mut GlobalOptions)
+        -> Result<(), CliError> {
+    match args.subcommand() {
+        ("node-name", Some(m)) => {
+            let new = match m.value_of("name") {
+                Some(n) => n,
+                None    => {
+                    println!("{}", opts.cfg.node_name);
+                    return Ok(());
+                }
+            };
+            let old = opts.cfg.node_name.clone();
+            if old == new {
+                info!("node name is already '{}'.", new);
+                return Ok(());
+            }
+            if m.is_present("migrate") {
+                return rename_node(opts, &old, new);
+            }
+
+            // taking over a name another machine stores snapshots under would
+            // interleave both machines' snapshots in one chain
+            let found = nodes_stored(opts, &[&old, new])?;
+            if !found[1].is_empty() {
+                warn!("bkp: node '{}' already has snapshots on {}", new,
+                      found[1].join(", "));
+                if !confirm("Continue its snapshot chain from this machine?") {
+                    println!("aborted");
+                    return Ok(());
+                }
+            }
+            if !found[0].is_empty() {
+                warn!("bkp: snapshots stored as '{}' on {} stay under that \
+                       name, and new ones start a separate chain; use \
+                       --migrate to move them instead", old,
+                      found[0].join(", "));
+            }
+
+            opts.cfg.node_name = new.to_owned();
+            opts.cfg.save().or_fail("Cannot save config file")?;
+            info!("node name set to '{}'.", new);
+        },
+        (_, _) => return Err(CliError::Usage(
+                String::from("No config setting specified")))
+    }
+    Ok(())
+}
+
+/// Restore a keystore from an exported copy. This runs before the keystore is
+/// opened, since there won't be one to open.
+fn do_keystore_import(args: &clap::ArgMatches, kspath: &Path)
+        -> Result<(), CliError> {
+    if fs::symlink_metadata(kspath).is_ok() {
+        return Err(CliError::Usage(format!(
+            "A keystore already exists at {}; move it aside before importing",
+            kspath.display())));
+    }
+
+    let path = args.value_of("file").unwrap();
+    let mut f = fs::File::open(path).or_fail(&format!("Cannot open {}", path))?;
+    keys::Keystore::import(kspath, &mut f)
+        .or_fail("Failed to import keystore")?;
+    info!("keystore imported.");
+    Ok(())
+}
+
+/// Check that the keystore is intact and unlocks, and that every configured
+/// destination can be reached. This runs before the keystore is opened, so a
+/// damaged one can be diagnosed rather than just refused.
+fn do_doctor(cfg: &config::Config, node_name: &str, data_dir: &Path,
+             kspath: &Path, password: keys::PasswordSource)
+        -> Result<(), CliError> {
+    if !kspath.is_dir() {
+        return Err(CliError::Auth(format!(
+            "No keystore at {}; one is created the first time bkp runs",
+            kspath.display())));
+    }
+    let missing = keys::Keystore::missing_entries(kspath);
+    if !missing.is_empty() {
+        return Err(CliError::Auth(format!(
+            "Keystore at {} is damaged, missing: {}", kspath.display(),
+            missing.join(", "))));
+    }
+
+    // unlocking checks the password against the stored key hash, and reading
+    // the metadata key checks that the master key decrypts it
+    let mut ks = keys::Keystore::open(kspath)
+        .or_fail("Cannot open keystore")?;
+    ks.set_password_source(password);
+    ks.get_meta_key().or_fail("Cannot unlock keystore")?;
+    println!("keystore: okay");
+
+    let mut failure: Option<CliError> = None;
+    for g in cfg.target_groups.iter() {
+        for m in g.members.iter().filter(|m| cfg.find_target(m).is_none()) {
+            warn!("bkp: group '{}' refers to unknown destination '{}'",
+                  g.name, m);
+            failure = failure.or(Some(CliError::Config(String::new())));
+        }
+    }
+
+    // destinations are only read, so one which is in use by a snapshot can
+    // still be checked, and nothing is set up on one which hasn't been used
+    for t in cfg.targets.iter() {
+        let heads = remote::connect_tgt_read_only(t, node_name, &ks, data_dir)
+            .and_then(|b| b.list_heads());
+        let mut heads = match heads {
+            Ok(h)  => h,
+            Err(remote::BackendError::NotInitialized) => {
+                println!("{}: okay, not set up yet; it will be on first use",
+                         t.name);
+                continue;
+            },
+            Err(e) => {
+                warn!("bkp: {}: {}", t.name, e);
+                failure = failure.or(Some(e.into()));
+                continue;
+            }
+        };
+
+        heads.sort();
+        let own = if heads.iter().any(|n| n == node_name) { "has" }
+                  else { "has no" };
+        println!("{}: okay, {} snapshots from this node", t.name, own);
+        if !heads.is_empty() {
+            println!("\tnodes with snapshots: {}", heads.join(", "));
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e.with_message(
+                String::from("Not every destination is usable"))),
+        None    => Ok(())
+    }
+}
+
+fn do_test(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let profile = match args.value_of("profile").unwrap() {
+        "quick"      => history::IntegrityTestMode::Quick,
+        "normal"     => history::IntegrityTestMode::Normal,
+        "slow"       => history::IntegrityTestMode::Slow,
+        "exhaustive" => history::IntegrityTestMode::Exhaustive,
+        _            => panic!("unexpected test mode string")
+    };
+
+    let names = opts.cfg.targets.iter().map(|x| {x.name.clone()})
+        .chain(opts.cfg.target_groups.iter().map(|x| {x.name.clone()}));
+
+    let json = args.is_present("json");
+    let repair = args.is_present("repair");
+    let mut reports = Vec::new();
+    let mut failure: Option<CliError> = None;
+    let mut snap_found = false;
+    for t in names {
+        // only repairs write anything
+        let b = if repair { connect_backend(t.clone(), opts) }
+                else { open_backend(t.clone(), opts) };
+        if let Err(e) = b {
+            if json { reports.push(report::DestCheck::failed(&t, &e)); }
+            else { warn!("bkp: skipping destination '{}': {}", t, e); }
+            failure = failure.or(Some(e.into()));
+            continue;
+        }
+
+        // construct a history object
+        let mut b = b.unwrap();
+        let hist = history::History::new(&mut b);
+        if let Err(e) = hist {
+            if json { reports.push(report::DestCheck::failed(&t, &e)); }
+            else { warn!("bkp: skipping destination '{}': {}", t, e); }
+            failure = failure.or(Some(e.into()));
+            continue;
+        }
+
+        let mut hist = hist.unwrap();
+        if let Some(id) = args.value_of("snapshot") {
+            // the chunk size was already validated by clap
+            if let Some(sz) = args.value_of("chunk_size") {
+                hist.set_chunk_size(sz.parse().unwrap());
+            }
+            // repaired blocks are compressed the same way snapshots store them
+            hist.set_no_compress_exts(opts.cfg.no_compress_exts.clone());
+            match verify_snapshot(&t, &mut hist, id, repair) {
+                Ok(found) => snap_found = snap_found || found,
+                Err(e)    => failure = failure.or(Some(e))
+            }
+            continue;
+        }
+
+        // run the check
+        match hist.check(profile) {
+            Err(e) => {
+                if json { reports.push(report::DestCheck::failed(&t, &e)); }
+                else { warn!("bkp: skipping destination '{}': {}", t, e); }
+                failure = failure.or(Some(e.into()));
+                continue;
+            },
+            Ok(r) => {
+                if !r.is_ok() {
+                    failure = failure.or(
+                        Some(CliError::Integrity(String::new())));
+                }
+                if json {
+                    reports.push(report::DestCheck::new(&t, &r));
+                } else if r.is_ok() {
+                    println!("{}: okay", t);
+                } else {
+                    println!("{}: failed", t);
+                    for &(ref snap, ref found) in r.snapshots.iter() {
+                        for &(ref path, ref tag, fault) in
+                                found.bad_blocks.iter() {
+                            println!("\t{}: {} block {} (in snapshot {})",
+                                     path.display(), fault, tag, snap);
+                        }
+                        for &(ref path, ref tag) in found.bad_objects.iter() {
+                            println!("\t{}: unreadable object {} \
+                                      (in snapshot {})",
+                                     path.display(), tag, snap);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if json {
+        report::print(&reports).or_fail("Cannot write report")?;
+    }
+
+    // a snapshot only has to be on some of the destinations, but not finding
+    // it anywhere is a mistake
+    if let Some(id) = args.value_of("snapshot") {
+        if !snap_found && failure.is_none() {
+            return Err(CliError::Usage(
+                    format!("No destination has a snapshot with ID {}", id)));
+        }
+    }
+
+    // exit with the status of the first failure
+    match failure {
+        Some(e) => Err(e.with_message(
+                String::from("Not every destination passed the test"))),
+        None    => Ok(())
+    }
+}
+
+/// Verify the snapshot whose ID starts with `id` and print any problems found,
+/// failing if there were any. With `repair`, bad blocks are regenerated from
+/// local files where possible, and only what's left counts as a failure.
+///
+/// Destinations which don't have the snapshot are skipped with a warning, and
+/// `Ok(false)` is returned for them.
+fn verify_snapshot(name: &str, hist: &mut history::History, id: &str,
+                   repair: bool) -> Result<bool, CliError> {
+    let chain = match hist.snapshots() {
+        Ok(c)  => c,
+        Err(e) => {
+            warn!("bkp: skipping destination '{}': {}", name, e);
+            return Err(e.into());
+        }
+    };
+    let snap = chain.iter().find(|s| s.0.has_prefix(id));
+    let snap = match snap {
+        Some(s) => &s.1,
+        None    => {
+            warn!("bkp: skipping destination '{}': no snapshot with ID {}",
+                  name, id);
+            return Ok(false);
+        }
+    };
+
+    let report = match hist.verify_snapshot(snap) {
+        Ok(r)  => r,
+        Err(e) => {
+            warn!("bkp: skipping destination '{}': {}", name, e);
+            return Err(e.into());
+        }
+    };
+    if report.is_ok() {
+        println!("{}: okay ({} blocks verified)", name, report.blocks_checked);
+        return Ok(true);
+    }
+
+    println!("{}: failed", name);
+    for &(ref path, ref tag, fault) in report.bad_blocks.iter() {
+        println!("\t{}: {} block {}", path.display(), fault, tag);
+    }
+    for &(ref path, ref tag) in report.bad_objects.iter() {
+        println!("\t{}: unreadable object {}", path.display(), tag);
+    }
+    let damaged = CliError::Integrity(
+        format!("{}: snapshot {} is damaged", name, id));
+    if !repair || report.bad_blocks.is_empty() {
+        return Err(damaged);
+    }
+
+    let fixed = hist.repair_blocks(&report)
+        .or_fail(&format!("{}: cannot repair blocks", name))?;
+    for tag in fixed.repaired.iter() {
+        println!("\trepaired block {}", tag);
+    }
+    for &(ref path, ref tag, _) in fixed.unrepairable.iter() {
+        println!("\t{}: cannot regenerate block {}", path.display(), tag);
+    }
+    if fixed.unrepairable.is_empty() && report.bad_objects.is_empty() {
+        println!("{}: repaired", name);
+        Ok(true)
+    } else {
+        Err(damaged)
+    }
+}
+
+/// Gather statistics for a destination, using the local cache if it's still
+/// valid and `bypass_cache` isn't set.
+fn collect_stats(name: &str, opts: &GlobalOptions, bypass_cache: bool)
+        -> history::Result<history::Stats> {
+    let mut backend = open_backend(name.to_owned(), opts)?;
+    let hist = history::History::new(&mut backend)?;
+    let cache_path = opts.data_dir.join("stats").join(name);
+
+    // the cache is only good as long as the head hasn't moved
+    if !bypass_cache {
+        let cached = fs::File::open(&cache_path)
+            .and_then(|mut f| history::Stats::load(&mut f));
+        if let Ok(cached) = cached {
+            if cached.head == hist.head_id()? {
+                return Ok(cached);
+            }
+        }
+    }
+
+    let stats = hist.stats()?;
+
+    // failing to update the cache isn't fatal
+    let _ = fs::create_dir_all(opts.data_dir.join("stats"))
+        .and_then(|_| fs::File::create(&cache_path))
+        .and_then(|mut f| stats.save(&mut f));
+    Ok(stats)
+}
+
+fn do_stat(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let names: Vec<String> = match args.values_of("dest") {
+        Some(v) => v.map(String::from).collect(),
+        None    => opts.cfg.targets.iter().map(|x| {x.name.clone()})
+            .chain(opts.cfg.target_groups.iter().map(|x| {x.name.clone()}))
+            .collect()
+    };
+
+    let json = args.is_present("json");
+    let per_snapshot = args.is_present("snapshots");
+    let mut reports = Vec::new();
+    let mut failure: Option<CliError> = None;
+    let max_col = names.iter().map(|x| x.len()).max().unwrap_or(0);
+    for name in names.iter() {
+        match collect_stats(name, opts, args.is_present("remote")) {
+            Ok(s) => if json {
+                let mut r = report::DestStats::new(name, &s);
+                if per_snapshot {
+                    r.per_snapshot = Some(s.snapshot_stats.iter()
+                        .map(report::SnapshotSpace::from).collect());
+                }
+                reports.push(r);
+            } else {
+                let latest = s.latest.map(util::format_time)
+                              .unwrap_or(String::from("never"));
+                let dedup = s.dedup_ratio().map(|r| format!("{:.2}x", r))
+                             .unwrap_or(String::from("no"));
+                println!("{1:0$}:   {2} snapshots, {3} blocks, {4} of files, \
+                          {5} stored, {6} dedup, latest {7}",
+                         max_col, name, s.snapshots, s.unique_blocks,
+                         util::format_size(s.logical_bytes),
+                         util::format_size(s.stored_bytes), dedup, latest);
+                if per_snapshot {
+                    for snap in s.snapshot_stats.iter() {
+                        println!("\t{}  {} referenced, {} added", snap.id,
+                                 util::format_size(snap.referenced_bytes),
+                                 util::format_size(snap.added_bytes));
+                    }
+                }
+            },
+            Err(e) => {
+                if json {
+                    reports.push(report::DestStats::failed(name, &e));
+                } else {
+                    println!("{1:0$}:   {2}", max_col, name, e);
+                }
+                failure = failure.or(Some(e.into()));
+            }
+        }
+    }
+    if json {
+        report::print(&reports).or_fail("Cannot write report")?;
+    }
+
+    // exit with the status of the first failure
+    match failure {
+        Some(e) => Err(e.with_message(String::from(
+                "Cannot collect statistics for every destination"))),
+        None    => Ok(())
+    }
+}
+
+fn do_diff(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let now = std::time::SystemTime::now();
+
+    let mut backend = open_backend(remote, opts)
+        .or_fail("backend connection failed")?;
+    let history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let chain = history.snapshots()
+        .or_fail("failed to read snapshots")?;
+
+    // times were already validated by clap
+    let as_of = |t: &str| -> Result<_, CliError> {
+        let t = util::parse_time(t, now).unwrap();
+        Ok(history.snapshot_as_of(t)
+                  .or_fail("failed to read snapshots")?
+                  .map(|s| (*s).clone()))
+    };
+
+    // by default, compare the newest snapshot against its parent
+    let to = match args.value_of("to") {
+        Some(t) => as_of(t)?,
+        None    => chain.first().map(|s| s.1.clone())
+    };
+    let from = match args.value_of("from") {
+        Some(t) => as_of(t)?,
+        None    => to.as_ref()
+                     .and_then(|s| s.parent)
+                     .and_then(|p| chain.iter().find(|s| s.0 == p))
+                     .map(|s| s.1.clone())
+    };
+    let (from, to) = match (from, to) {
+        (Some(f), Some(t)) => (f, t),
+        _                  => return Err(CliError::Failure(
+                String::from("no snapshots to compare")))
+    };
+
+    let changes = history.diff(&from, &to)
+        .or_fail("failed to compare snapshots")?;
+    if args.is_present("json") {
+        return report::print(&report::Diff::new(&from, &to, &changes))
+            .or_fail("Cannot write report");
+    }
+    if changes.is_empty() {
+        println!("no changes");
+    }
+    print_changes(&changes);
+    Ok(())
+}
+
+fn do_recover(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let mode = match args.value_of("profile").unwrap() {
+        "quick"      => history::IntegrityTestMode::Quick,
+        "normal"     => history::IntegrityTestMode::Normal,
+        "slow"       => history::IntegrityTestMode::Slow,
+        "exhaustive" => history::IntegrityTestMode::Exhaustive,
+        _            => panic!("unexpected test mode string")
+    };
+
+    let mut backend = connect_backend(remote, opts)
+        .or_fail("backend connection failed")?;
+    let mut history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let found = history.find_recovery_point(mode)
+        .or_fail("failed to scan for snapshots")?;
+
+    println!("found {} snapshots", found.snapshots);
+    let (tag, snap) = match found.newest_intact {
+        Some(x) => x,
+        None    => return Err(CliError::Integrity(
+                String::from("no intact snapshot to recover")))
+    };
+    println!("newest intact snapshot is {} from {}, with {} in its chain",
+             tag, util::format_time(snap.create_time), found.chain_length);
+
+    if history.head_id().ok() == Some(Some(tag)) {
+        println!("the head already points to it");
+        return Ok(());
+    }
+    if !args.is_present("yes") && !confirm("Reset the head to this snapshot?") {
+        println!("aborted");
+        return Ok(());
+    }
+    history.reset_head(&tag).or_fail("failed to reset head")?;
+    Ok(())
+}
+
+fn do_snapshots(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let mut backend = open_backend(remote, opts)
+        .or_fail("backend connection failed")?;
+    if let Some(node) = args.value_of("from") {
+        view_node(&mut backend, node)
+            .or_fail("cannot read the other node's snapshots")?;
+    }
+    let history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let chain = history.snapshots()
+        .or_fail("failed to read snapshots")?;
+
+    // limits were already validated by clap
+    let limit = args.value_of("limit").map_or(chain.len(),
+                                              |n| n.parse().unwrap());
+    if args.is_present("json") {
+        let mut entries = Vec::new();
+        for (i, &(ref tag, ref snap)) in chain.iter().enumerate().take(limit) {
+            let mut entry = report::SnapshotEntry::new(tag, snap);
+            if let (true, Some(&(_, ref parent))) =
+                    (args.is_present("changes"), chain.get(i + 1)) {
+                entry.changes = Some(history.diff(parent, snap)
+                    .or_fail("failed to compare snapshots")?.len());
+            }
+            entries.push(entry);
+        }
+        return report::print(&entries).or_fail("Cannot write report");
+    }
+
+    if chain.is_empty() {
+        println!("no snapshots");
+        return Ok(());
+    }
+    for (i, &(ref tag, ref snap)) in chain.iter().enumerate().take(limit) {
+        print!("{}  {}  root {}", tag, util::format_time(snap.create_time),
+               snap.root);
+
+        // the chain runs newest first, so the parent is the next entry
+        if args.is_present("changes") {
+            match chain.get(i + 1) {
+                Some(&(_, ref parent)) => {
+                    let changes = history.diff(parent, snap)
+                        .or_fail("failed to compare snapshots")?;
+                    print!("  {} changed", changes.len());
+                },
+                None => print!("  full")
+            }
+        }
+        println!("");
+    }
+    Ok(())
+}
+
+fn do_heads(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let mut backend = open_backend(remote.clone(), opts)
+        .or_fail("backend connection failed")?;
+    let mut history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let heads = history.node_heads().or_fail("failed to read heads")?;
+
+    if heads.is_empty() {
+        println!("no snapshots");
+        return Ok(());
+    }
+    for h in heads.iter() {
+        match h.snapshot {
+            Some((ref tag, ref snap)) =>
+                println!("{}  {}  {}", tag, util::format_time(snap.create_time),
+                         h.node),
+            None => println!("{:64}  {:23}  {}", "(unreadable)", "", h.node)
+        }
+    }
+
+    // objects are shared between nodes, so damage done through one node's
+    // view of the repository shows up in the others' snapshots
+    let mut damaged = false;
+    for h in heads.iter() {
+        if h.snapshot.is_none() {
+            warn!("bkp: {}: cannot read the head of node '{}' without its \
+                   metadata key, so `bkp gc` from here keeps every data block",
+                  remote, h.node);
+        } else if h.missing > 0 {
+            warn!("bkp: {}: the head of node '{}' refers to {} missing \
+                   objects, which may have been removed by another node's gc",
+                  remote, h.node, h.missing);
+            damaged = true;
+        }
+        if h.at_risk > 0 {
+            warn!("bkp: {}: the head of node '{}' refers to {} objects it \
+                   cannot read, likely stored by another node, whose gc may \
+                   remove them", remote, h.node, h.at_risk);
+        }
+    }
+    if damaged {
+        return Err(CliError::Integrity(
+                String::from("Some snapshots refer to missing objects")));
+    }
+    Ok(())
 }
 
-fn fail_error<E: Error>(msg: &str, err: E) {
-    writeln!(std::io::stderr(), "bkp: {}: {}", msg, err).unwrap();
-    std::process::exit(1);
+/// Print a list of changed paths, one per line, marked with how they changed
+fn print_changes(changes: &[history::PathChange]) {
+    for c in changes.iter() {
+        let mark = match c.kind {
+            history::ChangeKind::Added    => 'A',
+            history::ChangeKind::Removed  => 'D',
+            history::ChangeKind::Modified => 'M'
+        };
+        println!("{} {}", mark, c.path.display());
+    }
 }
 
-trait UnwrapOrFail<T> {
-    /// Unwrap the result or fail with the given error message
-    fn unwrap_or_fail(self, msg: &str) -> T;
-}
+fn do_repack(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let names: Vec<String> = match args.values_of("dest") {
+        Some(v) => v.map(String::from).collect(),
+        None    => opts.cfg.targets.iter().map(|x| {x.name.clone()}).collect()
+    };
 
-impl<T, E: Error> UnwrapOrFail<T> for Result<T, E> {
-    fn unwrap_or_fail(self, msg: &str) -> T {
-        match self {
-            Err(e) => {
-                fail_error(msg, e);
-                unreachable!()
-            },
-            Ok(x) => x
-        }
+    for name in names.iter() {
+        check_removable(name, opts)?;
+    }
+    for name in names {
+        let mut backend = connect_backend(name.clone(), opts)
+            .or_fail("backend connection failed")?;
+        let packed = backend.repack()
+            .or_fail("failed to repack metadata")?;
+        info!("{}: packed {} metadata objects", name, packed);
     }
+    Ok(())
 }
 
-fn connect_backend(name: String, opts: &GlobalOptions)
-        -> Result<Box<remote::Backend>, remote::BackendError> {
-    use remote::BackendError;
-    if let Some(t) = opts.cfg.find_target(&name) {
-        remote::connect_tgt(t, &opts.cfg.node_name, &opts.keystore)
-    } else if let Some(g) = opts.cfg.find_group(&name) {
-        // bind names to actual targets
-        let tgts = g.members.iter()
-            .map(|ref n| opts.cfg.find_target(&n)
-                                 .ok_or(BackendError::InvalidOption))
+fn do_gc(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let dry_run = args.is_present("dry_run");
+    if !dry_run { check_removable(&remote, opts)?; }
 
-            .collect::<Result<Vec<&config::BackupTarget>, BackendError>>()?;
+    let mut backend = connect_backend(remote.clone(), opts)
+        .or_fail("backend connection failed")?;
+    let mut history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let report = history.gc(dry_run)
+        .or_fail("failed to collect unreferenced data")?;
 
-        // connect all of them
-        remote::connect_group(tgts, &opts.cfg.node_name, &opts.keystore)
-    } else {
-        Err(BackendError::InvalidOption)
+    for node in report.skipped_nodes.iter() {
+        warn!("bkp: {}: cannot read snapshots of node {}, keeping all data \
+               blocks", remote, node);
     }
-}
-
-fn do_dest(args: &clap::ArgMatches, opts: &mut GlobalOptions) {
-    match args.subcommand() {
-        ("add", Some(m)) => { // add a destination
-            let name = m.value_of("name").unwrap();
-            let url = m.value_of("url").unwrap();
-            let user = m.value_of("user");
-            let password = m.value_of("password");
 
-            // make sure the specified destination doesn't already exist
-            if opts.cfg.targets.iter().any(|t| {t.name == name}) {
-                err_write!("bkp: Destination '{}' already exists", name);
-                std::process::exit(1);
-            }
+    // a dry run's report is the point of running it
+    let summary = format!("{}: {} {} metadata objects and {} blocks ({})",
+                          remote,
+                          if dry_run { "would remove" } else { "removed" },
+                          report.meta_objects, report.blocks,
+                          util::format_size(report.bytes));
+    if dry_run { println!("{}", summary); } else { info!("{}", summary); }
+    Ok(())
+}
 
-            // parse the target URL
-            let url = Url::parse(&url)
-                .unwrap_or_fail("Cannot parse given URL");
+fn do_check_replication(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let name = args.value_of("group").unwrap();
+    let group = opts.cfg.find_group(name)
+        .ok_or_else(|| CliError::Usage(format!("no such group: {}", name)))?;
+    let tgts = group_targets(group, opts)
+        .or_fail("group refers to an unknown destination")?;
+    let mut backend = remote::open_group(tgts.iter().collect(),
+                                         &opts.node_name, &opts.keystore,
+                                         &opts.data_dir)
+        .or_fail("backend connection failed")?;
 
-            // build the new target
-            let tgt = config::BackupTarget {
-                name: name.to_owned(),
-                url: url,
-                user: user.map(String::from),
-                password: password.map(String::from),
-                key_file: None,
-                options: config::TargetOptions {
-                    reliable: true,
-                    upload_cost: 1,
-                    download_cost: 1
-                }
-            };
-            opts.cfg.targets.push(tgt);
-            opts.cfg.save().unwrap_or_fail("Failed to save config file");
-        },
-        (s, _) if (s == "list") || s.is_empty() => { // list destinations
-            let max_left_col = opts.cfg.targets.iter()
-                    .map(|ref x| x.name.len())
-                    .max().unwrap_or(0);
-            for t in opts.cfg.targets.iter() {
-                println!("{1:0$}  {2}", max_left_col, t.name, t.url.as_str());
-            }
-        },
-        ("remove", Some(m)) => { // remove destinations
-            unimplemented!()
-        },
-        ("test", Some(m)) => { // test destination connectivity
-            let mut has_errs = false;
-            let max_col = m.values_of("name").unwrap()
-                    .map(|ref x| x.len()).max().unwrap_or(0);
-            for name in m.values_of("name").unwrap() {
-                let tgt = connect_backend(name.to_owned(), &opts);
-                match tgt {
-                    Ok(_)  => println!("{1:0$}:   successful", max_col, name),
-                    Err(e) => {
-                        println!("{1:0$}:   {2}", max_col, name, e);
-                        has_errs = true;
-                    }
-                }
-            }
+    let blocks = backend.unreliable_blocks()
+        .or_fail("failed to list stored blocks")?;
+    if blocks.is_empty() {
+        info!("{}: every block is stored on a reliable member", name);
+        return Ok(());
+    }
+    for tag in blocks.iter() {
+        println!("{}", tag);
+    }
 
-            if has_errs {
-                std::process::exit(1);
-            }
-        },
-        (_, _) => panic!("No subcommand handler found")
+    if args.is_present("fix") {
+        let copies = backend.replicate(&blocks)
+            .or_fail("failed to replicate blocks")?;
+        info!("{}: copied {} blocks to reliable members", name, copies);
+        Ok(())
+    } else {
+        Err(CliError::Integrity(format!(
+                    "{}: {} blocks are only stored on unreliable members",
+                    name, blocks.len())))
     }
 }
 
-fn do_test(args: &clap::ArgMatches, opts: &GlobalOptions) {
-    let profile = match args.value_of("profile").unwrap() {
-        "quick"      => history::IntegrityTestMode::Quick,
-        "normal"     => history::IntegrityTestMode::Normal,
-        "slow"       => history::IntegrityTestMode::Slow,
-        "exhaustive" => history::IntegrityTestMode::Exhaustive,
-        _            => panic!("unexpected test mode string")
+fn do_clean(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    use std::collections::HashSet;
+    use std::time::{Duration, SystemTime};
+
+    let names: Vec<String> = match args.values_of("dest") {
+        Some(v) => v.map(String::from).collect(),
+        None    => opts.cfg.targets.iter().map(|x| {x.name.clone()}).collect()
     };
 
-    let names = opts.cfg.targets.iter().map(|x| {x.name.clone()})
-        .chain(opts.cfg.target_groups.iter().map(|x| {x.name.clone()}));
+    // parse predicates. durations were already validated by clap
+    let older_than = args.value_of("older_than")
+        .map(|s| util::parse_duration(s).unwrap());
+    let newer_than = args.value_of("newer_than")
+        .map(|s| util::parse_duration(s).unwrap());
+    let full = args.value_of("snap_type").map(|t| t == "full");
+    let exists = args.value_of("exists").map(|e| e == "yes");
+    let count = |n: &str| {
+        args.value_of(n).map_or(0, |c| c.parse::<usize>().unwrap())
+    };
+    let policy = history::RetentionPolicy {
+        last: count("keep_last"),
+        daily: count("keep_daily"),
+        weekly: count("keep_weekly"),
+        monthly: count("keep_monthly")
+    };
+    let dry_run = args.is_present("dry_run");
+    let now = SystemTime::now();
+    if !dry_run {
+        for name in names.iter() { check_removable(name, opts)?; }
+    }
 
-    for t in names {
-        let b = connect_backend(t.clone(), opts);
-        if let Err(e) = b {
-            println!("bkp: skipping destination '{}': {}", t, e);
-            continue;
+    for name in names {
+        let mut backend = connect_backend(name.clone(), opts)
+            .or_fail("backend connection failed")?;
+        let mut history = history::History::new(&mut backend)
+            .or_fail("failed to configure history layer")?;
+        let chain = history.snapshots()
+            .or_fail("failed to read snapshots")?;
+
+        // find snapshots which match every given predicate
+        let retained = policy.retained(&chain);
+        let mut matched = HashSet::new();
+        for &(ref tag, ref snap) in chain.iter() {
+            if !policy.is_empty() && retained.contains(tag) { continue; }
+            let age = now.duration_since(snap.create_time)
+                         .unwrap_or(Duration::from_secs(0));
+            if older_than.map_or(false, |d| age <= d) { continue; }
+            if newer_than.map_or(false, |d| age >= d) { continue; }
+            if full.map_or(false, |f| f != snap.parent.is_none()) { continue; }
+            if let Some(e) = exists {
+                let present = history.exists_locally(snap)
+                    .or_fail("failed to read snapshot")?;
+                if present != e { continue; }
+            }
+            matched.insert(*tag);
         }
 
-        // construct a history object
-        let mut b = b.unwrap();
-        let hist = history::History::new(&mut b);
-        if let Err(e) = hist {
-            println!("bkp: skipping destination '{}': {}", t, e);
+        if matched.is_empty() {
+            info!("{}: nothing to remove", name);
+            continue;
+        }
+        if matched.len() == chain.len() {
+            warn!("bkp: {}: refusing to remove every snapshot", name);
             continue;
         }
 
-        // run the check
-        match hist.unwrap().check(profile) {
-            Err(e) => {
-                println!("bkp: skipping destination '{}': {}", t, e);
-                continue;
-            },
-            Ok(true)  => println!("{}: okay", t),
-            Ok(false) => println!("{}: failed", t),
+        for &(ref tag, ref snap) in chain.iter() {
+            if !matched.contains(tag) { continue; }
+            let verb = if dry_run { "would remove" } else { "removing" };
+            let line = format!("{}: {} snapshot {} from {}", name, verb, tag,
+                               util::format_time(snap.create_time));
+            if dry_run { println!("{}", line); } else { info!("{}", line); }
         }
-    }
-}
+        if dry_run { continue; }
 
-fn do_stat(args: &clap::ArgMatches, opts: &GlobalOptions) {
-    unimplemented!()
+        history.remove_snapshots(&matched)
+            .or_fail("failed to remove snapshots")?;
+        let report = history.gc(false)
+            .or_fail("failed to collect unreferenced data")?;
+        info!("{}: removed {} metadata objects and {} blocks ({})",
+              name, report.meta_objects, report.blocks,
+              util::format_size(report.bytes));
+    }
+    Ok(())
 }
 
-fn do_clean(args: &clap::ArgMatches, opts: &GlobalOptions) {
-    unimplemented!()
+/// Validate a count given on the command line, which has to be at least one
+fn positive_count(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _              => Err(String::from("Not a positive number"))
+    }
 }
 
-fn do_snap(args: &clap::ArgMatches, opts: &GlobalOptions) {
+fn do_snap(args: &clap::ArgMatches, opts: &mut GlobalOptions)
+        -> Result<(), CliError> {
     let remote = args.value_of("remote").unwrap().to_owned();
     let snap_paths: Vec<&str> = args.values_of("local").unwrap().collect();
+    let chunk_size = match args.value_of("chunk_size") {
+        Some(s) => Some(s.parse::<usize>().map_err(|e| CliError::Usage(
+                format!("invalid chunk size: {}", e)))?),
+        None    => None
+    };
+
+    // compression settings were already validated by clap
+    opts.compression = args.value_of("compress")
+        .and_then(compression::Compression::from_name);
+    opts.compression_level = args.value_of("compress_level")
+        .map(|s| s.parse::<u32>().unwrap());
+    if let (Some(alg), Some(level)) = (opts.compression,
+                                       opts.compression_level) {
+        if !alg.is_valid_level(level) {
+            let (lo, hi) = alg.levels();
+            return Err(CliError::Usage(
+                    format!("{} compression levels range from {} to {}",
+                            alg, lo, hi)));
+        }
+    }
+
+    let journal = opts.data_dir.join("journal").join(&remote);
+
+    // work out how much there is to do before doing any of it. this reads
+    // every changed file an extra time, so it's only done when asked for, or
+    // when it's all a dry run does. nothing is written while planning, so the
+    // destination is only opened for reading.
+    let plan = if args.is_present("estimate") || args.is_present("dry_run") {
+        let mut backend = match open_backend(remote.clone(), opts) {
+            // nothing is stored there yet, so everything is new
+            Err(remote::BackendError::NotInitialized) =>
+                Ok(Box::new(remote::memory::MemoryBackend::new())
+                   as Box<remote::Backend>),
+            r => r
+        }.or_fail("backend connection failed")?;
+        let mut history = history::History::new(&mut backend)
+            .or_fail("failed to configure history layer")?;
+        configure_snap(&mut history, args, opts, chunk_size, &journal);
+        let plan = history.plan_paths(snap_paths.iter())
+                          .or_fail("failed to scan paths")?;
+        if args.is_present("dry_run") {
+            print_changes(&plan.changes);
+        }
+        if args.is_present("dry_run") || !opts.quiet {
+            println!("{} files to store, {} new blocks ({} of {}) to upload",
+                     plan.changes.len(), plan.new_blocks,
+                     util::format_size(plan.new_bytes),
+                     util::format_size(plan.upload_bytes));
+        }
+        Some(plan)
+    } else {
+        None
+    };
+    if args.is_present("dry_run") {
+        return Ok(());
+    }
 
     let mut remote = connect_backend(remote, opts)
-        .unwrap_or_fail("backend connection failed");
+        .or_fail("backend connection failed")?;
+    if args.is_present("rescan") {
+        remote.rebuild_index().or_fail("failed to rescan remote blocks")?;
+    }
 
     // construct a history object
     let mut history = history::History::new(&mut remote)
-        .unwrap_or_fail("failed to configure history layer");
+        .or_fail("failed to configure history layer")?;
+    configure_snap(&mut history, args, opts, chunk_size, &journal);
+
+    let progress = make_progress(opts);
+    if let Some(ref plan) = plan {
+        progress.set_total(plan.upload_bytes);
+    }
+    history.set_progress(progress.clone());
 
     // update paths
     let new_tree = history.update_paths(snap_paths)
-                          .unwrap_or_fail("failed to write modified trees");
+                          .or_fail("failed to write modified trees")?;
+    progress.finish();
 
     // build a new snapshot
     let snap = history.new_snapshot(new_tree)
-                      .unwrap_or_fail("failed to create snapshot");
+                      .or_fail("failed to create snapshot")?;
+
+    info!("snapshot created.");
+    Ok(())
+}
+
+/// Apply `snap`'s options to the history a snapshot is planned or taken with
+fn configure_snap(history: &mut history::History, args: &clap::ArgMatches,
+                  opts: &GlobalOptions, chunk_size: Option<usize>,
+                  journal: &Path) {
+    if let Some(sz) = chunk_size {
+        history.set_chunk_size(sz);
+    }
+    history.set_store_xattrs(!args.is_present("no_xattrs"));
+    history.set_store_atime(!args.is_present("no_atime"));
+    history.set_trust_mtime(!args.is_present("no_trust_mtime"));
+    history.set_one_file_system(args.is_present("one_file_system"));
+    history.set_dereference(args.is_present("dereference"));
+    history.set_no_compress_exts(match args.value_of("no_compress_ext") {
+        Some(exts) => config::split_extensions(exts),
+        None       => opts.cfg.no_compress_exts.clone()
+    });
+    history.set_exclude_caches(args.is_present("exclude_caches"));
+    if let Some(names) = args.values_of_os("exclude_if_present") {
+        history.set_exclude_markers(names.map(|n| n.to_owned()).collect());
+    }
+    history.set_prune_empty_dirs(args.is_present("prune_empty_dirs"));
+    history.set_verify_commit(args.is_present("verify"));
+    history.set_journal(journal);
+    if let Some(pats) = args.values_of("exclude") {
+        history.set_excludes(pats.filter_map(exclude::Pattern::parse).collect());
+    }
+}
+
+/// Ask the user a yes/no question, returning whether they answered yes
+fn confirm(prompt: &str) -> bool {
+    use std::ascii::AsciiExt;
+    loop {
+        print!("{} (y/n) ", prompt);
+        std::io::stdout().flush().unwrap();
+        let mut response = String::new();
+        if std::io::stdin().read_line(&mut response).unwrap() == 0 {
+            return false; // no answer is coming
+        }
+
+        match response.chars().next().map(|x| x.to_ascii_lowercase()) {
+            Some('y') => return true,
+            Some('n') => return false,
+            _         => {}, // ask again
+        }
+    }
+}
+
+/// Work out the overwrite mode selected by a restore's `--overwrite` flag
+fn overwrite_mode(args: &clap::ArgMatches) -> history::OverwriteMode {
+    use history::OverwriteMode;
+    if !args.is_present("overwrite") {
+        return OverwriteMode::Never;
+    }
+
+    // the value was already validated by clap
+    match args.value_of("overwrite") {
+        None | Some("always") => OverwriteMode::Always,
+        Some("never")         => OverwriteMode::Never,
+        Some(_)               => OverwriteMode::IfNewer
+    }
+}
+
+/// Find the newest snapshot, or the newest one at or before a given time,
+/// failing if there isn't one
+fn select_snapshot<'a>(history: &'a history::History,
+                       as_of: Option<std::time::SystemTime>)
+        -> Result<history::ContextWrapper<'a, metadata::Snapshot>, CliError> {
+    let snapshot = match as_of {
+        None    => history.get_snapshot(),
+        Some(t) => history.snapshot_as_of(t)
+    }.or_fail("failed to read snapshot")?;
+
+    match (snapshot, as_of) {
+        (Some(s), _)    => Ok(s),
+        (None, None)    => Err(CliError::Failure(
+                String::from("destination has no snapshots"))),
+        (None, Some(t)) => Err(CliError::Failure(
+                format!("no snapshot exists at or before {}",
+                        util::format_time(t))))
+    }
+}
 
-    println!("snapshot created.");
+/// Print one entry of a stored tree, in either short or `ls -l` style
+fn print_entry(obj: &MetaObject, long: bool) -> Result<(), CliError> {
+    let name = obj.name().unwrap_or_default();
+    let name = name.to_string_lossy();
+    let meta = match obj.meta() {
+        Some(m) if long => m,
+        _               => {
+            match obj {
+                &MetaObject::Tree(_) => println!("{}/", name),
+                _                    => println!("{}", name)
+            }
+            return Ok(());
+        }
+    };
+
+    let (kind, size) = match obj {
+        &MetaObject::Tree(_)       => ('d', None),
+        &MetaObject::File(ref f)   => ('-', f.size),
+        &MetaObject::HardLink(_)   => ('-', None),
+        &MetaObject::Symlink(ref l) => ('l', Some(l.target.len() as u64)),
+        &MetaObject::Special(ref s) => match s.kind {
+            metadata::SpecialKind::Fifo        => ('p', None),
+            metadata::SpecialKind::Socket      => ('s', None),
+            metadata::SpecialKind::CharDevice  => ('c', None),
+            metadata::SpecialKind::BlockDevice => ('b', None),
+        },
+        &MetaObject::Snapshot(_)   =>
+            // snapshots never appear inside trees
+            return Err(history::Error::IntegrityError)
+                .or_fail("cannot read stored objects")
+    };
+    let size = size.map(|x| x.to_string()).unwrap_or(String::from("-"));
+    let target = match obj {
+        &MetaObject::Symlink(ref l) =>
+            format!(" -> {}", String::from_utf8_lossy(&l.target)),
+        _ => String::new()
+    };
+
+    println!("{}{} {:>5} {:>5} {:>12} {} {}{}", kind,
+             util::format_mode(meta.mode), meta.uid, meta.gid, size,
+             util::format_time(meta.mtime), name, target);
+    Ok(())
+}
+
+/// List the children of a stored tree, descending into subtrees if requested
+fn list_tree(path: &Path, obj: &history::ContextWrapper<MetaObject>,
+             long: bool, recursive: bool) -> Result<(), CliError> {
+    let children = obj.children().or_fail("cannot read stored objects")?
+                                 .unwrap_or(Vec::new());
+    if recursive {
+        println!("{}:", path.display());
+    }
+    for c in children.iter() {
+        print_entry(c, long)?;
+    }
+
+    if recursive {
+        for c in children.iter() {
+            if let &MetaObject::Tree(_) = &**c {
+                println!("");
+                let name = c.name().unwrap_or_default();
+                list_tree(&path.join(name), c, long, recursive)?;
+            }
+        }
+    }
+    Ok(())
 }
 
-fn do_restore(args: &clap::ArgMatches, opts: &GlobalOptions) {
+fn do_ls(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
     let remote = args.value_of("remote").unwrap().to_owned();
+    let path = Path::new(args.value_of("path").unwrap_or("/"));
 
-    // TODO: avoid specifying remote by searching for all remotes with a file
+    // the time was already validated by clap
+    let as_of = args.value_of("as_of").map(|t| {
+        util::parse_time(t, std::time::SystemTime::now()).unwrap()
+    });
 
-    let objects: Vec<&Path> = args.values_of("local").unwrap()
-                                  .map(Path::new).collect();
+    let mut backend = open_backend(remote, opts)
+        .or_fail("backend connection failed")?;
+    let history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let snapshot = select_snapshot(&history, as_of)?;
 
-    let mut remote = connect_backend(remote, opts)
-                    .unwrap_or_fail("backend connection failed");
-    let mut history = history::History::new(&mut remote)
-                     .unwrap_or_fail("failed to configure history layer");
+    let obj = match snapshot.get(path)
+                            .or_fail("cannot read stored objects")? {
+        Some(o) => o,
+        None    => return Err(CliError::Failure(
+                format!("no such path in snapshot: {}", path.display())))
+    };
 
-    // TODO: figure out the target time, if any
+    let long = args.is_present("long");
+    match *obj {
+        MetaObject::Tree(_) =>
+            list_tree(path, &obj, long, args.is_present("recursive"))?,
+        _                   => print_entry(&obj, long)?
+    }
+    Ok(())
+}
 
-    // find the requested snapshot
-    // TODO: add command for recovering backups with broken head snapshot
-    let mut snapshot = history.get_snapshot()
-                              .unwrap_or_fail("failed to read root snapshot");
-    if snapshot.is_none() {
-        eprintln!("bkp: cannot restore from empty target");
-        std::process::exit(1);
-    }
-    let snapshot = loop {
-        match snapshot {
-            None => {
-                eprintln!("bkp: no matching snapshot");
-                // TODO: show most recent one?
-                std::process::exit(1);
-            },
-            Some(snap) => {
-                // TODO: Add target time check here
-                if true {
-                    break snap;
-                }
-                snapshot = snap.parent()
-                               .unwrap_or_fail("failed to read snapshot");
+fn do_cat(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let path = Path::new(args.value_of("path").unwrap());
+
+    // the time was already validated by clap
+    let as_of = args.value_of("as_of").map(|t| {
+        util::parse_time(t, std::time::SystemTime::now()).unwrap()
+    });
+
+    let mut backend = open_backend(remote, opts)
+        .or_fail("backend connection failed")?;
+    let history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let snapshot = select_snapshot(&history, as_of)?;
+
+    let obj = match snapshot.get(path)
+                            .or_fail("cannot read stored objects")? {
+        Some(o) => o,
+        None    => return Err(CliError::Failure(
+                format!("no such path in snapshot: {}", path.display())))
+    };
+    let problem = match *obj {
+        MetaObject::File(_) | MetaObject::HardLink(_) => None,
+        MetaObject::Tree(_)    => Some("is a directory"),
+        MetaObject::Symlink(_) => Some("is a symbolic link"),
+        _                      => Some("is not a regular file")
+    };
+    if let Some(p) = problem {
+        return Err(CliError::Usage(format!("{} {}", path.display(), p)));
+    }
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    obj.write_contents(&mut out).or_fail("cannot read stored file")?;
+    Ok(())
+}
+
+#[cfg(feature = "mount")]
+fn do_mount(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    let remote = args.value_of("remote").unwrap().to_owned();
+    let mountpoint = Path::new(args.value_of("mountpoint").unwrap());
+
+    // the time was already validated by clap
+    let as_of = args.value_of("as_of").map(|t| {
+        util::parse_time(t, std::time::SystemTime::now()).unwrap()
+    });
+
+    let mut backend = open_backend(remote, opts)
+        .or_fail("backend connection failed")?;
+    let history = history::History::new(&mut backend)
+        .or_fail("failed to configure history layer")?;
+    let snapshot = select_snapshot(&history, as_of)?;
+    let root = match snapshot.get("/").or_fail("cannot read stored objects")? {
+        Some(r) => r,
+        None    => return Err(CliError::Integrity(
+                String::from("snapshot has no root directory")))
+    };
+
+    mount::mount(mount::SnapshotFs::new(root), mountpoint)
+        .or_fail("cannot mount snapshot")?;
+    Ok(())
+}
+
+/// Ask the user to pick one of several options, returning its index
+fn choose(prompt: &str, options: &[String]) -> Option<usize> {
+    for (i, x) in options.iter().enumerate() {
+        println!("  {}) {}", i + 1, x);
+    }
+    loop {
+        print!("{} [1-{}] ", prompt, options.len());
+        std::io::stdout().flush().unwrap();
+        let mut response = String::new();
+        if std::io::stdin().read_line(&mut response).unwrap() == 0 {
+            return None; // no answer is coming
+        }
+
+        match response.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= options.len() => return Some(n - 1),
+            _                                     => {}, // ask again
+        }
+    }
+}
+
+/// Switch a backend over to reading another node's snapshots
+fn view_node(backend: &mut Box<remote::Backend>, node: &str)
+        -> Result<(), remote::BackendError> {
+    if !backend.list_heads()?.iter().any(|n| n == node) {
+        return Err(remote::BackendError::BackendError(
+                format!("no snapshots stored for node '{}'", node)));
+    }
+    backend.view_node(node)
+}
+
+/// Search the configured destinations for ones whose snapshot contains all the
+/// given paths, returning the name of the cheapest one to download from.
+///
+/// If the destinations hold different versions of the paths, the user is asked
+/// which to use.
+fn find_restore_remote(paths: &[&Path], as_of: Option<std::time::SystemTime>,
+                       from: Option<&str>, opts: &GlobalOptions)
+        -> Result<String, CliError> {
+    let mut found: Vec<(&config::BackupTarget, Vec<metadata::IdentityTag>)> =
+        Vec::new();
+    for tgt in opts.cfg.targets.iter() {
+        let mut backend = match open_backend(tgt.name.clone(), opts) {
+            Ok(b)  => b,
+            Err(e) => {
+                warn!("bkp: skipping destination {}: {}", tgt.name, e);
+                continue;
+            }
+        };
+        if let Some(node) = from {
+            if let Err(e) = view_node(&mut backend, node) {
+                warn!("bkp: skipping destination {}: {}", tgt.name, e);
+                continue;
             }
         }
+        let history = history::History::new(&mut backend)
+            .or_fail("failed to configure history layer")?;
+        let snapshot = match as_of {
+            None    => history.get_snapshot(),
+            Some(t) => history.snapshot_as_of(t)
+        }.or_fail("failed to read snapshot")?;
+        let snapshot = match snapshot {
+            Some(s) => s,
+            None    => continue
+        };
+
+        // identify the stored version of each path, so copies can be compared
+        let ids: history::Result<Option<Vec<_>>> = if paths.is_empty() {
+            Ok(Some(vec![snapshot.root]))
+        } else {
+            paths.iter().map(|p| snapshot.get_id(p)).collect()
+        };
+        if let Some(ids) = ids.or_fail("cannot read stored objects")? {
+            found.push((tgt, ids));
+        }
+    }
+
+    if found.is_empty() {
+        return Err(CliError::Failure(
+                String::from("no destination holds the requested paths")));
+    }
+    found.sort_by_key(|x| x.0.options.download_cost);
+
+    // make sure every copy is the same before picking one arbitrarily
+    if found.iter().all(|x| x.1 == found[0].1) {
+        return Ok(found[0].0.name.clone());
+    }
+    println!("The destinations hold different versions of these paths:");
+    let names: Vec<String> = found.iter().map(|x| x.0.name.clone()).collect();
+    match choose("Which destination should be restored from?", &names) {
+        Some(i) => Ok(names[i].clone()),
+        None    => Err(CliError::Failure(String::from("aborted")))
+    }
+}
+
+fn do_restore(args: &clap::ArgMatches, opts: &GlobalOptions)
+        -> Result<(), CliError> {
+    // with --any, every positional argument is a path to restore
+    let mut objects: Vec<&Path> = Vec::new();
+    if args.is_present("any") {
+        objects.extend(args.value_of("remote").map(Path::new));
+    }
+    objects.extend(args.values_of("local").into_iter().flat_map(|v| v)
+                       .map(Path::new));
+
+    // figure out the target time, if any. it was already validated by clap
+    let as_of = args.value_of("as_of").map(|t| {
+        util::parse_time(t, std::time::SystemTime::now()).unwrap()
+    });
+
+    let remote = if args.is_present("any") {
+        find_restore_remote(&objects, as_of, args.value_of("from"), opts)?
+    } else {
+        args.value_of("remote").unwrap().to_owned()
+    };
+
+    let mut remote = open_backend(remote, opts)
+                    .or_fail("backend connection failed")?;
+    if let Some(node) = args.value_of("from") {
+        view_node(&mut remote, node)
+            .or_fail("cannot read the other node's snapshots")?;
+    }
+    let mut history = history::History::new(&mut remote)
+                     .or_fail("failed to configure history layer")?;
+
+    // find the requested snapshot
+    let snapshot = select_snapshot(&history, as_of)?;
+
+    let base_path = Path::new(args.value_of("into").unwrap_or("/"));
+    let progress = make_progress(opts);
+    let mut options = history::RestoreOptions::new()
+        .overwrite_mode(overwrite_mode(args))
+        .ignore_permissions(args.is_present("no_perms"))
+        .ignore_attributes(args.is_present("no_attrs"))
+        .verify_blocks(!args.is_present("no_verify"))
+        .progress(progress.clone());
+    for m in args.values_of("map").into_iter().flat_map(|v| v) {
+        // already validated by clap
+        let (from, to) = m.split_at(m.find('=').unwrap());
+        options = options.map_path(from, &to[1..]);
+    }
+    let check_result = |path: &Path, r: history::Result<()>| match r {
+        Ok(()) => Ok(()),
+        Err(history::Error::InvalidArgument) => {
+            error!("bkp: possible integrity violation found!");
+            error!("     invalid object type at path: {}",
+                   path.to_str().unwrap_or("<unprintable>"));
+            Ok(())
+        },
+        Err(e) => Err(CliError::from(e).context("cannot restore object"))
     };
 
+    // with no paths given, restore everything
+    if objects.is_empty() {
+        // restoring over the running system is rarely what's wanted
+        if args.value_of("into").is_none() &&
+                !confirm("Restore the entire snapshot over /?") {
+            println!("aborted");
+            return Ok(());
+        }
+
+        check_result(Path::new("/"), snapshot.restore(&base_path, &options))?;
+        progress.finish();
+        warn_unowned(&options);
+        return Ok(());
+    }
+
     // retrieve the objects we're interested in
     let objects: history::Result<Vec<_>> = objects.into_iter()
                                                   .map(|obj| snapshot.get(&obj).map(|r| (obj, r)))
                                                   .collect();
-    let objects = objects.unwrap_or_fail("cannot read stored objects");
+    let objects = objects.or_fail("cannot read stored objects")?;
     
     // warn about missing files, if any
     if objects.iter().any(|x| x.1.is_none()) {
@@ -277,23 +1910,9 @@ fn do_restore(args: &clap::ArgMatches, opts: &GlobalOptions) {
         }
         println!("");
 
-        use std::ascii::AsciiExt;
-        let abort = loop {
-            print!("Do you want to continue restoring? (y/n) ");
-            std::io::stdout().flush().unwrap();
-            let mut response = String::new();
-            std::io::stdin().read_line(&mut response).unwrap();
-            
-            match response.chars().next().map(|x| x.to_ascii_lowercase()) {
-                Some('y') => break false, // no abort
-                Some('n') => break true,  // abort
-                _         => {},          // ask again
-            }
-        };
-
-        if abort {
+        if !confirm("Do you want to continue restoring?") {
             println!("aborted");
-            return;
+            return Ok(());
         }
     }
 
@@ -302,58 +1921,89 @@ fn do_restore(args: &clap::ArgMatches, opts: &GlobalOptions) {
                                  .collect();
 
     // actually reconstruct them
-    let base_path = Path::new(args.value_of("into").unwrap_or("/"));
-    let options = history::RestoreOptions::new()
-        .overwrite(args.is_present("overwrite"))
-        .ignore_permissions(args.is_present("no_perms"));
     for (path, obj) in objects {
-        match obj.restore(&base_path, &options) {
-            Ok(()) => {},
-            Err(history::Error::InvalidArgument) => {
-                eprintln!("bkp: possible integrity violation found!");
-                eprintln!("     invalid object type at path: {}",
-                          path.to_str().unwrap_or("<unprintable>"));
-            },
-            Err(e) => fail_error("cannot restore object", e)
-        }
+        check_result(path, obj.restore_from(path, &base_path, &options))?;
+    }
+    progress.finish();
+    warn_unowned(&options);
+    Ok(())
+}
+
+/// Let the user know if restored files couldn't be given their stored owners
+fn warn_unowned(options: &history::RestoreOptions) {
+    let count = options.ownership_failures();
+    if count > 0 {
+        warn!("bkp: couldn't set the owner of {} restored paths", count);
+        warn!("     restoring ownership requires root privileges; setuid \
+               and setgid bits were cleared on them");
     }
 }
 
-fn load_config(pth: &Path) -> config::Config {
+fn load_config(pth: &Path) -> Result<config::Config, CliError> {
     let cfg = config::Config::load(&pth);
-    if let Err(e) = cfg {
-        if let config::ConfigErr::IOError(ref err) = e {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                err_write!("Creating new configuration file");
+    if let Err(config::ConfigErr::IOError(ref err)) = cfg {
+        if err.kind() == std::io::ErrorKind::NotFound {
+            info!("Creating new configuration file");
 
-                // try to create a new config
-                let cfg = config::Config::default();
-                cfg.save().ok().unwrap_or(());
-                return cfg
-            }
+            // try to create a new config
+            let cfg = config::Config::default();
+            cfg.save().ok().unwrap_or(());
+            return Ok(cfg)
         }
-        let errstr = match e {
-            config::ConfigErr::ParseError(x) => x,
-            config::ConfigErr::IOError(x) => String::from(x.description())
-        };
-        writeln!(std::io::stderr(),
-            "bkp: Cannot load config file: {}", errstr).unwrap();
-        std::process::exit(1);
     }
-    return cfg.unwrap();
-} 
+    cfg.or_fail("Cannot load config file")
+}
 
 fn main() {
+    if let Err(e) = run() {
+        match e {
+            // help and version output isn't an error
+            CliError::Args(ref e) if !e.use_stderr() => e.exit(),
+            CliError::Args(ref e) => error!("{}", e.message),
+            ref e                 => error!("bkp: {}", e)
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), CliError> {
     let opt_matches = clap_app!(bkp =>
         (version: "0.1")
         (author: "Noah Zentzis <nzentzis@gmail.com>")
         (about: "Automated system backup utility")
+        (after_help: "EXIT STATUS:
+    0  success
+    1  any other failure
+    2  invalid command-line arguments
+    3  config file or destination configuration problems
+    4  keystore, password, or key problems
+    5  a destination couldn't be reached or stopped responding
+    6  backup data is missing or damaged")
         (@arg CONFIG: -c --config +takes_value "Specifies a config file to use")
         (@arg DATADIR: -D --data-dir +takes_value "Specify the local data path")
+        (@arg KEYSTORE: -K --keystore +takes_value
+         "Specify the keystore path, overriding the config file. Defaults to \
+         a keystore inside the data path")
+        (@arg PASSWORD_COMMAND: --("password-command") +takes_value
+         conflicts_with[PASSWORD_FILE]
+         "Run a shell command to get the keystore password, using the first \
+         line of its output, rather than asking for it")
+        (@arg PASSWORD_FILE: --("password-file") +takes_value
+         "Read the keystore password from the first line of a file, or of \
+         standard input if given -, rather than asking for it")
         (@arg BACKEND: -t --target +takes_value
          "Override the default destination")
+        (@arg NODE_NAME: --("node-name") +takes_value {validate_node_name}
+         "Store and read snapshots under a different node name for this run, \
+         rather than the configured one")
         (@arg VERBOSE: -v --verbose "Enable verbose terminal output")
         (@arg QUIET: -q --quiet "Silence non-error terminal output")
+        (@arg LIMIT_RATE: --("limit-rate") +takes_value {validate_rate}
+         "Limit transfers to a given rate in bytes per second, e.g. 500k")
+        (@arg ADMIN_KEY: --("admin-key") +takes_value
+         "Read the admin key of append-only destinations from a file, allowing \
+         data to be removed from them. This guards against mistakes, not \
+         against anyone who can edit the config file.")
         (@subcommand dest =>
          (about: "Query and modify available backup destinations")
          (@subcommand add =>
@@ -364,7 +2014,56 @@ fn main() {
               "The new destination's URL" )
           (@arg user: -u --user +takes_value "Set the associated username")
           (@arg password: -p --password +takes_value
-           "Set the associated password"))
+           "Set the associated password")
+          (@arg reliable: --reliable +takes_value
+           possible_values(&["true", "false"])
+           "Whether the destination can be trusted to keep data safe")
+          (@arg upload_cost: --("upload-cost") +takes_value
+           {validate_cost} "Relative cost of uploading to the destination")
+          (@arg download_cost: --("download-cost") +takes_value
+           {validate_cost} "Relative cost of downloading from the destination")
+          (@arg strict_host_keys: --("strict-host-keys") +takes_value
+           possible_values(&["true", "false"])
+           "Whether to refuse SSH hosts whose keys aren't in known_hosts \
+            (the default)")
+          (@arg connect_timeout: --("connect-timeout") +takes_value
+           {validate_seconds} "Seconds to wait when connecting over SSH")
+          (@arg keepalive: --keepalive +takes_value {validate_seconds}
+           "Seconds between SSH keepalive messages, or 0 to disable them")
+          (@arg upload_limit: --("upload-limit") +takes_value {validate_rate}
+           "Maximum upload rate in bytes per second, or 0 for no limit")
+          (@arg download_limit: --("download-limit") +takes_value
+           {validate_rate}
+           "Maximum download rate in bytes per second, or 0 for no limit")
+          (@arg append_only: --("append-only") +takes_value
+           possible_values(&["true", "false"])
+           "Whether to refuse removing anything stored on the destination"))
+         (@subcommand set =>
+          (about: "Change an existing destination's options")
+          (@arg name: +required "The destination to modify")
+          (@arg reliable: --reliable +takes_value
+           possible_values(&["true", "false"])
+           "Whether the destination can be trusted to keep data safe")
+          (@arg upload_cost: --("upload-cost") +takes_value
+           {validate_cost} "Relative cost of uploading to the destination")
+          (@arg download_cost: --("download-cost") +takes_value
+           {validate_cost} "Relative cost of downloading from the destination")
+          (@arg strict_host_keys: --("strict-host-keys") +takes_value
+           possible_values(&["true", "false"])
+           "Whether to refuse SSH hosts whose keys aren't in known_hosts \
+            (the default)")
+          (@arg connect_timeout: --("connect-timeout") +takes_value
+           {validate_seconds} "Seconds to wait when connecting over SSH")
+          (@arg keepalive: --keepalive +takes_value {validate_seconds}
+           "Seconds between SSH keepalive messages, or 0 to disable them")
+          (@arg upload_limit: --("upload-limit") +takes_value {validate_rate}
+           "Maximum upload rate in bytes per second, or 0 for no limit")
+          (@arg download_limit: --("download-limit") +takes_value
+           {validate_rate}
+           "Maximum download rate in bytes per second, or 0 for no limit")
+          (@arg append_only: --("append-only") +takes_value
+           possible_values(&["true", "false"])
+           "Whether to refuse removing anything stored on the destination"))
          (@subcommand list =>
           (about: "List the available destinations")
           (@arg no_groups: -n --("no-groups")
@@ -373,23 +2072,79 @@ fn main() {
           (about: "Remove an existing destination")
           (@arg name: +required "The destination name to remove")
           (@arg scrub: -S --scrub "Remove existing backups from the target"))
+         (@subcommand group =>
+          (about: "Manage groups of destinations")
+          (@subcommand add =>
+           (about: "Create a new destination group")
+           (@arg name: +required "The name of the new group")
+           (@arg member: +required ... "Destinations to include in the group"))
+          (@subcommand remove =>
+           (about: "Remove a destination group")
+           (@arg name: +required "The group to remove"))
+          (@subcommand members =>
+           (about: "Change which destinations are in a group")
+           (@arg name: +required "The group to modify")
+           (@arg add: -a --add +takes_value +multiple number_of_values(1)
+            "Add a destination to the group")
+           (@arg remove: -r --remove +takes_value +multiple number_of_values(1)
+            "Remove a destination from the group")))
+         (@subcommand unlock =>
+          (about: "Forcibly remove a destination's lock, if a crashed process \
+                   left it behind")
+          (@arg name: +required "The destination to unlock"))
          (@subcommand test =>
           (about: "Test connectivity to a destination")
+          (@arg verbose: -v --verbose
+           "Show how each stage of connecting went, and how long it took")
           (@arg name: +required * "The destination to test")))
+        (@subcommand doctor =>
+         (about: "Check that the keystore and configured destinations are \
+                  usable"))
+        (@subcommand keystore =>
+         (about: "Manage the local keystore")
+         (@subcommand passwd =>
+          (about: "Change the keystore password"))
+         (@subcommand export =>
+          (about: "Write a password-protected copy of every key to a file")
+          (@arg file: +required "The file to write"))
+         (@subcommand import =>
+          (about: "Recreate the keystore from an exported copy")
+          (@arg file: +required "The exported copy to read")))
+        (@subcommand node =>
+         (about: "Manage the name this machine's snapshots are stored under")
+         (@subcommand rename =>
+          (about: "Move a node's snapshots over to a new name")
+          (@arg old: +required "The current node name")
+          (@arg new: +required {validate_node_name} "The new node name")))
         (@subcommand test =>
          (about: "Test integrity of existing backups")
+         (alias: "fsck")
          (@arg profile: +takes_value
           possible_values(&["quick", "normal", "slow", "exhaustive"])
           default_value("normal")
           "The test profile to run")
          (@arg all: -a --all
-          "Test backups from all machines rather than just this one"))
+          "Test backups from all machines rather than just this one")
+         (@arg snapshot: -s --snapshot +takes_value
+          "Download and verify every block of the snapshot with the given ID")
+         (@arg repair: --repair requires[snapshot]
+          "Upload missing or corrupt blocks of the snapshot again, from the \
+          local files they were stored from if those haven't changed")
+         (@arg chunk_size: -C --("chunk-size") +takes_value requires[repair]
+          {positive_count}
+          "Chunk size the snapshot's files were stored with, if not the \
+          default")
+         (@arg json: --json conflicts_with[snapshot]
+          "Print the results as JSON"))
         (@subcommand stat =>
          (about: "Show backup statistics")
          (@arg dest: +takes_value ...
           "Only show data about the given destinations")
          (@arg remote: -r --remote
-          "Query remote servers, bypassing local caches"))
+          "Query remote servers, bypassing local caches")
+         (@arg snapshots: -s --snapshots
+          "Also show how much space each snapshot uses")
+         (@arg json: --json "Print the statistics as JSON"))
         (@subcommand clean =>
          (about: "Remove backup data matching specific criteria. \
           All given predicates must match in order for data to be removed.")
@@ -403,33 +2158,220 @@ fn main() {
            possible_values(&["diff", "full"])
            "Match data in snapshots with type")
           (@arg older_than: -o --("older-than") +takes_value
-           "Match data older than a certain age")
+           {|s| {util::parse_duration(&s).map(|_| ())
+               .ok_or(String::from("Not a valid duration"))}}
+           "Match data older than a certain age (e.g. 30d, 1w12h)")
           (@arg newer_than: -N --("newer-than") +takes_value
-           "Match data newer than a certain age")
+           {|s| {util::parse_duration(&s).map(|_| ())
+               .ok_or(String::from("Not a valid duration"))}}
+           "Match data newer than a certain age (e.g. 30d, 1w12h)")
           (@arg exists: -e --exists +takes_value
            possible_values(&["yes", "no"])
-           "Match data based on whether it exists on the host")))
+           "Match data based on whether it exists on the host")
+          (@arg keep_last: --("keep-last") +takes_value
+           {positive_count}
+           "Match snapshots other than the N newest")
+          (@arg keep_daily: --("keep-daily") +takes_value
+           {positive_count}
+           "Match snapshots other than the newest of each of the last N days \
+           with any. May be combined with the other --keep options, and \
+           snapshots kept by any of them are kept")
+          (@arg keep_weekly: --("keep-weekly") +takes_value
+           {positive_count}
+           "Match snapshots other than the newest of each of the last N weeks \
+           with any")
+          (@arg keep_monthly: --("keep-monthly") +takes_value
+           {positive_count}
+           "Match snapshots other than the newest of each of the last N months \
+           with any")))
+        (@subcommand gc =>
+         (about: "Remove data which isn't referenced by any machine's snapshots")
+         (@arg remote: +required "Remote to collect garbage on")
+         (@arg dry_run: -n --("dry-run")
+          "Don't remove anything, just show how much would be reclaimed"))
+        (@subcommand diff =>
+         (about: "Show the paths which changed between two snapshots")
+         (@arg remote: +required "Remote to compare snapshots from")
+         (@arg from: +takes_value
+          {|s| {util::parse_time(&s, std::time::SystemTime::now()).map(|_| ())
+              .ok_or(String::from("Not a valid date/time or age"))}}
+          "Compare from the most recent snapshot before this date/time")
+         (@arg to: +takes_value
+          {|s| {util::parse_time(&s, std::time::SystemTime::now()).map(|_| ())
+              .ok_or(String::from("Not a valid date/time or age"))}}
+          "Compare to the most recent snapshot before this date/time")
+         (@arg json: --json "Print the changes as JSON"))
+        (@subcommand recover =>
+         (about: "Reset a missing or broken head to the newest intact snapshot")
+         (@arg remote: +required "Remote to recover")
+         (@arg profile: -p --profile +takes_value
+          possible_values(&["quick", "normal", "slow", "exhaustive"])
+          default_value("normal")
+          "The integrity test profile candidate snapshots must pass")
+         (@arg yes: -y --yes "Don't ask before resetting the head"))
+        (@subcommand snapshots =>
+         (about: "List the snapshots stored for this machine, newest first")
+         (@arg remote: +required "Remote to list snapshots from")
+         (@arg limit: -n --limit +takes_value
+          {|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())}
+          "Only show the most recent N snapshots")
+         (@arg changes: -c --changes
+          "Show how many paths changed relative to each snapshot's parent")
+         (@arg from: -f --from +takes_value
+          "List the snapshots of another machine, given its node name")
+         (@arg json: --json "Print the list as JSON"))
+        (@subcommand heads =>
+         (about: "List the newest snapshot of every machine storing snapshots \
+                  on a destination")
+         (@arg remote: +required "Remote to list heads from"))
+        (@subcommand ls =>
+         (about: "List the contents of a stored directory")
+         (@arg remote: +required "Remote to list files from")
+         (@arg path: +takes_value "Stored path to list, defaulting to /")
+         (@arg as_of: -t --time +takes_value
+          {|s| {util::parse_time(&s, std::time::SystemTime::now()).map(|_| ())
+              .ok_or(String::from("Not a valid date/time or age"))}}
+          "List from the most recent snapshot before given date/time")
+         (@arg long: -l --long
+          "Show type, permissions, owner, size and mtime of each entry")
+         (@arg recursive: -R --recursive "List subdirectories recursively"))
+        (@subcommand cat =>
+         (about: "Write the contents of a stored file to stdout")
+         (@arg remote: +required "Remote to read the file from")
+         (@arg path: +required "Stored path of the file")
+         (@arg as_of: -t --time +takes_value
+          {|s| {util::parse_time(&s, std::time::SystemTime::now()).map(|_| ())
+              .ok_or(String::from("Not a valid date/time or age"))}}
+          "Read from the most recent snapshot before given date/time"))
+        (@subcommand mount =>
+         (about: "Mount a snapshot as a read-only filesystem")
+         (@arg remote: +required "Remote to read the snapshot from")
+         (@arg mountpoint: +required "Directory to mount the snapshot on")
+         (@arg as_of: -t --time +takes_value
+          {|s| {util::parse_time(&s, std::time::SystemTime::now()).map(|_| ())
+              .ok_or(String::from("Not a valid date/time or age"))}}
+          "Mount the most recent snapshot before given date/time"))
+        (@subcommand repack =>
+         (about: "Consolidate loose metadata objects into packfiles")
+         (@arg dest: +takes_value ...
+          "Only repack the given destinations"))
         (@subcommand snap =>
          (about: "Take a snapshot of local files")
          (@arg remote: +takes_value "Remote to store data in")
          (@arg local: +takes_value ... "Files or directories to snapshot")
          (@arg no_trust_mtime: -T --("no-trust-mtime")
-          "Use content hashes to check for file changes rather than FS's mtime"))
+          "Use content hashes to check for file changes rather than FS's mtime")
+         (@arg chunk_size: -C --("chunk-size") +takes_value
+          {|s| {s.parse::<usize>().map_err(|e| e.to_string())
+              .and_then(|n| if n > 0 { Ok(()) }
+                            else { Err(String::from("must be nonzero")) })}}
+          "Target size in bytes of stored file chunks")
+         (@arg no_xattrs: -X --("no-xattrs")
+          "Don't record extended attributes of stored files")
+         (@arg no_atime: --("no-atime")
+          "Don't record access times, so that files which were only read \
+          since the last snapshot don't need their metadata stored again")
+         (@arg compress: --compress +takes_value {validate_compression}
+          "Compress new data with the given algorithm (none, deflate or zstd) \
+          instead of the destination's own")
+         (@arg compress_level: --("compress-level") +takes_value
+          {|s| s.parse::<u32>().map(|_| ()).map_err(|e| e.to_string())}
+          "Compression level to use for new data")
+         (@arg no_compress_ext: --("no-compress-ext") +takes_value
+          "Store files with these comma-separated extensions, e.g. \
+          jpg,mp4,zip, without compressing them, instead of those in the \
+          config file")
+         (@arg rescan: --rescan
+          "Rebuild the local index of blocks stored on the remote first")
+         (@arg dry_run: -n --("dry-run")
+          "List new and changed files without storing anything")
+         (@arg estimate: --estimate
+          "Scan for how much data there is to upload before uploading any, \
+          to show the time remaining. Changed files are read twice.")
+         (@arg exclude: -x --exclude +takes_value +multiple number_of_values(1)
+          "Leave out paths matching a pattern (may be repeated)")
+         (@arg one_file_system: --("one-file-system")
+          "Don't descend into other filesystems mounted below the given \
+          paths")
+         (@arg dereference: -L --dereference
+          "Follow symlinks, storing the files and directories they point to \
+          rather than the links themselves")
+         (@arg exclude_caches: --("exclude-caches")
+          "Leave out the contents of directories tagged with a CACHEDIR.TAG \
+          file, keeping only the tag")
+         (@arg exclude_if_present: --("exclude-if-present") +takes_value
+          +multiple number_of_values(1)
+          "Leave out the contents of directories containing a file with this \
+          name, keeping only that file (may be repeated)")
+         (@arg prune_empty_dirs: --("prune-empty-dirs")
+          "Leave out directories with nothing stored in them, such as ones \
+          whose contents were all excluded")
+         (@arg verify: --verify
+          "Check that everything the new snapshot refers to is stored on the \
+          remote before committing it"))
         (@subcommand restore =>
          (about: "Restore local files from backup")
-         (@arg remote: +required "Remote to restore from")
-         (@arg local: ... min_values(1) "Files or directories to restore")
+         (@arg remote: required_unless[any] "Remote to restore from")
+         (@arg any: -A --any
+          "Restore from whichever destination holds the paths. All positional \
+          arguments are then treated as paths")
+         (@arg local: ...
+          "Files or directories to restore. If none are given, the entire \
+          snapshot is restored")
          (@arg as_of: -t --time +takes_value
+          {|s| {util::parse_time(&s, std::time::SystemTime::now()).map(|_| ())
+              .ok_or(String::from("Not a valid date/time or age"))}}
           "Restore to most recent snapshot before given date/time")
-         (@arg overwrite: -o --overwrite "Overwrite existing local files")
-         (@arg from: -f --from +takes_value "Restore data from another machine")
+         (@arg overwrite: -o --overwrite +takes_value min_values(0)
+          require_equals(true) possible_values(&["always", "never", "if-newer"])
+          "Overwrite existing local files, optionally only if older than \
+          the stored copy")
+         (@arg from: -f --from +takes_value
+          "Restore data snapshotted by another machine, given its node name")
          (@arg no_perms: -p --("no-perms")
           "Don't restore filesystem permissions")
          (@arg no_attrs: -a --("no-attrs") "Don't restore file metadata")
+         (@arg no_verify: --("no-verify")
+          "Don't check restored data against the hashes it was stored under")
          (@arg into: -i --into conflicts_with[overwrite] +takes_value
           "Restore to a given path")
+         (@arg map: -m --map +takes_value +multiple number_of_values(1)
+          {|s| if s.find('=').map_or(false, |i| i > 0 && i + 1 < s.len()) {
+              Ok(()) } else { Err(String::from("expected <stored>=<local>")) }}
+          "Restore a stored path, and everything under it, to a different \
+          local path, given as <stored>=<local> (may be repeated). Where \
+          mappings overlap, the longest stored path wins")
          )
-        ).get_matches();
+        )
+        // the macro only takes identifiers as subcommand names
+        .subcommand(clap::SubCommand::with_name("check-replication")
+            .about("List blocks which are only stored on unreliable members \
+                    of a group")
+            .arg(clap::Arg::with_name("group").required(true)
+                 .help("Group to check"))
+            .arg(clap::Arg::with_name("fix").long("fix")
+                 .help("Copy those blocks to the group's reliable members")))
+        .subcommand(clap::SubCommand::with_name("config")
+            .about("Query and modify settings in the config file")
+            .subcommand(clap::SubCommand::with_name("node-name")
+                .about("Show or change the name this machine's snapshots are \
+                        stored under")
+                .after_help("Each node's snapshots form their own chain, so \
+                             after changing the name, new snapshots start a \
+                             new chain unless --migrate is given.")
+                .arg(clap::Arg::with_name("name").validator(validate_node_name)
+                     .help("The new node name"))
+                .arg(clap::Arg::with_name("migrate").long("migrate")
+                     .requires("name")
+                     .help("Move existing snapshots over to the new name, \
+                            like `bkp node rename`"))))
+        .get_matches_safe()?;
+
+    // --quiet wins if both are given
+    log::set_level(if opt_matches.is_present("QUIET") { log::Level::Quiet }
+                   else if opt_matches.is_present("VERBOSE") {
+                       log::Level::Verbose
+                   } else { log::Level::Normal });
 
     // load a config file
     let config_path = opt_matches
@@ -437,7 +2379,9 @@ fn main() {
         .map(Path::new)
         .map(Path::to_path_buf)
         .unwrap_or(std::env::home_dir().unwrap().join(".bkprc"));
-    let cfg = load_config(&config_path);
+    let cfg = load_config(&config_path)?;
+    let node_name = opt_matches.value_of("NODE_NAME").map(String::from)
+        .unwrap_or(cfg.node_name.clone());
 
     // create the data dir if needed
     let data_dir = opt_matches.value_of("DATADIR").map(Path::new)
@@ -446,41 +2390,47 @@ fn main() {
     if let Err(e) = fs::metadata(&data_dir) {
         if e.kind() == std::io::ErrorKind::NotFound {
             if fs::create_dir(&data_dir).is_err() {
-                writeln!(std::io::stderr(), "bkp: Cannot create directory: {}",
-                    data_dir.display()).unwrap();
-                std::process::exit(1);
+                return Err(CliError::Failure(format!(
+                    "Cannot create directory: {}", data_dir.display())));
             }
         } else {
-            writeln!(std::io::stderr(), "bkp: Cannot access directory: {}",
-                data_dir.display()).unwrap();
-            std::process::exit(1);
+            return Err(CliError::Failure(format!(
+                "Cannot access directory: {}", data_dir.display())));
         }
     }
 
     // open the key store
-    let kspath = data_dir.join("keystore");
-    let ks = match fs::metadata(&kspath) {
-        Ok(_) => match keys::Keystore::open(&kspath) {
-            Ok(k) => k,
-            Err(e) => {
-                err_write!("bkp: Cannot open keystore: {}", e.description());
-                std::process::exit(1);
-            }
-        },
+    let kspath = opt_matches.value_of("KEYSTORE").map(PathBuf::from)
+        .or_else(|| cfg.keystore_path())
+        .unwrap_or(data_dir.join("keystore"));
+    if let ("keystore", Some(m)) = opt_matches.subcommand() {
+        if let ("import", Some(m)) = m.subcommand() {
+            return do_keystore_import(m, &kspath);
+        }
+    }
+    let password_cmd = opt_matches.value_of("PASSWORD_COMMAND");
+    let password = if let Some(cmd) = password_cmd {
+        keys::PasswordSource::Command(cmd.to_owned())
+    } else if let Some(f) = opt_matches.value_of_os("PASSWORD_FILE") {
+        keys::PasswordSource::File(PathBuf::from(f))
+    } else {
+        keys::PasswordSource::Prompt
+    };
+    if opt_matches.subcommand_name() == Some("doctor") {
+        return do_doctor(&cfg, &node_name, &data_dir, &kspath, password);
+    }
+    let mut ks = match fs::metadata(&kspath) {
+        Ok(_) => keys::Keystore::open(&kspath)
+            .or_fail("Cannot open keystore")?,
         Err(e) => if e.kind() == std::io::ErrorKind::NotFound {
-            match keys::Keystore::create(&kspath) {
-                Ok(k) => k,
-                Err(e) => {
-                    err_write!("bkp: Cannot create keystore: {}", e.description());
-                    std::process::exit(1);
-                }
-            }
+            keys::Keystore::create(&kspath)
+                .or_fail("Cannot create keystore")?
         } else {
-            writeln!(std::io::stderr(), "bkp: Cannot access keystore: {}",
-                kspath.display()).unwrap();
-            std::process::exit(1);
+            return Err(CliError::Failure(format!(
+                "Cannot access keystore: {}", kspath.display())));
         }
     };
+    ks.set_password_source(password);
 
     // parse global flags
     let mut global_flags = GlobalOptions {
@@ -488,17 +2438,61 @@ fn main() {
         verbose: opt_matches.is_present("VERBOSE"),
         quiet: opt_matches.is_present("QUIET"),
         data_dir: data_dir,
-        keystore: ks
+        keystore: ks,
+        limit_rate: opt_matches.value_of("LIMIT_RATE")
+                               .and_then(util::parse_size),
+        compression: None,
+        compression_level: None,
+        node_name: node_name,
+        admin_key: match opt_matches.value_of_os("ADMIN_KEY") {
+            Some(p) => Some(read_admin_key(Path::new(p))?),
+            None    => None
+        }
     };
 
+    // snapshots stay under the configured name, but if the machine has been
+    // renamed the user probably wants them to follow
+    let overridden = opt_matches.is_present("NODE_NAME");
+    if let Some(host) = global_flags.cfg.renamed_host() {
+        let managing = match opt_matches.subcommand_name() {
+            Some("node") | Some("config") => true,
+            _                             => false
+        };
+        if !overridden && !managing {
+            warn!("bkp: warning: hostname '{}' differs from node name '{}'; \
+                   use `bkp node rename {} {}` to switch names",
+                  host, global_flags.cfg.node_name,
+                  global_flags.cfg.node_name, host);
+        }
+    }
+
     // figure out what to do
     match opt_matches.subcommand() {
-        ("", _) => { println!("bkp: No subcommand specified"); },
+        ("", _) => Err(CliError::Usage(
+                String::from("No subcommand specified"))),
         ("dest", Some(m)) => do_dest(m, &mut global_flags),
+        ("keystore", Some(m)) => do_keystore(m, &global_flags),
+        ("node", Some(m)) => do_node(m, &mut global_flags),
+        ("config", Some(m)) => do_config(m, &mut global_flags),
         ("test", Some(m)) => do_test(m, &global_flags),
         ("stat", Some(m)) => do_stat(m, &global_flags),
         ("clean", Some(m)) => do_clean(m, &global_flags),
-        ("snap", Some(m)) => do_snap(m, &global_flags),
+        ("repack", Some(m)) => do_repack(m, &global_flags),
+        ("gc", Some(m)) => do_gc(m, &global_flags),
+        ("check-replication", Some(m)) => do_check_replication(m,
+                                                               &global_flags),
+        ("diff", Some(m)) => do_diff(m, &global_flags),
+        ("snapshots", Some(m)) => do_snapshots(m, &global_flags),
+        ("heads", Some(m)) => do_heads(m, &global_flags),
+        ("recover", Some(m)) => do_recover(m, &global_flags),
+        ("ls", Some(m)) => do_ls(m, &global_flags),
+        ("cat", Some(m)) => do_cat(m, &global_flags),
+        #[cfg(feature = "mount")]
+        ("mount", Some(m)) => do_mount(m, &global_flags),
+        #[cfg(not(feature = "mount"))]
+        ("mount", Some(_)) => Err(CliError::Usage(
+                String::from("bkp was built without FUSE support"))),
+        ("snap", Some(m)) => do_snap(m, &mut global_flags),
         ("restore", Some(m)) => do_restore(m, &global_flags),
         (_, _) => panic!("No subcommand handler found!")
     }
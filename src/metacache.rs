@@ -0,0 +1,118 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+
+use metadata::{IdentityTag, MetaObject};
+use remote::{Backend, BackendResult};
+
+/// Number of decrypted metadata objects kept around by default
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+struct Entries {
+    /// Cached objects, and when each was last used
+    objects: HashMap<IdentityTag, (u64, MetaObject)>,
+
+    /// Cached objects' tags, ordered by when they were last used
+    used: BTreeMap<u64, IdentityTag>
+}
+
+/// A cache of the most recently used metadata objects read from a backend.
+///
+/// Objects are identified by their contents, so a cached object never goes
+/// stale unless it's deleted from the backend.
+pub struct MetaCache {
+    capacity: usize,
+    entries: RefCell<Entries>,
+
+    /// Incremented on every use, to order entries by when they were last used
+    clock: Cell<u64>
+}
+
+impl MetaCache {
+    pub fn new(capacity: usize) -> MetaCache {
+        MetaCache {
+            capacity: capacity,
+            entries: RefCell::new(Entries { objects: HashMap::new(),
+                                            used: BTreeMap::new() }),
+            clock: Cell::new(0)
+        }
+    }
+
+    /// Read an object, only going to the backend if it isn't cached
+    pub fn read(&self, backend: &Backend, tag: &IdentityTag)
+            -> BackendResult<MetaObject> {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+
+        let mut entries = self.entries.borrow_mut();
+        let last_used = entries.objects.get_mut(tag).map(|e| {
+            let last = e.0;
+            e.0 = now;
+            (last, e.1.clone())
+        });
+        if let Some((last, obj)) = last_used {
+            entries.used.remove(&last);
+            entries.used.insert(now, *tag);
+            return Ok(obj);
+        }
+
+        let obj = backend.read_meta(tag)?;
+        if self.capacity == 0 { return Ok(obj); }
+        if entries.objects.len() >= self.capacity {
+            let oldest = entries.used.iter().next().map(|(&t, &id)| (t, id));
+            if let Some((t, id)) = oldest {
+                entries.used.remove(&t);
+                entries.objects.remove(&id);
+            }
+        }
+        entries.objects.insert(*tag, (now, obj.clone()));
+        entries.used.insert(now, *tag);
+        Ok(obj)
+    }
+
+    /// Forget an object, for when it's deleted from the backend
+    pub fn remove(&self, tag: &IdentityTag) {
+        let mut entries = self.entries.borrow_mut();
+        if let Some((t, _)) = entries.objects.remove(tag) {
+            entries.used.remove(&t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metacache::MetaCache;
+    use metadata::{FSMetadata, MetaObject};
+    use remote::Backend;
+    use remote::memory::MemoryBackend;
+
+    fn file(name: &str) -> MetaObject {
+        MetaObject::file(name, FSMetadata::default(), Vec::new())
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let mut backend = MemoryBackend::new();
+        let reads = backend.meta_reads.clone();
+        let tags = ["a", "b", "c"].iter()
+            .map(|n| backend.write_meta(&file(n)).unwrap())
+            .collect::<Vec<_>>();
+        let cache = MetaCache::new(2);
+
+        assert_eq!(cache.read(&backend, &tags[0]).unwrap(), file("a"));
+        cache.read(&backend, &tags[1]).unwrap();
+        cache.read(&backend, &tags[0]).unwrap();
+        assert_eq!(reads.get(), 2);
+
+        // b is the least recently used, so it makes room for c
+        cache.read(&backend, &tags[2]).unwrap();
+        cache.read(&backend, &tags[0]).unwrap();
+        assert_eq!(reads.get(), 3);
+        cache.read(&backend, &tags[1]).unwrap();
+        assert_eq!(reads.get(), 4);
+
+        // removed objects are read from the backend again
+        cache.remove(&tags[1]);
+        cache.read(&backend, &tags[1]).unwrap();
+        assert_eq!(reads.get(), 5);
+    }
+}
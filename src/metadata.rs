@@ -1,34 +1,172 @@
 extern crate ring;
 extern crate byteorder;
+extern crate xattr;
+extern crate libc;
 
 use std::time;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::default::Default;
 use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::MetadataExt;
 use metadata::byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use util::{Hasher, DevNull};
 
 pub const IDENTITY_LEN: usize = ring::digest::SHA256_OUTPUT_LEN;
-pub type IdentityTag = [u8; IDENTITY_LEN];
+
+/// Number of leading zero bytes marking a tag as a hole rather than a block
+const HOLE_PREFIX_LEN: usize = IDENTITY_LEN - 8;
+
+/// The hash identifying a stored block or metadata object by its contents.
+///
+/// Tags are written out as lowercase hex wherever they need to be human
+/// readable, including the names of stored objects.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IdentityTag([u8; IDENTITY_LEN]);
+
+impl IdentityTag {
+    /// Wrap a raw hash as an identity tag
+    pub fn from_bytes(bytes: [u8; IDENTITY_LEN]) -> Self {
+        IdentityTag(bytes)
+    }
+
+    /// Build a tag from a byte slice, returning `None` if it's the wrong length
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != IDENTITY_LEN { return None; }
+        let mut r = [0u8; IDENTITY_LEN];
+        r.copy_from_slice(bytes);
+        Some(IdentityTag(r))
+    }
+
+    /// Read a tag's raw bytes from a stream
+    pub fn read_from<R: Read>(f: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; IDENTITY_LEN];
+        f.read_exact(&mut buf)?;
+        Ok(IdentityTag(buf))
+    }
+
+    /// Get the tag's raw bytes
+    pub fn as_bytes(&self) -> &[u8; IDENTITY_LEN] {
+        &self.0
+    }
+
+    /// Get the first `len` hex digits of the tag, for abbreviated display
+    pub fn hex_prefix(&self, len: usize) -> String {
+        let mut s = self.to_string();
+        s.truncate(len);
+        s
+    }
+
+    /// Get the name of the directory objects with this tag are stored under,
+    /// which is the hex form of its first byte
+    pub fn dir_prefix(&self) -> String {
+        self.hex_prefix(2)
+    }
+
+    /// Make the marker standing in for a run of `len` zero bytes in a file's
+    /// chunk list.
+    ///
+    /// Holes aren't stored anywhere. Their markers start with bytes no real
+    /// hash could plausibly share, followed by the run's length.
+    pub fn hole(len: u64) -> Self {
+        assert!(len > 0, "holes must be nonempty");
+        let mut r = [0u8; IDENTITY_LEN];
+        (&mut r[HOLE_PREFIX_LEN..]).write_u64::<LittleEndian>(len).unwrap();
+        IdentityTag(r)
+    }
+
+    /// If this is a hole marker, get the length of the hole
+    pub fn hole_len(&self) -> Option<u64> {
+        if self.0[..HOLE_PREFIX_LEN].iter().any(|&b| b != 0) { return None; }
+        match (&self.0[HOLE_PREFIX_LEN..]).read_u64::<LittleEndian>() {
+            Ok(0)  => None,
+            Ok(n)  => Some(n),
+            Err(_) => None
+        }
+    }
+
+    /// Check whether the tag's hex form starts with a given (case-insensitive)
+    /// prefix
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        self.to_string().starts_with(&prefix.to_lowercase())
+    }
+}
+
+impl fmt::Display for IdentityTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for IdentityTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IdentityTag({})", self)
+    }
+}
+
+/// The error returned when a string isn't a valid hex identity tag
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ParseTagError;
+
+impl fmt::Display for ParseTagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not a {}-digit hex identity tag", IDENTITY_LEN * 2)
+    }
+}
+
+impl FromStr for IdentityTag {
+    type Err = ParseTagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != IDENTITY_LEN * 2 || !s.chars().all(|c| c.is_digit(16)) {
+            return Err(ParseTagError);
+        }
+
+        // slicing by byte offset is safe now that we know it's all ASCII
+        let mut r = [0u8; IDENTITY_LEN];
+        for (i, b) in r.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i*2..i*2 + 2], 16).unwrap();
+        }
+        Ok(IdentityTag(r))
+    }
+}
 
 /// Convert the given digest into an identity tag.
 /// 
 /// Panics if the digest isn't the right size.
 pub fn tag_from_digest(d: ring::digest::Digest) -> IdentityTag {
-    if d.algorithm().output_len != IDENTITY_LEN {
-        panic!("Cannot generate identity from incorrect-length digest");
-    }
-    let hash = d.as_ref();
-    let mut r = [0u8; IDENTITY_LEN];
-    for i in 0..IDENTITY_LEN { r[i] = hash[i]; }
-    r
+    IdentityTag::from_slice(d.as_ref()).expect(
+        "Cannot generate identity from incorrect-length digest")
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+/// Flag set in the type byte of FS objects whose metadata includes nanosecond
+/// timestamp components. Objects written by older versions lack it.
+const PRECISE_TIMES: u8 = 0x80;
+
+/// Flag set in the type byte of FS objects whose metadata is followed by a list
+/// of extended attributes
+const HAS_XATTRS: u8 = 0x40;
+
+/// Flag set in the type byte of file objects which record the file's size
+const HAS_SIZE: u8 = 0x20;
+
+/// Flag set in the type byte of file objects whose chunk list includes hole
+/// markers. Older versions don't know about holes, so this makes them refuse
+/// the object rather than go looking for blocks that don't exist.
+const HAS_HOLES: u8 = 0x10;
+
+/// Mask of the type byte bits holding metadata format flags
+const FORMAT_FLAGS: u8 = PRECISE_TIMES | HAS_XATTRS | HAS_SIZE | HAS_HOLES;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct FSMetadata {
     /// Modification time
     pub mtime: time::SystemTime,
@@ -44,37 +182,120 @@ pub struct FSMetadata {
 
     /// UNIX mode bits
     pub mode: u32,
+
+    /// Extended attributes as (name, value) pairs, sorted by name
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 impl FSMetadata {
-    fn load<R: Read>(f: &mut R) -> io::Result<FSMetadata> {
-        let mt = time::UNIX_EPOCH +
-            time::Duration::from_secs(f.read_u64::<LittleEndian>()?);
-        let at = time::UNIX_EPOCH +
-            time::Duration::from_secs(f.read_u64::<LittleEndian>()?);
+    /// Load metadata in the format indicated by the object's type byte flags
+    fn load<R: Read>(f: &mut R, flags: u8) -> io::Result<FSMetadata> {
+        let mt_secs = f.read_u64::<LittleEndian>()?;
+        let at_secs = f.read_u64::<LittleEndian>()?;
         let uid = f.read_u32::<LittleEndian>()? as u32;
         let gid = f.read_u32::<LittleEndian>()? as u32;
         let mode = f.read_u16::<LittleEndian>()? as u32;
 
-        Ok(FSMetadata { mtime: mt, atime: at, uid, gid, mode })
+        // older objects only have whole seconds
+        let (mt_nanos, at_nanos) = if flags & PRECISE_TIMES != 0 {
+            (f.read_u32::<LittleEndian>()?, f.read_u32::<LittleEndian>()?)
+        } else {
+            (0, 0)
+        };
+        if mt_nanos >= 1_000_000_000 || at_nanos >= 1_000_000_000 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "Invalid timestamp"));
+        }
+
+        let mut xattrs = Vec::new();
+        if flags & HAS_XATTRS != 0 {
+            let count = f.read_u16::<LittleEndian>()?;
+            for _ in 0..count {
+                let mut name = vec![0u8; f.read_u16::<LittleEndian>()? as usize];
+                f.read_exact(&mut name)?;
+                let mut value = vec![0u8; f.read_u32::<LittleEndian>()? as usize];
+                f.read_exact(&mut value)?;
+                xattrs.push((name, value));
+            }
+        }
+
+        let mt = time::UNIX_EPOCH + time::Duration::new(mt_secs, mt_nanos);
+        let at = time::UNIX_EPOCH + time::Duration::new(at_secs, at_nanos);
+        Ok(FSMetadata { mtime: mt, atime: at, uid, gid, mode, xattrs })
+    }
+
+    /// The type byte flags describing how this metadata is encoded
+    fn flags(&self) -> u8 {
+        if self.xattrs.is_empty() { PRECISE_TIMES }
+        else { PRECISE_TIMES | HAS_XATTRS }
     }
 
     fn save<W: Write>(&self, f: &mut W) -> io::Result<()> {
-        match self.mtime.duration_since(time::UNIX_EPOCH) {
-            Err(_) => f.write_u64::<LittleEndian>(0)?, // clamp to the epoch
-            Ok(x)  => f.write_u64::<LittleEndian>(x.as_secs())?
-        }
-        match self.atime.duration_since(time::UNIX_EPOCH) {
-            Err(_) => f.write_u64::<LittleEndian>(0)?, // clamp to the epoch
-            Ok(x)  => f.write_u64::<LittleEndian>(x.as_secs())?
-        }
+        // clamp to the epoch
+        let zero = time::Duration::from_secs(0);
+        let mt = self.mtime.duration_since(time::UNIX_EPOCH).unwrap_or(zero);
+        let at = self.atime.duration_since(time::UNIX_EPOCH).unwrap_or(zero);
 
+        f.write_u64::<LittleEndian>(mt.as_secs())?;
+        f.write_u64::<LittleEndian>(at.as_secs())?;
         f.write_u32::<LittleEndian>(self.uid as u32)?;
         f.write_u32::<LittleEndian>(self.gid as u32)?;
-        f.write_u16::<LittleEndian>(self.mode as u16)
+        f.write_u16::<LittleEndian>(self.mode as u16)?;
+        f.write_u32::<LittleEndian>(mt.subsec_nanos())?;
+        f.write_u32::<LittleEndian>(at.subsec_nanos())?;
+
+        if !self.xattrs.is_empty() {
+            f.write_u16::<LittleEndian>(self.xattrs.len() as u16)?;
+            for &(ref name, ref value) in self.xattrs.iter() {
+                f.write_u16::<LittleEndian>(name.len() as u16)?;
+                f.write_all(name)?;
+                f.write_u32::<LittleEndian>(value.len() as u32)?;
+                f.write_all(value)?;
+            }
+        }
+        Ok(())
     }
 }
 
+/// Read the extended attributes of the file at `path`, without following
+/// symlinks.
+///
+/// Filesystems without xattr support, and attributes which can't be read, are
+/// treated as having no attributes rather than failing.
+pub fn read_xattrs(path: &Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let names = match xattr::list(path) {
+        Ok(n)  => n,
+        Err(_) => return Vec::new()
+    };
+
+    let mut result: Vec<(Vec<u8>, Vec<u8>)> = names
+        .filter_map(|name| match xattr::get(path, &name) {
+            Ok(Some(v)) => Some((name.as_bytes().to_vec(), v)),
+            _           => None
+        })
+        .collect();
+    result.sort();
+    result
+}
+
+/// Apply the given extended attributes to the file at `path`, without
+/// following symlinks.
+///
+/// Attributes the filesystem or the current user can't set are skipped.
+pub fn write_xattrs(path: &Path, xattrs: &[(Vec<u8>, Vec<u8>)])
+        -> io::Result<()> {
+    for &(ref name, ref value) in xattrs.iter() {
+        if let Err(e) = xattr::set(path, OsStr::from_bytes(name), value) {
+            match e.raw_os_error() {
+                Some(libc::ENOTSUP) | Some(libc::EPERM) |
+                    Some(libc::EACCES) => continue,
+                _ => return Err(e)
+            }
+        }
+    }
+    Ok(())
+}
+
 pub trait IntoFSMetadata {
     fn into_metadata(self) -> FSMetadata;
 }
@@ -86,7 +307,8 @@ impl Default for FSMetadata {
             atime: time::SystemTime::now(),
             uid: 0,
             gid: 0,
-            mode: 0o755
+            mode: 0o755,
+            xattrs: Vec::new()
         }
     }
 }
@@ -98,7 +320,8 @@ impl IntoFSMetadata for fs::Metadata {
             atime: self.accessed().unwrap(),
             uid: self.uid(),
             gid: self.gid(),
-            mode: self.mode()
+            mode: self.mode(),
+            xattrs: Vec::new()
         }
     }
 }
@@ -143,8 +366,13 @@ pub struct FileObject {
     /// filesystem metadata attached to this object
     pub meta: FSMetadata,
 
-    /// the IDs of the file's content chunks
-    pub body: Vec<IdentityTag>
+    /// the IDs of the file's content chunks, some of which may be hole markers
+    /// standing in for runs of zeros
+    pub body: Vec<IdentityTag>,
+
+    /// the file's length in bytes, if known. Objects written by older versions
+    /// don't record it.
+    pub size: Option<u64>
 }
 
 /// Data about a symbolic link
@@ -160,29 +388,86 @@ pub struct SymlinkObject {
     pub target: Vec<u8>
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// An additional name for a file stored elsewhere in the same snapshot
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HardLinkObject {
+    /// filesystem name as a byte string
+    pub name: Vec<u8>,
+
+    /// filesystem metadata attached to this object
+    pub meta: FSMetadata,
+
+    /// the ID of the file object stored for the inode's first occurrence
+    pub target: IdentityTag
+}
+
+/// The kinds of special file that can be stored
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice
+}
+
+impl SpecialKind {
+    fn id(&self) -> u8 {
+        match *self {
+            SpecialKind::Fifo        => 0u8,
+            SpecialKind::Socket      => 1u8,
+            SpecialKind::CharDevice  => 2u8,
+            SpecialKind::BlockDevice => 3u8,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<SpecialKind> {
+        match id {
+            0u8 => Some(SpecialKind::Fifo),
+            1u8 => Some(SpecialKind::Socket),
+            2u8 => Some(SpecialKind::CharDevice),
+            3u8 => Some(SpecialKind::BlockDevice),
+            _   => None
+        }
+    }
+}
+
+/// Data about a FIFO, socket, or device node
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SpecialObject {
+    /// filesystem name as a byte string
+    pub name: Vec<u8>,
+
+    /// filesystem metadata attached to this object
+    pub meta: FSMetadata,
+
+    /// what kind of special file this is
+    pub kind: SpecialKind,
+
+    /// the device number, for device nodes
+    pub rdev: u64
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum MetaObject {
     Snapshot(Snapshot),
     Tree(TreeObject),
     File(FileObject),
-    Symlink(SymlinkObject)
+    Symlink(SymlinkObject),
+    HardLink(HardLinkObject),
+    Special(SpecialObject)
 }
 
 impl MetaObject {
     fn load_id<R: Read>(f: &mut R) -> io::Result<IdentityTag> {
-        let mut buf = [0u8; IDENTITY_LEN];
-        f.read_exact(&mut buf)?;
-        Ok(buf)
+        IdentityTag::read_from(f)
     }
 
-    #[allow(dead_code)]
     /// Compute the object's identity tag
     pub fn ident(&self) -> IdentityTag {
         let mut dev = DevNull::new();
         self.save(&mut dev).unwrap()
     }
 
-    #[allow(dead_code)]
     /// Utility function to generate a new file object
     pub fn file<S, M, I>(name: &S, meta: M, data: I) -> Self
         where S: AsRef<OsStr> + ?Sized,
@@ -191,10 +476,10 @@ impl MetaObject {
         MetaObject::File(FileObject {
             name: name.as_ref().to_owned().into_vec(),
             meta: meta.into_metadata(),
-            body: data.into_iter().collect() })
+            body: data.into_iter().collect(),
+            size: None })
     }
 
-    #[allow(dead_code)]
     /// Utility function to generate a new tree object
     pub fn tree<S, M, I>(name: &S, meta: M, children: I) -> Self
         where S: AsRef<OsStr> + ?Sized,
@@ -206,7 +491,6 @@ impl MetaObject {
             children: children.into_iter().collect() })
     }
 
-    #[allow(dead_code)]
     /// Utility function to generate a new symlink object
     pub fn symlink<S, M, T>(name: &S, meta: M, tgt: &T) -> Self
         where S: AsRef<OsStr> + ?Sized,
@@ -218,7 +502,28 @@ impl MetaObject {
                 target: tgt.as_ref().to_owned().into_vec() })
     }
 
-    #[allow(dead_code)]
+    /// Utility function to generate a new hard link object
+    pub fn hard_link<S, M>(name: &S, meta: M, target: IdentityTag) -> Self
+        where S: AsRef<OsStr> + ?Sized,
+              M: IntoFSMetadata {
+        MetaObject::HardLink(HardLinkObject {
+                name: name.as_ref().to_owned().into_vec(),
+                meta: meta.into_metadata(),
+                target: target })
+    }
+
+    /// Utility function to generate a new special file object
+    pub fn special<S, M>(name: &S, meta: M, kind: SpecialKind, rdev: u64)
+            -> Self
+        where S: AsRef<OsStr> + ?Sized,
+              M: IntoFSMetadata {
+        MetaObject::Special(SpecialObject {
+                name: name.as_ref().to_owned().into_vec(),
+                meta: meta.into_metadata(),
+                kind: kind,
+                rdev: rdev })
+    }
+
     /// Utility function to generate a new snapshot object
     /// 
     /// Fills in the creation time field with the current time
@@ -233,12 +538,40 @@ impl MetaObject {
                 create_time: ctime, root: root, parent: parent})
     }
 
+    /// Get the filesystem metadata attached to this object, if any
+    pub fn meta(&self) -> Option<&FSMetadata> {
+        match self {
+            &MetaObject::Snapshot(_) => None,
+            &MetaObject::Tree(ref t) => Some(&t.meta),
+            &MetaObject::File(ref f) => Some(&f.meta),
+            &MetaObject::Symlink(ref l) => Some(&l.meta),
+            &MetaObject::HardLink(ref l) => Some(&l.meta),
+            &MetaObject::Special(ref s) => Some(&s.meta),
+        }
+    }
+
     pub fn name(&self) -> Option<OsString> {
         match self {
             &MetaObject::Snapshot(_) => None,
             &MetaObject::Tree(ref t) => Some(OsString::from_vec(t.name.clone())),
             &MetaObject::File(ref f) => Some(OsString::from_vec(f.name.clone())),
             &MetaObject::Symlink(ref l) => Some(OsString::from_vec(l.name.clone())),
+            &MetaObject::HardLink(ref l) => Some(OsString::from_vec(l.name.clone())),
+            &MetaObject::Special(ref s) => Some(OsString::from_vec(s.name.clone())),
+        }
+    }
+
+    /// Change the object's name. Snapshots don't have one, so they're left as
+    /// they are.
+    pub fn set_name(&mut self, name: &OsStr) {
+        let name = name.as_bytes().to_vec();
+        match self {
+            &mut MetaObject::Snapshot(_) => {},
+            &mut MetaObject::Tree(ref mut t) => t.name = name,
+            &mut MetaObject::File(ref mut f) => f.name = name,
+            &mut MetaObject::Symlink(ref mut l) => l.name = name,
+            &mut MetaObject::HardLink(ref mut l) => l.name = name,
+            &mut MetaObject::Special(ref mut s) => s.name = name,
         }
     }
 
@@ -246,9 +579,10 @@ impl MetaObject {
     pub fn load<R: Read>(mut f: &mut R) -> io::Result<MetaObject> {
         // read required prefix bytes
         let node_type = f.read_u8()?;
+        let flags = node_type & FORMAT_FLAGS;
 
         // read type-specific bytes
-        let content = match node_type {
+        let content = match node_type & !FORMAT_FLAGS {
             0u8 => { // version
                 let created_time = time::UNIX_EPOCH +
                     time::Duration::from_secs(f.read_u64::<LittleEndian>()?);
@@ -265,7 +599,7 @@ impl MetaObject {
                 let mut name = vec![0u8; namelen as usize];
                 f.read_exact(&mut name)?;
 
-                let meta = FSMetadata::load(&mut f)?;
+                let meta = FSMetadata::load(&mut f, flags)?;
                 let num_children = f.read_u32::<LittleEndian>()?;
                 let mut children = Vec::with_capacity(num_children as usize);
                 for _ in 0..num_children {
@@ -280,7 +614,7 @@ impl MetaObject {
                 let mut name = vec![0u8; namelen as usize];
                 f.read_exact(&mut name)?;
 
-                let meta = FSMetadata::load(&mut f)?;
+                let meta = FSMetadata::load(&mut f, flags)?;
 
                 let tgtlen = f.read_u32::<LittleEndian>()?;
                 let mut tgt = vec![0u8; tgtlen as usize];
@@ -294,7 +628,7 @@ impl MetaObject {
                 let mut name = vec![0u8; namelen as usize];
                 f.read_exact(&mut name)?;
 
-                let meta = FSMetadata::load(&mut f)?;
+                let meta = FSMetadata::load(&mut f, flags)?;
 
                 let num_chunks = f.read_u32::<LittleEndian>()?;
                 let mut chunks = Vec::with_capacity(num_chunks as usize);
@@ -302,8 +636,39 @@ impl MetaObject {
                     chunks.push(MetaObject::load_id(&mut f)?);
                 }
 
+                let size = if flags & HAS_SIZE != 0 {
+                    Some(f.read_u64::<LittleEndian>()?)
+                } else {
+                    None
+                };
+
                 MetaObject::File(FileObject {
-                    name: name, meta: meta, body: chunks })
+                    name: name, meta: meta, body: chunks, size: size })
+            },
+            4u8 => { // hard link
+                let namelen = f.read_u16::<LittleEndian>()?;
+                let mut name = vec![0u8; namelen as usize];
+                f.read_exact(&mut name)?;
+
+                let meta = FSMetadata::load(&mut f, flags)?;
+                let target = MetaObject::load_id(&mut f)?;
+
+                MetaObject::HardLink(HardLinkObject {
+                    name: name, meta: meta, target: target })
+            },
+            5u8 => { // special file
+                let namelen = f.read_u16::<LittleEndian>()?;
+                let mut name = vec![0u8; namelen as usize];
+                f.read_exact(&mut name)?;
+
+                let meta = FSMetadata::load(&mut f, flags)?;
+                let kind = SpecialKind::from_id(f.read_u8()?).ok_or(
+                    io::Error::new(io::ErrorKind::InvalidData,
+                                   "Unknown special file type"))?;
+                let rdev = f.read_u64::<LittleEndian>()?;
+
+                MetaObject::Special(SpecialObject {
+                    name: name, meta: meta, kind: kind, rdev: rdev })
             },
             _   => return Err(io::Error::new(io::ErrorKind::InvalidData,
                                              "Incorrect content format")),
@@ -328,44 +693,71 @@ impl MetaObject {
             &MetaObject::Snapshot(ref snap) => {
                 f.write_u8(0u8)?;
                 MetaObject::write_time(&mut f, snap.create_time)?;
-                f.write(&snap.root)?;
+                f.write(snap.root.as_bytes())?;
                 if let Some(p) = snap.parent {
                     f.write_u8(1);
-                    f.write(&p)?;
+                    f.write(p.as_bytes())?;
                 } else {
                     f.write_u8(0);
                 }
             },
             &MetaObject::Tree(ref tree) => {
-                f.write_u8(1u8)?;
+                f.write_u8(1u8 | tree.meta.flags())?;
                 f.write_u16::<LittleEndian>(tree.name.len() as u16)?;
                 f.write(&tree.name)?;
                 tree.meta.save(&mut f)?;
 
                 f.write_u32::<LittleEndian>(tree.children.len() as u32)?;
                 for c in tree.children.iter() {
-                    f.write(c)?;
+                    f.write(c.as_bytes())?;
                 }
             },
             &MetaObject::File(ref file) => {
-                f.write_u8(3u8)?;
+                let size_flag = if file.size.is_some() { HAS_SIZE } else { 0 };
+                let holes_flag = if file.body.iter()
+                                     .any(|c| c.hole_len().is_some()) {
+                    HAS_HOLES
+                } else {
+                    0
+                };
+                f.write_u8(3u8 | file.meta.flags() | size_flag | holes_flag)?;
                 f.write_u16::<LittleEndian>(file.name.len() as u16)?;
                 f.write(&file.name)?;
                 file.meta.save(&mut f)?;
 
                 f.write_u32::<LittleEndian>(file.body.len() as u32)?;
                 for c in file.body.iter() {
-                    f.write(c)?;
+                    f.write(c.as_bytes())?;
+                }
+                if let Some(size) = file.size {
+                    f.write_u64::<LittleEndian>(size)?;
                 }
             },
             &MetaObject::Symlink(ref link) => {
-                f.write_u8(2u8)?;
+                f.write_u8(2u8 | link.meta.flags())?;
                 f.write_u16::<LittleEndian>(link.name.len() as u16)?;
                 f.write(&link.name)?;
                 link.meta.save(&mut f)?;
 
-                f.write_u32::<LittleEndian>(link.name.len() as u32)?;
+                f.write_u32::<LittleEndian>(link.target.len() as u32)?;
+                f.write(&link.target)?;
+            },
+            &MetaObject::HardLink(ref link) => {
+                f.write_u8(4u8 | link.meta.flags())?;
+                f.write_u16::<LittleEndian>(link.name.len() as u16)?;
                 f.write(&link.name)?;
+                link.meta.save(&mut f)?;
+
+                f.write(link.target.as_bytes())?;
+            },
+            &MetaObject::Special(ref special) => {
+                f.write_u8(5u8 | special.meta.flags())?;
+                f.write_u16::<LittleEndian>(special.name.len() as u16)?;
+                f.write(&special.name)?;
+                special.meta.save(&mut f)?;
+
+                f.write_u8(special.kind.id())?;
+                f.write_u64::<LittleEndian>(special.rdev)?;
             },
         }
 
@@ -386,8 +778,45 @@ mod tests {
         assert_eq!(m2, m);
     }
 
+    #[test]
+    fn tag_hex_roundtrip() {
+        let mut bytes = [0u8; IDENTITY_LEN];
+        for (i, b) in bytes.iter_mut().enumerate() { *b = i as u8 * 7; }
+        let tag = IdentityTag::from_bytes(bytes);
+
+        let hex = tag.to_string();
+        assert_eq!(hex.len(), IDENTITY_LEN * 2);
+        assert!(hex.starts_with("00070e15"));
+        assert_eq!(hex.parse::<IdentityTag>(), Ok(tag));
+        assert_eq!(hex.to_uppercase().parse::<IdentityTag>(), Ok(tag));
+
+        assert_eq!(tag.dir_prefix(), "00");
+        assert_eq!(tag.hex_prefix(6), "00070e");
+        assert!(tag.has_prefix("00070E1"));
+        assert!(!tag.has_prefix("0008"));
+
+        // wrong lengths and non-hex digits are rejected
+        assert!(hex[1..].parse::<IdentityTag>().is_err());
+        assert!(format!("{}0", hex).parse::<IdentityTag>().is_err());
+        assert!(format!("+{}", &hex[1..]).parse::<IdentityTag>().is_err());
+        assert!(IdentityTag::from_slice(&bytes[1..]).is_none());
+    }
+
     #[test]
     fn roundtrip_test() {
+        check_roundtrip(MetaObject::file(
+                "precise",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::new(12345, 678),
+                    atime: time::UNIX_EPOCH +
+                        time::Duration::new(23456, 999_999_999),
+                    uid: 12,
+                    gid: 4,
+                    mode: 0o644,
+                    xattrs: Vec::new()
+                },
+                vec![]
+        ));
         check_roundtrip(MetaObject::file(
                 "test1",
                 FSMetadata {
@@ -395,7 +824,8 @@ mod tests {
                     atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
                     uid: 12,
                     gid: 4,
-                    mode: 12345
+                    mode: 12345,
+                    xattrs: Vec::new()
                 },
                 vec![]
         ));
@@ -406,11 +836,12 @@ mod tests {
                     atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
                     uid: 0,
                     gid: 0xffffffff,
-                    mode: 12345
+                    mode: 12345,
+                    xattrs: Vec::new()
                 },
-                vec![b"012345678901234567890123456789ab".to_owned(),
-                     b"012345678901234567890123456789ab".to_owned(),
-                     b"012345678901234567890123456789ab".to_owned()]
+                vec![IdentityTag::from_bytes(*b"012345678901234567890123456789ab"),
+                     IdentityTag::from_bytes(*b"012345678901234567890123456789ab"),
+                     IdentityTag::from_bytes(*b"012345678901234567890123456789ab")]
         ));
         check_roundtrip(MetaObject::file(
                 "test3",
@@ -419,11 +850,178 @@ mod tests {
                     atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
                     uid: 0xffffffff,
                     gid: 0,
-                    mode: 12345
+                    mode: 12345,
+                    xattrs: Vec::new()
                 },
-                vec![b"012345678901234567890123456789ab".to_owned()]
+                vec![IdentityTag::from_bytes(*b"012345678901234567890123456789ab")]
         ));
-        check_roundtrip(MetaObject::snapshot([1u8; 32], Some([2u8; 32])));
-        check_roundtrip(MetaObject::snapshot([1u8; 32], None));
+        let tag = |b: u8| IdentityTag::from_bytes([b; 32]);
+        check_roundtrip(MetaObject::snapshot(tag(1), Some(tag(2))));
+        check_roundtrip(MetaObject::snapshot(tag(1), None));
+    }
+
+    #[test]
+    fn xattr_roundtrip_test() {
+        check_roundtrip(MetaObject::file(
+                "labelled",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::from_secs(12345),
+                    atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
+                    uid: 12,
+                    gid: 4,
+                    mode: 0o644,
+                    xattrs: vec![(b"security.selinux".to_vec(),
+                                  b"system_u:object_r:etc_t:s0".to_vec()),
+                                 (b"user.empty".to_vec(), vec![])]
+                },
+                vec![]
+        ));
+    }
+
+    #[test]
+    fn xattr_filesystem_test() {
+        use std::env;
+        use std::fs;
+        use std::path::Path;
+
+        let path = env::temp_dir().join("bkp-xattr-test");
+        fs::File::create(&path).unwrap();
+        let attrs = vec![(b"user.bkp.test".to_vec(), b"value".to_vec())];
+
+        // only meaningful where the filesystem supports user xattrs
+        if super::xattr::set(&path, "user.bkp.test", b"value").is_err() {
+            fs::remove_file(&path).unwrap();
+            return;
+        }
+        assert_eq!(read_xattrs(&path), attrs);
+
+        super::xattr::remove(&path, "user.bkp.test").unwrap();
+        assert!(read_xattrs(&path).is_empty());
+        write_xattrs(&path, &attrs).unwrap();
+        assert_eq!(read_xattrs(&path), attrs);
+
+        fs::remove_file(&path).unwrap();
+        assert!(read_xattrs(Path::new("/nonexistent/bkp")).is_empty());
+    }
+
+    #[test]
+    fn legacy_metadata_test() {
+        // a file object written before timestamps carried nanoseconds
+        let mut data = vec![3u8, 1, 0, b'f'];
+        data.extend_from_slice(&[0x39, 0x30, 0, 0, 0, 0, 0, 0]); // mtime
+        data.extend_from_slice(&[0xa0, 0x5b, 0, 0, 0, 0, 0, 0]); // atime
+        data.extend_from_slice(&[12, 0, 0, 0, 4, 0, 0, 0]);      // owner
+        data.extend_from_slice(&[0xa4, 0x01]);                   // mode
+        data.extend_from_slice(&[0, 0, 0, 0]);                   // no chunks
+
+        let obj = MetaObject::load(&mut Cursor::new(data)).unwrap();
+        assert_eq!(obj, MetaObject::file(
+                "f",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::from_secs(12345),
+                    atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
+                    uid: 12,
+                    gid: 4,
+                    mode: 0o644,
+                    xattrs: Vec::new()
+                },
+                vec![]));
+    }
+
+    #[test]
+    fn sized_file_roundtrip_test() {
+        let mut obj = MetaObject::file(
+                "sized",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::from_secs(12345),
+                    atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
+                    uid: 12,
+                    gid: 4,
+                    mode: 0o644,
+                    xattrs: Vec::new()
+                },
+                vec![IdentityTag::from_bytes([3u8; 32])]);
+        if let MetaObject::File(ref mut f) = obj {
+            f.size = Some(123456789);
+        }
+        check_roundtrip(obj);
+    }
+
+    #[test]
+    fn hard_link_roundtrip_test() {
+        check_roundtrip(MetaObject::hard_link(
+                "second-name",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::from_secs(12345),
+                    atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
+                    uid: 1000,
+                    gid: 100,
+                    mode: 0o644,
+                    xattrs: Vec::new()
+                },
+                IdentityTag::from_bytes([7u8; 32])));
+    }
+
+    #[test]
+    fn special_roundtrip_test() {
+        check_roundtrip(MetaObject::special(
+                "null",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::from_secs(12345),
+                    atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
+                    uid: 0,
+                    gid: 0,
+                    mode: 0o20666,
+                    xattrs: Vec::new()
+                },
+                SpecialKind::CharDevice,
+                0x103));
+    }
+
+    #[test]
+    fn symlink_roundtrip_test() {
+        let obj = MetaObject::symlink(
+                "link",
+                FSMetadata {
+                    mtime: time::UNIX_EPOCH + time::Duration::from_secs(12345),
+                    atime: time::UNIX_EPOCH + time::Duration::from_secs(23456),
+                    uid: 1000,
+                    gid: 1000,
+                    mode: 0o777,
+                    xattrs: Vec::new()
+                },
+                "/some/other/target");
+        check_roundtrip(obj);
+
+        // make sure the target isn't replaced by the name along the way
+        let obj = MetaObject::symlink("a", FSMetadata::default(), "bcdef");
+        let mut v = Vec::new();
+        obj.save(&mut v).unwrap();
+        match MetaObject::load(&mut Cursor::new(v)).unwrap() {
+            MetaObject::Symlink(l) => {
+                assert_eq!(l.name, b"a".to_vec());
+                assert_eq!(l.target, b"bcdef".to_vec());
+            },
+            _ => panic!("wrong object type loaded")
+        }
+    }
+
+    #[test]
+    fn hole_markers() {
+        let hole = IdentityTag::hole(1 << 40);
+        assert_eq!(hole.hole_len(), Some(1 << 40));
+        assert_eq!(IdentityTag::from_bytes([0u8; IDENTITY_LEN]).hole_len(),
+                   None);
+        assert_eq!(IdentityTag::from_bytes([7u8; IDENTITY_LEN]).hole_len(),
+                   None);
+
+        // files with holes are flagged, so older versions won't load them
+        let block = IdentityTag::from_bytes([7u8; IDENTITY_LEN]);
+        let obj = MetaObject::file("sparse", FSMetadata::default(),
+                                   vec![block, hole, block]);
+        let mut v = Vec::new();
+        obj.save(&mut v).unwrap();
+        assert!(v[0] & HAS_HOLES != 0);
+        check_roundtrip(obj);
     }
 }
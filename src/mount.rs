@@ -0,0 +1,513 @@
+//! Read-only FUSE filesystem presenting the contents of a stored snapshot
+
+extern crate fuse;
+extern crate libc;
+extern crate time;
+
+use std::cmp;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::fuse::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyData,
+                 ReplyDirectory, ReplyEntry, Request};
+use self::time::Timespec;
+
+use history::{self, ContextWrapper};
+use metadata::{IdentityTag, MetaObject, SpecialKind};
+
+/// Inode number of the filesystem root
+const ROOT_INO: u64 = 1;
+
+/// How long the kernel may cache attributes and lookups. Snapshots never
+/// change, so this can be long.
+const TTL: Timespec = Timespec { sec: 3600, nsec: 0 };
+
+/// A stored object which has been given an inode number
+struct Inode<'a> {
+    parent: u64,
+    object: ContextWrapper<'a, MetaObject>,
+
+    /// Names and inode numbers of a tree's children, once they've been read
+    children: Option<Vec<(OsString, u64)>>,
+
+    /// The blocks holding a file's contents, once they've been looked up
+    blocks: Option<Vec<IdentityTag>>,
+
+    /// Lengths of the leading blocks which have been fetched so far
+    block_lens: Vec<u64>,
+
+    /// The file's length, if it's known yet
+    size: Option<u64>
+}
+
+/// A read-only filesystem presenting a stored tree.
+///
+/// Objects are assigned inode numbers as they're first looked up, and keep
+/// their decrypted metadata for as long as the filesystem is mounted.
+pub struct SnapshotFs<'a> {
+    inodes: Vec<Inode<'a>>,
+
+    /// The most recently read block, since reads usually come in pieces
+    /// smaller than a block
+    last_block: Option<(IdentityTag, Vec<u8>)>
+}
+
+impl<'a> SnapshotFs<'a> {
+    /// Present a stored tree as the root of a filesystem
+    pub fn new(root: ContextWrapper<'a, MetaObject>) -> Self {
+        let mut fs = SnapshotFs { inodes: Vec::new(), last_block: None };
+        fs.add_inode(ROOT_INO, root);
+        fs
+    }
+
+    /// Assign the next inode number to an object
+    fn add_inode(&mut self, parent: u64, obj: ContextWrapper<'a, MetaObject>)
+            -> u64 {
+        let size = match *obj {
+            MetaObject::File(ref f) => f.size,
+            _                       => None
+        };
+        self.inodes.push(Inode {
+            parent: parent,
+            object: obj,
+            children: None,
+            blocks: None,
+            block_lens: Vec::new(),
+            size: size
+        });
+        self.inodes.len() as u64
+    }
+
+    /// Find the index of an inode number's entry, if it's valid
+    fn index(&self, ino: u64) -> Option<usize> {
+        if ino >= ROOT_INO && ino <= self.inodes.len() as u64 {
+            Some((ino - ROOT_INO) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Read a tree's children, assigning them inode numbers the first time
+    fn load_children(&mut self, idx: usize) -> history::Result<()> {
+        let objs = {
+            let inode = &self.inodes[idx];
+            if inode.children.is_some() {
+                return Ok(());
+            }
+            inode.object.children()?.unwrap_or_default()
+        };
+
+        let ino = idx as u64 + ROOT_INO;
+        let mut children = Vec::new();
+        for c in objs {
+            let name = c.name().unwrap_or_default();
+            children.push((name, self.add_inode(ino, c)));
+        }
+        self.inodes[idx].children = Some(children);
+        Ok(())
+    }
+
+    /// Look up the blocks holding a file's contents, following hard links.
+    /// Returns false for objects without contents.
+    fn load_blocks(&mut self, idx: usize) -> history::Result<bool> {
+        if self.inodes[idx].blocks.is_some() {
+            return Ok(true);
+        }
+        let file = match self.inodes[idx].object.contents()? {
+            Some(f) => f,
+            None    => return Ok(false)
+        };
+
+        let inode = &mut self.inodes[idx];
+        if inode.size.is_none() {
+            inode.size = file.size;
+        }
+        inode.blocks = Some(file.body);
+        Ok(true)
+    }
+
+    /// Fetch the `n`th block of a file, keeping it for the next read
+    fn fetch_block(&mut self, idx: usize, n: usize) -> history::Result<&[u8]> {
+        let tag = self.inodes[idx].blocks.as_ref().unwrap()[n];
+        let cached = match self.last_block {
+            Some((ref t, _)) => *t == tag,
+            None             => false
+        };
+
+        if !cached {
+            let data = self.inodes[idx].object.read_block(&tag)?;
+
+            // blocks are always fetched in order, so this is the next length
+            let inode = &mut self.inodes[idx];
+            if inode.block_lens.len() == n {
+                inode.block_lens.push(data.len() as u64);
+            }
+            self.last_block = Some((tag, data));
+        }
+        Ok(&self.last_block.as_ref().unwrap().1[..])
+    }
+
+    /// Find the length of the `n`th block of a file, fetching it if needed
+    fn block_len(&mut self, idx: usize, n: usize) -> history::Result<u64> {
+        if let Some(&len) = self.inodes[idx].block_lens.get(n) {
+            return Ok(len);
+        }
+
+        // holes know their own length
+        let tag = self.inodes[idx].blocks.as_ref().unwrap()[n];
+        if let Some(len) = tag.hole_len() {
+            let inode = &mut self.inodes[idx];
+            if inode.block_lens.len() == n {
+                inode.block_lens.push(len);
+            }
+            return Ok(len);
+        }
+        Ok(self.fetch_block(idx, n)?.len() as u64)
+    }
+
+    /// Work out an object's size. Files written by older versions don't record
+    /// it, so this reads every block of them the first time.
+    fn size(&mut self, idx: usize) -> history::Result<u64> {
+        if let MetaObject::Symlink(ref l) = *self.inodes[idx].object {
+            return Ok(l.target.len() as u64);
+        }
+        if !self.load_blocks(idx)? {
+            return Ok(0);
+        }
+        if let Some(size) = self.inodes[idx].size {
+            return Ok(size);
+        }
+
+        let count = self.inodes[idx].blocks.as_ref().map_or(0, |b| b.len());
+        let mut size = 0;
+        for n in 0..count {
+            size += self.block_len(idx, n)?;
+        }
+        self.inodes[idx].size = Some(size);
+        Ok(size)
+    }
+
+    /// Read up to `size` bytes of a file's contents starting at `offset`
+    fn read_range(&mut self, idx: usize, offset: u64, size: u64)
+            -> history::Result<Vec<u8>> {
+        if !self.load_blocks(idx)? {
+            return Err(history::Error::InvalidArgument);
+        }
+
+        // skip over blocks before the range, fetching only the ones whose
+        // lengths aren't known yet
+        let count = self.inodes[idx].blocks.as_ref().map_or(0, |b| b.len());
+        let end = offset + size;
+        let mut out = Vec::new();
+        let mut pos = 0;
+        for n in 0..count {
+            if pos >= end {
+                break;
+            }
+            let len = self.block_len(idx, n)?;
+            if pos + len > offset {
+                let from = offset.saturating_sub(pos) as usize;
+                let to = cmp::min(len, end - pos) as usize;
+                let is_hole = self.inodes[idx].blocks.as_ref().unwrap()[n]
+                                  .hole_len().is_some();
+                if is_hole {
+                    let zeros = out.len() + (to - from);
+                    out.resize(zeros, 0);
+                } else {
+                    let data = self.fetch_block(idx, n)?;
+                    out.extend_from_slice(&data[from..to]);
+                }
+            }
+            pos += len;
+        }
+        Ok(out)
+    }
+
+    /// Build the attributes reported for an inode
+    fn attr(&mut self, idx: usize) -> history::Result<FileAttr> {
+        let size = self.size(idx)?;
+        let inode = &self.inodes[idx];
+        let meta = match inode.object.meta() {
+            Some(m) => m,
+            None    => return Err(history::Error::IntegrityError)
+        };
+        let rdev = match *inode.object {
+            MetaObject::Special(ref s) => s.rdev as u32,
+            _                          => 0
+        };
+
+        Ok(FileAttr {
+            ino: idx as u64 + ROOT_INO,
+            size: size,
+            blocks: (size + 511) / 512,
+            atime: timespec(if meta.atime == UNIX_EPOCH { meta.mtime }
+                            else { meta.atime }),
+            mtime: timespec(meta.mtime),
+            ctime: timespec(meta.mtime),
+            crtime: timespec(meta.mtime),
+            kind: file_type(&inode.object),
+            perm: (meta.mode & 0o7777) as u16,
+            nlink: 1,
+            uid: meta.uid,
+            gid: meta.gid,
+            rdev: rdev,
+            flags: 0
+        })
+    }
+}
+
+impl<'a> Filesystem for SnapshotFs<'a> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr,
+              reply: ReplyEntry) {
+        let idx = match self.index(parent) {
+            Some(i) => i,
+            None    => return reply.error(libc::ENOENT)
+        };
+        if let Err(e) = self.load_children(idx) {
+            return reply.error(errno("cannot read directory", e));
+        }
+
+        let child = self.inodes[idx].children.as_ref()
+            .and_then(|c| c.iter().find(|x| x.0.as_os_str() == name))
+            .map(|x| x.1);
+        let child = match child.and_then(|c| self.index(c)) {
+            Some(c) => c,
+            None    => return reply.error(libc::ENOENT)
+        };
+        match self.attr(child) {
+            Ok(a)  => reply.entry(&TTL, &a, 0),
+            Err(e) => reply.error(errno("cannot read object", e))
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let idx = match self.index(ino) {
+            Some(i) => i,
+            None    => return reply.error(libc::ENOENT)
+        };
+        match self.attr(idx) {
+            Ok(a)  => reply.attr(&TTL, &a),
+            Err(e) => reply.error(errno("cannot read object", e))
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let idx = match self.index(ino) {
+            Some(i) => i,
+            None    => return reply.error(libc::ENOENT)
+        };
+        match *self.inodes[idx].object {
+            MetaObject::Symlink(ref l) => reply.data(&l.target),
+            _                          => reply.error(libc::EINVAL)
+        }
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64,
+            size: u32, reply: ReplyData) {
+        let idx = match self.index(ino) {
+            Some(i) => i,
+            None    => return reply.error(libc::ENOENT)
+        };
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        match self.read_range(idx, offset as u64, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(history::Error::InvalidArgument) => reply.error(libc::EINVAL),
+            Err(e)   => reply.error(errno("cannot read file contents", e))
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64,
+               mut reply: ReplyDirectory) {
+        let idx = match self.index(ino) {
+            Some(i) => i,
+            None    => return reply.error(libc::ENOENT)
+        };
+        if let MetaObject::Tree(_) = *self.inodes[idx].object {} else {
+            return reply.error(libc::ENOTDIR);
+        }
+        if let Err(e) = self.load_children(idx) {
+            return reply.error(errno("cannot read directory", e));
+        }
+
+        let inode = &self.inodes[idx];
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (inode.parent, FileType::Directory, OsString::from(".."))];
+        for &(ref name, child) in inode.children.as_ref().unwrap().iter() {
+            let kind = file_type(&self.inodes[(child - ROOT_INO) as usize]
+                                     .object);
+            entries.push((child, kind, name.clone()));
+        }
+
+        // each entry's offset is the one to resume listing after it from
+        let skip = cmp::max(offset, 0) as usize;
+        for (i, e) in entries.into_iter().enumerate().skip(skip) {
+            if reply.add(e.0, (i + 1) as i64, e.1, &e.2) {
+                break; // the reply buffer is full
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Map a stored object's type to the one reported to the kernel
+fn file_type(obj: &MetaObject) -> FileType {
+    match obj {
+        &MetaObject::Tree(_)        => FileType::Directory,
+        &MetaObject::Symlink(_)     => FileType::Symlink,
+        &MetaObject::Special(ref s) => match s.kind {
+            SpecialKind::Fifo        => FileType::NamedPipe,
+            SpecialKind::Socket      => FileType::Socket,
+            SpecialKind::CharDevice  => FileType::CharDevice,
+            SpecialKind::BlockDevice => FileType::BlockDevice
+        },
+        _                           => FileType::RegularFile
+    }
+}
+
+/// Convert a stored time to the representation FUSE uses
+fn timespec(t: SystemTime) -> Timespec {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d)  => Timespec::new(d.as_secs() as i64, d.subsec_nanos() as i32),
+        Err(_) => Timespec::new(0, 0)
+    }
+}
+
+/// Report a failed operation, returning the error code to reply with
+fn errno(what: &str, err: history::Error) -> libc::c_int {
+    error!("bkp: {}: {}", what, err);
+    libc::EIO
+}
+
+/// Set when the process is asked to stop
+static UNMOUNT_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn request_unmount(_: libc::c_int) {
+    UNMOUNT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Ask the system to unmount a FUSE filesystem
+fn unmount(mountpoint: &Path) -> bool {
+    let run = |cmd: &mut Command| cmd.status().map(|s| s.success())
+                                     .unwrap_or(false);
+    run(Command::new("fusermount").arg("-u").arg(mountpoint)) ||
+        run(Command::new("umount").arg(mountpoint))
+}
+
+/// Mount a filesystem read-only and serve requests for it until it's unmounted,
+/// either externally or by interrupting the process.
+pub fn mount(fs: SnapshotFs, mountpoint: &Path) -> io::Result<()> {
+    // the session can't be stopped safely from a signal handler, so have a
+    // thread watch for signals and unmount, which ends the session normally
+    unsafe {
+        libc::signal(libc::SIGINT, request_unmount as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_unmount as libc::sighandler_t);
+    }
+    let target = mountpoint.to_path_buf();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        if UNMOUNT_REQUESTED.swap(false, Ordering::SeqCst) &&
+                !unmount(&target) {
+            error!("bkp: cannot unmount {}, is it still in use?",
+                   target.display());
+        }
+    });
+
+    let options = [OsStr::new("-o"), OsStr::new("ro,fsname=bkp")];
+    fuse::mount(fs, &mountpoint, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use std::time::UNIX_EPOCH;
+
+    use history::History;
+    use metadata::{FileObject, FSMetadata, IdentityTag, MetaObject, Snapshot};
+    use mount::SnapshotFs;
+    use remote::{Backend, BlockStore, MetadataStore};
+    use remote::memory::MemoryBackend;
+
+    /// Store a snapshot holding two files with the same contents, "hello",
+    /// four zero bytes and "world". `old` doesn't record its length, and `new`
+    /// does.
+    fn build_backend() -> (Box<Backend>, Rc<Cell<usize>>) {
+        let mem = MemoryBackend::new();
+        let reads = mem.block_reads.clone();
+        let mut backend: Box<Backend> = Box::new(mem);
+
+        let body = vec![backend.write_block(b"hello").unwrap(),
+                        IdentityTag::hole(4),
+                        backend.write_block(b"world").unwrap()];
+        let file = |name: &str, size| MetaObject::File(FileObject {
+            name: name.as_bytes().to_vec(),
+            meta: FSMetadata::default(),
+            body: body.clone(),
+            size: size
+        });
+        let old = backend.write_meta(&file("old", None)).unwrap();
+        let new = backend.write_meta(&file("new", Some(14))).unwrap();
+        let root = MetaObject::tree("", FSMetadata::default(), vec![old, new]);
+        let root = backend.write_meta(&root).unwrap();
+        let snap = backend.write_meta(&MetaObject::Snapshot(Snapshot {
+            create_time: UNIX_EPOCH, root: root, parent: None
+        })).unwrap();
+        backend.set_head(&snap).unwrap();
+        (backend, reads)
+    }
+
+    #[test]
+    fn file_sizes() {
+        let (mut backend, reads) = build_backend();
+        let history = History::new(&mut backend).unwrap();
+        let snap = history.get_snapshot().unwrap().unwrap();
+        let mut fs = SnapshotFs::new(snap.get("/").unwrap().unwrap());
+        fs.load_children(0).unwrap();
+
+        // children are sorted by name, so `new` comes first. its length is
+        // recorded, so no blocks are needed
+        assert_eq!(fs.size(1).unwrap(), 14);
+        assert_eq!(reads.get(), 0);
+
+        // but the older one's blocks have to be fetched, apart from holes
+        assert_eq!(fs.size(2).unwrap(), 14);
+        assert_eq!(reads.get(), 2);
+        assert_eq!(fs.size(2).unwrap(), 14);
+        assert_eq!(reads.get(), 2);
+
+        // trees have no contents
+        assert_eq!(fs.size(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_ranges() {
+        let (mut backend, _) = build_backend();
+        let history = History::new(&mut backend).unwrap();
+        let snap = history.get_snapshot().unwrap().unwrap();
+        let mut fs = SnapshotFs::new(snap.get("/").unwrap().unwrap());
+        fs.load_children(0).unwrap();
+
+        for idx in 1..3 {
+            assert_eq!(fs.read_range(idx, 0, 100).unwrap(),
+                       b"hello\0\0\0\0world".to_vec());
+
+            // ranges can start and end partway through blocks and holes
+            assert_eq!(fs.read_range(idx, 3, 8).unwrap(),
+                       b"lo\0\0\0\0wo".to_vec());
+            assert_eq!(fs.read_range(idx, 6, 2).unwrap(), b"\0\0".to_vec());
+            assert_eq!(fs.read_range(idx, 12, 10).unwrap(), b"ld".to_vec());
+            assert_eq!(fs.read_range(idx, 14, 10).unwrap(), Vec::new());
+        }
+
+        // only files have contents
+        assert!(fs.read_range(0, 0, 10).is_err());
+    }
+}
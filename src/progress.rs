@@ -0,0 +1,197 @@
+extern crate libc;
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use util;
+
+/// How often the terminal status line is redrawn
+const REDRAW_INTERVAL_MS: u64 = 100;
+
+/// Receives notifications about the progress of snapshots and restores
+pub trait Progress {
+    /// Called when work on a file begins
+    fn file_started(&self, path: &Path);
+
+    /// Called as file contents are transferred, with the number of bytes just
+    /// handled
+    fn bytes_written(&self, n: u64);
+
+    /// Called whenever a filesystem object has been stored or restored
+    fn object_stored(&self);
+
+    /// Called when a path is deliberately left out, with the reason why
+    fn path_skipped(&self, _path: &Path, _why: &str) {}
+
+    /// Called before any data is transferred if the total number of bytes to
+    /// handle is known in advance, so that the time remaining can be estimated
+    fn set_total(&self, _bytes: u64) {}
+
+    /// Called once the operation is complete
+    fn finish(&self) {}
+}
+
+/// A progress reporter which ignores everything
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn file_started(&self, _: &Path) {}
+    fn bytes_written(&self, _: u64) {}
+    fn object_stored(&self) {}
+}
+
+/// Reports progress on the terminal.
+///
+/// When stdout is a TTY, a status line with running totals is kept up to date.
+/// In verbose mode, the name of each file is printed as well, along with any
+/// paths which are skipped.
+pub struct TerminalProgress {
+    /// Whether to redraw a status line in place
+    status_line: bool,
+
+    /// Whether to print every file that's handled
+    verbose: bool,
+
+    files: Cell<u64>,
+    bytes: Cell<u64>,
+    objects: Cell<u64>,
+    current: RefCell<PathBuf>,
+
+    /// Total bytes expected, if known
+    total: Cell<Option<u64>>,
+
+    /// When the operation started, for estimating the time remaining
+    started: Instant,
+
+    /// When the status line was last drawn, if ever
+    last_draw: Cell<Option<Instant>>
+}
+
+impl TerminalProgress {
+    pub fn new(verbose: bool) -> Self {
+        TerminalProgress {
+            status_line: stdout_is_tty(),
+            verbose: verbose,
+            files: Cell::new(0),
+            bytes: Cell::new(0),
+            objects: Cell::new(0),
+            current: RefCell::new(PathBuf::new()),
+            total: Cell::new(None),
+            started: Instant::now(),
+            last_draw: Cell::new(None)
+        }
+    }
+
+    /// Redraw the status line, unless it was drawn very recently
+    fn draw(&self, force: bool) {
+        if !self.status_line { return; }
+
+        let now = Instant::now();
+        let interval = Duration::from_millis(REDRAW_INTERVAL_MS);
+        if let Some(t) = self.last_draw.get() {
+            if !force && now.duration_since(t) < interval { return; }
+        }
+        self.last_draw.set(Some(now));
+
+        let bytes = match self.total.get() {
+            Some(t) => format!("{} of {}", util::format_size(self.bytes.get()),
+                               util::format_size(t)),
+            None    => util::format_size(self.bytes.get())
+        };
+        let mut line = format!("{} files, {}, {} objects",
+                               self.files.get(), bytes, self.objects.get());
+        if let Some(eta) = self.remaining(now) {
+            line += &format!(", {} left", format_duration(eta));
+        }
+        if !force {
+            line += &format!("  {}", self.current.borrow().display());
+        }
+
+        // keep the line from wrapping, so it can be overwritten in place
+        let line: String = line.chars().take(79).collect();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let _ = write!(out, "\r{:79}", line);
+        let _ = out.flush();
+    }
+
+    /// Estimate the time remaining from the rate so far, if the total is known
+    /// and enough has been done to go on
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        let (done, total) = match self.total.get() {
+            Some(t) => (self.bytes.get(), t),
+            None    => return None
+        };
+        let elapsed = now.duration_since(self.started);
+        if done == 0 || done >= total || elapsed.as_secs() < 1 {
+            return None;
+        }
+
+        let elapsed = elapsed.as_secs() as f64 +
+                      elapsed.subsec_nanos() as f64 / 1e9;
+        let left = elapsed * (total - done) as f64 / done as f64;
+        Some(Duration::from_secs(left as u64))
+    }
+}
+
+/// Format a duration compactly, e.g. "1h05m" or "42s"
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+impl Progress for TerminalProgress {
+    fn file_started(&self, path: &Path) {
+        self.files.set(self.files.get() + 1);
+        *self.current.borrow_mut() = path.to_owned();
+
+        if self.verbose {
+            // move past the status line rather than printing over it
+            if self.status_line { print!("\r{:79}\r", ""); }
+            println!("{}", path.display());
+        }
+        self.draw(self.verbose);
+    }
+
+    fn bytes_written(&self, n: u64) {
+        self.bytes.set(self.bytes.get() + n);
+        self.draw(false);
+    }
+
+    fn object_stored(&self) {
+        self.objects.set(self.objects.get() + 1);
+        self.draw(false);
+    }
+
+    fn path_skipped(&self, path: &Path, why: &str) {
+        if self.verbose {
+            if self.status_line { print!("\r{:79}\r", ""); }
+            println!("skipping {}: {}", path.display(), why);
+        }
+    }
+
+    fn set_total(&self, bytes: u64) {
+        self.total.set(Some(bytes));
+    }
+
+    fn finish(&self) {
+        if self.status_line {
+            self.draw(true);
+            println!("");
+        }
+    }
+}
+
+/// Check whether stdout is attached to a terminal
+fn stdout_is_tty() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
+}
@@ -0,0 +1,229 @@
+extern crate ring;
+
+use metadata::{IdentityTag, MetaObject, tag_from_digest};
+use remote::*;
+
+/// A backend which can have snapshots added to it, but never loses anything
+/// already stored.
+///
+/// Deletes fail with `BackendError::AppendOnly`, as does anything built on
+/// them like repacking or renaming a node. Objects which are already stored
+/// aren't written again, so they can't be overwritten either; blocks are only
+/// checked for when the inner backend might replace them. Heads can still
+/// be moved, since that's how snapshots are added, but the snapshots they
+/// pointed to stay where they were.
+pub struct AppendOnlyBackend(Box<Backend>);
+
+impl AppendOnlyBackend {
+    pub fn new(inner: Box<Backend>) -> Self {
+        AppendOnlyBackend(inner)
+    }
+
+    /// Store the blocks which aren't stored yet using `write`, and return
+    /// the tags of all of them.
+    ///
+    /// Backends which never overwrite blocks get the whole batch, so it isn't
+    /// held up by checking for each block first.
+    fn write_new<F>(&mut self, blocks: &[Vec<u8>], write: F)
+            -> BackendResult<Vec<IdentityTag>>
+            where F: FnOnce(&mut Backend, &[Vec<u8>])
+                       -> BackendResult<Vec<IdentityTag>> {
+        if self.0.keeps_existing_blocks() {
+            return write(&mut *self.0, blocks);
+        }
+
+        let tags: Vec<IdentityTag> = blocks.iter().map(|b| block_tag(b))
+                                           .collect();
+        let mut is_new = Vec::with_capacity(blocks.len());
+        for t in tags.iter() {
+            is_new.push(!self.0.has_block(t)?);
+        }
+
+        // only copy the blocks when some of them have to be left out
+        if is_new.iter().all(|&n| n) {
+            write(&mut *self.0, blocks)?;
+        } else {
+            let new: Vec<Vec<u8>> = blocks.iter().zip(is_new.iter())
+                                          .filter(|&(_, &n)| n)
+                                          .map(|(b, _)| b.clone())
+                                          .collect();
+            write(&mut *self.0, &new)?;
+        }
+        Ok(tags)
+    }
+}
+
+/// The tag a block is stored under
+fn block_tag(data: &[u8]) -> IdentityTag {
+    tag_from_digest(ring::digest::digest(&ring::digest::SHA256, data))
+}
+
+impl MetadataStore for AppendOnlyBackend {
+    fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+        self.0.list_meta()
+    }
+
+    fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+        self.0.read_meta(ident)
+    }
+
+    fn write_meta(&mut self, obj: &MetaObject) -> BackendResult<IdentityTag> {
+        let tag = obj.save(&mut Vec::new())?;
+        if self.0.has_meta(&tag)? { return Ok(tag); }
+        self.0.write_meta(obj)
+    }
+
+    fn write_new_meta(&mut self, obj: &MetaObject)
+            -> BackendResult<IdentityTag> {
+        self.0.write_new_meta(obj)
+    }
+
+    fn has_meta(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        self.0.has_meta(ident)
+    }
+
+    fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+        self.0.get_head()
+    }
+
+    fn set_head(&mut self, tag: &IdentityTag) -> BackendResult<()> {
+        self.0.set_head(tag)
+    }
+
+    fn list_heads(&self) -> BackendResult<Vec<String>> {
+        self.0.list_heads()
+    }
+
+    fn delete_meta(&mut self, _ident: &IdentityTag) -> BackendResult<()> {
+        Err(BackendError::AppendOnly)
+    }
+
+    fn view_node(&mut self, node: &str) -> BackendResult<()> {
+        self.0.view_node(node)
+    }
+
+    fn viewed_node(&self) -> Option<String> {
+        self.0.viewed_node()
+    }
+
+    /// Renaming removes the old node's head, so it isn't allowed
+    fn rename_node(&mut self, _old: &str, _new: &str) -> BackendResult<()> {
+        Err(BackendError::AppendOnly)
+    }
+
+    /// Repacking removes the objects it packs, so it isn't allowed
+    fn repack(&mut self) -> BackendResult<usize> {
+        Err(BackendError::AppendOnly)
+    }
+}
+
+impl BlockStore for AppendOnlyBackend {
+    fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        self.0.list_blocks()
+    }
+
+    fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+        self.0.read_block(ident)
+    }
+
+    fn has_block(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        self.0.has_block(ident)
+    }
+
+    fn block_size(&self, ident: &IdentityTag) -> BackendResult<u64> {
+        self.0.block_size(ident)
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> BackendResult<IdentityTag> {
+        if !self.0.keeps_existing_blocks() {
+            let tag = block_tag(data);
+            if self.0.has_block(&tag)? { return Ok(tag); }
+        }
+        self.0.write_block(data)
+    }
+
+    fn write_blocks(&mut self, blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        self.write_new(blocks, |b, new| b.write_blocks(new))
+    }
+
+    fn write_uncompressed(&mut self, blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        self.write_new(blocks, |b, new| b.write_uncompressed(new))
+    }
+
+    fn delete_block(&mut self, _ident: &IdentityTag) -> BackendResult<()> {
+        Err(BackendError::AppendOnly)
+    }
+
+    fn keeps_existing_blocks(&self) -> bool { true }
+
+    fn rebuild_index(&mut self) -> BackendResult<()> {
+        self.0.rebuild_index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use history::History;
+    use metadata::{FSMetadata, MetaObject};
+    use remote::*;
+    use remote::appendonly::AppendOnlyBackend;
+    use remote::memory::MemoryBackend;
+
+    #[test]
+    fn deletes_refused() {
+        let mut inner = MemoryBackend::new();
+        let block_writes = inner.block_writes.clone();
+        let meta_writes = inner.meta_writes.clone();
+        let junk = inner.write_block(b"unreferenced").unwrap();
+        let mut backend: Box<Backend> =
+            Box::new(AppendOnlyBackend::new(Box::new(inner)));
+
+        // new objects are stored, but existing ones aren't written again
+        let block = backend.write_block(b"data").unwrap();
+        assert_eq!(backend.write_block(b"data").unwrap(), block);
+        assert_eq!(backend.write_blocks(&[b"data".to_vec(), b"more".to_vec()])
+                          .unwrap().len(), 2);
+        assert_eq!(block_writes.get(), 3);
+        let file = MetaObject::file("f", FSMetadata::default(), vec![block]);
+        let tag = backend.write_meta(&file).unwrap();
+        backend.write_meta(&file).unwrap();
+        assert_eq!(meta_writes.get(), 1);
+
+        match backend.delete_block(&junk) {
+            Err(BackendError::AppendOnly) => {},
+            _ => panic!("deleted a block from an append-only backend")
+        }
+        match backend.delete_meta(&tag) {
+            Err(BackendError::AppendOnly) => {},
+            _ => panic!("deleted metadata from an append-only backend")
+        }
+        assert!(backend.repack().is_err());
+        assert!(backend.rename_node("a", "b").is_err());
+
+        // collecting garbage fails rather than removing anything
+        {
+            let mut hist = History::new(&mut backend).unwrap();
+            assert!(hist.gc(false).is_err());
+        }
+        assert!(backend.has_block(&junk).unwrap());
+        assert!(backend.has_meta(&tag).unwrap());
+    }
+
+    #[test]
+    fn batches_passed_through() {
+        let mut inner = MemoryBackend::new();
+        inner.keep_existing = true;
+        let block_writes = inner.block_writes.clone();
+        inner.write_block(b"data").unwrap();
+        let mut backend = AppendOnlyBackend::new(Box::new(inner));
+
+        // a backend which never overwrites gets every block, stored or not
+        let batch = [b"data".to_vec(), b"more".to_vec()];
+        let tags = backend.write_blocks(&batch).unwrap();
+        assert_eq!(block_writes.get(), 3);
+        assert_eq!(tags.len(), 2);
+        assert!(tags.iter().all(|t| backend.has_block(t).unwrap()));
+    }
+}
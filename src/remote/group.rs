@@ -0,0 +1,458 @@
+use std::collections::HashSet;
+
+use config::TargetOptions;
+use metadata::{IdentityTag, MetaObject};
+use remote::*;
+
+/// A backend which replicates data across a group of member backends.
+///
+/// Writes go to every member, while reads are served by the first member that
+/// has the requested object, trying cheaper and more reliable members first.
+///
+/// Members marked reliable are trusted to hold the only copy of a block.
+/// Anything stored only on the others can be found with `unreliable_blocks`
+/// and copied over with `replicate`.
+pub struct GroupBackend {
+    /// The member backends and their target options
+    members: Vec<(Box<Backend>, TargetOptions)>,
+
+    /// Indices into `members` in the order they should be tried for reads
+    read_order: Vec<usize>,
+}
+
+impl GroupBackend {
+    /// Build a group out of the given connected members
+    pub fn new(mut members: Vec<(Box<Backend>, TargetOptions)>) -> Self {
+        // cheap uploads go first, so we fail early if a costly one would fail
+        members.sort_by_key(|m| m.1.upload_cost);
+
+        // prefer cheap downloads, then reliable members
+        let mut read_order: Vec<usize> = (0..members.len()).collect();
+        read_order.sort_by_key(|&i| (members[i].1.download_cost,
+                                     !members[i].1.reliable));
+
+        GroupBackend { members: members, read_order: read_order }
+    }
+
+    /// Try a read operation on each member in read order, returning the first
+    /// successful result.
+    fn read_any<T, F>(&self, mut op: F) -> BackendResult<T>
+            where F: FnMut(&Backend) -> BackendResult<T> {
+        let mut last_err = BackendError::InvalidOption;
+        for &i in self.read_order.iter() {
+            match op(&*self.members[i].0) {
+                Ok(r)  => return Ok(r),
+                Err(e) => last_err = e
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Find the blocks which are only stored on members that aren't marked
+    /// reliable, and so could be lost along with them. If no member is
+    /// reliable, that's every block.
+    pub fn unreliable_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        let mut safe = HashSet::new();
+        let mut at_risk = HashSet::new();
+        for &(ref m, ref opts) in self.members.iter() {
+            let blocks = m.list_blocks()?;
+            if opts.reliable { safe.extend(blocks); }
+            else { at_risk.extend(blocks); }
+        }
+
+        let mut result: Vec<IdentityTag> = at_risk.difference(&safe)
+                                                  .cloned().collect();
+        result.sort();
+        Ok(result)
+    }
+
+    /// Copy blocks to every reliable member which doesn't have them yet, from
+    /// whichever member does. Returns how many copies were made.
+    pub fn replicate(&mut self, tags: &[IdentityTag]) -> BackendResult<usize> {
+        if !self.members.iter().any(|m| m.1.reliable) {
+            return Err(BackendError::BackendError(
+                    String::from("group has no reliable members")));
+        }
+
+        let mut copies = 0;
+        for tag in tags.iter() {
+            let data = self.read_any(|m| m.read_block(tag))?;
+            for &mut (ref mut m, ref opts) in self.members.iter_mut() {
+                if !opts.reliable || m.has_block(tag)? { continue; }
+                if m.write_block(&data)? != *tag {
+                    return Err(BackendError::BackendError(
+                            format!("block {} is corrupt", tag)));
+                }
+                copies += 1;
+            }
+        }
+        Ok(copies)
+    }
+}
+
+impl MetadataStore for GroupBackend {
+    fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+        let mut result = Vec::new();
+        for &(ref m, _) in self.members.iter() {
+            result.extend(m.list_meta()?);
+        }
+        result.sort();
+        result.dedup();
+        Ok(result)
+    }
+
+    fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+        self.read_any(|m| m.read_meta(ident))
+    }
+
+    fn write_meta(&mut self, obj: &MetaObject) -> BackendResult<IdentityTag> {
+        let mut tag = None;
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            let t = m.write_meta(obj)?;
+            if tag.is_some() && tag != Some(t) {
+                return Err(BackendError::BackendError(
+                        String::from("group members disagree on object tag")));
+            }
+            tag = Some(t);
+        }
+        tag.ok_or(BackendError::InvalidOption)
+    }
+
+    /// An object only counts as stored if every member has it
+    fn has_meta(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        for &(ref m, _) in self.members.iter() {
+            if !m.has_meta(ident)? { return Ok(false); }
+        }
+        Ok(true)
+    }
+
+    fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+        // pick the most recent snapshot among the members' heads
+        let mut best: Option<MetaObject> = None;
+        let mut found_any = false;
+        let mut last_err = None;
+        for &i in self.read_order.iter() {
+            let head = match self.members[i].0.get_head() {
+                Ok(h)  => { found_any = true; h },
+                Err(e) => { last_err = Some(e); continue; }
+            };
+
+            let newer = match (&head, &best) {
+                (&Some(MetaObject::Snapshot(ref h)),
+                 &Some(MetaObject::Snapshot(ref b))) =>
+                    h.create_time > b.create_time,
+                (&Some(MetaObject::Snapshot(_)), &None) => true,
+                _ => false
+            };
+            if newer { best = head; }
+        }
+
+        match last_err {
+            Some(e) if !found_any => Err(e),
+            _                     => Ok(best)
+        }
+    }
+
+    fn set_head(&mut self, tag: &IdentityTag) -> BackendResult<()> {
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            m.set_head(tag)?;
+        }
+        Ok(())
+    }
+
+    fn list_heads(&self) -> BackendResult<Vec<String>> {
+        let mut result = Vec::new();
+        for &(ref m, _) in self.members.iter() {
+            result.extend(m.list_heads()?);
+        }
+        result.sort();
+        result.dedup();
+        Ok(result)
+    }
+
+    fn delete_meta(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            m.delete_meta(ident)?;
+        }
+        Ok(())
+    }
+
+    fn view_node(&mut self, node: &str) -> BackendResult<()> {
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            m.view_node(node)?;
+        }
+        Ok(())
+    }
+
+    fn viewed_node(&self) -> Option<String> {
+        self.members.first().and_then(|m| m.0.viewed_node())
+    }
+
+    fn rename_node(&mut self, old: &str, new: &str) -> BackendResult<()> {
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            m.rename_node(old, new)?;
+        }
+        Ok(())
+    }
+
+    fn repack(&mut self) -> BackendResult<usize> {
+        let mut packed = 0;
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            packed += m.repack()?;
+        }
+        Ok(packed)
+    }
+}
+
+impl BlockStore for GroupBackend {
+    fn keeps_existing_blocks(&self) -> bool {
+        self.members.iter().all(|&(ref m, _)| m.keeps_existing_blocks())
+    }
+
+    fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        let mut result = Vec::new();
+        for &(ref m, _) in self.members.iter() {
+            result.extend(m.list_blocks()?);
+        }
+        result.sort();
+        result.dedup();
+        Ok(result)
+    }
+
+    fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+        self.read_any(|m| m.read_block(ident))
+    }
+
+    fn block_size(&self, ident: &IdentityTag) -> BackendResult<u64> {
+        self.read_any(|m| m.block_size(ident))
+    }
+
+    /// A block only counts as stored if every member has it, since otherwise
+    /// writing it would still upload it somewhere
+    fn has_block(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        for &(ref m, _) in self.members.iter() {
+            if !m.has_block(ident)? { return Ok(false); }
+        }
+        Ok(true)
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> BackendResult<IdentityTag> {
+        let mut tag = None;
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            let t = m.write_block(data)?;
+            if tag.is_some() && tag != Some(t) {
+                return Err(BackendError::BackendError(
+                        String::from("group members disagree on block tag")));
+            }
+            tag = Some(t);
+        }
+        tag.ok_or(BackendError::InvalidOption)
+    }
+
+    fn write_blocks(&mut self, blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        let mut tags: Option<Vec<IdentityTag>> = None;
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            let t = m.write_blocks(blocks)?;
+            if tags.is_some() && tags.as_ref() != Some(&t) {
+                return Err(BackendError::BackendError(
+                        String::from("group members disagree on block tag")));
+            }
+            tags = Some(t);
+        }
+        tags.ok_or(BackendError::InvalidOption)
+    }
+
+    fn write_uncompressed(&mut self, blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        let mut tags: Option<Vec<IdentityTag>> = None;
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            let t = m.write_uncompressed(blocks)?;
+            if tags.is_some() && tags.as_ref() != Some(&t) {
+                return Err(BackendError::BackendError(
+                        String::from("group members disagree on block tag")));
+            }
+            tags = Some(t);
+        }
+        tags.ok_or(BackendError::InvalidOption)
+    }
+
+    fn delete_block(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            m.delete_block(ident)?;
+        }
+        Ok(())
+    }
+
+    fn rebuild_index(&mut self) -> BackendResult<()> {
+        for &mut (ref mut m, _) in self.members.iter_mut() {
+            m.rebuild_index()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate ring;
+
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use config::TargetOptions;
+    use metadata::{IdentityTag, MetaObject, FSMetadata, tag_from_digest};
+    use remote::*;
+    use remote::group::GroupBackend;
+
+    #[derive(Default)]
+    struct Contents {
+        blocks: HashMap<IdentityTag, Vec<u8>>,
+        meta: HashMap<IdentityTag, Vec<u8>>,
+        head: Option<IdentityTag>
+    }
+
+    /// Simple in-memory store whose contents can be inspected after it's
+    /// moved into a group
+    struct SharedStore(Rc<RefCell<Contents>>);
+
+    impl MetadataStore for SharedStore {
+        fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+            Ok(self.0.borrow().meta.keys().cloned().collect())
+        }
+
+        fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+            let c = self.0.borrow();
+            let data = c.meta.get(ident).ok_or(BackendError::InvalidOption)?;
+            Ok(MetaObject::load(&mut Cursor::new(data))?)
+        }
+
+        fn write_meta(&mut self, obj: &MetaObject)
+                -> BackendResult<IdentityTag> {
+            let mut v = Vec::new();
+            let tag = obj.save(&mut v)?;
+            self.0.borrow_mut().meta.insert(tag, v);
+            Ok(tag)
+        }
+
+        fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+            let head = self.0.borrow().head;
+            match head {
+                Some(t) => self.read_meta(&t).map(Some),
+                None    => Ok(None)
+            }
+        }
+
+        fn set_head(&mut self, tag: &IdentityTag) -> BackendResult<()> {
+            self.0.borrow_mut().head = Some(*tag);
+            Ok(())
+        }
+
+        fn list_heads(&self) -> BackendResult<Vec<String>> {
+            Ok(self.0.borrow().head.iter().map(|_| String::from("node"))
+                                           .collect())
+        }
+
+        fn delete_meta(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+            self.0.borrow_mut().meta.remove(ident);
+            Ok(())
+        }
+    }
+
+    impl BlockStore for SharedStore {
+        fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+            Ok(self.0.borrow().blocks.keys().cloned().collect())
+        }
+
+        fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+            self.0.borrow().blocks.get(ident).cloned()
+                .ok_or(BackendError::InvalidOption)
+        }
+
+        fn write_block(&mut self, data: &[u8]) -> BackendResult<IdentityTag> {
+            let tag = tag_from_digest(
+                ring::digest::digest(&ring::digest::SHA256, data));
+            self.0.borrow_mut().blocks.insert(tag, data.to_vec());
+            Ok(tag)
+        }
+
+        fn delete_block(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+            self.0.borrow_mut().blocks.remove(ident);
+            Ok(())
+        }
+    }
+
+    fn options(download_cost: i32) -> TargetOptions {
+        TargetOptions {
+            download_cost: download_cost,
+            ..TargetOptions::default()
+        }
+    }
+
+    fn make_group() -> (GroupBackend, Rc<RefCell<Contents>>,
+                        Rc<RefCell<Contents>>) {
+        let a = Rc::new(RefCell::new(Contents::default()));
+        let b = Rc::new(RefCell::new(Contents::default()));
+        let group = GroupBackend::new(vec![
+            (Box::new(SharedStore(a.clone())) as Box<Backend>, options(1)),
+            (Box::new(SharedStore(b.clone())) as Box<Backend>, options(5))]);
+        (group, a, b)
+    }
+
+    #[test]
+    fn write_replicates() {
+        let (mut group, a, b) = make_group();
+
+        let tag = group.write_block(b"some block data").unwrap();
+        assert!(a.borrow().blocks.contains_key(&tag));
+        assert!(b.borrow().blocks.contains_key(&tag));
+
+        let obj = MetaObject::tree("dir", FSMetadata::default(), vec![tag]);
+        let mtag = group.write_meta(&obj).unwrap();
+        assert!(a.borrow().meta.contains_key(&mtag));
+        assert!(b.borrow().meta.contains_key(&mtag));
+
+        group.set_head(&mtag).unwrap();
+        assert_eq!(a.borrow().head, Some(mtag));
+        assert_eq!(b.borrow().head, Some(mtag));
+    }
+
+    #[test]
+    fn replicate_to_reliable_members() {
+        let a = Rc::new(RefCell::new(Contents::default()));
+        let b = Rc::new(RefCell::new(Contents::default()));
+        let unreliable = TargetOptions { reliable: false,
+                                         ..TargetOptions::default() };
+        let mut group = GroupBackend::new(vec![
+            (Box::new(SharedStore(a.clone())) as Box<Backend>, unreliable),
+            (Box::new(SharedStore(b.clone())) as Box<Backend>, options(5))]);
+
+        let tag = group.write_block(b"some block data").unwrap();
+        assert!(group.unreliable_blocks().unwrap().is_empty());
+
+        // the reliable member loses its copy
+        b.borrow_mut().blocks.remove(&tag);
+        assert_eq!(group.unreliable_blocks().unwrap(), vec![tag]);
+
+        assert_eq!(group.replicate(&[tag]).unwrap(), 1);
+        assert_eq!(b.borrow().blocks.get(&tag).unwrap(),
+                   &b"some block data".to_vec());
+        assert!(group.unreliable_blocks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_falls_through() {
+        let (mut group, a, b) = make_group();
+
+        let tag = group.write_block(b"some block data").unwrap();
+
+        // drop it from the preferred member
+        a.borrow_mut().blocks.remove(&tag);
+        assert_eq!(group.read_block(&tag).unwrap(), b"some block data".to_vec());
+
+        // and from the other one too
+        b.borrow_mut().blocks.remove(&tag);
+        assert!(group.read_block(&tag).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use metadata::{IdentityTag, IDENTITY_LEN};
+
+/// Record marker for a block known to be stored on the remote
+const RECORD_ADD: u8 = 1;
+
+/// Record marker for a block which was removed from the remote
+const RECORD_REMOVE: u8 = 0;
+
+/// A local, on-disk index of the blocks known to be stored on a remote.
+///
+/// Consulting it lets backends skip asking the remote whether a block exists
+/// before uploading it. The index is an append-only log of added and removed
+/// tags, which is compacted whenever it's rebuilt.
+///
+/// Since other nodes may collect garbage on a shared remote, the index can go
+/// stale. Rebuild it from the remote's block listing when that's a concern.
+pub struct BlockIndex {
+    path: PathBuf,
+    known: HashSet<IdentityTag>,
+    log: fs::File
+}
+
+impl BlockIndex {
+    /// Open the index at a given path, creating it if it doesn't exist
+    pub fn open(path: &Path) -> io::Result<BlockIndex> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // replay the log
+        let mut known = HashSet::new();
+        if path.exists() {
+            let mut data = Vec::new();
+            fs::File::open(path)?.read_to_end(&mut data)?;
+
+            // a trailing partial record is left over from an interrupted write
+            for rec in data.chunks(IDENTITY_LEN + 1) {
+                if rec.len() != IDENTITY_LEN + 1 { break; }
+                let tag = IdentityTag::from_slice(&rec[1..]).unwrap();
+                if rec[0] == RECORD_ADD {
+                    known.insert(tag);
+                } else {
+                    known.remove(&tag);
+                }
+            }
+        }
+
+        let log = fs::OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(BlockIndex { path: path.to_owned(), known: known, log: log })
+    }
+
+    /// Check whether a block is known to be on the remote
+    pub fn contains(&self, tag: &IdentityTag) -> bool {
+        self.known.contains(tag)
+    }
+
+    /// Record that a block is stored on the remote
+    pub fn insert(&mut self, tag: &IdentityTag) -> io::Result<()> {
+        if self.known.insert(*tag) {
+            self.append(RECORD_ADD, tag)?;
+        }
+        Ok(())
+    }
+
+    /// Record that a block was removed from the remote
+    pub fn remove(&mut self, tag: &IdentityTag) -> io::Result<()> {
+        if self.known.remove(tag) {
+            self.append(RECORD_REMOVE, tag)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the index's contents with a fresh listing of the remote's blocks
+    pub fn rebuild(&mut self, tags: &[IdentityTag]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("new");
+        {
+            let mut f = fs::File::create(&tmp_path)?;
+            for tag in tags.iter() {
+                f.write_all(&[RECORD_ADD])?;
+                f.write_all(tag.as_bytes())?;
+            }
+            f.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.known = tags.iter().cloned().collect();
+        self.log = fs::OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn append(&mut self, op: u8, tag: &IdentityTag) -> io::Result<()> {
+        let mut rec = Vec::with_capacity(IDENTITY_LEN + 1);
+        rec.push(op);
+        rec.extend_from_slice(tag.as_bytes());
+        self.log.write_all(&rec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use metadata::IdentityTag;
+    use remote::index::BlockIndex;
+
+    #[test]
+    fn index_persists() {
+        let tag = |b: u8| IdentityTag::from_bytes([b; 32]);
+        let path = env::temp_dir().join("bkp-index-test").join("blocks");
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut idx = BlockIndex::open(&path).unwrap();
+            assert!(!idx.contains(&tag(1)));
+            idx.insert(&tag(1)).unwrap();
+            idx.insert(&tag(2)).unwrap();
+            idx.remove(&tag(1)).unwrap();
+            assert!(!idx.contains(&tag(1)));
+            assert!(idx.contains(&tag(2)));
+        }
+
+        // the log should replay to the same state
+        let mut idx = BlockIndex::open(&path).unwrap();
+        assert!(!idx.contains(&tag(1)));
+        assert!(idx.contains(&tag(2)));
+
+        idx.rebuild(&[tag(3)]).unwrap();
+        idx.insert(&tag(4)).unwrap();
+        let idx = BlockIndex::open(&path).unwrap();
+        assert!(!idx.contains(&tag(2)));
+        assert!(idx.contains(&tag(3)));
+        assert!(idx.contains(&tag(4)));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,751 @@
+extern crate ring;
+
+use std::ops::Drop;
+use std::path::{Path, PathBuf};
+use std::cell::Cell;
+use std::fs;
+use std::io;
+
+use std::io::{Cursor,Read,Write};
+
+use metadata::{IdentityTag, MetaObject, tag_from_digest};
+use remote::*;
+use keys::{MetaKey, DataKey, SEAL_OVERHEAD};
+use util::ToHex;
+
+pub struct ConnectOptions<'a> {
+    /// The local directory to use as a storage root
+    pub root: &'a Path,
+
+    /// The local nodename. Used for creating head pointers
+    pub nodename: String,
+
+    /// The keystore to use for data encryption/decryption
+    pub keystore: keys::Keystore,
+
+    /// Only open an existing store, without locking or initializing it
+    pub read_only: bool
+}
+
+pub struct Backend {
+    /// The root path of the store
+    root: PathBuf,
+
+    /// The name under which this store's data key is kept in the keystore
+    key_name: String,
+
+    /// The node name to use for head pointers
+    node: String,
+
+    /// Another node whose head and metadata are being read instead of ours
+    view: Option<String>,
+
+    /// The keystore to use for data encryption/decryption
+    keystore: keys::Keystore,
+
+    // cached data and metadata keys
+    datakey: Cell<Option<DataKey>>,
+    metakey: Cell<Option<MetaKey>>,
+
+    /// Whether we hold the store's lock file, and need to remove it when
+    /// we're done
+    locked: bool
+}
+
+/// Build the path of an object under one of the store's subdirectories
+fn object_path(root: &Path, kind: &str, ident: &IdentityTag) -> PathBuf {
+    let mut path = root.join(kind);
+    path.push(ident.dir_prefix());
+    path.push(ident.to_string());
+    path
+}
+
+/// Write the given data to a new file at the given path, creating its parent
+/// directory if needed. Existing files are left untouched, since objects are
+/// keyed by their contents.
+fn write_object(path: &Path, data: &[u8]) -> BackendResult<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.exists() { fs::create_dir(parent)?; }
+    }
+
+    // short-circuit if it's already stored
+    let mut f = match fs::OpenOptions::new().write(true).create_new(true)
+                                            .open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists =>
+            return Ok(()),
+        Err(e) => return Err(e.into())
+    };
+    f.write_all(data)?;
+    f.sync_all()?;
+    Ok(())
+}
+
+/// List the identity tags of all objects stored under a given directory
+fn list_objects(dir: &Path) -> BackendResult<Vec<IdentityTag>> {
+    let mut result = Vec::new();
+
+    for prefix in fs::read_dir(dir)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(prefix.path())? {
+            let file = file?;
+            // anything not named by a valid tag isn't an object
+            if let Some(tag) = file.file_name().to_str()
+                                   .and_then(|n| n.parse().ok()) {
+                result.push(tag);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Remove the lock file under a storage root, returning when it was taken
+pub fn break_lock(root: &Path) -> BackendResult<Option<LockInfo>> {
+    let lock_path = root.join("bkp.lock");
+    let meta = match fs::metadata(&lock_path) {
+        Ok(m)  => m,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into())
+    };
+    fs::remove_file(&lock_path)?;
+    Ok(Some(LockInfo::unknown(meta.modified()?)))
+}
+
+/// Go through each stage of opening a store, recording how each went in
+/// `report`. The store is left as it was, apart from briefly locking it.
+pub fn probe(opts: ConnectOptions, report: &mut ProbeReport) {
+    let root = match fs::canonicalize(opts.root) {
+        Ok(ref r) if r.is_dir() => r.to_owned(),
+        _ => return report.fail("directory", format!(
+                "cannot access {}", opts.root.display()))
+    };
+    let initialized = root.join("metadata").exists() &&
+                      root.join("blocks").exists();
+    report.pass("directory", format!(
+            "{} exists, {}", root.display(),
+            if initialized { "initialized" }
+            else { "not initialized yet; it will be on first use" }));
+
+    let lock_path = root.join("bkp.lock");
+    let lock = fs::OpenOptions::new().write(true).create_new(true)
+                                     .open(&lock_path)
+                                     .and_then(|_| fs::remove_file(&lock_path));
+    match lock {
+        Ok(()) => report.pass("lock", "acquired and released"),
+        Err(e) => return report.fail("lock", format!("unable to lock - {}", e))
+    }
+
+    report.check_keys(&opts.keystore, &key_name(&root), initialized);
+}
+
+/// The name a store's data key is kept under. There's no host to key it by,
+/// so it's keyed by the store's location.
+fn key_name(root: &Path) -> String {
+    let path = root.to_string_lossy();
+    let hash = ring::digest::digest(&ring::digest::SHA256, path.as_bytes());
+    format!("local-{}", hash.as_ref().to_hex())
+}
+
+impl Backend {
+    /// Initialize a store at the root if one doesn't exist already, and make
+    /// sure the local keystore has the keys needed to access it.
+    fn initialize(&mut self) -> Result<(), BackendError> {
+        let meta_root = self.root.join("metadata");
+        let mkeys_root = self.root.join("metakeys");
+        if !meta_root.exists() || !self.root.join("blocks").exists() {
+            info!("initializing local target under {:?}", self.root);
+            fs::create_dir(&meta_root)?;
+            fs::create_dir(&mkeys_root)?;
+            fs::create_dir(&self.root.join("blocks"))?;
+            fs::create_dir(&self.root.join("heads"))?;
+
+            // generate data key for the store and keep a copy there
+            let data_key = self.keystore.new_data_key(&self.key_name)?;
+            {
+                let mut dkey = fs::File::create(&self.root.join("datakey"))?;
+                data_key.write(&self.keystore, &mut dkey)?;
+            }
+        }
+
+        // make sure we have the store's data key locally
+        if let Err(_) = self.keystore.get_data_key(&self.key_name) {
+            info!("retriving remote data key");
+            let mut f = fs::File::open(&self.root.join("datakey"))?;
+            self.keystore.store_data_key(&self.key_name, &mut f)?;
+        }
+
+        // make sure we have the appropriate meta key there
+        let our_meta = mkeys_root.join(&self.node);
+        if !our_meta.exists() {
+            let meta_key = self.keystore.get_meta_key()?;
+            let mut mkey = fs::File::create(&our_meta)?;
+            meta_key.write(&self.keystore, &mut mkey)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure a store has already been set up at the root, and that the
+    /// local keystore has its data key. Nothing under the root is changed.
+    fn check_store(&self) -> Result<(), BackendError> {
+        if !self.root.join("metadata").exists() ||
+                !self.root.join("blocks").exists() {
+            return Err(BackendError::NotInitialized);
+        }
+
+        if let Err(_) = self.keystore.get_data_key(&self.key_name) {
+            info!("retriving remote data key");
+            let mut f = fs::File::open(&self.root.join("datakey"))?;
+            self.keystore.store_data_key(&self.key_name, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// The node whose head is currently being accessed
+    fn head_node(&self) -> &str {
+        self.view.as_ref().unwrap_or(&self.node)
+    }
+
+    /// Get the local meta key
+    fn meta_key(&self) -> MetaKey {
+        match self.metakey.get() {
+            Some(r) => r,
+            None => {
+                self.metakey.replace(self.keystore.get_meta_key().ok());
+                self.metakey.get().unwrap()
+            }
+        }
+    }
+
+    /// Get the local data key
+    fn data_key(&self) -> DataKey {
+        match self.datakey.get() {
+            Some(r) => r,
+            None => {
+                self.datakey.replace(
+                    self.keystore.get_data_key(&self.key_name).ok());
+                self.datakey.get().unwrap()
+            }
+        }
+    }
+
+    /// Lock the target atomically. If we fail, return an error.
+    fn lock(&mut self) -> Result<(), BackendError> {
+        let lock_path = self.root.join("bkp.lock");
+        fs::OpenOptions::new().write(true)
+                              .create_new(true)
+                              .open(&lock_path)
+                              .map_err(|e| BackendError::BackendError(
+                                  format!("unable to lock - {}", e)))?;
+        self.locked = true;
+        Ok(())
+    }
+
+    /// Release an atomic lock on the target
+    fn unlock(&mut self) -> Result<(), BackendError> {
+        if self.locked {
+            fs::remove_file(&self.root.join("bkp.lock"))?;
+            self.locked = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        // this also runs when creating the backend fails partway through, so
+        // a failed connection doesn't leave the target locked
+        if let Err(e) = self.unlock() {
+            error!("bkp: failed to unlock target: {}", e);
+        }
+    }
+}
+
+impl MetadataStore for Backend {
+    fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+        list_objects(&self.root.join("metadata"))
+    }
+
+    fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+        let path = object_path(&self.root, "metadata", ident);
+        let data = {
+            let mut f = fs::File::open(&path)?;
+            let mut data = Vec::new();
+            f.read_to_end(&mut data)?;
+            self.meta_key().decrypt(data)?
+        };
+
+        // read the meta object
+        Ok(MetaObject::load(&mut Cursor::new(data))?)
+    }
+
+    fn write_meta(&mut self, obj: &MetaObject) -> BackendResult<IdentityTag> {
+        // encode the object and encrypt it
+        let (tag, encoded) = {
+            let mut v = Vec::new();
+            let tag = obj.save(&mut v)?;
+            (tag, self.meta_key().encrypt(v)?)
+        };
+
+        // no need to lock here, since the files are keyed by contents
+        write_object(&object_path(&self.root, "metadata", &tag), &encoded)?;
+        Ok(tag)
+    }
+
+    fn has_meta(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        Ok(object_path(&self.root, "metadata", ident).exists())
+    }
+
+    fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+        let path = self.root.join("heads").join(self.head_node());
+
+        // the target is locked for as long as we're connected, so the head
+        // can't change underneath us
+        let ident = match fs::File::open(&path) {
+            Ok(mut f) => IdentityTag::read_from(&mut f)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                return Ok(None),
+            Err(e)    => return Err(e.into())
+        };
+
+        // get the object
+        self.read_meta(&ident).map(Some)
+    }
+
+    fn set_head(&mut self, tag: &IdentityTag) -> BackendResult<()> {
+        let path = self.root.join("heads").join(self.head_node());
+
+        let mut f = fs::File::create(&path)?;
+        f.write_all(tag.as_bytes())?;
+        f.sync_all()?;
+        Ok(())
+    }
+
+    fn list_heads(&self) -> BackendResult<Vec<String>> {
+        let mut result = Vec::new();
+        for entry in fs::read_dir(&self.root.join("heads"))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() { continue; }
+            if let Some(n) = entry.file_name().to_str() {
+                result.push(n.to_owned());
+            }
+        }
+        Ok(result)
+    }
+
+    fn delete_meta(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        fs::remove_file(&object_path(&self.root, "metadata", ident))?;
+        Ok(())
+    }
+
+    fn view_node(&mut self, node: &str) -> BackendResult<()> {
+        if node == self.node {
+            // back to our own key, which is loaded on demand
+            self.view = None;
+            self.metakey.set(None);
+            return Ok(());
+        }
+
+        let key = match self.keystore.get_node_meta_key(node) {
+            Ok(k)  => k,
+            Err(keys::Error::MissingNodeKey(_)) => {
+                // fetch it from the store's copy, if we can decrypt that
+                let path = self.root.join("metakeys").join(node);
+                let mut f = fs::File::open(&path).map_err(|_|
+                    keys::Error::MissingNodeKey(node.to_owned()))?;
+                self.keystore.store_node_meta_key(node, &mut f)?
+            },
+            Err(e) => return Err(e.into())
+        };
+
+        self.view = Some(node.to_owned());
+        self.metakey.set(Some(key));
+        Ok(())
+    }
+
+    fn viewed_node(&self) -> Option<String> {
+        Some(self.head_node().to_owned())
+    }
+
+    fn rename_node(&mut self, old: &str, new: &str) -> BackendResult<()> {
+        let heads = self.root.join("heads");
+        let mkeys = self.root.join("metakeys");
+        if heads.join(new).exists() {
+            return Err(BackendError::BackendError(
+                    format!("node '{}' already has snapshots", new)));
+        }
+
+        // copy the key before moving the head, so the head is never left
+        // without a key to read it with
+        if mkeys.join(old).exists() {
+            fs::copy(mkeys.join(old), mkeys.join(new))?;
+        }
+        fs::rename(heads.join(old), heads.join(new))?;
+        if mkeys.join(old).exists() {
+            fs::remove_file(mkeys.join(old))?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockStore for Backend {
+    fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        list_objects(&self.root.join("blocks"))
+    }
+
+    fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+        let path = object_path(&self.root, "blocks", ident);
+        let mut f = fs::File::open(&path)?;
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        Ok(self.data_key().decrypt(data)?)
+    }
+
+    fn has_block(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        Ok(object_path(&self.root, "blocks", ident).exists())
+    }
+
+    fn block_size(&self, ident: &IdentityTag) -> BackendResult<u64> {
+        Ok(fs::metadata(&object_path(&self.root, "blocks", ident))?.len())
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> BackendResult<IdentityTag> {
+        // hash the data
+        let tag = tag_from_digest(ring::digest::digest(&ring::digest::SHA256,
+                                                       data));
+
+        // encrypt the data and write it to a file. the copy is made with room
+        // for encryption, so it's the only one
+        let mut buf = Vec::with_capacity(data.len() + SEAL_OVERHEAD);
+        buf.extend_from_slice(data);
+        let encrypted = self.data_key().encrypt(buf)?;
+        write_object(&object_path(&self.root, "blocks", &tag), &encrypted)?;
+        Ok(tag)
+    }
+
+    fn delete_block(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        fs::remove_file(&object_path(&self.root, "blocks", ident))?;
+        Ok(())
+    }
+
+    fn keeps_existing_blocks(&self) -> bool { true }
+}
+
+impl<'a> RemoteBackend<ConnectOptions<'a>> for Backend {
+    fn create(opts: ConnectOptions) -> Result<Backend, BackendError> {
+        // make sure the target directory exists
+        let root = fs::canonicalize(opts.root).map_err(|_|
+            BackendError::BackendError(String::from("cannot access directory")))?;
+        if !root.is_dir() {
+            return Err(BackendError::BackendError(
+                    String::from("cannot access directory")));
+        }
+
+        let mut backend = Backend {
+            key_name: key_name(&root),
+            root: root,
+            node: opts.nodename,
+            view: None,
+            keystore: opts.keystore,
+            datakey: Cell::new(None),
+            metakey: Cell::new(None),
+            locked: false
+        };
+
+        if opts.read_only {
+            backend.check_store()?;
+            return Ok(backend);
+        }
+
+        // acquire exclusive access *before* initializing so two processes don't
+        // clobber each other. it's held until the backend is dropped
+        backend.lock()?;
+        backend.initialize()?;
+
+        Ok(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate ring;
+
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time;
+    use self::ring::rand::{SecureRandom, SystemRandom};
+
+    use history::History;
+    use keys;
+    use metadata::{FSMetadata, MetaObject, Snapshot};
+    use remote::*;
+    use remote::local::{probe, Backend, ConnectOptions};
+    use remote::readonly::ReadOnlyBackend;
+
+    fn master_key() -> [u8; 32] {
+        let mut mkey = [0u8; 32];
+        SystemRandom::new().fill(&mut mkey).unwrap();
+        mkey
+    }
+
+    fn connect(dir: &Path, node: &str, mkey: [u8; 32]) -> Backend {
+        let ks = keys::Keystore::with_master_key(&dir.join(node), mkey)
+            .unwrap();
+        Backend::create(ConnectOptions { root: &dir.join("store"),
+                                         nodename: node.to_owned(),
+                                         keystore: ks,
+                                         read_only: false }).unwrap()
+    }
+
+    #[test]
+    fn probe_store() {
+        let dir = env::temp_dir().join("bkp-local-probe-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store")).unwrap();
+        let ks = keys::Keystore::with_master_key(&dir.join("keys"),
+                                                 master_key()).unwrap();
+        let store = dir.join("store");
+        let opts = || ConnectOptions { root: &store,
+                                       nodename: String::from("node"),
+                                       keystore: ks.clone(),
+                                       read_only: false };
+        let run = || {
+            let mut report = ProbeReport::default();
+            probe(opts(), &mut report);
+            report
+        };
+
+        // a new store is fine, and gets its data key once it's set up
+        let report = run();
+        assert!(report.is_ok());
+        let names: Vec<_> = report.stages.iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["directory", "lock", "keys"]);
+        assert!(report.stages[0].result.as_ref().unwrap()
+                      .contains("not initialized"));
+        assert!(report.stages[2].result.as_ref().unwrap()
+                      .contains("will be created"));
+
+        drop(Backend::create(opts()).unwrap());
+        let report = run();
+        assert!(report.is_ok());
+        assert_eq!(report.stages[2].result,
+                   Ok(String::from("metadata and data keys available")));
+
+        // someone else holding the lock stops it there
+        fs::File::create(dir.join("store").join("bkp.lock")).unwrap();
+        let report = run();
+        assert_eq!(report.failure().map(|s| s.name), Some("lock"));
+        assert_eq!(report.stages.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_other_node() {
+        let dir = env::temp_dir().join("bkp-local-nodes-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store")).unwrap();
+
+        // two machines whose keystores share a master password, but each have
+        // their own metadata key
+        let mkey = master_key();
+        let connect = |node: &str| connect(&dir, node, mkey);
+
+        let mut first = connect("first");
+        let root = MetaObject::tree("", FSMetadata::default(), vec![]);
+        let root = first.write_meta(&root).unwrap();
+        let snap = MetaObject::Snapshot(Snapshot {
+            create_time: time::UNIX_EPOCH, root: root, parent: None });
+        let snap = first.write_meta(&snap).unwrap();
+        first.set_head(&snap).unwrap();
+        drop(first);
+
+        let mut second = connect("second");
+        assert!(second.get_head().unwrap().is_none());
+        match second.view_node("missing") {
+            Err(BackendError::KeyError(keys::Error::MissingNodeKey(_))) => {},
+            _ => panic!("viewed a node without its key")
+        }
+
+        second.view_node("first").unwrap();
+        match second.get_head().unwrap() {
+            Some(MetaObject::Snapshot(s)) => assert_eq!(s.root, root),
+            _ => panic!("wrong head for other node")
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locked_while_connected() {
+        let dir = env::temp_dir().join("bkp-local-lock-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store")).unwrap();
+        let mkey = master_key();
+        let lock = dir.join("store").join("bkp.lock");
+
+        // the lock outlives initialization, and keeps others out
+        let first = connect(&dir, "first", mkey);
+        assert!(lock.exists());
+        let ks = keys::Keystore::with_master_key(&dir.join("second"), mkey)
+            .unwrap();
+        let second = Backend::create(ConnectOptions {
+            root: &dir.join("store"),
+            nodename: String::from("second"),
+            keystore: ks,
+            read_only: false
+        });
+        assert!(second.is_err());
+        assert!(lock.exists());
+
+        drop(first);
+        assert!(!lock.exists());
+        drop(connect(&dir, "second", mkey));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = env::temp_dir().join("bkp-local-read-only-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store")).unwrap();
+        let mkey = master_key();
+        let open = |node: &str| {
+            let ks = keys::Keystore::with_master_key(&dir.join(node), mkey)
+                .unwrap();
+            Backend::create(ConnectOptions { root: &dir.join("store"),
+                                             nodename: node.to_owned(),
+                                             keystore: ks,
+                                             read_only: true })
+        };
+
+        // nothing is set up on a target which hasn't been used yet
+        match open("reader") {
+            Err(BackendError::NotInitialized) => {},
+            _ => panic!("opened a store which doesn't exist")
+        }
+        assert_eq!(fs::read_dir(dir.join("store")).unwrap().count(), 0);
+
+        let mut writer = connect(&dir, "writer", mkey);
+        let block = writer.write_block(b"some data").unwrap();
+
+        // reading works while another process holds the lock, and fetches the
+        // data key without leaving our metadata key behind
+        assert!(dir.join("store").join("bkp.lock").exists());
+        let mut reader = ReadOnlyBackend::new(Box::new(open("reader")
+                                                           .unwrap()));
+        assert_eq!(reader.read_block(&block).unwrap(), b"some data");
+        assert!(!dir.join("store").join("metakeys").join("reader").exists());
+        match reader.write_block(b"more data") {
+            Err(BackendError::ReadOnly) => {},
+            _ => panic!("wrote through a read-only connection")
+        }
+        assert_eq!(writer.list_blocks().unwrap(), vec![block]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rename_node() {
+        let dir = env::temp_dir().join("bkp-local-rename-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store")).unwrap();
+        let mkey = master_key();
+
+        // one machine, connecting under its old name and then its new one
+        let ks = keys::Keystore::with_master_key(&dir.join("keys"), mkey)
+            .unwrap();
+        let connect = |node: &str| Backend::create(ConnectOptions {
+            root: &dir.join("store"),
+            nodename: node.to_owned(),
+            keystore: ks.clone(),
+            read_only: false
+        }).unwrap();
+
+        let mut old = connect("old");
+        let root = MetaObject::tree("", FSMetadata::default(), vec![]);
+        let root = old.write_meta(&root).unwrap();
+        let snap = MetaObject::Snapshot(Snapshot {
+            create_time: time::UNIX_EPOCH, root: root, parent: None });
+        let snap = old.write_meta(&snap).unwrap();
+        old.set_head(&snap).unwrap();
+
+        // a name which already has snapshots isn't clobbered
+        fs::File::create(dir.join("store").join("heads").join("taken"))
+            .unwrap().write_all(snap.as_bytes()).unwrap();
+        assert!(old.rename_node("old", "taken").is_err());
+
+        old.rename_node("old", "new").unwrap();
+        assert!(!old.list_heads().unwrap().contains(&String::from("old")));
+        assert!(!dir.join("store").join("metakeys").join("old").exists());
+        drop(old);
+
+        let new = connect("new");
+        match new.get_head().unwrap() {
+            Some(MetaObject::Snapshot(s)) => assert_eq!(s.root, root),
+            _ => panic!("head wasn't carried over")
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gc_across_nodes() {
+        let dir = env::temp_dir().join("bkp-local-gc-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("store")).unwrap();
+        let mkey = master_key();
+
+        // the first node stores a file which only it references
+        let mut first = connect(&dir, "first", mkey);
+        let block = first.write_block(b"first's data").unwrap();
+        let file = MetaObject::file("file", FSMetadata::default(), vec![block]);
+        let file = first.write_meta(&file).unwrap();
+        let root = MetaObject::tree("", FSMetadata::default(), vec![file]);
+        let root = first.write_meta(&root).unwrap();
+        let snap = MetaObject::Snapshot(Snapshot {
+            create_time: time::UNIX_EPOCH, root: root, parent: None });
+        let snap = first.write_meta(&snap).unwrap();
+        first.set_head(&snap).unwrap();
+        drop(first);
+
+        // the second leaves some garbage behind
+        let mut second: Box<::remote::Backend> =
+            Box::new(connect(&dir, "second", mkey));
+        let junk = second.write_block(b"junk").unwrap();
+        let junk = MetaObject::file("junk", FSMetadata::default(), vec![junk]);
+        second.write_meta(&junk).unwrap();
+
+        {
+            let mut hist = History::new(&mut second).unwrap();
+            let report = hist.gc(false).unwrap();
+            assert_eq!((report.meta_objects, report.blocks), (1, 1));
+            assert!(report.skipped_nodes.is_empty());
+        }
+        assert_eq!(second.viewed_node(), Some(String::from("second")));
+        assert!(second.read_block(&block).is_ok());
+        assert!(second.list_meta().unwrap().contains(&file));
+
+        // a node whose chain can't be read keeps every block alive
+        let store = dir.join("store");
+        fs::File::create(store.join("heads").join("third")).unwrap()
+            .write_all(snap.as_bytes()).unwrap();
+        fs::File::create(store.join("metakeys").join("third")).unwrap()
+            .write_all(b"not a key").unwrap();
+        second.write_block(b"more junk").unwrap();
+        let mut hist = History::new(&mut second).unwrap();
+        let report = hist.gc(true).unwrap();
+        assert_eq!(report.skipped_nodes, vec![String::from("third")]);
+        assert_eq!(report.blocks, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
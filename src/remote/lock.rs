@@ -0,0 +1,122 @@
+extern crate libc;
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use util;
+
+/// Who holds a target's lock file, and since when
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockInfo {
+    /// Node name of the lock's holder. Empty if unknown.
+    pub node: String,
+
+    /// Process ID of the lock's holder on its node. Zero if unknown.
+    pub pid: u32,
+
+    /// When the lock was taken
+    pub time: SystemTime
+}
+
+impl LockInfo {
+    /// Describe a lock taken by this process right now
+    pub fn current(node: &str) -> Self {
+        // round to whole seconds, which is all that's stored
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+                                    .map(|d| d.as_secs()).unwrap_or(0);
+        LockInfo {
+            node: node.to_owned(),
+            pid: unsafe { libc::getpid() } as u32,
+            time: UNIX_EPOCH + Duration::from_secs(secs)
+        }
+    }
+
+    /// Describe a lock whose holder isn't recorded, taken at a given time
+    pub fn unknown(time: SystemTime) -> Self {
+        LockInfo { node: String::new(), pid: 0, time: time }
+    }
+
+    /// Serialize the lock info for writing into a lock file
+    pub fn encode(&self) -> String {
+        let secs = self.time.duration_since(UNIX_EPOCH)
+                            .map(|d| d.as_secs()).unwrap_or(0);
+        format!("{} {} {}\n", self.node, self.pid, secs)
+    }
+
+    /// Parse the contents of a lock file. Lock files from older versions are
+    /// empty, so this returns `None` for them.
+    pub fn parse(data: &str) -> Option<Self> {
+        let parts: Vec<&str> = data.split_whitespace().collect();
+        if parts.len() != 3 { return None; }
+
+        let pid = match parts[1].parse() { Ok(x) => x, Err(_) => return None };
+        let secs = match parts[2].parse() { Ok(x) => x, Err(_) => return None };
+        Some(LockInfo {
+            node: parts[0].to_owned(),
+            pid: pid,
+            time: UNIX_EPOCH + Duration::from_secs(secs)
+        })
+    }
+
+    /// Decide whether this lock can safely be broken by `node`.
+    ///
+    /// A lock taken on the same node is stale exactly when its process is no
+    /// longer running, and is never broken while that process lives. Locks
+    /// from other nodes can't be checked that way, so they're considered
+    /// stale once they're older than `timeout`.
+    pub fn is_stale(&self, node: &str, now: SystemTime, timeout: Duration)
+            -> bool {
+        if self.node == node && self.pid != 0 {
+            return !process_alive(self.pid);
+        }
+
+        now.duration_since(self.time).map(|age| age > timeout).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let node = if self.node.is_empty() { "unknown node" }
+                   else { self.node.as_str() };
+        write!(f, "{} (pid {}) since {}", node, self.pid,
+               util::format_time(self.time))
+    }
+}
+
+/// Check whether a process with the given PID is running on this machine
+fn process_alive(pid: u32) -> bool {
+    let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+
+    // EPERM means the process exists but belongs to someone else
+    r == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use remote::lock::LockInfo;
+
+    #[test]
+    fn lock_info_roundtrip() {
+        let info = LockInfo::current("node");
+        assert_eq!(LockInfo::parse(&info.encode()), Some(info));
+        assert_eq!(LockInfo::parse(""), None);
+        assert_eq!(LockInfo::parse("node x 12"), None);
+    }
+
+    #[test]
+    fn stale_locks() {
+        let hour = Duration::from_secs(3600);
+        let old = LockInfo { node: String::from("other"), pid: 1,
+                             time: UNIX_EPOCH };
+        let now = UNIX_EPOCH + hour * 2;
+        assert!(old.is_stale("me", now, hour));
+        assert!(!old.is_stale("me", now, hour * 3));
+
+        // a live process on the same node keeps its lock, however old
+        let live = LockInfo { time: UNIX_EPOCH, ..LockInfo::current("me") };
+        assert!(!live.is_stale("me", now, hour));
+    }
+}
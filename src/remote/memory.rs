@@ -0,0 +1,160 @@
+extern crate ring;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::rc::Rc;
+
+use metadata::{IdentityTag, MetaObject, tag_from_digest};
+use remote::*;
+
+/// A backend which keeps everything in memory, for exercising the layers above
+/// the backends without a live remote.
+#[derive(Default)]
+pub struct MemoryBackend {
+    /// Stored data blocks, keyed by their identity
+    pub blocks: HashMap<IdentityTag, Vec<u8>>,
+
+    /// Encoded metadata objects, keyed by their identity
+    pub meta: HashMap<IdentityTag, Vec<u8>>,
+
+    /// The current head snapshot, if any
+    pub head: Option<IdentityTag>,
+
+    /// Number of calls to `write_meta`, shared so that it can still be read
+    /// once the backend has been boxed up
+    pub meta_writes: Rc<Cell<usize>>,
+
+    /// Number of calls to `read_meta`, shared like `meta_writes`
+    pub meta_reads: Rc<Cell<usize>>,
+
+    /// Number of blocks written, shared like `meta_writes`
+    pub block_writes: Rc<Cell<usize>>,
+
+    /// Number of blocks read, shared like `meta_writes`
+    pub block_reads: Rc<Cell<usize>>,
+
+    /// Blocks which were written with `write_uncompressed`, shared like
+    /// `meta_writes`. Nothing is compressed here, but a real backend would
+    /// have stored these without compression.
+    pub uncompressed: Rc<RefCell<HashSet<IdentityTag>>>,
+
+    /// If set, writing blocks fails once this many have been written, as if
+    /// the connection dropped partway through a snapshot
+    pub block_limit: Rc<Cell<Option<usize>>>,
+
+    /// If set, reading metadata fails as if the connection had dropped,
+    /// shared like `meta_writes`
+    pub fail_meta_reads: Rc<Cell<bool>>,
+
+    /// Metadata objects which can't be decrypted, as if another node had
+    /// stored them under its own key. Shared like `meta_writes`.
+    pub foreign_meta: Rc<RefCell<HashSet<IdentityTag>>>,
+
+    /// If set, writing a block which is already stored leaves it alone, like
+    /// backends which never overwrite anything
+    pub keep_existing: bool
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self { MemoryBackend::default() }
+}
+
+impl MetadataStore for MemoryBackend {
+    fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+        Ok(self.meta.keys().cloned().collect())
+    }
+
+    fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+        self.meta_reads.set(self.meta_reads.get() + 1);
+        if self.fail_meta_reads.get() {
+            return Err(BackendError::CommsError);
+        }
+        if self.foreign_meta.borrow().contains(ident) {
+            return Err(keys::Error::CryptoError.into());
+        }
+        let data = self.meta.get(ident).ok_or(BackendError::InvalidOption)?;
+        Ok(MetaObject::load(&mut Cursor::new(data))?)
+    }
+
+    fn write_meta(&mut self, obj: &MetaObject) -> BackendResult<IdentityTag> {
+        let mut v = Vec::new();
+        let tag = obj.save(&mut v)?;
+        self.meta.insert(tag, v);
+        self.meta_writes.set(self.meta_writes.get() + 1);
+        Ok(tag)
+    }
+
+    fn has_meta(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        Ok(self.meta.contains_key(ident))
+    }
+
+    fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+        match self.head {
+            Some(t) => self.read_meta(&t).map(Some),
+            None    => Ok(None)
+        }
+    }
+
+    fn set_head(&mut self, tag: &IdentityTag) -> BackendResult<()> {
+        self.head = Some(*tag);
+        Ok(())
+    }
+
+    fn list_heads(&self) -> BackendResult<Vec<String>> {
+        Ok(self.head.iter().map(|_| String::from("memory")).collect())
+    }
+
+    fn delete_meta(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        self.meta.remove(ident);
+        Ok(())
+    }
+}
+
+impl BlockStore for MemoryBackend {
+    fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        Ok(self.blocks.keys().cloned().collect())
+    }
+
+    fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+        self.block_reads.set(self.block_reads.get() + 1);
+        self.blocks.get(ident).cloned().ok_or(BackendError::InvalidOption)
+    }
+
+    fn has_block(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        Ok(self.blocks.contains_key(ident))
+    }
+
+    fn block_size(&self, ident: &IdentityTag) -> BackendResult<u64> {
+        self.blocks.get(ident).map(|b| b.len() as u64)
+            .ok_or(BackendError::InvalidOption)
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> BackendResult<IdentityTag> {
+        if self.block_limit.get() == Some(self.block_writes.get()) {
+            return Err(BackendError::ConnectionFailed);
+        }
+        self.block_writes.set(self.block_writes.get() + 1);
+
+        let tag = tag_from_digest(
+            ring::digest::digest(&ring::digest::SHA256, data));
+        if !self.keep_existing || !self.blocks.contains_key(&tag) {
+            self.blocks.insert(tag, data.to_vec());
+        }
+        Ok(tag)
+    }
+
+    fn write_uncompressed(&mut self, blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        let tags = self.write_blocks(blocks)?;
+        self.uncompressed.borrow_mut().extend(tags.iter().cloned());
+        Ok(tags)
+    }
+
+    fn delete_block(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        self.blocks.remove(ident);
+        Ok(())
+    }
+
+    fn keeps_existing_blocks(&self) -> bool { self.keep_existing }
+}
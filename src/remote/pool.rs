@@ -0,0 +1,176 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::thread::JoinHandle;
+
+use remote::*;
+
+/// A single connection which can store encoded objects on a remote
+pub trait Uploader {
+    /// Store the given data at a path on the remote
+    fn upload(&mut self, path: &Path, data: &[u8]) -> BackendResult<()>;
+}
+
+/// An object waiting to be uploaded, along with its index in the batch
+struct Job {
+    index: usize,
+    path: PathBuf,
+    data: Vec<u8>
+}
+
+/// A pool of worker threads, each with its own connection, which upload
+/// objects in parallel.
+pub struct UploadPool {
+    jobs: Option<Sender<Job>>,
+    results: Receiver<(usize, BackendResult<()>)>,
+    workers: Vec<JoinHandle<()>>
+}
+
+impl UploadPool {
+    /// Start a pool of `threads` workers. Each worker opens its own connection
+    /// using `connect`.
+    pub fn new<F, U>(threads: usize, connect: F) -> Self
+            where F: Fn() -> BackendResult<U> + Send + Sync + 'static,
+                  U: Uploader {
+        let (job_tx, job_rx) = channel::<Job>();
+        let (res_tx, res_rx) = channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let connect = Arc::new(connect);
+
+        let workers = (0..threads.max(1)).map(|_| {
+            let job_rx = job_rx.clone();
+            let res_tx = res_tx.clone();
+            let connect = connect.clone();
+            thread::spawn(move || {
+                let mut conn = (*connect)();
+                loop {
+                    // only hold the lock long enough to grab one job
+                    let job = match job_rx.lock().unwrap().recv() {
+                        Ok(j)  => j,
+                        Err(_) => break // pool was dropped
+                    };
+
+                    // if we couldn't connect, fail jobs rather than hanging
+                    let r = match conn {
+                        Ok(ref mut c) => c.upload(&job.path, &job.data),
+                        Err(ref e) => Err(BackendError::BackendError(
+                                format!("upload connection failed: {}", e)))
+                    };
+                    if res_tx.send((job.index, r)).is_err() { break; }
+                }
+            })
+        }).collect();
+
+        UploadPool {
+            jobs: Some(job_tx),
+            results: res_rx,
+            workers: workers
+        }
+    }
+
+    /// Upload a batch of objects, waiting for all of them to finish.
+    ///
+    /// Returns the result of each upload, in the same order as the objects.
+    pub fn upload_all(&self, objects: Vec<(PathBuf, Vec<u8>)>)
+            -> Vec<BackendResult<()>> {
+        let count = objects.len();
+        let jobs = self.jobs.as_ref().unwrap();
+        for (i, (path, data)) in objects.into_iter().enumerate() {
+            jobs.send(Job { index: i, path: path, data: data }).unwrap();
+        }
+
+        let mut results: Vec<Option<BackendResult<()>>> =
+            (0..count).map(|_| None).collect();
+        for _ in 0..count {
+            let (i, r) = self.results.recv()
+                .unwrap_or((0, Err(BackendError::ResourceError)));
+            results[i] = Some(r);
+        }
+
+        results.into_iter()
+               .map(|r| r.unwrap_or(Err(BackendError::ResourceError)))
+               .collect()
+    }
+}
+
+impl Drop for UploadPool {
+    fn drop(&mut self) {
+        // closing the job channel tells the workers to exit
+        self.jobs.take();
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use remote::*;
+    use remote::pool::{Uploader, UploadPool};
+
+    /// Uploader which tracks how many uploads are running at once
+    struct SlowUploader {
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>
+    }
+
+    impl Uploader for SlowUploader {
+        fn upload(&mut self, path: &Path, _: &[u8]) -> BackendResult<()> {
+            let n = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            if n > self.max_in_flight.load(Ordering::SeqCst) {
+                self.max_in_flight.store(n, Ordering::SeqCst);
+            }
+            thread::sleep(Duration::from_millis(50));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            if path.ends_with("bad") {
+                Err(BackendError::CommsError)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn make_pool(threads: usize) -> (UploadPool, Arc<AtomicUsize>) {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let max = max_in_flight.clone();
+        let pool = UploadPool::new(threads, move || Ok(SlowUploader {
+            in_flight: in_flight.clone(),
+            max_in_flight: max_in_flight.clone()
+        }));
+        (pool, max)
+    }
+
+    #[test]
+    fn uploads_in_parallel() {
+        let (pool, max) = make_pool(4);
+        let objects = (0..8).map(|i| (PathBuf::from(format!("obj{}", i)),
+                                      vec![i as u8]))
+                            .collect();
+
+        let results = pool.upload_all(objects);
+        assert_eq!(results.len(), 8);
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert!(max.load(Ordering::SeqCst) > 1);
+        assert!(max.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[test]
+    fn reports_failures_in_order() {
+        let (pool, _) = make_pool(2);
+        let results = pool.upload_all(vec![(PathBuf::from("good"), vec![]),
+                                           (PathBuf::from("bad"), vec![]),
+                                           (PathBuf::from("good"), vec![])]);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+    }
+}
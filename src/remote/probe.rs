@@ -0,0 +1,136 @@
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use keys;
+
+/// One stage of connecting to a target, and how it went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProbeStage {
+    /// Which stage this was, e.g. `connect` or `lock`
+    pub name: &'static str,
+
+    /// What was found if the stage succeeded, or why it failed
+    pub result: Result<String, String>,
+
+    /// How long the stage took, for ones where that's worth knowing
+    pub elapsed: Option<Duration>
+}
+
+impl fmt::Display for ProbeStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.result {
+            Ok(ref s)  => write!(f, "{}: ok, {}", self.name, s)?,
+            Err(ref s) => write!(f, "{}: FAILED, {}", self.name, s)?
+        }
+        if let Some(d) = self.elapsed {
+            write!(f, " ({} ms)", d.as_secs() * 1000 +
+                                  (d.subsec_nanos() / 1000000) as u64)?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of going through each stage of connecting to a target without
+/// using it, for finding out why a target which connects can't be used.
+///
+/// Stages run in order, and stop at the first one that fails.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProbeReport {
+    pub stages: Vec<ProbeStage>
+}
+
+impl ProbeReport {
+    /// The stage which failed, if any did
+    pub fn failure(&self) -> Option<&ProbeStage> {
+        self.stages.iter().find(|s| s.result.is_err())
+    }
+
+    /// Whether every stage succeeded
+    pub fn is_ok(&self) -> bool { self.failure().is_none() }
+
+    /// Record a stage which succeeded
+    pub fn pass<S: Into<String>>(&mut self, name: &'static str, detail: S) {
+        self.stages.push(ProbeStage { name: name, result: Ok(detail.into()),
+                                      elapsed: None });
+    }
+
+    /// Record a stage which failed
+    pub fn fail<S: fmt::Display>(&mut self, name: &'static str, err: S) {
+        self.stages.push(ProbeStage { name: name,
+                                      result: Err(err.to_string()),
+                                      elapsed: None });
+    }
+
+    /// Run a stage and record how it went and how long it took, returning its
+    /// result if it succeeded
+    pub fn timed<T, E, F, D>(&mut self, name: &'static str, stage: F,
+                             describe: D) -> Option<T>
+            where F: FnOnce() -> Result<T, E>, E: fmt::Display,
+                  D: FnOnce(&T) -> String {
+        let start = Instant::now();
+        let res = stage();
+        let elapsed = Some(start.elapsed());
+        match res {
+            Ok(x)  => {
+                self.stages.push(ProbeStage { name: name,
+                                              result: Ok(describe(&x)),
+                                              elapsed: elapsed });
+                Some(x)
+            },
+            Err(e) => {
+                self.stages.push(ProbeStage { name: name,
+                                              result: Err(e.to_string()),
+                                              elapsed: elapsed });
+                None
+            }
+        }
+    }
+
+    /// Check that the keystore holds the keys for a target's data: this node's
+    /// metadata key, and the data key stored under `key_name` unless the
+    /// target hasn't been set up yet
+    pub fn check_keys(&mut self, ks: &keys::Keystore, key_name: &str,
+                      initialized: bool) {
+        if let Err(e) = ks.get_meta_key() {
+            return self.fail("keys", format!("no usable metadata key: {}", e));
+        }
+        match ks.get_data_key(key_name) {
+            Ok(_) => self.pass("keys", "metadata and data keys available"),
+            Err(keys::Error::IOError(ref e))
+                    if e.kind() == io::ErrorKind::NotFound => {
+                let from = if initialized { "fetched from the target" }
+                           else { "created" };
+                self.pass("keys", format!("metadata key available, data key \
+                                           will be {} on first use", from))
+            },
+            Err(e) => self.fail("keys", format!("no usable data key: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use remote::probe::{ProbeReport, ProbeStage};
+
+    #[test]
+    fn probe_stages() {
+        let mut report = ProbeReport::default();
+        report.pass("resolve", "1 address");
+        assert_eq!(report.timed("connect", || Ok::<u32, String>(22),
+                                |p| format!("port {}", p)), Some(22));
+        assert!(report.is_ok());
+
+        assert_eq!(report.timed("lock", || Err::<(), _>("locked by someone"),
+                                |_| String::new()), None);
+        assert_eq!(report.failure().map(|s| s.name), Some("lock"));
+        assert_eq!(report.stages[2].result,
+                   Err(String::from("locked by someone")));
+
+        let stage = ProbeStage { name: "connect", result: Ok(String::from("x")),
+                                 elapsed: Some(Duration::from_millis(1500)) };
+        assert_eq!(stage.to_string(), "connect: ok, x (1500 ms)");
+    }
+}
@@ -0,0 +1,110 @@
+use metadata::{IdentityTag, MetaObject};
+use remote::*;
+
+/// A backend which passes reads through to another one, and refuses anything
+/// that would change what's stored.
+///
+/// Read-only connections neither lock nor initialize the target, so other
+/// processes may be writing to it at the same time. Refusing writes here
+/// keeps them from racing with those processes.
+pub struct ReadOnlyBackend(Box<Backend>);
+
+impl ReadOnlyBackend {
+    pub fn new(inner: Box<Backend>) -> Self {
+        ReadOnlyBackend(inner)
+    }
+}
+
+impl MetadataStore for ReadOnlyBackend {
+    fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+        self.0.list_meta()
+    }
+
+    fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+        self.0.read_meta(ident)
+    }
+
+    fn write_meta(&mut self, _obj: &MetaObject)
+            -> BackendResult<IdentityTag> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn has_meta(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        self.0.has_meta(ident)
+    }
+
+    fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+        self.0.get_head()
+    }
+
+    fn set_head(&mut self, _tag: &IdentityTag) -> BackendResult<()> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn list_heads(&self) -> BackendResult<Vec<String>> {
+        self.0.list_heads()
+    }
+
+    fn delete_meta(&mut self, _ident: &IdentityTag) -> BackendResult<()> {
+        Err(BackendError::ReadOnly)
+    }
+
+    /// Viewing another node only changes what's read, so it's allowed
+    fn view_node(&mut self, node: &str) -> BackendResult<()> {
+        self.0.view_node(node)
+    }
+
+    fn viewed_node(&self) -> Option<String> {
+        self.0.viewed_node()
+    }
+
+    fn rename_node(&mut self, _old: &str, _new: &str) -> BackendResult<()> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn repack(&mut self) -> BackendResult<usize> {
+        Err(BackendError::ReadOnly)
+    }
+}
+
+impl BlockStore for ReadOnlyBackend {
+    fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        self.0.list_blocks()
+    }
+
+    fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+        self.0.read_block(ident)
+    }
+
+    fn has_block(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        self.0.has_block(ident)
+    }
+
+    fn block_size(&self, ident: &IdentityTag) -> BackendResult<u64> {
+        self.0.block_size(ident)
+    }
+
+    fn write_block(&mut self, _data: &[u8]) -> BackendResult<IdentityTag> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn write_blocks(&mut self, _blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn write_uncompressed(&mut self, _blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn delete_block(&mut self, _ident: &IdentityTag) -> BackendResult<()> {
+        Err(BackendError::ReadOnly)
+    }
+
+    /// The index is a local cache of what's on the target, so rebuilding it
+    /// doesn't write anything there
+    fn rebuild_index(&mut self) -> BackendResult<()> {
+        self.0.rebuild_index()
+    }
+}
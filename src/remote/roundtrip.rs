@@ -0,0 +1,176 @@
+//! End-to-end tests which store a directory tree in a snapshot, restore it,
+//! and check that it comes back exactly as it was.
+//!
+//! The same scenario runs against each kind of backend. The SSH one needs a
+//! server to talk to, so it only runs when asked for with `--ignored`, and
+//! stores its data under `BKP_TEST_SSH_URL`, e.g.
+//! `ssh://user@localhost/tmp/bkp-test`. That directory has to exist already,
+//! and authentication goes through ssh-agent.
+
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use config::{BackupTarget, TargetOptions};
+use history::{History, Restorable, RestoreOptions};
+use keys::Keystore;
+use remote::{connect_tgt, Backend};
+use remote::memory::MemoryBackend;
+
+/// Create a file with the given contents and permissions
+fn write_file(path: &Path, data: &[u8], mode: u32) {
+    fs::File::create(path).unwrap().write_all(data).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// Fill `dir` with a tree covering each kind of object bkp stores
+fn populate(dir: &Path) {
+    fs::create_dir_all(dir.join("docs")).unwrap();
+    write_file(&dir.join("docs").join("notes.txt"), b"some notes\n", 0o640);
+    write_file(&dir.join("docs").join("empty"), b"", 0o644);
+    write_file(&dir.join("run.sh"), b"#!/bin/sh\necho hi\n", 0o755);
+
+    // big enough to be split into several blocks, and not compressible
+    let mut data = Vec::with_capacity(3 * 1024 * 1024 + 17);
+    let mut x: u32 = 12345;
+    while data.len() < data.capacity() {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        data.push((x >> 16) as u8);
+    }
+    write_file(&dir.join("data.bin"), &data, 0o600);
+    fs::hard_link(dir.join("data.bin"), dir.join("data-link")).unwrap();
+
+    symlink("notes.txt", dir.join("docs").join("link")).unwrap();
+    symlink("missing/target", dir.join("dangling")).unwrap();
+    fs::set_permissions(dir.join("docs"), fs::Permissions::from_mode(0o750))
+        .unwrap();
+}
+
+/// Check that two trees hold the same objects, with the same contents and
+/// metadata
+fn assert_same_tree(orig: &Path, copy: &Path) {
+    let a = fs::symlink_metadata(orig).unwrap();
+    let b = fs::symlink_metadata(copy)
+        .unwrap_or_else(|e| panic!("{:?} wasn't restored: {}", copy, e));
+    assert_eq!(a.file_type(), b.file_type(), "type of {:?}", copy);
+    assert_eq!(a.mode(), b.mode(), "mode of {:?}", copy);
+    assert_eq!((a.uid(), a.gid()), (b.uid(), b.gid()), "owner of {:?}", copy);
+
+    if a.file_type().is_dir() {
+        let list = |p: &Path| {
+            let mut names: Vec<_> = fs::read_dir(p).unwrap()
+                .map(|e| e.unwrap().file_name()).collect();
+            names.sort();
+            names
+        };
+        let names = list(orig);
+        assert_eq!(names, list(copy), "contents of {:?}", copy);
+        for name in names {
+            assert_same_tree(&orig.join(&name), &copy.join(&name));
+        }
+    } else if a.file_type().is_symlink() {
+        assert_eq!(fs::read_link(orig).unwrap(), fs::read_link(copy).unwrap(),
+                   "target of {:?}", copy);
+    } else {
+        let read = |p: &Path| {
+            let mut data = Vec::new();
+            fs::File::open(p).unwrap().read_to_end(&mut data).unwrap();
+            data
+        };
+        assert!(read(orig) == read(copy), "contents of {:?}", copy);
+        assert_eq!((a.mtime(), a.mtime_nsec()), (b.mtime(), b.mtime_nsec()),
+                   "mtime of {:?}", copy);
+        assert_eq!(a.nlink(), b.nlink(), "links to {:?}", copy);
+    }
+}
+
+/// Snapshot a freshly-built tree into `backend`, restore it from the new
+/// snapshot, and check that nothing was lost along the way
+fn snap_and_restore(backend: &mut Box<Backend>, name: &str) {
+    let base = env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("restored")).unwrap();
+    let src = base.canonicalize().unwrap().join("src");
+    populate(&src);
+
+    let mut history = History::new(backend).unwrap();
+    let root = history.update_paths(vec![src.as_os_str()]).unwrap();
+    history.new_snapshot(root).unwrap();
+
+    let dest = base.join("restored");
+    {
+        let snap = history.get_snapshot().unwrap().expect("no snapshot");
+        let obj = snap.get(&src).unwrap().expect("tree wasn't stored");
+        obj.restore(&dest, &RestoreOptions::new()).unwrap();
+    }
+    assert_same_tree(&src, &dest.join("src"));
+
+    // hard links should still share one file
+    let inode = |p: &Path| fs::metadata(p).unwrap().ino();
+    assert_eq!(inode(&dest.join("src").join("data.bin")),
+               inode(&dest.join("src").join("data-link")));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+fn target(name: &str, url: Url) -> BackupTarget {
+    BackupTarget {
+        name: name.to_owned(),
+        url: url,
+        user: None,
+        password: None,
+        key_file: None,
+        agent_identity: None,
+        options: TargetOptions::default()
+    }
+}
+
+#[test]
+fn memory_roundtrip() {
+    let mut backend: Box<Backend> = Box::new(MemoryBackend::new());
+    snap_and_restore(&mut backend, "bkp-roundtrip-memory");
+}
+
+#[test]
+fn local_roundtrip() {
+    let dir = env::temp_dir().join("bkp-roundtrip-local-store");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("store")).unwrap();
+
+    let ks = Keystore::with_master_key(&dir.join("keys"), [3u8; 32]).unwrap();
+    let url = Url::from_file_path(dir.join("store")).unwrap();
+    let mut backend = connect_tgt(&target("local", url), "roundtrip", &ks,
+                                  &dir).unwrap();
+    snap_and_restore(&mut backend, "bkp-roundtrip-local");
+    drop(backend);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore] // needs an SSH server
+fn ssh_roundtrip() {
+    let url = env::var("BKP_TEST_SSH_URL")
+        .expect("BKP_TEST_SSH_URL must name a test server");
+    let url = Url::parse(&url).unwrap();
+    let dir = env::temp_dir().join("bkp-roundtrip-ssh-keys");
+    let _ = fs::remove_dir_all(&dir);
+
+    // the server's data key outlives each run, so every run has to be able to
+    // decrypt it. each run stores its snapshots under a new node, though, so
+    // they don't depend on metadata keys from earlier runs.
+    let ks = Keystore::with_master_key(&dir.join("keys"), [5u8; 32]).unwrap();
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let node = format!("roundtrip-{}-{}", secs, process::id());
+    let mut backend = connect_tgt(&target("ssh", url), &node, &ks, &dir)
+        .unwrap();
+    snap_and_restore(&mut backend, "bkp-roundtrip-ssh");
+    drop(backend);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,219 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Settings from an OpenSSH client configuration file which apply to a host.
+///
+/// Only the settings needed to find and log into the host are read. `Match`
+/// blocks and `Include` directives aren't supported, and settings under them
+/// are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    /// The real host name to connect to, for when the host is an alias
+    pub hostname: Option<String>,
+
+    pub port: Option<u16>,
+    pub user: Option<String>,
+
+    /// The first identity file given for the host
+    pub identity_file: Option<PathBuf>
+}
+
+impl HostConfig {
+    /// Find the settings for a host in the user's `~/.ssh/config`, if there is
+    /// one. A file which can't be read is treated as empty.
+    pub fn for_host(host: &str) -> HostConfig {
+        let home = match env::home_dir() {
+            Some(h) => h,
+            None    => return HostConfig::default()
+        };
+        let path = home.join(".ssh").join("config");
+        HostConfig::load(&path, host, &home).unwrap_or_default()
+    }
+
+    /// Find the settings for a host in a configuration file, expanding `~` in
+    /// paths to `home`
+    pub fn load(path: &Path, host: &str, home: &Path)
+            -> io::Result<HostConfig> {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+        Ok(HostConfig::parse(&text, host, home))
+    }
+
+    /// Find the settings for a host in the text of a configuration file.
+    ///
+    /// Like `ssh`, the first value found for each setting is the one used, so
+    /// specific hosts should come before wildcard patterns.
+    pub fn parse(text: &str, host: &str, home: &Path) -> HostConfig {
+        let mut cfg = HostConfig::default();
+
+        // settings before the first Host line apply to every host
+        let mut applies = true;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            // keywords are separated from their arguments by whitespace or a
+            // single '='
+            let split = line.find(|c: char| c.is_whitespace() || c == '=')
+                            .unwrap_or(line.len());
+            let keyword = line[..split].to_lowercase();
+            let rest = line[split..].trim_left();
+            let rest = if rest.starts_with('=') { rest[1..].trim_left() }
+                       else { rest };
+            let value = unquote(rest);
+
+            match keyword.as_str() {
+                "host" => applies = host_matches(rest, host),
+                "match" => applies = false,
+                _ if !applies || value.is_empty() => {},
+                "hostname" if cfg.hostname.is_none() => {
+                    cfg.hostname = Some(value.replace("%h", host)
+                                             .replace("%%", "%"));
+                },
+                "port" if cfg.port.is_none() => {
+                    cfg.port = value.parse().ok();
+                },
+                "user" if cfg.user.is_none() => {
+                    cfg.user = Some(value.to_owned());
+                },
+                "identityfile" if cfg.identity_file.is_none() => {
+                    cfg.identity_file = Some(expand_path(value, host, home));
+                },
+                _ => {}
+            }
+        }
+        cfg
+    }
+}
+
+/// Strip the quotes from a quoted argument
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len()-1]
+    } else {
+        s
+    }
+}
+
+/// Expand `~` and the tokens `ssh` supports in identity file paths
+fn expand_path(path: &str, host: &str, home: &Path) -> PathBuf {
+    let home_str = home.to_string_lossy();
+    let path = path.replace("%d", &home_str)
+                   .replace("%h", host)
+                   .replace("%%", "%");
+    if path == "~" {
+        home.to_owned()
+    } else if path.starts_with("~/") {
+        home.join(&path[2..])
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+/// Check whether a host matches a `Host` line's patterns. Any pattern can
+/// match, but a negated pattern which matches rules the host out entirely.
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut found = false;
+    for pat in patterns.split_whitespace().map(unquote) {
+        if pat.starts_with('!') {
+            if wildcard_match(pat[1..].as_bytes(), host.as_bytes()) {
+                return false;
+            }
+        } else if wildcard_match(pat.as_bytes(), host.as_bytes()) {
+            found = true;
+        }
+    }
+    found
+}
+
+/// Match a host name against a pattern where `*` stands for any number of
+/// characters and `?` for exactly one. Host names aren't case-sensitive.
+fn wildcard_match(pat: &[u8], name: &[u8]) -> bool {
+    match pat.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) =>
+            (0..name.len() + 1).any(|i| wildcard_match(rest, &name[i..])),
+        Some((&b'?', rest)) =>
+            !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) =>
+            name.first().map_or(false, |n| n.eq_ignore_ascii_case(c)) &&
+                wildcard_match(rest, &name[1..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use remote::sshconfig::HostConfig;
+
+    const FIXTURE: &'static str = "\
+# settings for everything come first
+Compression yes
+
+Host backup nas
+    HostName storage.example.com
+    Port 2222
+    IdentityFile ~/.ssh/backup_ed25519
+
+Host *.lan !printer.lan
+    User admin
+    Port=2200
+    HostName \"%h.example.com\"
+
+Match exec \"false\"
+    User nobody
+
+Host *
+    User fallback
+    Port 22
+    IdentityFile %d/.ssh/id_rsa
+";
+
+    #[test]
+    fn alias_resolved() {
+        let dir = env::temp_dir().join("bkp-ssh-config");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        fs::File::create(&path).unwrap()
+            .write_all(FIXTURE.as_bytes()).unwrap();
+
+        let home = Path::new("/home/me");
+        let cfg = HostConfig::load(&path, "backup", home).unwrap();
+        assert_eq!(cfg, HostConfig {
+            hostname: Some(String::from("storage.example.com")),
+            port: Some(2222),
+            user: Some(String::from("fallback")),
+            identity_file: Some(PathBuf::from("/home/me/.ssh/backup_ed25519"))
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn host_patterns() {
+        let home = Path::new("/home/me");
+        let cfg = HostConfig::parse(FIXTURE, "Pi.lan", home);
+        assert_eq!(cfg.hostname, Some(String::from("Pi.lan.example.com")));
+        assert_eq!(cfg.port, Some(2200));
+        assert_eq!(cfg.user, Some(String::from("admin")));
+        assert_eq!(cfg.identity_file,
+                   Some(PathBuf::from("/home/me/.ssh/id_rsa")));
+
+        // negated patterns rule a host out, and Match blocks are skipped
+        let cfg = HostConfig::parse(FIXTURE, "printer.lan", home);
+        assert_eq!(cfg.hostname, None);
+        assert_eq!(cfg.user, Some(String::from("fallback")));
+        assert_eq!(cfg.port, Some(22));
+
+        assert_eq!(HostConfig::parse("Host other\nPort 1\n", "h", home),
+                   HostConfig::default());
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting the rate at which data is transferred.
+///
+/// The bucket holds up to one second's worth of bytes, so short bursts go
+/// through immediately. A transfer larger than what's left in the bucket
+/// still goes through in one piece, but the caller is made to wait until the
+/// bucket would have refilled enough to pay for it.
+pub struct Throttle {
+    /// Sustained rate, in bytes per second
+    rate: u64,
+
+    /// Bytes currently available, and when that was last updated. The count
+    /// goes negative when a transfer borrows against future refills.
+    state: Mutex<(f64, Instant)>
+}
+
+impl Throttle {
+    /// Create a throttle allowing `rate` bytes per second. The bucket starts
+    /// out full.
+    pub fn new(rate: u64) -> Self {
+        Throttle::starting_at(rate, Instant::now())
+    }
+
+    fn starting_at(rate: u64, now: Instant) -> Self {
+        Throttle { rate: rate.max(1), state: Mutex::new((rate as f64, now)) }
+    }
+
+    /// Take `bytes` out of the bucket at time `now`, returning how long the
+    /// caller has to wait before the transfer fits within the rate limit
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let rate = self.rate as f64;
+
+        // refill for the time that's passed, up to the bucket's capacity
+        let elapsed = now.duration_since(state.1);
+        let elapsed = elapsed.as_secs() as f64 +
+                      elapsed.subsec_nanos() as f64 / 1e9;
+        let tokens = (state.0 + elapsed * rate).min(rate) - bytes as f64;
+        *state = (tokens, now);
+
+        if tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let secs = -tokens / rate;
+            Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+        }
+    }
+
+    /// Block until `bytes` more bytes can be transferred
+    pub fn consume(&self, bytes: u64) {
+        let delay = self.reserve(bytes, Instant::now());
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Wait for an optional throttle to allow transferring `bytes` bytes
+pub fn limit(throttle: &Option<Arc<Throttle>>, bytes: usize) {
+    if let Some(ref t) = *throttle {
+        t.consume(bytes as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use remote::throttle::Throttle;
+
+    #[test]
+    fn delays_large_bursts() {
+        let start = Instant::now();
+        let t = Throttle::starting_at(1000, start);
+        let zero = Duration::from_secs(0);
+
+        // anything fitting in the bucket goes straight through
+        assert_eq!(t.reserve(600, start), zero);
+        assert_eq!(t.reserve(400, start), zero);
+
+        // a burst bigger than the whole bucket has to wait for the refill
+        assert_eq!(t.reserve(2500, start), Duration::from_millis(2500));
+
+        // once that debt is paid off, the bucket fills back up to its limit
+        let later = start + Duration::from_secs(10);
+        assert_eq!(t.reserve(1000, later), zero);
+        assert_eq!(t.reserve(500, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn consume_sleeps() {
+        let t = Throttle::new(100000);
+        let start = Instant::now();
+        t.consume(100000); // drains the bucket without waiting
+        t.consume(20000);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
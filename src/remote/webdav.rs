@@ -0,0 +1,1486 @@
+extern crate reqwest;
+extern crate ring;
+extern crate rpassword;
+
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use std::ops::Drop;
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+use std::io::{Cursor, Read};
+
+use self::reqwest::header::Headers;
+use self::rpassword::prompt_password_stderr;
+use url::Url;
+use url::percent_encoding::{percent_decode, utf8_percent_encode,
+                            PATH_SEGMENT_ENCODE_SET};
+
+use metadata::{IdentityTag, MetaObject, tag_from_digest};
+use remote::*;
+use keys::{MetaKey, DataKey};
+use compression;
+use compression::Compression;
+use remote::lock::LockInfo;
+use remote::throttle::{limit, Throttle};
+use util::ToHex;
+
+/// Default number of times to retry a request after a transient failure
+pub const DEFAULT_RETRIES: u32 = 4;
+
+/// Default delay before the first retry. Each later retry waits twice as long
+/// as the one before it.
+pub const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+
+/// Where the lock file lives, relative to the storage root
+const LOCK_PATH: &'static str = "bkp.lock";
+
+/// PROPFIND request body asking only for each resource's type
+const PROPFIND_BODY: &'static str =
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+     <d:propfind xmlns:d=\"DAV:\"><d:prop><d:resourcetype/></d:prop>\
+     </d:propfind>\n";
+
+/// PROPFIND request body asking only for each resource's size
+const PROPFIND_SIZE_BODY: &'static str =
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+     <d:propfind xmlns:d=\"DAV:\"><d:prop><d:getcontentlength/></d:prop>\
+     </d:propfind>\n";
+
+pub struct ConnectOptions {
+    /// The URL of the collection to use as a storage root. Only `http` and
+    /// `https` URLs work, and any credentials in it are ignored.
+    pub url: Url,
+
+    /// Which user to authenticate as, if the server needs authentication
+    pub user: Option<String>,
+
+    /// The user's password. If a user is given without one, it's prompted
+    /// for.
+    pub password: Option<String>,
+
+    /// The local nodename. Used for creating remote head pointers
+    pub nodename: String,
+
+    /// The keystore to use for data encryption/decryption
+    pub keystore: keys::Keystore,
+
+    /// The compression to apply to objects before encrypting them
+    pub compression: Compression,
+
+    /// The compression level to use, or `None` for the algorithm's default
+    pub compression_level: Option<u32>,
+
+    /// How many times to retry a request which failed due to a network error
+    /// or a temporary server error
+    pub retries: u32,
+
+    /// How long to wait before the first retry
+    pub retry_delay: Duration,
+
+    /// How old another node's lock has to be before it's assumed to be stale
+    /// and broken. Locks held by live processes on this node are never broken.
+    pub lock_timeout: Duration,
+
+    /// Maximum rate to upload data at, in bytes per second. Zero means no
+    /// limit.
+    pub upload_limit: u64,
+
+    /// Maximum rate to download data at, in bytes per second. Zero means no
+    /// limit.
+    pub download_limit: u64,
+
+    /// Only open an existing store, without locking or initializing it
+    pub read_only: bool
+}
+
+/// A request to make of the server, for a path relative to the storage root.
+/// Collections' paths end with a slash.
+struct Request<'a> {
+    method: &'static str,
+    path: &'a str,
+    body: &'a [u8],
+
+    /// How deep a PROPFIND should look
+    depth: Option<u32>,
+
+    /// Where a MOVE should put the resource, relative to the storage root
+    destination: Option<&'a str>,
+
+    /// Whether to fail rather than replace an existing resource
+    exclusive: bool
+}
+
+impl<'a> Request<'a> {
+    fn new(method: &'static str, path: &'a str) -> Self {
+        Request {
+            method: method,
+            path: path,
+            body: &[],
+            depth: None,
+            destination: None,
+            exclusive: false
+        }
+    }
+}
+
+/// The server's answer to a request
+struct Response {
+    status: u16,
+    body: Vec<u8>
+}
+
+/// Something which can carry requests to a WebDAV server
+trait Transport {
+    /// Send a request and return the server's response, whatever its status.
+    /// Fails with `CommsError` if no response arrives.
+    fn send(&self, req: &Request) -> BackendResult<Response>;
+
+    /// The URL path of the storage root, which PROPFIND responses name
+    /// resources by
+    fn root_path(&self) -> &str;
+}
+
+/// Talks to a real server over HTTP or HTTPS
+struct HttpTransport {
+    client: reqwest::Client,
+    root: Url,
+    user: Option<String>,
+    password: Option<String>
+}
+
+impl HttpTransport {
+    fn new(opts: &ConnectOptions) -> BackendResult<HttpTransport> {
+        let client = reqwest::Client::builder().build()
+            .map_err(|_| BackendError::ConnectionFailed)?;
+
+        let password = match opts.user {
+            Some(ref user) if opts.password.is_none() => {
+                let host = opts.url.host_str().unwrap_or("server");
+                Some(prompt_password_stderr(
+                        &format!("Password for {} on {}: ", user, host))?)
+            },
+            _ => opts.password.clone()
+        };
+
+        Ok(HttpTransport {
+            client: client,
+            root: opts.url.clone(),
+            user: opts.user.clone(),
+            password: password
+        })
+    }
+
+    fn url(&self, path: &str) -> BackendResult<Url> {
+        self.root.join(path)
+            .map_err(|_| BackendError::InvalidURL("invalid object path"))
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, req: &Request) -> BackendResult<Response> {
+        let method = req.method.parse::<reqwest::Method>()
+            .map_err(|_| BackendError::InvalidOption)?;
+
+        let mut headers = Headers::new();
+        if let Some(depth) = req.depth {
+            headers.set_raw("Depth", depth.to_string());
+        }
+        if let Some(dest) = req.destination {
+            headers.set_raw("Destination", self.url(dest)?.to_string());
+        }
+        if req.exclusive {
+            // PUT and MOVE spell this differently
+            if req.method == "PUT" {
+                headers.set_raw("If-None-Match", "*");
+            } else {
+                headers.set_raw("Overwrite", "F");
+            }
+        }
+
+        let mut builder = self.client.request(method, self.url(req.path)?);
+        builder.headers(headers);
+        if let Some(ref user) = self.user {
+            builder.basic_auth(user.clone(), self.password.clone());
+        }
+        if !req.body.is_empty() || req.method == "PUT" {
+            builder.body(req.body.to_vec());
+        }
+
+        let mut resp = builder.send().map_err(|e| {
+            verbose!("WebDAV request failed: {}", e);
+            BackendError::CommsError
+        })?;
+        let mut body = Vec::new();
+        resp.read_to_end(&mut body)?;
+        Ok(Response { status: resp.status().as_u16(), body: body })
+    }
+
+    fn root_path(&self) -> &str {
+        self.root.path()
+    }
+}
+
+pub struct Backend {
+    /// How requests get to the server
+    transport: Box<Transport>,
+
+    /// The storage root's URL, for messages
+    location: String,
+
+    /// The name under which this store's data key is kept in the keystore
+    key_name: String,
+
+    /// The node name to use for head pointers
+    node: String,
+
+    /// Another node whose head and metadata are being read instead of ours
+    view: Option<String>,
+
+    /// The keystore to use for data encryption/decryption
+    keystore: keys::Keystore,
+
+    /// How objects are compressed before they're encrypted
+    compression: Compression,
+    compression_level: Option<u32>,
+
+    // retry policy for failed requests
+    retries: u32,
+    retry_delay: Duration,
+
+    /// Collections known to exist, so they aren't created again
+    known_dirs: RefCell<HashSet<String>>,
+
+    // transfer rate limits
+    upload_throttle: Option<Arc<Throttle>>,
+    download_throttle: Option<Arc<Throttle>>,
+
+    // cached data and metadata keys
+    datakey: Cell<Option<DataKey>>,
+    metakey: Cell<Option<MetaKey>>,
+
+    /// Whether we hold the target's lock
+    locked: bool,
+    lock_timeout: Duration
+}
+
+/// Check whether a response status means the request could succeed if it
+/// were sent again
+fn is_transient_status(status: u16) -> bool {
+    match status {
+        408 | 429 => true,
+        // the server can't do this at all
+        501 | 505 => false,
+        s         => s >= 500
+    }
+}
+
+fn is_success(status: u16) -> bool {
+    status >= 200 && status < 300
+}
+
+/// Describe a response which the request it answered didn't expect
+fn status_error(req: &Request, resp: &Response) -> BackendError {
+    match resp.status {
+        401 => BackendError::BackendError(
+            String::from("authentication failed")),
+        403 => BackendError::BackendError(
+            format!("access to {} denied", req.path)),
+        s   => BackendError::BackendError(
+            format!("{} {} failed with HTTP status {}", req.method, req.path,
+                    s))
+    }
+}
+
+/// Make sense of a lock file's contents. A lock we can't make sense of isn't
+/// safe to break automatically, so it's treated as fresh.
+fn parse_lock(data: &[u8]) -> LockInfo {
+    LockInfo::parse(&String::from_utf8_lossy(data))
+        .unwrap_or(LockInfo::unknown(SystemTime::now()))
+}
+
+/// Build a shared throttle for a rate limit, if there is one
+fn make_throttle(rate: u64) -> Option<Arc<Throttle>> {
+    if rate == 0 { None } else { Some(Arc::new(Throttle::new(rate))) }
+}
+
+/// Build the path of an object under one of the store's collections
+fn object_path(kind: &str, ident: &IdentityTag) -> String {
+    format!("{}/{}/{}", kind, ident.dir_prefix(), ident)
+}
+
+/// Build the path of a node's file under one of the store's collections
+fn node_path(kind: &str, node: &str) -> String {
+    format!("{}/{}", kind, utf8_percent_encode(node, PATH_SEGMENT_ENCODE_SET))
+}
+
+/// Check whether some XML contains an element with the given local name
+fn has_element(xml: &str, name: &str) -> bool {
+    xml.match_indices(name).any(|(i, _)| {
+        let before = xml[..i].chars().last();
+        let after = xml[i + name.len()..].chars().next();
+        (before == Some('<') || before == Some(':')) &&
+            (after == Some('/') || after == Some('>') || after == Some(' '))
+    })
+}
+
+/// Pull a resource's size out of a PROPFIND response asking for its
+/// `getcontentlength`
+fn parse_content_length(body: &str) -> Option<u64> {
+    let name = "getcontentlength";
+    for (i, _) in body.match_indices(name) {
+        // only look at start tags, which may have a namespace prefix
+        let open = match body[..i].rfind('<') {
+            Some(o) => o,
+            None    => continue
+        };
+        let prefix = &body[open + 1..i];
+        if prefix.starts_with('/') ||
+                prefix.contains(|c: char| c == '>' || c.is_whitespace()) {
+            continue;
+        }
+
+        // the value is everything from the end of the start tag to the next
+        // tag
+        let rest = &body[i + name.len()..];
+        let value = match (rest.find('>'), rest.find('<')) {
+            (Some(s), Some(e)) if s < e => &rest[s + 1..e],
+            _                           => continue
+        };
+        return value.trim().parse().ok();
+    }
+    None
+}
+
+/// Pull the children of a collection out of a PROPFIND multistatus response,
+/// as `(name, is_collection)` pairs. `dir` is the collection's URL path.
+///
+/// Servers differ in which namespace prefixes they use, so this only looks at
+/// local element names rather than properly parsing the XML.
+fn parse_multistatus(body: &str, dir: &str) -> Vec<(String, bool)> {
+    let decode = |s: &str| percent_decode(s.as_bytes()).decode_utf8_lossy()
+                                                        .into_owned();
+    let dir = decode(dir.trim_right_matches('/'));
+
+    // both the start and end tags of each response end like this, so every
+    // other piece is the inside of one
+    let mut result = Vec::new();
+    for entry in body.split("response>") {
+        let start = match entry.find("href>") {
+            Some(i) => i + 5,
+            None    => continue
+        };
+        let href = match entry[start..].find('<') {
+            Some(len) => &entry[start..start + len],
+            None      => continue
+        };
+        let href = decode(&href.trim().replace("&amp;", "&"));
+
+        // hrefs may be full URLs rather than just paths
+        let path = match href.find("://") {
+            Some(i) => {
+                let rest = &href[i + 3..];
+                rest.find('/').map_or("", |j| &rest[j..])
+            },
+            None    => &href[..]
+        };
+        let path = path.trim_right_matches('/');
+
+        // the collection itself is listed too
+        if path == dir { continue; }
+
+        let name = match path.rfind('/') {
+            Some(i) => &path[i + 1..],
+            None    => path
+        };
+        result.push((name.to_owned(), has_element(entry, "collection")));
+    }
+    result
+}
+
+impl Backend {
+    /// Set up a backend talking through a transport, without touching the
+    /// server yet
+    fn new(transport: Box<Transport>, opts: ConnectOptions) -> Backend {
+        let mut location = opts.url.clone();
+        let _ = location.set_username("");
+        let _ = location.set_password(None);
+        let location = location.into_string();
+
+        // key the data key by the store's location, since a server can hold
+        // any number of stores
+        let hash = ring::digest::digest(&ring::digest::SHA256,
+                                        location.as_bytes());
+        let key_name = format!("webdav-{}", hash.as_ref().to_hex());
+
+        Backend {
+            transport: transport,
+            location: location,
+            key_name: key_name,
+            node: opts.nodename,
+            view: None,
+            keystore: opts.keystore,
+            compression: opts.compression,
+            compression_level: opts.compression_level,
+            retries: opts.retries,
+            retry_delay: opts.retry_delay,
+            known_dirs: RefCell::new(HashSet::new()),
+            upload_throttle: make_throttle(opts.upload_limit),
+            download_throttle: make_throttle(opts.download_limit),
+            datakey: Cell::new(None),
+            metakey: Cell::new(None),
+            locked: false,
+            lock_timeout: opts.lock_timeout
+        }
+    }
+
+    /// Connect through a transport, locking the target and initializing a
+    /// store there if there isn't one yet. Read-only connections only check
+    /// that there's a store.
+    fn open(transport: Box<Transport>, opts: ConnectOptions)
+            -> BackendResult<Backend> {
+        let read_only = opts.read_only;
+        let mut backend = Backend::new(transport, opts);
+
+        // make sure the target collection exists
+        if !backend.exists("")? {
+            return Err(BackendError::BackendError(
+                    String::from("cannot access directory")));
+        }
+
+        if read_only {
+            backend.check_store()?;
+            return Ok(backend);
+        }
+
+        // acquire exclusive access *before* initializing so two processes don't
+        // clobber each other. the lock is held until the backend is dropped,
+        // which also happens if initializing fails.
+        backend.lock()?;
+        backend.initialize()?;
+
+        Ok(backend)
+    }
+
+    /// Initialize a store on the target if one doesn't exist already, and make
+    /// sure the local keystore has the keys needed to access it.
+    fn initialize(&mut self) -> Result<(), BackendError> {
+        if !self.exists("metadata/")? || !self.exists("blocks/")? {
+            info!("initializing WebDAV target at {}", self.location);
+            for dir in &["metadata/", "metakeys/", "blocks/", "heads/"] {
+                self.make_dir(dir)?;
+            }
+
+            // generate data key for the remote and store it there
+            let data_key = self.keystore.new_data_key(&self.key_name)?;
+            let mut dkey = Vec::new();
+            data_key.write(&self.keystore, &mut dkey)?;
+            self.put("datakey", &dkey)?;
+        }
+
+        // make sure we have the remote's data key locally
+        if let Err(_) = self.keystore.get_data_key(&self.key_name) {
+            info!("retriving remote data key");
+            let data = self.read("datakey")?;
+            self.keystore.store_data_key(&self.key_name,
+                                         &mut Cursor::new(data))?;
+        }
+
+        // make sure we have the appropriate meta key there
+        let our_meta = node_path("metakeys", &self.node);
+        if !self.exists(&our_meta)? {
+            let meta_key = self.keystore.get_meta_key()?;
+            let mut mkey = Vec::new();
+            meta_key.write(&self.keystore, &mut mkey)?;
+            self.put(&our_meta, &mkey)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure a store has already been set up on the target, and that the
+    /// local keystore has its data key. Nothing on the target is changed.
+    fn check_store(&self) -> Result<(), BackendError> {
+        if !self.exists("metadata/")? || !self.exists("blocks/")? {
+            return Err(BackendError::NotInitialized);
+        }
+
+        if let Err(_) = self.keystore.get_data_key(&self.key_name) {
+            info!("retriving remote data key");
+            let data = self.read("datakey")?;
+            self.keystore.store_data_key(&self.key_name,
+                                         &mut Cursor::new(data))?;
+        }
+        Ok(())
+    }
+
+    /// The node whose head is currently being accessed
+    fn head_node(&self) -> &str {
+        self.view.as_ref().unwrap_or(&self.node)
+    }
+
+    /// Get the local meta key
+    fn meta_key(&self) -> MetaKey {
+        match self.metakey.get() {
+            Some(r) => r,
+            None => {
+                self.metakey.replace(self.keystore.get_meta_key().ok());
+                self.metakey.get().unwrap()
+            }
+        }
+    }
+
+    /// Get the local data key
+    fn data_key(&self) -> DataKey {
+        match self.datakey.get() {
+            Some(r) => r,
+            None => {
+                self.datakey.replace(
+                    self.keystore.get_data_key(&self.key_name).ok());
+                self.datakey.get().unwrap()
+            }
+        }
+    }
+
+    /// Send a request, retrying it with exponential backoff if no response
+    /// arrives or the server reports a temporary failure. Returns the last
+    /// response, whatever its status.
+    fn send(&self, req: &Request) -> BackendResult<Response> {
+        let mut attempt = 0;
+        loop {
+            match self.transport.send(req) {
+                Ok(resp) => {
+                    if !is_transient_status(resp.status) ||
+                            attempt >= self.retries {
+                        return Ok(resp);
+                    }
+                },
+                Err(BackendError::CommsError) if attempt < self.retries => {},
+                Err(e) => return Err(e)
+            }
+
+            thread::sleep(self.retry_delay * 2u32.pow(attempt));
+            attempt += 1;
+        }
+    }
+
+    /// Check whether a resource exists
+    fn exists(&self, path: &str) -> BackendResult<bool> {
+        let mut req = Request::new("PROPFIND", path);
+        req.depth = Some(0);
+        req.body = PROPFIND_BODY.as_bytes();
+        let resp = self.send(&req)?;
+        match resp.status {
+            207 => Ok(true),
+            404 => Ok(false),
+            _   => Err(status_error(&req, &resp))
+        }
+    }
+
+    /// Find a resource's size, without downloading it
+    fn size(&self, path: &str) -> BackendResult<u64> {
+        let mut req = Request::new("PROPFIND", path);
+        req.depth = Some(0);
+        req.body = PROPFIND_SIZE_BODY.as_bytes();
+        let resp = self.send(&req)?;
+        match resp.status {
+            207 => parse_content_length(&String::from_utf8_lossy(&resp.body))
+                .ok_or(BackendError::BackendError(
+                        format!("no size given for {}", path))),
+            404 => Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{} not found", path)).into()),
+            _   => Err(status_error(&req, &resp))
+        }
+    }
+
+    /// Read a resource's contents, if it exists
+    fn get(&self, path: &str) -> BackendResult<Option<Vec<u8>>> {
+        let req = Request::new("GET", path);
+        let resp = self.send(&req)?;
+        match resp.status {
+            200 => {
+                limit(&self.download_throttle, resp.body.len());
+                Ok(Some(resp.body))
+            },
+            404 => Ok(None),
+            _   => Err(status_error(&req, &resp))
+        }
+    }
+
+    /// Read a resource's contents, failing if it doesn't exist
+    fn read(&self, path: &str) -> BackendResult<Vec<u8>> {
+        match self.get(path)? {
+            Some(data) => Ok(data),
+            None       => Err(io::Error::new(io::ErrorKind::NotFound,
+                                             format!("{} not found", path))
+                                  .into())
+        }
+    }
+
+    /// Write a resource, replacing whatever was there
+    fn put(&self, path: &str, data: &[u8]) -> BackendResult<()> {
+        let mut req = Request::new("PUT", path);
+        req.body = data;
+        limit(&self.upload_throttle, data.len());
+        let resp = self.send(&req)?;
+        if is_success(resp.status) { Ok(()) }
+        else { Err(status_error(&req, &resp)) }
+    }
+
+    /// Create a collection, if it doesn't exist already
+    fn make_dir(&self, path: &str) -> BackendResult<()> {
+        let req = Request::new("MKCOL", path);
+        let resp = self.send(&req)?;
+        // 405 Method Not Allowed means there's something there already
+        if is_success(resp.status) || resp.status == 405 {
+            self.known_dirs.borrow_mut().insert(path.to_owned());
+            Ok(())
+        } else {
+            Err(status_error(&req, &resp))
+        }
+    }
+
+    /// Remove a resource
+    fn delete(&self, path: &str) -> BackendResult<()> {
+        let req = Request::new("DELETE", path);
+        let resp = self.send(&req)?;
+        if is_success(resp.status) { Ok(()) }
+        else { Err(status_error(&req, &resp)) }
+    }
+
+    /// Move a resource somewhere new, failing if something is there already
+    fn move_to(&self, from: &str, to: &str) -> BackendResult<()> {
+        let mut req = Request::new("MOVE", from);
+        req.destination = Some(to);
+        req.exclusive = true;
+        let resp = self.send(&req)?;
+        if is_success(resp.status) { Ok(()) }
+        else { Err(status_error(&req, &resp)) }
+    }
+
+    /// List a collection's members as `(name, is_collection)` pairs
+    fn list(&self, dir: &str) -> BackendResult<Vec<(String, bool)>> {
+        let mut req = Request::new("PROPFIND", dir);
+        req.depth = Some(1);
+        req.body = PROPFIND_BODY.as_bytes();
+        let resp = self.send(&req)?;
+        if resp.status != 207 { return Err(status_error(&req, &resp)); }
+
+        let full_path = format!("{}{}", self.transport.root_path(), dir);
+        Ok(parse_multistatus(&String::from_utf8_lossy(&resp.body),
+                             &full_path))
+    }
+
+    /// Compress a block with the given algorithm, encrypt it, and store it
+    fn store_block(&self, data: &[u8], alg: Compression)
+            -> BackendResult<IdentityTag> {
+        // hash the data
+        let tag = tag_from_digest(ring::digest::digest(&ring::digest::SHA256,
+                                                       data));
+
+        // compress and encrypt the data and write it out. no need to lock
+        // here, since the files are keyed by contents
+        let packed = compression::compress(alg, self.compression_level, data)?;
+        let encrypted = self.data_key().encrypt(packed)?;
+        self.put_object(&object_path("blocks", &tag), &encrypted, false)?;
+        Ok(tag)
+    }
+
+    /// Store an object at the given path, creating its parent collection if
+    /// needed. Objects are keyed by their contents, so existing ones are left
+    /// untouched.
+    ///
+    /// The object is uploaded under a temporary name and then moved into
+    /// place, so an interrupted upload never leaves a partial object behind.
+    /// Objects the caller knows to be new aren't looked for first.
+    fn put_object(&self, path: &str, data: &[u8], known_new: bool)
+            -> BackendResult<()> {
+        let parent = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        let known = self.known_dirs.borrow().contains(parent);
+        let new_dir = !known && {
+            let existed = self.exists(parent)?;
+            if !existed { self.make_dir(parent)?; }
+            self.known_dirs.borrow_mut().insert(parent.to_owned());
+            !existed
+        };
+
+        // short-circuit if it's already stored. nothing can be stored in a
+        // collection we just made
+        if !new_dir && !known_new && self.exists(path)? { return Ok(()); }
+
+        // actually write it
+        let tmp_path = format!("{}.tmp", path);
+        self.put(&tmp_path, data)?;
+        if let Err(e) = self.move_to(&tmp_path, path) {
+            if !self.exists(path)? { return Err(e); }
+
+            // someone else stored it first, or the move went through but its
+            // response was lost
+            if self.exists(&tmp_path)? { self.delete(&tmp_path)?; }
+        }
+        Ok(())
+    }
+
+    /// Encode, encrypt and store a metadata object, looking for it first unless
+    /// the caller knows it's new
+    fn store_meta(&self, obj: &MetaObject, known_new: bool)
+            -> BackendResult<IdentityTag> {
+        // encode the object and encrypt it
+        let (tag, encoded) = {
+            let mut v = Vec::new();
+            let tag = obj.save(&mut v)?;
+            let packed = compression::compress(self.compression,
+                                               self.compression_level, &v)?;
+            (tag, self.meta_key().encrypt(packed)?)
+        };
+
+        // no need to lock here, since the files are keyed by contents
+        self.put_object(&object_path("metadata", &tag), &encoded, known_new)?;
+        Ok(tag)
+    }
+
+    /// List the identity tags of all objects stored under a given collection
+    fn list_objects(&self, kind: &str) -> BackendResult<Vec<IdentityTag>> {
+        let mut result = Vec::new();
+        for (prefix, is_dir) in self.list(&format!("{}/", kind))? {
+            if !is_dir { continue; }
+
+            for (name, _) in self.list(&format!("{}/{}/", kind, prefix))? {
+                // anything not named by a valid tag isn't an object
+                if let Ok(tag) = name.parse::<IdentityTag>() {
+                    result.push(tag);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Find out who holds the target's lock, if anyone
+    fn read_lock(&self) -> BackendResult<Option<LockInfo>> {
+        Ok(self.get(LOCK_PATH)?.map(|d| parse_lock(&d)))
+    }
+
+    /// Create the lock file, recording who holds it.
+    ///
+    /// Fails if the target is already locked, unless the existing lock is
+    /// stale, in which case it's broken with a warning. See
+    /// `break_stale_lock`.
+    fn lock(&mut self) -> Result<(), BackendError> {
+        let ours = LockInfo::current(&self.node);
+        let name = format!("{}.{}-{}",
+                           LOCK_PATH,
+                           utf8_percent_encode(&ours.node,
+                                               PATH_SEGMENT_ENCODE_SET),
+                           ours.pid);
+        let staged = format!("{}.tmp", name);
+        let aside = format!("{}.stale", name);
+
+        let result = self.take_lock(&ours, &staged, &aside);
+        if result.is_ok() {
+            self.locked = true;
+        } else {
+            // don't leave our copy of the lock behind. it may never have been
+            // written, so failing to remove it doesn't matter.
+            let _ = self.delete(&staged);
+        }
+        result
+    }
+
+    /// Try to move a copy of our lock into place, breaking a stale lock at
+    /// most once.
+    ///
+    /// Not every server honors If-None-Match on a PUT, so the lock is written
+    /// under a name of our own first. A MOVE which mustn't overwrite is what
+    /// makes it exist only if nothing was there already.
+    fn take_lock(&self, ours: &LockInfo, staged: &str, aside: &str)
+            -> Result<(), BackendError> {
+        let info = ours.encode();
+
+        // only break a lock once, in case another process takes it in between
+        for _ in 0..2 {
+            self.put(staged, info.as_bytes())?;
+            let mut req = Request::new("MOVE", staged);
+            req.destination = Some(LOCK_PATH);
+            req.exclusive = true;
+            let resp = self.send(&req)?;
+            match resp.status {
+                s if is_success(s) => return Ok(()),
+                // 404 means our copy was already moved, by an earlier try
+                // whose response was lost
+                412 | 404            => {},
+                _                  => return Err(status_error(&req, &resp))
+            }
+
+            let data = match self.get(LOCK_PATH)? {
+                Some(d) => d,
+                None    => continue     // released in the meantime
+            };
+
+            // if the response to our MOVE was lost, retrying it finds our own
+            // lock already there
+            if data == info.as_bytes() {
+                return Ok(());
+            }
+
+            let holder = parse_lock(&data);
+            if !holder.is_stale(&self.node, SystemTime::now(),
+                                self.lock_timeout) {
+                return Err(BackendError::BackendError(
+                        format!("target is locked by {}", holder)));
+            }
+
+            warn!("bkp: warning: breaking stale lock held by {}", holder);
+            self.break_stale_lock(&holder, aside)?;
+        }
+
+        Err(BackendError::BackendError(
+                String::from("unable to lock - target was locked again")))
+    }
+
+    /// Remove a lock which was found to be held by `holder`, without removing
+    /// a fresh lock that another process has put in its place since.
+    ///
+    /// The lock is moved aside first, which only one process breaking it can
+    /// do. Only if it's still the stale lock once it's out of the way is it
+    /// removed; otherwise it's put back.
+    fn break_stale_lock(&self, holder: &LockInfo, aside: &str)
+            -> Result<(), BackendError> {
+        // a move whose response was lost still leaves the lock with us
+        if self.move_to(LOCK_PATH, aside).is_err() && !self.exists(aside)? {
+            // someone else broke it first, so try taking the lock again
+            return Ok(());
+        }
+
+        let moved = parse_lock(&self.read(aside)?);
+        if moved != *holder {
+            self.move_to(aside, LOCK_PATH)?;
+            return Err(BackendError::BackendError(
+                    format!("target is locked by {}", moved)));
+        }
+        self.delete(aside)
+    }
+
+    /// Release our lock on the target
+    fn unlock(&mut self) -> Result<(), BackendError> {
+        if self.locked {
+            self.delete(LOCK_PATH)?;
+            self.locked = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        // this also runs when creating the backend fails partway through, so
+        // a failed connection doesn't leave the target locked
+        if let Err(e) = self.unlock() {
+            error!("bkp: failed to unlock target: {}", e);
+        }
+    }
+}
+
+/// Connect to a target and remove its lock file regardless of who holds it.
+/// Returns the lock's previous holder, if the target was locked.
+pub fn break_lock(opts: ConnectOptions)
+        -> Result<Option<LockInfo>, BackendError> {
+    let transport = HttpTransport::new(&opts)?;
+    let backend = Backend::new(Box::new(transport), opts);
+    let holder = backend.read_lock()?;
+    if holder.is_some() {
+        backend.delete(LOCK_PATH)?;
+    }
+    Ok(holder)
+}
+
+/// Go through each stage of connecting to a target, recording how each went
+/// in `report`. The target is left as it was, apart from briefly locking it.
+pub fn probe(opts: ConnectOptions, report: &mut ProbeReport) {
+    let transport = match HttpTransport::new(&opts) {
+        Ok(t)  => t,
+        Err(e) => return report.fail("connect", e)
+    };
+    let mut backend = Backend::new(Box::new(transport), opts);
+
+    // the first request is what connects, so that's what gets timed
+    let found = match report.timed("connect", || backend.exists(""),
+                                   |_| String::from("server responded")) {
+        Some(f) => f,
+        None    => return
+    };
+    if !found {
+        return report.fail("directory", format!("cannot access {}",
+                                                backend.location));
+    }
+    let initialized = match backend.exists("metadata/")
+            .and_then(|m| Ok(m && backend.exists("blocks/")?)) {
+        Ok(i)  => i,
+        Err(e) => return report.fail("directory", e)
+    };
+    report.pass("directory", format!(
+            "{} exists, {}", backend.location,
+            if initialized { "initialized" }
+            else { "not initialized yet; it will be on first use" }));
+
+    match backend.lock().and_then(|_| backend.unlock()) {
+        Ok(()) => report.pass("lock", "acquired and released"),
+        Err(e) => return report.fail("lock", e)
+    }
+    report.check_keys(&backend.keystore, &backend.key_name, initialized);
+}
+
+impl MetadataStore for Backend {
+    fn list_meta(&self) -> BackendResult<Vec<IdentityTag>> {
+        self.list_objects("metadata")
+    }
+
+    fn read_meta(&self, ident: &IdentityTag) -> BackendResult<MetaObject> {
+        let data = self.read(&object_path("metadata", ident))?;
+        let data = compression::decompress(self.meta_key().decrypt(data)?)?;
+
+        // read the meta object
+        Ok(MetaObject::load(&mut Cursor::new(data))?)
+    }
+
+    fn has_meta(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        self.exists(&object_path("metadata", ident))
+    }
+
+    fn write_meta(&mut self, obj: &MetaObject) -> BackendResult<IdentityTag> {
+        self.store_meta(obj, false)
+    }
+
+    fn write_new_meta(&mut self, obj: &MetaObject)
+            -> BackendResult<IdentityTag> {
+        self.store_meta(obj, true)
+    }
+
+    fn get_head(&self) -> BackendResult<Option<MetaObject>> {
+        // the target is locked for as long as we're connected, so the head
+        // can't change underneath us
+        let data = match self.get(&node_path("heads", self.head_node()))? {
+            Some(d) => d,
+            None    => return Ok(None)
+        };
+        let ident = IdentityTag::read_from(&mut Cursor::new(data))?;
+
+        // get the object
+        self.read_meta(&ident).map(Some)
+    }
+
+    fn set_head(&mut self, tag: &IdentityTag) -> BackendResult<()> {
+        self.put(&node_path("heads", self.head_node()), tag.as_bytes())
+    }
+
+    fn list_heads(&self) -> BackendResult<Vec<String>> {
+        Ok(self.list("heads/")?
+               .into_iter()
+               .filter(|&(_, is_dir)| !is_dir)
+               .map(|(name, _)| name)
+               .collect())
+    }
+
+    fn delete_meta(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        self.delete(&object_path("metadata", ident))
+    }
+
+    fn view_node(&mut self, node: &str) -> BackendResult<()> {
+        if node == self.node {
+            // back to our own key, which is loaded on demand
+            self.view = None;
+            self.metakey.set(None);
+            return Ok(());
+        }
+
+        let key = match self.keystore.get_node_meta_key(node) {
+            Ok(k)  => k,
+            Err(keys::Error::MissingNodeKey(_)) => {
+                // fetch it from the remote's copy, if we can decrypt that
+                let data = self.get(&node_path("metakeys", node))?
+                    .ok_or(keys::Error::MissingNodeKey(node.to_owned()))?;
+                self.keystore.store_node_meta_key(node,
+                                                  &mut Cursor::new(data))?
+            },
+            Err(e) => return Err(e.into())
+        };
+
+        self.view = Some(node.to_owned());
+        self.metakey.set(Some(key));
+        Ok(())
+    }
+
+    fn viewed_node(&self) -> Option<String> {
+        Some(self.head_node().to_owned())
+    }
+
+    fn rename_node(&mut self, old: &str, new: &str) -> BackendResult<()> {
+        // the target is locked for as long as we're connected, so nothing else
+        // can be writing heads
+        if self.exists(&node_path("heads", new))? {
+            return Err(BackendError::BackendError(
+                    format!("node '{}' already has snapshots", new)));
+        }
+
+        // copy the key before moving the head, so the head is never left
+        // without a key to read it with
+        let key = self.get(&node_path("metakeys", old))?;
+        if let Some(ref key) = key {
+            self.put(&node_path("metakeys", new), key)?;
+        }
+
+        self.move_to(&node_path("heads", old), &node_path("heads", new))?;
+        if key.is_some() {
+            self.delete(&node_path("metakeys", old))?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockStore for Backend {
+    fn list_blocks(&self) -> BackendResult<Vec<IdentityTag>> {
+        self.list_objects("blocks")
+    }
+
+    fn read_block(&self, ident: &IdentityTag) -> BackendResult<Vec<u8>> {
+        let data = self.read(&object_path("blocks", ident))?;
+        Ok(compression::decompress(self.data_key().decrypt(data)?)?)
+    }
+
+    fn has_block(&self, ident: &IdentityTag) -> BackendResult<bool> {
+        self.exists(&object_path("blocks", ident))
+    }
+
+    fn block_size(&self, ident: &IdentityTag) -> BackendResult<u64> {
+        self.size(&object_path("blocks", ident))
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> BackendResult<IdentityTag> {
+        self.store_block(data, self.compression)
+    }
+
+    fn write_uncompressed(&mut self, blocks: &[Vec<u8>])
+            -> BackendResult<Vec<IdentityTag>> {
+        blocks.iter().map(|b| self.store_block(b, Compression::None)).collect()
+    }
+
+    fn delete_block(&mut self, ident: &IdentityTag) -> BackendResult<()> {
+        self.delete(&object_path("blocks", ident))
+    }
+
+    fn keeps_existing_blocks(&self) -> bool { true }
+}
+
+impl RemoteBackend<ConnectOptions> for Backend {
+    fn create(opts: ConnectOptions) -> Result<Backend, BackendError> {
+        let transport = HttpTransport::new(&opts)?;
+        Backend::open(Box::new(transport), opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use url::Url;
+
+    use compression::Compression;
+    use keys::Keystore;
+    use metadata::{FSMetadata, IdentityTag, MetaObject};
+    use remote::{BackendError, BackendResult, BlockStore, MetadataStore};
+    use remote::lock::LockInfo;
+    use remote::webdav::{parse_content_length, parse_lock, parse_multistatus,
+                         Backend, ConnectOptions, Request, Response,
+                         Transport};
+
+    /// An in-memory WebDAV server, which can be told to fail requests.
+    ///
+    /// Resources are keyed by their path under the root, and collections'
+    /// paths end with a slash.
+    struct MockDav {
+        files: RefCell<BTreeMap<String, Vec<u8>>>,
+
+        /// How many of the next requests to answer with 503 Service
+        /// Unavailable
+        failures: Cell<usize>,
+
+        /// How many requests have been received
+        requests: Cell<usize>,
+
+        /// A method and path suffix whose next request is carried out, but
+        /// whose response is lost on the way back
+        lose_response: RefCell<Option<(&'static str, &'static str)>>
+    }
+
+    impl MockDav {
+        fn new() -> Rc<MockDav> {
+            let mut files = BTreeMap::new();
+            files.insert(String::new(), Vec::new());
+            Rc::new(MockDav {
+                files: RefCell::new(files),
+                failures: Cell::new(0),
+                requests: Cell::new(0),
+                lose_response: RefCell::new(None)
+            })
+        }
+    }
+
+    /// The collection containing a resource
+    fn parent(path: &str) -> &str {
+        let path = path.trim_right_matches('/');
+        &path[..path.rfind('/').map_or(0, |i| i + 1)]
+    }
+
+    fn reply(status: u16, body: Vec<u8>) -> BackendResult<Response> {
+        Ok(Response { status: status, body: body })
+    }
+
+    impl Transport for Rc<MockDav> {
+        fn send(&self, req: &Request) -> BackendResult<Response> {
+            self.requests.set(self.requests.get() + 1);
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return reply(503, Vec::new());
+            }
+
+            let lost = self.lose_response.borrow().as_ref()
+                           .map_or(false, |&(m, p)| {
+                               m == req.method && req.path.ends_with(p)
+                           });
+            let resp = self.handle(req);
+            if lost {
+                *self.lose_response.borrow_mut() = None;
+                return Err(BackendError::CommsError);
+            }
+            resp
+        }
+
+        fn root_path(&self) -> &str {
+            "/dav/"
+        }
+    }
+
+    impl MockDav {
+        /// Carry out a request
+        fn handle(&self, req: &Request) -> BackendResult<Response> {
+            let mut files = self.files.borrow_mut();
+            let path = req.path.to_owned();
+            let exists = files.contains_key(&path);
+            match req.method {
+                "PROPFIND" if !exists => reply(404, Vec::new()),
+                "PROPFIND" => {
+                    let mut xml = String::from(
+                        "<?xml version=\"1.0\"?>\n\
+                         <d:multistatus xmlns:d=\"DAV:\">\n");
+                    let entries = files.keys().filter(|k| {
+                        **k == path || (req.depth == Some(1) &&
+                                        !k.is_empty() && parent(k) == path)
+                    });
+                    for k in entries {
+                        let props = if k.is_empty() || k.ends_with('/') {
+                            String::from("<d:resourcetype><d:collection/>\
+                                          </d:resourcetype>")
+                        } else {
+                            format!("<d:resourcetype/><d:getcontentlength>{}\
+                                     </d:getcontentlength>", files[k].len())
+                        };
+                        xml.push_str(&format!(
+                            "<d:response><d:href>/dav/{}</d:href><d:propstat>\
+                             <d:prop>{}</d:prop>\
+                             <d:status>HTTP/1.1 200 OK</d:status>\
+                             </d:propstat></d:response>\n", k, props));
+                    }
+                    xml.push_str("</d:multistatus>\n");
+                    reply(207, xml.into_bytes())
+                },
+                "GET" => match files.get(&path) {
+                    Some(data) => reply(200, data.clone()),
+                    None       => reply(404, Vec::new())
+                },
+                "PUT" | "MKCOL" if !files.contains_key(parent(&path)) =>
+                    reply(409, Vec::new()),
+                "PUT" if exists && req.exclusive => reply(412, Vec::new()),
+                "PUT" => {
+                    files.insert(path, req.body.to_vec());
+                    reply(201, Vec::new())
+                },
+                "MKCOL" if exists => reply(405, Vec::new()),
+                "MKCOL" => {
+                    files.insert(path, Vec::new());
+                    reply(201, Vec::new())
+                },
+                "DELETE" => match files.remove(&path) {
+                    Some(_) => reply(204, Vec::new()),
+                    None    => reply(404, Vec::new())
+                },
+                "MOVE" => {
+                    let dest = req.destination.unwrap().to_owned();
+                    if files.contains_key(&dest) && req.exclusive {
+                        return reply(412, Vec::new());
+                    }
+                    match files.remove(&path) {
+                        Some(data) => {
+                            files.insert(dest, data);
+                            reply(201, Vec::new())
+                        },
+                        None => reply(404, Vec::new())
+                    }
+                },
+                _ => reply(501, Vec::new())
+            }
+        }
+    }
+
+    fn options(ks: &Keystore, node: &str) -> ConnectOptions {
+        ConnectOptions {
+            url: Url::parse("https://dav.example.com/dav/").unwrap(),
+            user: None,
+            password: None,
+            nodename: node.to_owned(),
+            keystore: ks.clone(),
+            compression: Compression::Zstd,
+            compression_level: None,
+            retries: 3,
+            retry_delay: Duration::from_millis(1),
+            lock_timeout: Duration::from_secs(3600),
+            upload_limit: 0,
+            download_limit: 0,
+            read_only: false
+        }
+    }
+
+    fn connect(dav: &Rc<MockDav>, ks: &Keystore, node: &str)
+            -> BackendResult<Backend> {
+        Backend::open(Box::new(dav.clone()), options(ks, node))
+    }
+
+    #[test]
+    fn store_and_read_back() {
+        let dir = env::temp_dir().join("bkp-webdav-store");
+        let _ = fs::remove_dir_all(&dir);
+        let ks = Keystore::with_master_key(&dir, [6u8; 32]).unwrap();
+        let dav = MockDav::new();
+
+        let (block, snap) = {
+            let mut b = connect(&dav, &ks, "node").unwrap();
+            let block = b.write_block(b"some block data").unwrap();
+            let obj = MetaObject::tree("dir", FSMetadata::default(),
+                                       vec![block]);
+            let snap = b.write_meta(&obj).unwrap();
+            b.set_head(&snap).unwrap();
+
+            // storing something twice doesn't upload it again
+            let before = dav.requests.get();
+            b.write_block(b"some block data").unwrap();
+            assert_eq!(dav.requests.get() - before, 1);
+            (block, snap)
+        };
+
+        // the lock went away with the connection, and everything is laid out
+        // like on any other remote
+        {
+            let files = dav.files.borrow();
+            assert!(!files.contains_key("bkp.lock"));
+            assert!(files.contains_key("datakey"));
+            assert!(files.contains_key("metakeys/node"));
+            assert!(files.contains_key(&format!("blocks/{}/{}",
+                                                block.dir_prefix(), block)));
+        }
+
+        let b = connect(&dav, &ks, "node").unwrap();
+        assert_eq!(b.read_block(&block).unwrap(), b"some block data".to_vec());
+        assert!(b.has_block(&block).unwrap());
+        let stored = dav.files.borrow()[&format!("blocks/{}/{}",
+                                                 block.dir_prefix(), block)]
+                        .len() as u64;
+        assert_eq!(b.block_size(&block).unwrap(), stored);
+        assert_eq!(b.list_blocks().unwrap(), vec![block]);
+        assert_eq!(b.list_meta().unwrap(), vec![snap]);
+        assert_eq!(b.list_heads().unwrap(), vec![String::from("node")]);
+        assert!(b.get_head().unwrap().is_some());
+        drop(b);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uncompressed_blocks() {
+        let dir = env::temp_dir().join("bkp-webdav-uncompressed");
+        let _ = fs::remove_dir_all(&dir);
+        let ks = Keystore::with_master_key(&dir, [6u8; 32]).unwrap();
+        let dav = MockDav::new();
+        let mut b = connect(&dav, &ks, "node").unwrap();
+        let packed = b.write_block(b"compressible").unwrap();
+        let raw = b.write_uncompressed(&[b"already compressed".to_vec()])
+                   .unwrap();
+
+        assert_eq!(b.read_block(&raw[0]).unwrap(),
+                   b"already compressed".to_vec());
+
+        // the first byte of a stored block says how it was compressed
+        let algorithm = |tag: &IdentityTag| {
+            let path = format!("blocks/{}/{}", tag.dir_prefix(), tag);
+            let data = dav.files.borrow()[&path].clone();
+            b.data_key().decrypt(data).unwrap()[0]
+        };
+        assert_eq!(algorithm(&packed), 2);
+        assert_eq!(algorithm(&raw[0]), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lock_rejects_second_connection() {
+        let dir = env::temp_dir().join("bkp-webdav-lock");
+        let _ = fs::remove_dir_all(&dir);
+        let ks = Keystore::with_master_key(&dir, [6u8; 32]).unwrap();
+        let dav = MockDav::new();
+
+        let first = connect(&dav, &ks, "node").unwrap();
+        assert!(connect(&dav, &ks, "other").is_err());
+        drop(first);
+        assert!(connect(&dav, &ks, "other").is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_lock_broken_once() {
+        let dir = env::temp_dir().join("bkp-webdav-stale-lock");
+        let _ = fs::remove_dir_all(&dir);
+        let ks = Keystore::with_master_key(&dir, [6u8; 32]).unwrap();
+        let dav = MockDav::new();
+
+        // a lock left behind by another node long ago
+        let stale = LockInfo { node: String::from("gone"), pid: 1,
+                               time: UNIX_EPOCH };
+        dav.files.borrow_mut().insert(String::from("bkp.lock"),
+                                      stale.encode().into_bytes());
+
+        // the first contender breaks it and takes the lock
+        let first = connect(&dav, &ks, "first").unwrap();
+        let held = dav.files.borrow()["bkp.lock"].clone();
+        assert_eq!(parse_lock(&held).node, "first");
+
+        // a second contender which judged the old lock stale before then
+        // leaves the fresh lock alone
+        let mut opts = options(&ks, "second");
+        opts.read_only = true;
+        let second = Backend::open(Box::new(dav.clone()), opts).unwrap();
+        assert!(second.break_stale_lock(&stale, "bkp.lock.second-1.stale")
+                      .is_err());
+        assert_eq!(dav.files.borrow()["bkp.lock"], held);
+        assert!(connect(&dav, &ks, "second").is_err());
+
+        // and nobody's copies of the lock were left behind
+        assert!(dav.files.borrow().keys()
+                   .all(|k| !k.starts_with("bkp.lock.")));
+        drop(second);
+        drop(first);
+        assert!(!dav.files.borrow().contains_key("bkp.lock"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lost_responses_are_retried() {
+        let dir = env::temp_dir().join("bkp-webdav-lost");
+        let _ = fs::remove_dir_all(&dir);
+        let ks = Keystore::with_master_key(&dir, [6u8; 32]).unwrap();
+        let dav = MockDav::new();
+
+        // the retried lock request finds our own lock, not someone else's
+        *dav.lose_response.borrow_mut() = Some(("MOVE", ".tmp"));
+        let mut b = connect(&dav, &ks, "node").unwrap();
+        assert!(dav.lose_response.borrow().is_none());
+
+        // and a retried move finds the object already in place
+        let data = b"moved before the response was lost";
+        *dav.lose_response.borrow_mut() = Some(("MOVE", ".tmp"));
+        let tag = b.write_block(data).unwrap();
+        assert!(dav.lose_response.borrow().is_none());
+        assert_eq!(b.read_block(&tag).unwrap(), data.to_vec());
+        let path = format!("blocks/{}/{}.tmp", tag.dir_prefix(), tag);
+        assert!(!dav.files.borrow().contains_key(&path));
+
+        // stored blocks are never replaced
+        assert!(b.keeps_existing_blocks());
+        drop(b);
+        assert!(!dav.files.borrow().contains_key("bkp.lock"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let dir = env::temp_dir().join("bkp-webdav-retry");
+        let _ = fs::remove_dir_all(&dir);
+        let ks = Keystore::with_master_key(&dir, [6u8; 32]).unwrap();
+        let dav = MockDav::new();
+        let mut b = connect(&dav, &ks, "node").unwrap();
+
+        // a few failures are ridden out
+        dav.failures.set(3);
+        let tag = b.write_block(b"retried").unwrap();
+        assert_eq!(b.read_block(&tag).unwrap(), b"retried".to_vec());
+
+        // but a server which keeps failing is given up on
+        dav.failures.set(100);
+        let before = dav.requests.get();
+        assert!(b.read_block(&tag).is_err());
+        assert_eq!(dav.requests.get() - before, 4);
+
+        dav.failures.set(0);
+        drop(b);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_server_listings() {
+        // apache-style, with full URLs and an escaped name
+        let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+            <D:multistatus xmlns:D=\"DAV:\">\n\
+            <D:response xmlns:lp1=\"DAV:\">\n\
+            <D:href>http://host/dav/heads/</D:href>\n\
+            <D:propstat><D:prop><lp1:resourcetype><D:collection/>\
+            </lp1:resourcetype></D:prop></D:propstat>\n\
+            </D:response>\n\
+            <D:response xmlns:lp1=\"DAV:\">\n\
+            <D:href>http://host/dav/heads/my%20laptop</D:href>\n\
+            <D:propstat><D:prop><lp1:resourcetype/></D:prop></D:propstat>\n\
+            </D:response>\n\
+            <D:response xmlns:lp1=\"DAV:\">\n\
+            <D:href>http://host/dav/heads/old/</D:href>\n\
+            <D:propstat><D:prop><lp1:resourcetype>\
+            <D:collection /></lp1:resourcetype></D:prop></D:propstat>\n\
+            </D:response>\n\
+            </D:multistatus>\n";
+        assert_eq!(parse_multistatus(body, "/dav/heads/"),
+                   vec![(String::from("my laptop"), false),
+                        (String::from("old"), true)]);
+
+        // and one without a namespace prefix, naming resources by path
+        let body = "<multistatus xmlns=\"DAV:\"><response>\
+            <href>/dav/blocks/</href><propstat><prop><resourcetype>\
+            <collection/></resourcetype></prop></propstat></response>\
+            <response><href>/dav/blocks/ab/</href><propstat><prop>\
+            <resourcetype><collection/></resourcetype></prop></propstat>\
+            </response></multistatus>";
+        assert_eq!(parse_multistatus(body, "/dav/blocks/"),
+                   vec![(String::from("ab"), true)]);
+
+        // sizes come back the same way
+        let body = "<D:multistatus xmlns:D=\"DAV:\"><D:response>\
+            <D:href>/dav/blocks/ab/abcd</D:href><D:propstat><D:prop>\
+            <lp1:getcontentlength xmlns:lp1=\"DAV:\"> 1234 \
+            </lp1:getcontentlength></D:prop></D:propstat></D:response>\
+            </D:multistatus>";
+        assert_eq!(parse_content_length(body), Some(1234));
+        assert_eq!(parse_content_length("<multistatus/>"), None);
+    }
+}
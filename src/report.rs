@@ -0,0 +1,256 @@
+extern crate serde;
+extern crate serde_json;
+
+use std::io;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::serde::Serialize;
+
+use history;
+use metadata::{IdentityTag, Snapshot};
+
+/// Times are reported as seconds since the Unix epoch
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Statistics about one destination, as reported by `stat --json`.
+///
+/// If the statistics couldn't be collected, `error` says why and the counts
+/// are all null.
+#[derive(Serialize)]
+pub struct DestStats {
+    pub name: String,
+    pub error: Option<String>,
+    pub snapshots: Option<u64>,
+    pub unique_blocks: Option<u64>,
+    pub logical_bytes: Option<u64>,
+    pub latest_snapshot: Option<u64>,
+    pub referenced_bytes: Option<u64>,
+    pub stored_bytes: Option<u64>,
+    pub dedup_ratio: Option<f64>,
+
+    /// Space used by each snapshot, if that was asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_snapshot: Option<Vec<SnapshotSpace>>
+}
+
+impl DestStats {
+    pub fn new(name: &str, stats: &history::Stats) -> Self {
+        DestStats {
+            name: name.to_owned(),
+            error: None,
+            snapshots: Some(stats.snapshots),
+            unique_blocks: Some(stats.unique_blocks),
+            logical_bytes: Some(stats.logical_bytes),
+            latest_snapshot: stats.latest.map(unix_time),
+            referenced_bytes: Some(stats.referenced_bytes),
+            stored_bytes: Some(stats.stored_bytes),
+            dedup_ratio: stats.dedup_ratio(),
+            per_snapshot: None
+        }
+    }
+
+    pub fn failed<E: ToString>(name: &str, err: &E) -> Self {
+        DestStats {
+            name: name.to_owned(),
+            error: Some(err.to_string()),
+            snapshots: None,
+            unique_blocks: None,
+            logical_bytes: None,
+            latest_snapshot: None,
+            referenced_bytes: None,
+            stored_bytes: None,
+            dedup_ratio: None,
+            per_snapshot: None
+        }
+    }
+}
+
+/// The space used by one snapshot, as listed by `stat --json --snapshots`
+#[derive(Serialize)]
+pub struct SnapshotSpace {
+    pub id: String,
+    pub referenced_bytes: u64,
+    pub added_bytes: u64
+}
+
+impl<'a> From<&'a history::SnapshotStats> for SnapshotSpace {
+    fn from(s: &'a history::SnapshotStats) -> Self {
+        SnapshotSpace {
+            id: s.id.to_string(),
+            referenced_bytes: s.referenced_bytes,
+            added_bytes: s.added_bytes
+        }
+    }
+}
+
+/// One snapshot in the listing printed by `snapshots --json`
+#[derive(Serialize)]
+pub struct SnapshotEntry {
+    pub id: String,
+    pub time: u64,
+    pub root: String,
+    pub parent: Option<String>,
+
+    /// Number of paths changed since the parent, if that was asked for and
+    /// there is a parent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<usize>
+}
+
+impl SnapshotEntry {
+    pub fn new(tag: &IdentityTag, snap: &Snapshot) -> Self {
+        SnapshotEntry {
+            id: tag.to_string(),
+            time: unix_time(snap.create_time),
+            root: snap.root.to_string(),
+            parent: snap.parent.map(|p| p.to_string()),
+            changes: None
+        }
+    }
+}
+
+/// A changed path, as listed by `diff --json`
+#[derive(Serialize)]
+pub struct Change {
+    pub path: String,
+
+    /// One of "added", "removed" or "modified"
+    pub kind: &'static str
+}
+
+impl<'a> From<&'a history::PathChange> for Change {
+    fn from(c: &'a history::PathChange) -> Self {
+        Change {
+            path: c.path.to_string_lossy().into_owned(),
+            kind: match c.kind {
+                history::ChangeKind::Added    => "added",
+                history::ChangeKind::Removed  => "removed",
+                history::ChangeKind::Modified => "modified"
+            }
+        }
+    }
+}
+
+/// The result of `diff --json`
+#[derive(Serialize)]
+pub struct Diff {
+    pub from: u64,
+    pub to: u64,
+    pub changes: Vec<Change>
+}
+
+impl Diff {
+    pub fn new(from: &Snapshot, to: &Snapshot,
+               changes: &[history::PathChange]) -> Self {
+        Diff {
+            from: unix_time(from.create_time),
+            to: unix_time(to.create_time),
+            changes: changes.iter().map(Change::from).collect()
+        }
+    }
+}
+
+/// A problem found by `test --json`
+#[derive(Serialize)]
+pub struct Fault {
+    pub kind: String,
+    pub path: String,
+    pub object: String,
+    pub snapshot: String
+}
+
+/// The outcome of testing one destination, as reported by `test --json`.
+///
+/// `status` is "ok" if no problems were found, "failed" if some were, and
+/// "error" if the test couldn't be run, in which case `error` says why.
+#[derive(Serialize)]
+pub struct DestCheck {
+    pub name: String,
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub faults: Vec<Fault>
+}
+
+impl DestCheck {
+    pub fn new(name: &str, report: &history::CheckReport) -> Self {
+        DestCheck {
+            name: name.to_owned(),
+            status: if report.is_ok() { "ok" } else { "failed" },
+            error: None,
+            faults: report.snapshots.iter().flat_map(|&(ref snap, ref r)| {
+                let blocks = r.bad_blocks.iter().map(move |b| Fault {
+                    kind: format!("{} block", b.2),
+                    path: b.0.display().to_string(),
+                    object: b.1.to_string(),
+                    snapshot: snap.to_string()
+                });
+                let objects = r.bad_objects.iter().map(move |o| Fault {
+                    kind: String::from("unreadable object"),
+                    path: o.0.display().to_string(),
+                    object: o.1.to_string(),
+                    snapshot: snap.to_string()
+                });
+                blocks.chain(objects)
+            }).collect()
+        }
+    }
+
+    pub fn failed<E: ToString>(name: &str, err: &E) -> Self {
+        DestCheck {
+            name: name.to_owned(),
+            status: "error",
+            error: Some(err.to_string()),
+            faults: Vec::new()
+        }
+    }
+}
+
+/// Write a report to stdout as JSON
+pub fn print<T: Serialize>(report: &T) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, report)?;
+    writeln!(out, "")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use history::Stats;
+    use report::DestStats;
+
+    #[test]
+    fn stats_json() {
+        let stats = Stats {
+            snapshots: 3,
+            unique_blocks: 10,
+            logical_bytes: 4096,
+            latest: Some(UNIX_EPOCH + Duration::from_secs(1500000000)),
+            referenced_bytes: 12288,
+            stored_bytes: 4096,
+            ..Stats::default()
+        };
+        let ok = serde_json::to_string(&DestStats::new("primary", &stats))
+            .unwrap();
+        assert_eq!(ok, "{\"name\":\"primary\",\"error\":null,\"snapshots\":3,\
+                        \"unique_blocks\":10,\"logical_bytes\":4096,\
+                        \"latest_snapshot\":1500000000,\
+                        \"referenced_bytes\":12288,\"stored_bytes\":4096,\
+                        \"dedup_ratio\":3.0}");
+
+        let failed = DestStats::failed("backup", &"connection failed");
+        let failed = serde_json::to_string(&failed).unwrap();
+        assert_eq!(failed, "{\"name\":\"backup\",\
+                            \"error\":\"connection failed\",\
+                            \"snapshots\":null,\"unique_blocks\":null,\
+                            \"logical_bytes\":null,\"latest_snapshot\":null,\
+                            \"referenced_bytes\":null,\"stored_bytes\":null,\
+                            \"dedup_ratio\":null}");
+    }
+}
@@ -2,6 +2,7 @@ extern crate ring;
 
 use std::io;
 use std::io::{Read, Write};
+use std::time;
 use ring::digest;
 
 /// A trait to easily convert binary data to hex
@@ -68,6 +69,205 @@ impl Write for DevNull {
     fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
 
+/// Format a byte count in human-readable binary units
+pub fn format_size(n: u64) -> String {
+    const UNITS: [&'static str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+
+    let mut size = n as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Parse a byte count such as `512`, `64k`, or `2M`
+///
+/// Suffixes are binary multiples: `k` is 1024 bytes, `m` is 1024 `k`, and `g`
+/// is 1024 `m`. They're case-insensitive.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len()-1], 1 << 10),
+        Some('m') | Some('M') => (&s[..s.len()-1], 1 << 20),
+        Some('g') | Some('G') => (&s[..s.len()-1], 1 << 30),
+        _                     => (s, 1)
+    };
+
+    if num.is_empty() || !num.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+    num.parse::<u64>().ok().and_then(|n| n.checked_mul(mult))
+}
+
+/// Parse a human-readable duration such as `30m`, `2d`, or `1w3d12h`
+///
+/// Each component is a number followed by one of the units `s`, `m`, `h`, `d`,
+/// or `w`.
+pub fn parse_duration(s: &str) -> Option<time::Duration> {
+    let mut total: u64 = 0;
+    let mut num: Option<u64> = None;
+    for c in s.trim().chars() {
+        if let Some(d) = c.to_digit(10) {
+            num = num.unwrap_or(0).checked_mul(10)
+                     .and_then(|n| n.checked_add(d as u64));
+            if num.is_none() { return None; } // overflow
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 60 * 60 * 24,
+            'w' => 60 * 60 * 24 * 7,
+            _   => return None
+        };
+        match num.take().and_then(|n| n.checked_mul(unit))
+                        .and_then(|n| total.checked_add(n)) {
+            Some(t) => total = t,
+            None    => return None // missing number or overflow
+        }
+    }
+
+    // trailing numbers without a unit aren't allowed
+    if num.is_some() || s.trim().is_empty() {
+        return None;
+    }
+    Some(time::Duration::from_secs(total))
+}
+
+/// Number of days between the Unix epoch and the given civil date
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parse a point in time, relative to `now`
+///
+/// Accepts either a duration in the past (see `parse_duration`), a UTC date in
+/// the form `YYYY-MM-DD` optionally followed by `HH:MM` or `HH:MM:SS`, or a
+/// Unix timestamp prefixed with `@`.
+pub fn parse_time(s: &str, now: time::SystemTime) -> Option<time::SystemTime> {
+    let s = s.trim();
+    if let Some(d) = parse_duration(s) {
+        return match now.duration_since(time::UNIX_EPOCH) {
+            Ok(n) if n >= d => Some(now - d),
+            _               => None
+        };
+    }
+    if s.starts_with("@") {
+        return s[1..].parse::<u64>().ok()
+            .map(|t| time::UNIX_EPOCH + time::Duration::from_secs(t));
+    }
+
+    // split into date and optional time-of-day
+    let mut parts = s.splitn(2, |c| c == ' ' || c == 'T');
+    let date: Vec<i64> = match parts.next().unwrap_or("").split('-')
+                                    .map(|x| x.parse::<i64>().ok())
+                                    .collect::<Option<Vec<i64>>>() {
+        Some(ref d) if d.len() == 3 => d.clone(),
+        _                           => return None
+    };
+    let tod: Vec<i64> = match parts.next() {
+        None    => vec![0, 0, 0],
+        Some(t) => match t.split(':').map(|x| x.parse::<i64>().ok())
+                          .collect::<Option<Vec<i64>>>() {
+            Some(ref t) if t.len() == 2 => vec![t[0], t[1], 0],
+            Some(ref t) if t.len() == 3 => t.clone(),
+            _                           => return None
+        }
+    };
+
+    if date[1] < 1 || date[1] > 12 || date[2] < 1 || date[2] > 31 ||
+            tod[0] > 23 || tod[1] > 59 || tod[2] > 60 ||
+            tod.iter().any(|&x| x < 0) {
+        return None;
+    }
+
+    let secs = days_from_civil(date[0], date[1], date[2]) * 86400 +
+               tod[0] * 3600 + tod[1] * 60 + tod[2];
+    if secs < 0 {
+        return None;
+    }
+    Some(time::UNIX_EPOCH + time::Duration::from_secs(secs as u64))
+}
+
+/// Convert a number of days since the Unix epoch to a civil date (proleptic
+/// Gregorian), as a year, month and day
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Seconds since the Unix epoch, clamping earlier times to the epoch
+fn epoch_secs(t: time::SystemTime) -> i64 {
+    match t.duration_since(time::UNIX_EPOCH) {
+        Ok(d)  => d.as_secs() as i64,
+        Err(_) => 0
+    }
+}
+
+/// Format a point in time as a UTC date and time
+pub fn format_time(t: time::SystemTime) -> String {
+    let secs = epoch_secs(t);
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days(secs / 86400);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year, month, day, rem / 3600, (rem / 60) % 60, rem % 60)
+}
+
+/// The UTC calendar periods a point in time falls in, each numbered so that
+/// later periods get larger numbers: its day, its week (starting on Monday)
+/// and its month
+pub fn calendar_periods(t: time::SystemTime) -> (i64, i64, i64) {
+    let days = epoch_secs(t) / 86400;
+    let (year, month, _) = civil_from_days(days);
+
+    // the epoch was a Thursday
+    (days, (days + 3) / 7, year * 12 + month - 1)
+}
+
+/// Format UNIX permission bits the way `ls -l` does, e.g. `rwxr-xr-x`
+pub fn format_mode(mode: u32) -> String {
+    let mut s = String::with_capacity(9);
+    for i in 0..3 {
+        let bits = (mode >> (6 - 3 * i)) & 0o7;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+
+        // setuid, setgid and sticky bits replace the execute flags
+        let special = mode & (0o4000 >> i) != 0;
+        let exec = bits & 1 != 0;
+        s.push(match (special, exec, i) {
+            (true, true, 2)  => 't',
+            (true, false, 2) => 'T',
+            (true, true, _)  => 's',
+            (true, false, _) => 'S',
+            (false, true, _) => 'x',
+            (false, false, _) => '-'
+        });
+    }
+    s
+}
+
 #[test]
 fn tohex_test() { // make sure the ToHex trait works properly
     let v: Vec<u8> = vec![1,2,3,4,5,6,250,251,252,253];
@@ -89,3 +289,84 @@ fn sha256_test() { // make sure the Hasher works properly
     }
     assert!(v.len() == 75);
 }
+
+#[test]
+fn format_size_test() {
+    assert_eq!(format_size(0), "0 B");
+    assert_eq!(format_size(1023), "1023 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+}
+
+#[test]
+fn parse_size_test() {
+    assert_eq!(parse_size("512"), Some(512));
+    assert_eq!(parse_size("64k"), Some(64 * 1024));
+    assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+    assert_eq!(parse_size("1g"), Some(1 << 30));
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("k"), None);
+    assert_eq!(parse_size("-5"), None);
+    assert_eq!(parse_size("3x"), None);
+}
+
+#[test]
+fn format_mode_test() {
+    assert_eq!(format_mode(0o755), "rwxr-xr-x");
+    assert_eq!(format_mode(0o640), "rw-r-----");
+    assert_eq!(format_mode(0o4755), "rwsr-xr-x");
+    assert_eq!(format_mode(0o1777), "rwxrwxrwt");
+    assert_eq!(format_mode(0o2644), "rw-r-Sr--");
+}
+
+#[test]
+fn format_time_test() {
+    let t = time::UNIX_EPOCH + time::Duration::from_secs(0);
+    assert_eq!(format_time(t), "1970-01-01 00:00:00 UTC");
+    let t = time::UNIX_EPOCH + time::Duration::from_secs(951782400 + 3661);
+    assert_eq!(format_time(t), "2000-02-29 01:01:01 UTC");
+}
+
+#[test]
+fn calendar_periods_test() {
+    let at = |s: &str| parse_time(s, time::UNIX_EPOCH).unwrap();
+    let (d1, w1, m1) = calendar_periods(at("2017-07-30 23:59"));  // Sunday
+    let (d2, w2, m2) = calendar_periods(at("2017-07-31 00:00"));  // Monday
+    let (d3, w3, m3) = calendar_periods(at("2017-08-01 12:00"));
+    assert_eq!((d2 - d1, d3 - d2), (1, 1));
+    assert_eq!((w2 - w1, w3 - w2), (1, 0));
+    assert_eq!((m2 - m1, m3 - m2), (0, 1));
+}
+
+#[test]
+fn parse_duration_test() {
+    use std::time::Duration;
+    assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+    assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+    assert_eq!(parse_duration("2d"), Some(Duration::from_secs(2 * 86400)));
+    assert_eq!(parse_duration("1w1d1h"),
+               Some(Duration::from_secs(8 * 86400 + 3600)));
+    assert_eq!(parse_duration(""), None);
+    assert_eq!(parse_duration("12"), None);
+    assert_eq!(parse_duration("3x"), None);
+    assert_eq!(parse_duration("h"), None);
+}
+
+#[test]
+fn parse_time_test() {
+    use std::time::Duration;
+    let now = time::UNIX_EPOCH + Duration::from_secs(1000000);
+
+    assert_eq!(parse_time("1h", now),
+               Some(time::UNIX_EPOCH + Duration::from_secs(1000000 - 3600)));
+    assert_eq!(parse_time("@12345", now),
+               Some(time::UNIX_EPOCH + Duration::from_secs(12345)));
+    assert_eq!(parse_time("1970-01-02", now),
+               Some(time::UNIX_EPOCH + Duration::from_secs(86400)));
+    assert_eq!(parse_time("2000-02-29 01:01:01", now),
+               Some(time::UNIX_EPOCH + Duration::from_secs(951782400 + 3661)));
+    assert_eq!(parse_time("2000-02-29T01:01", now),
+               Some(time::UNIX_EPOCH + Duration::from_secs(951782400 + 3660)));
+    assert_eq!(parse_time("2000-13-01", now), None);
+    assert_eq!(parse_time("yesterday", now), None);
+}